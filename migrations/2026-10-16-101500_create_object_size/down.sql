@@ -0,0 +1 @@
+drop table if exists object_size cascade;
\ No newline at end of file
@@ -0,0 +1,12 @@
+create table object_size (
+    audience text not null,
+    bucket text not null,
+    object text not null,
+    size int8 not null,
+
+    updated_at timestamptz not null default now(),
+
+    primary key (bucket, object)
+);
+
+create index object_size_audience_idx on object_size (audience);
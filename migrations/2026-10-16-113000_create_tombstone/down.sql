@@ -0,0 +1 @@
+drop table if exists tombstone cascade;
\ No newline at end of file
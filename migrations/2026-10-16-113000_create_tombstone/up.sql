@@ -0,0 +1,13 @@
+create table tombstone (
+    audience text not null,
+    backend text not null,
+    bucket text not null,
+    object text not null,
+
+    deleted_at timestamptz not null default now(),
+    purge_at timestamptz not null,
+
+    primary key (backend, bucket, object)
+);
+
+create index tombstone_purge_at_idx on tombstone (purge_at);
@@ -0,0 +1 @@
+drop table if exists share cascade;
\ No newline at end of file
@@ -0,0 +1,16 @@
+create table share (
+    token text not null,
+    backend text not null,
+    bucket text not null,
+    object text not null,
+    created_by text not null,
+
+    max_downloads int8,
+    downloads int8 not null default 0,
+    created_at timestamptz not null default now(),
+    expires_at timestamptz not null,
+
+    primary key (token)
+);
+
+create index share_expires_at_idx on share (expires_at);
@@ -0,0 +1,344 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures::{Async, Future, Poll};
+use http::{Request, Response};
+use log::info;
+use serde_json::{Map, Value};
+use tower_service::Service;
+use tower_web::middleware::Middleware;
+
+use crate::app::util::{extract_subject, AudienceEstimator, S3_DEFAULT_CLIENT};
+
+////////////////////////////////////////////////////////////////////////////////
+
+const TARGET: &str = "storage::http";
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AccessLogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AccessLogField {
+    Method,
+    Path,
+    Status,
+    LatencyMs,
+    Audience,
+    Backend,
+    RequestId,
+    Subject,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct AccessLogConfig {
+    #[serde(default = "AccessLogConfig::default_format")]
+    format: AccessLogFormat,
+    #[serde(default = "AccessLogConfig::default_fields")]
+    fields: Vec<AccessLogField>,
+}
+
+impl AccessLogConfig {
+    fn default_format() -> AccessLogFormat {
+        AccessLogFormat::Text
+    }
+
+    fn default_fields() -> Vec<AccessLogField> {
+        vec![
+            AccessLogField::Method,
+            AccessLogField::Path,
+            AccessLogField::Status,
+            AccessLogField::LatencyMs,
+        ]
+    }
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            format: Self::default_format(),
+            fields: Self::default_fields(),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Default)]
+struct AccessLogRecord {
+    method: String,
+    path: String,
+    status: u16,
+    latency_ms: u64,
+    audience: Option<String>,
+    backend: Option<String>,
+    request_id: Option<String>,
+    subject: Option<String>,
+}
+
+impl AccessLogRecord {
+    fn to_text(&self) -> String {
+        format!(
+            "\"{} {}\" {} {}ms",
+            self.method, self.path, self.status, self.latency_ms
+        )
+    }
+
+    fn to_json(&self, fields: &[AccessLogField]) -> String {
+        let mut acc = Map::new();
+        for field in fields {
+            let (key, val) = match field {
+                AccessLogField::Method => ("method", Value::from(self.method.clone())),
+                AccessLogField::Path => ("path", Value::from(self.path.clone())),
+                AccessLogField::Status => ("status", Value::from(self.status)),
+                AccessLogField::LatencyMs => ("latency_ms", Value::from(self.latency_ms)),
+                AccessLogField::Audience => ("audience", optional(&self.audience)),
+                AccessLogField::Backend => ("backend", optional(&self.backend)),
+                AccessLogField::RequestId => ("request_id", optional(&self.request_id)),
+                AccessLogField::Subject => ("subject", optional(&self.subject)),
+            };
+            acc.insert(key.to_owned(), val);
+        }
+        Value::Object(acc).to_string()
+    }
+}
+
+fn optional(val: &Option<String>) -> Value {
+    val.clone().map(Value::from).unwrap_or(Value::Null)
+}
+
+fn path_param<'a>(path: &'a str, name: &str) -> Option<&'a str> {
+    let mut segments = path.split('/');
+    while let Some(segment) = segments.next() {
+        if segment == name {
+            return segments.next().filter(|val| !val.is_empty());
+        }
+    }
+    None
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+struct State {
+    config: AccessLogConfig,
+    aud_estm: Arc<AudienceEstimator>,
+    authn: svc_authn::jose::ConfigMap,
+    anonymous_audience: String,
+}
+
+impl State {
+    fn record<B>(&self, req: &Request<B>) -> AccessLogRecord {
+        let path = req.uri().path();
+        let fields = &self.config.fields;
+
+        let audience = if fields.contains(&AccessLogField::Audience) {
+            path_param(path, "sets")
+                .or_else(|| path_param(path, "tags"))
+                .and_then(|set| self.aud_estm.parse_set(set).ok())
+                .map(|set| set.bucket().audience().to_owned())
+                .or_else(|| {
+                    path_param(path, "buckets")
+                        .and_then(|bucket| self.aud_estm.estimate(bucket).ok())
+                        .map(ToOwned::to_owned)
+                })
+        } else {
+            None
+        };
+
+        let backend = match path_param(path, "backends") {
+            Some(back) => Some(back.to_owned()),
+            None if path.starts_with("/api/") => Some(S3_DEFAULT_CLIENT.to_owned()),
+            None => None,
+        };
+
+        let request_id = req
+            .headers()
+            .get("x-request-id")
+            .and_then(|val| val.to_str().ok())
+            .map(ToOwned::to_owned);
+
+        let subject = if fields.contains(&AccessLogField::Subject) {
+            extract_subject(req, &self.authn, &self.anonymous_audience)
+                .ok()
+                .map(|sub| sub.to_string())
+        } else {
+            None
+        };
+
+        AccessLogRecord {
+            method: req.method().to_string(),
+            path: path.to_owned(),
+            audience,
+            backend,
+            request_id,
+            subject,
+            ..Default::default()
+        }
+    }
+
+    fn log(&self, record: &AccessLogRecord) {
+        match self.config.format {
+            AccessLogFormat::Text => info!(target: TARGET, "{}", record.to_text()),
+            AccessLogFormat::Json => {
+                info!(target: TARGET, "{}", record.to_json(&self.config.fields))
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+pub(crate) struct AccessLogMiddleware {
+    state: Arc<State>,
+}
+
+impl AccessLogMiddleware {
+    pub(crate) fn new(
+        config: AccessLogConfig,
+        aud_estm: Arc<AudienceEstimator>,
+        authn: svc_authn::jose::ConfigMap,
+        anonymous_audience: &str,
+    ) -> Self {
+        let state = State {
+            config,
+            aud_estm,
+            authn,
+            anonymous_audience: anonymous_audience.to_owned(),
+        };
+
+        Self {
+            state: Arc::new(state),
+        }
+    }
+}
+
+impl<S, RequestBody, ResponseBody> Middleware<S> for AccessLogMiddleware
+where
+    S: Service<Request = Request<RequestBody>, Response = Response<ResponseBody>>,
+{
+    type Request = Request<RequestBody>;
+    type Response = Response<ResponseBody>;
+    type Error = S::Error;
+    type Service = AccessLogService<S>;
+
+    fn wrap(&self, inner: S) -> Self::Service {
+        AccessLogService {
+            inner,
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AccessLogService<S> {
+    inner: S,
+    state: Arc<State>,
+}
+
+impl<S, RequestBody, ResponseBody> Service for AccessLogService<S>
+where
+    S: Service<Request = Request<RequestBody>, Response = Response<ResponseBody>>,
+{
+    type Request = Request<RequestBody>;
+    type Response = Response<ResponseBody>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: Self::Request) -> Self::Future {
+        let record = self.state.record(&req);
+
+        ResponseFuture {
+            inner: self.inner.call(req),
+            state: self.state.clone(),
+            record: Some(record),
+            start: Instant::now(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ResponseFuture<F> {
+    inner: F,
+    state: Arc<State>,
+    record: Option<AccessLogRecord>,
+    start: Instant,
+}
+
+impl<F, ResponseBody> Future for ResponseFuture<F>
+where
+    F: Future<Item = Response<ResponseBody>>,
+{
+    type Item = Response<ResponseBody>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let resp = match self.inner.poll()? {
+            Async::Ready(resp) => resp,
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+
+        if let Some(mut record) = self.record.take() {
+            record.status = resp.status().as_u16();
+            record.latency_ms = self.start.elapsed().as_millis() as u64;
+            self.state.log(&record);
+        }
+
+        Ok(Async::Ready(resp))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_contains_configured_fields() {
+        let record = AccessLogRecord {
+            method: "GET".to_owned(),
+            path: "/api/v2/sets/data.example.org::foo/objects/bar".to_owned(),
+            status: 303,
+            latency_ms: 12,
+            audience: Some("example.org".to_owned()),
+            backend: Some("default".to_owned()),
+            request_id: None,
+            subject: Some("john.usr.example.net".to_owned()),
+        };
+
+        let fields = vec![
+            AccessLogField::Method,
+            AccessLogField::Status,
+            AccessLogField::Audience,
+            AccessLogField::RequestId,
+        ];
+        let line: Value = serde_json::from_str(&record.to_json(&fields)).expect("Invalid JSON");
+        let obj = line.as_object().expect("Expected a JSON object");
+
+        assert_eq!(obj.len(), 4);
+        assert_eq!(obj["method"], "GET");
+        assert_eq!(obj["status"], 303);
+        assert_eq!(obj["audience"], "example.org");
+        assert_eq!(obj["request_id"], Value::Null);
+        assert!(!obj.contains_key("subject"));
+    }
+
+    #[test]
+    fn path_param_lookup() {
+        let path = "/api/v2/backends/alt/sets/data.example.org::foo/objects/bar";
+        assert_eq!(path_param(path, "backends"), Some("alt"));
+        assert_eq!(path_param(path, "sets"), Some("data.example.org::foo"));
+        assert_eq!(path_param(path, "buckets"), None);
+        assert_eq!(path_param("/api/v1/backends", "backends"), None);
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Loop};
+use futures::Future;
+use log::warn;
+use svc_authn::Authenticable;
+use tokio_timer::Delay;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct AuthzRetryConfig {
+    #[serde(default = "AuthzRetryConfig::default_attempts")]
+    attempts: usize,
+    #[serde(default = "AuthzRetryConfig::default_backoff_ms")]
+    backoff_ms: u64,
+}
+
+impl AuthzRetryConfig {
+    fn default_attempts() -> usize {
+        2
+    }
+
+    fn default_backoff_ms() -> u64 {
+        100
+    }
+}
+
+// Without the config section, authz requests aren't retried
+impl Default for AuthzRetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 0,
+            backoff_ms: Self::default_backoff_ms(),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug)]
+pub(crate) struct AuthzClient {
+    inner: svc_authz::ClientMap,
+    config: AuthzRetryConfig,
+    audiences: BTreeMap<String, String>,
+}
+
+impl AuthzClient {
+    pub(crate) fn new(inner: svc_authz::ClientMap, config: AuthzRetryConfig) -> Self {
+        Self {
+            inner,
+            config,
+            audiences: BTreeMap::new(),
+        }
+    }
+
+    // Audiences estimated by buckets are passed to the authz service under these names,
+    // settings of audiences are still looked up by the estimated ones
+    pub(crate) fn set_audiences(&mut self, audiences: &BTreeMap<String, String>) -> &mut Self {
+        self.audiences = audiences.clone();
+        self
+    }
+
+    pub(crate) fn authorize<A>(
+        &self,
+        audience: &str,
+        subject: &A,
+        object: Vec<&str>,
+        action: &str,
+    ) -> impl Future<Item = Result<(), svc_authz::Error>, Error = ()>
+    where
+        A: Authenticable + Clone,
+    {
+        let inner = self.inner.clone();
+        let audience = authz_audience(&self.audiences, audience).to_owned();
+        let subject = subject.clone();
+        let object = object
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect::<Vec<String>>();
+        let action = action.to_owned();
+
+        retry(
+            self.config.attempts,
+            Duration::from_millis(self.config.backoff_ms),
+            transient,
+            move || {
+                let object = object.iter().map(String::as_str).collect();
+                inner
+                    .authorize(&audience, &subject, object, &action)
+                    .map(|result| result.map(|_| ()))
+            },
+        )
+    }
+}
+
+fn authz_audience<'a>(audiences: &'a BTreeMap<String, String>, audience: &'a str) -> &'a str {
+    audiences
+        .get(audience)
+        .map(String::as_str)
+        .unwrap_or(audience)
+}
+
+// Only failures to reach the authz service are worth another attempt. A denial is the answer of the policy,
+// other errors would be the same again
+fn transient(err: &svc_authz::Error) -> bool {
+    matches!(err.kind(), svc_authz::ErrorKind::Network(_))
+}
+
+fn retry<F, R, T, E>(
+    attempts: usize,
+    backoff: Duration,
+    retryable: fn(&E) -> bool,
+    request: F,
+) -> impl Future<Item = Result<T, E>, Error = ()>
+where
+    F: Fn() -> R,
+    R: Future<Item = Result<T, E>, Error = ()>,
+    E: Display,
+{
+    future::loop_fn(0, move |attempt| {
+        request().and_then(move |result| match result {
+            Err(ref err) if attempt < attempts && retryable(err) => {
+                // The backoff doubles with every attempt
+                let delay = backoff * 2u32.pow(attempt.min(16) as u32);
+                warn!(
+                    "Authz request failed, retrying in {} ms: {}",
+                    delay.as_millis(),
+                    err
+                );
+                future::Either::A(
+                    Delay::new(Instant::now() + delay)
+                        .then(move |_| Ok(Loop::Continue(attempt + 1))),
+                )
+            }
+            result => future::Either::B(future::ok(Loop::Break(result))),
+        })
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use tokio::runtime::current_thread::Runtime;
+
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum MockError {
+        Network,
+        Forbidden,
+    }
+
+    impl Display for MockError {
+        fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(fmt, "{:?}", self)
+        }
+    }
+
+    fn mock_transient(err: &MockError) -> bool {
+        *err == MockError::Network
+    }
+
+    fn authorize(responses: Vec<Result<(), MockError>>) -> (Result<(), MockError>, usize) {
+        let calls = Cell::new(0);
+        let responses = RefCell::new(responses.into_iter());
+        let result = Runtime::new()
+            .expect("Error creating a runtime")
+            .block_on(retry(3, Duration::from_millis(1), mock_transient, || {
+                calls.set(calls.get() + 1);
+                let response = responses
+                    .borrow_mut()
+                    .next()
+                    .expect("Unexpected authz request");
+                future::ok(response)
+            }))
+            .expect("Error authorizing");
+        (result, calls.get())
+    }
+
+    #[test]
+    fn transport_errors_retried() {
+        let (result, calls) = authorize(vec![
+            Err(MockError::Network),
+            Err(MockError::Network),
+            Ok(()),
+        ]);
+        assert_eq!(result, Ok(()));
+        assert_eq!(calls, 3);
+
+        let (result, calls) = authorize(vec![Err(MockError::Network); 4]);
+        assert_eq!(result, Err(MockError::Network));
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn denial_not_retried() {
+        let (result, calls) = authorize(vec![Err(MockError::Forbidden), Ok(())]);
+        assert_eq!(result, Err(MockError::Forbidden));
+        assert_eq!(calls, 1);
+    }
+
+    // Errors of real authz clients: "example.org" is unreachable, "example.net" denies everything
+    fn authz_error(audience: &str) -> svc_authz::Error {
+        let id = svc_authn::AccountId::new("storage", "svc.example.org");
+        let config = serde_json::from_value(serde_json::json!({
+            "example.org": {
+                "type": "http",
+                "uri": "http://127.0.0.1:1/authz",
+                "algorithm": "ES256",
+                "key": "data/keys/svc.private_key.p8.der.sample",
+            },
+            "example.net": { "type": "local", "trusted": [] },
+        }))
+        .expect("Error parsing authz config");
+        let authz =
+            svc_authz::ClientMap::new(&id, None, config).expect("Error creating authz clients");
+        let subject = svc_authn::AccountId::new("john", "usr.example.org");
+
+        Runtime::new()
+            .expect("Error creating a runtime")
+            .block_on(
+                authz
+                    .authorize(
+                        audience,
+                        &subject,
+                        vec!["sets", "data.example.org::foo"],
+                        "read",
+                    )
+                    .map(|result| result.map(|_| ())),
+            )
+            .expect("Error authorizing")
+            .expect_err("Expected authz to fail")
+    }
+
+    #[test]
+    fn transient_errors_classified() {
+        // Network
+        assert!(transient(&authz_error("example.org")));
+        // Forbidden
+        assert!(!transient(&authz_error("example.net")));
+        // Audiences without authz configured
+        assert!(!transient(&authz_error("example.com")));
+    }
+
+    #[test]
+    fn audience_mapped() {
+        let mut audiences = BTreeMap::new();
+        audiences.insert("example.org.staging".to_owned(), "example.org".to_owned());
+
+        assert_eq!(
+            authz_audience(&audiences, "example.org.staging"),
+            "example.org"
+        );
+        assert_eq!(authz_audience(&audiences, "example.net"), "example.net");
+    }
+}
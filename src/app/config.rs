@@ -1,6 +1,9 @@
 use std::collections::BTreeMap;
 
+use regex::Regex;
+use unicode_normalization::UnicodeNormalization;
 use url::Url;
+use uuid::Uuid;
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct Config {
@@ -10,24 +13,269 @@ pub(crate) struct Config {
     pub(crate) authz: svc_authz::ConfigMap,
     pub(crate) http: crate::app::HttpConfig,
     pub(crate) audiences_settings: BTreeMap<String, AudienceSettings>,
+    pub(crate) allowed_audiences: Option<Vec<String>>,
+    pub(crate) authz_warmup: Option<crate::app::warmup::WarmupConfig>,
+    pub(crate) authz_retry: Option<crate::app::authz_retry::AuthzRetryConfig>,
+    pub(crate) self_test: Option<crate::app::self_test::SelfTestConfig>,
+    #[serde(default)]
+    pub(crate) method_actions: BTreeMap<String, String>,
+    #[serde(default)]
+    pub(crate) authz_audiences: BTreeMap<String, String>,
 }
 
 pub(crate) fn load() -> Result<Config, config::ConfigError> {
+    parser()?.try_into::<Config>()
+}
+
+// The configuration as parsed from all of its sources, with values of secret fields redacted
+pub(crate) fn load_redacted() -> Result<serde_json::Value, config::ConfigError> {
+    let mut value = parser()?.try_into::<serde_json::Value>()?;
+    redact(&mut value);
+    Ok(value)
+}
+
+fn parser() -> Result<config::Config, config::ConfigError> {
     let mut parser = config::Config::default();
     parser.merge(config::File::with_name("App"))?;
     parser.merge(config::Environment::with_prefix("APP").separator("__"))?;
-    parser.try_into::<Config>()
+    Ok(parser)
 }
 
-#[derive(Clone, Debug, Deserialize)]
+const REDACTED: &str = "***";
+
+// Fields are told secret by their names, since sections of other crates hold secrets of their own
+fn secret_field(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name == "key"
+        || name.ends_with("_key")
+        || name.contains("access_key")
+        || name.contains("secret")
+        || name.contains("password")
+        || name.contains("token")
+}
+
+fn redact(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (name, val) in map.iter_mut() {
+                if secret_field(name) && !val.is_null() {
+                    *val = serde_json::Value::from(REDACTED);
+                } else {
+                    redact(val);
+                }
+            }
+        }
+        serde_json::Value::Array(vals) => vals.iter_mut().for_each(redact),
+        _ => (),
+    }
+}
+
+// Values of secret settings are kept out of the Debug output, so that they never end up in logs
+#[derive(Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct Secret(String);
+
+impl Secret {
+    pub(crate) fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str(REDACTED)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
 pub(crate) struct AudienceSettings {
     allowed_referers: Option<Vec<String>>,
+    #[serde(default)]
+    require_referer: bool,
+    allowed_content_types: Option<Vec<String>>,
+    set_label: Option<SetLabelSettings>,
+    default_object: Option<String>,
+    #[serde(default)]
+    require_content_length: bool,
+    #[serde(default)]
+    case_insensitive_objects: bool,
+    allowed_response_overrides: Option<Vec<String>>,
+    max_response_overrides: Option<usize>,
+    #[serde(default)]
+    object_key_encoding: ObjectKeyEncoding,
+    download_max_size: Option<u64>,
+    #[serde(default)]
+    escape_object_separator: bool,
+    #[serde(default)]
+    subject_scoped_objects: bool,
+    #[serde(default)]
+    default_headers: BTreeMap<String, String>,
+    #[serde(default)]
+    default_headers_conflict: HeaderConflictPolicy,
+    max_object_depth: Option<usize>,
+    capability_key: Option<Secret>,
+    #[serde(default)]
+    reject_set_prefix: bool,
+    #[serde(default, deserialize_with = "crate::serde::optional_regex")]
+    object_pattern: Option<Regex>,
+    min_expires_in: Option<u64>,
+    #[serde(default)]
+    min_expires_in_policy: ExpiryPolicy,
+    #[serde(default)]
+    rewrite_rules: Vec<RewriteRule>,
+    #[serde(default)]
+    worm: bool,
+    #[serde(default)]
+    proxy_reads: bool,
+    inline_content_types: Option<Vec<String>>,
+    #[serde(default)]
+    strict_audience: bool,
+    usage_sample_rate: Option<f64>,
+    page_size: Option<u64>,
+    max_page_size: Option<u64>,
+    soft_delete_retention: Option<u64>,
+    #[serde(default)]
+    sharing: bool,
+    subject_header: Option<String>,
+    #[serde(default)]
+    reserved_prefixes: Vec<String>,
+    default_storage_class: Option<String>,
+    allowed_storage_classes: Option<Vec<String>>,
+}
+
+const STORAGE_CLASSES: &[&str] = &[
+    "STANDARD",
+    "REDUCED_REDUNDANCY",
+    "STANDARD_IA",
+    "ONEZONE_IA",
+    "INTELLIGENT_TIERING",
+    "GLACIER",
+    "GLACIER_IR",
+    "DEEP_ARCHIVE",
+];
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct RewriteRule {
+    #[serde(deserialize_with = "crate::serde::regex")]
+    pattern: Regex,
+    replacement: String,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExpiryPolicy {
+    // A shorter expiration time is raised to the minimum
+    Clamp,
+    // A shorter expiration time is rejected
+    Reject,
+}
+
+impl Default for ExpiryPolicy {
+    fn default() -> Self {
+        ExpiryPolicy::Clamp
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HeaderConflictPolicy {
+    // A value provided by the client replaces the default one
+    Client,
+    // A value provided by the client that differs from the default one is rejected
+    Reject,
+}
+
+impl Default for HeaderConflictPolicy {
+    fn default() -> Self {
+        HeaderConflictPolicy::Client
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ObjectKeyEncoding {
+    // Object names are kept as is, the signer percent-encodes them according to RFC 3986
+    Strict,
+    // Unicode names are brought to the canonical composition (NFC)
+    Nfc,
+    // Non-ASCII names are transliterated, what is left is percent-encoded
+    Transliterate,
+}
+
+impl Default for ObjectKeyEncoding {
+    fn default() -> Self {
+        ObjectKeyEncoding::Strict
+    }
+}
+
+const DEFAULT_RESPONSE_OVERRIDES: &[&str] = &[
+    "response-cache-control",
+    "response-content-disposition",
+    "response-content-type",
+];
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct SetLabelSettings {
+    #[serde(default = "SetLabelSettings::default_max_length")]
+    max_length: usize,
+    #[serde(default = "SetLabelSettings::default_allowed_symbols")]
+    allowed_symbols: String,
+    #[serde(default)]
+    schemes: Vec<SetIdScheme>,
+}
+
+impl SetLabelSettings {
+    fn default_max_length() -> usize {
+        64
+    }
+
+    fn default_allowed_symbols() -> String {
+        String::from("-_")
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SetIdScheme {
+    // Decimal digits, e.g. "123"
+    Integer,
+    // Hyphenated UUID, e.g. "67e55044-10b1-426f-9247-bb680e5fe0c8"
+    Uuid,
+    // A name ending with a hyphenated UUID, e.g. "avatar-67e55044-10b1-426f-9247-bb680e5fe0c8"
+    UuidSuffix,
+}
+
+impl SetIdScheme {
+    fn matches(self, label: &str) -> bool {
+        const UUID_LENGTH: usize = 36;
+
+        match self {
+            SetIdScheme::Integer => !label.is_empty() && label.chars().all(|c| c.is_ascii_digit()),
+            SetIdScheme::Uuid => label.len() == UUID_LENGTH && Uuid::parse_str(label).is_ok(),
+            SetIdScheme::UuidSuffix => {
+                let split = label.len().wrapping_sub(UUID_LENGTH);
+                label.len() > UUID_LENGTH
+                    && label.is_char_boundary(split)
+                    && Uuid::parse_str(&label[split..]).is_ok()
+            }
+        }
+    }
+}
+
+// Opaque origins, such as "null" of sandboxed pages, and values that aren't URIs
+// don't tell where the request came from
+fn web_origin(referer: &str) -> bool {
+    Url::parse(referer)
+        .map(|url| (url.scheme() == "http" || url.scheme() == "https") && url.host().is_some())
+        .unwrap_or(false)
 }
 
 impl AudienceSettings {
     pub fn valid_referer(&self, referer: Option<&str>) -> bool {
         match (&self.allowed_referers, referer) {
-            (None, _) => true,
+            (None, None) => !self.require_referer,
+            // Strict mode requires the request to come from a web page, even if any one is allowed
+            (None, Some(referer)) => !self.require_referer || web_origin(referer),
             (Some(_), None) => false,
             (Some(referers), Some(referer)) => {
                 if let Some(host) = Url::parse(referer)
@@ -47,16 +295,345 @@ impl AudienceSettings {
             }
         }
     }
+
+    // Trusted backends can't send a referer, a valid capability stands in for it
+    pub fn valid_caller(
+        &self,
+        scope: &crate::app::util::CapabilityScope,
+        referer: Option<&str>,
+        capability: Option<&str>,
+    ) -> bool {
+        self.valid_caller_at(scope, referer, capability, chrono::Utc::now().timestamp())
+    }
+
+    fn valid_caller_at(
+        &self,
+        scope: &crate::app::util::CapabilityScope,
+        referer: Option<&str>,
+        capability: Option<&str>,
+        now: i64,
+    ) -> bool {
+        let trusted = match (&self.capability_key, capability) {
+            (Some(key), Some(capability)) => {
+                crate::app::util::verify_capability(key.expose(), scope, capability, now)
+            }
+            _ => false,
+        };
+
+        trusted || self.valid_referer(referer)
+    }
+
+    // A backend may host buckets of several audiences, clients of strict ones must name the audience they expect
+    pub fn valid_expected_audience(&self, audience: &str, expected: Option<&str>) -> bool {
+        !self.strict_audience || expected == Some(audience)
+    }
+
+    // Objects of per-user layouts live under the account of the subject, whatever name the client asks for
+    pub fn scope_object(&self, account_id: &str, object: &str) -> String {
+        if self.subject_scoped_objects {
+            format!("{}/{}", account_id, object)
+        } else {
+            object.to_owned()
+        }
+    }
+
+    pub fn normalize_object(&self, object: &str) -> String {
+        let object = match self.object_key_encoding {
+            ObjectKeyEncoding::Strict => object.to_owned(),
+            ObjectKeyEncoding::Nfc => object.nfc().collect(),
+            ObjectKeyEncoding::Transliterate => crate::app::util::transliterate(object),
+        };
+
+        let object = if self.case_insensitive_objects {
+            object.to_lowercase()
+        } else {
+            object
+        };
+
+        // The key is split at the last separator, so the object must not contain one
+        if self.escape_object_separator {
+            object.replace('%', "%25").replace('.', "%2E")
+        } else {
+            object
+        }
+    }
+
+    // Header names of the request are expected to be lowercase, the name of a rejected header is returned on conflict
+    pub fn merge_default_headers(
+        &self,
+        mut headers: BTreeMap<String, String>,
+    ) -> Result<BTreeMap<String, String>, String> {
+        for (key, val) in &self.default_headers {
+            let key = key.to_lowercase();
+            match headers.get(&key) {
+                None => {
+                    headers.insert(key, val.to_owned());
+                }
+                Some(prev)
+                    if prev != val
+                        && self.default_headers_conflict == HeaderConflictPolicy::Reject =>
+                {
+                    return Err(key);
+                }
+                Some(_) => (),
+            }
+        }
+        Ok(headers)
+    }
+
+    // The subject is signed for backends identifying actors by a header, a client sending another one is rejected
+    pub fn merge_subject_header(
+        &self,
+        subject: &str,
+        mut headers: BTreeMap<String, String>,
+    ) -> Result<BTreeMap<String, String>, String> {
+        let key = match self.subject_header {
+            Some(ref key) => key.to_lowercase(),
+            None => return Ok(headers),
+        };
+
+        match headers.get(&key) {
+            Some(prev) if prev != subject => Err(key),
+            _ => {
+                headers.insert(key, subject.to_owned());
+                Ok(headers)
+            }
+        }
+    }
+
+    // Keys are stored as "set.object", so "bar.baz" of the set "foo" is also "baz" of the set "foo.bar"
+    // Only keys addressing a path within another set are rejected, e.g. "bar.baz/qux" of the set "foo",
+    // so that names with an extension like "readme.txt" are kept
+    pub fn valid_object_prefix(&self, object: &str) -> bool {
+        if !self.reject_set_prefix || self.escape_object_separator {
+            return true;
+        }
+
+        match object.find('.') {
+            Some(idx) => {
+                let (label, rest) = (&object[..idx], &object[idx + 1..]);
+                label.is_empty()
+                    || label.contains('/')
+                    || !rest.contains('/')
+                    || !self.valid_set_label(label)
+            }
+            None => true,
+        }
+    }
+
+    pub fn valid_object_pattern(&self, object: &str) -> bool {
+        match self.object_pattern {
+            None => true,
+            Some(ref pattern) => pattern.is_match(object),
+        }
+    }
+
+    // The requested storage class or the default one of the audience, the rejected class is returned
+    pub fn storage_class(&self, requested: Option<&str>) -> Result<Option<String>, String> {
+        let class = match requested.or_else(|| self.default_storage_class.as_deref()) {
+            Some(val) => val,
+            None => return Ok(None),
+        };
+
+        let allowed = match self.allowed_storage_classes {
+            Some(ref allowed) => allowed.iter().any(|val| val == class),
+            None => STORAGE_CLASSES.contains(&class),
+        };
+        if allowed {
+            Ok(Some(class.to_owned()))
+        } else {
+            Err(class.to_owned())
+        }
+    }
+
+    // Prefixes used by the service or operators themselves, the matching one is returned
+    pub fn reserved_prefix(&self, object: &str) -> Option<&str> {
+        self.reserved_prefixes
+            .iter()
+            .find(|prefix| {
+                if self.case_insensitive_objects {
+                    object.to_lowercase().starts_with(&prefix.to_lowercase())
+                } else {
+                    object.starts_with(prefix.as_str())
+                }
+            })
+            .map(String::as_str)
+    }
+
+    // Short expiration times fail on a small clock skew, the minimum is returned on rejection
+    pub fn expires_in(&self, expires_in: Option<u64>) -> Result<Option<u64>, u64> {
+        match (expires_in, self.min_expires_in) {
+            (Some(val), Some(min)) if val < min => match self.min_expires_in_policy {
+                ExpiryPolicy::Clamp => Ok(Some(min)),
+                ExpiryPolicy::Reject => Err(min),
+            },
+            (val, _) => Ok(val),
+        }
+    }
+
+    // Only the first matching rule is applied, so rules never chain
+    pub fn rewrite_object(&self, object: &str) -> String {
+        self.rewrite_rules
+            .iter()
+            .find(|rule| rule.pattern.is_match(object))
+            .map(|rule| {
+                rule.pattern
+                    .replace(object, rule.replacement.as_str())
+                    .into_owned()
+            })
+            .unwrap_or_else(|| object.to_owned())
+    }
+
+    pub fn default_object(&self) -> Option<&str> {
+        self.default_object.as_deref()
+    }
+
+    pub fn max_object_depth(&self) -> usize {
+        self.max_object_depth.unwrap_or(32)
+    }
+
+    pub fn download_max_size(&self) -> u64 {
+        self.download_max_size.unwrap_or(65536)
+    }
+
+    // Fraction of objects whose sizes are recorded when their metadata is fetched, none by default
+    pub fn usage_sample_rate(&self) -> Option<f64> {
+        self.usage_sample_rate
+    }
+
+    // Seconds deleted objects are kept for before they are purged, objects are deleted by clients without it
+    pub fn soft_delete_retention(&self) -> Option<u64> {
+        self.soft_delete_retention
+    }
+
+    // Objects are shared with anyone holding a token, without authn
+    pub fn sharing(&self) -> bool {
+        self.sharing
+    }
+
+    // Reads accepting application/octet-stream are served by the service itself, up to the download size
+    pub fn proxy_reads(&self) -> bool {
+        self.proxy_reads
+    }
+
+    // Requested page sizes of listings are clamped to the maximum, defaults of the listings apply
+    // without the settings, None leaves the page size to the listing itself
+    pub fn page_size(&self, requested: Option<u64>, default: Option<u64>, max: u64) -> Option<u64> {
+        let max = self.max_page_size.unwrap_or(max);
+        requested
+            .or(self.page_size)
+            .or(default)
+            .map(|size| size.min(max))
+    }
+
+    pub fn valid_set_label(&self, label: &str) -> bool {
+        match self.set_label {
+            None => true,
+            Some(ref settings) => {
+                !label.is_empty()
+                    && label.len() <= settings.max_length
+                    && label
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || settings.allowed_symbols.contains(c))
+                    // Audiences of mixed tenants list the schemes of set ids they accept, any by default
+                    && (settings.schemes.is_empty()
+                        || settings.schemes.iter().any(|scheme| scheme.matches(label)))
+            }
+        }
+    }
+
+    // Objects of compliance buckets are written once, existing ones are neither overwritten nor deleted
+    pub fn write_once(&self, method: &str) -> bool {
+        self.worm && (method == "PUT" || method == "DELETE")
+    }
+
+    pub fn valid_content_length(&self, method: &str, content_length: Option<u64>) -> bool {
+        !(self.require_content_length && method == "PUT" && content_length.is_none())
+    }
+
+    pub fn valid_response_override(&self, key: &str) -> bool {
+        match self.allowed_response_overrides {
+            None => DEFAULT_RESPONSE_OVERRIDES.contains(&key),
+            Some(ref overrides) => overrides.iter().any(|val| val == key),
+        }
+    }
+
+    // Every override is signed into the URI, so their number bounds the size of the signature,
+    // without the cap it's bounded only by the list of allowed overrides
+    pub fn valid_response_overrides_count(&self, count: usize) -> bool {
+        self.max_response_overrides.map_or(true, |max| count <= max)
+    }
+
+    pub fn valid_content_type(&self, content_type: Option<&str>) -> bool {
+        match (&self.allowed_content_types, content_type) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(content_types), Some(content_type)) => {
+                let media_type = content_type.split(';').next().unwrap_or("").trim();
+                content_types
+                    .iter()
+                    .any(|ct| ct.eq_ignore_ascii_case(media_type))
+            }
+        }
+    }
+
+    // Proxied objects of other content types are served as attachments, any is shown inline by default
+    pub fn inline_content_type(&self, content_type: &str) -> bool {
+        match self.inline_content_types {
+            None => true,
+            Some(ref content_types) => {
+                let media_type = content_type.split(';').next().unwrap_or("").trim();
+                content_types
+                    .iter()
+                    .any(|ct| ct.eq_ignore_ascii_case(media_type))
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn secrets_redacted() {
+        let mut value = serde_json::json!({
+            "id": "storage.svc.example.org",
+            "authn": { "iam.example.org": { "audience": ["usr.example.net"], "algorithm": "ES256", "key": "data/keys/iam.public_key.pem" } },
+            "authz": { "example.net": { "type": "http", "uri": "https://authz.example.org", "token": "secret" } },
+            "audiences_settings": { "example.net": { "capability_key": "secret", "object_key_encoding": "nfc" } },
+            "backend": { "alt": { "default": { "services": [{ "secret_access_key": "secret" }] } } }
+        });
+        redact(&mut value);
+
+        assert_eq!(value["id"], "storage.svc.example.org");
+        assert_eq!(value["authn"]["iam.example.org"]["key"], REDACTED);
+        assert_eq!(value["authn"]["iam.example.org"]["algorithm"], "ES256");
+        assert_eq!(value["authz"]["example.net"]["token"], REDACTED);
+        assert_eq!(
+            value["authz"]["example.net"]["uri"],
+            "https://authz.example.org"
+        );
+        assert_eq!(
+            value["audiences_settings"]["example.net"]["capability_key"],
+            REDACTED
+        );
+        assert_eq!(
+            value["audiences_settings"]["example.net"]["object_key_encoding"],
+            "nfc"
+        );
+        assert_eq!(
+            value["backend"]["alt"]["default"]["services"][0]["secret_access_key"],
+            REDACTED
+        );
+        assert!(!value.to_string().contains("\"secret\""));
+    }
+
     #[test]
     fn valid_referer_no_refs() {
         let s = AudienceSettings {
             allowed_referers: None,
+            ..Default::default()
         };
         assert_eq!(s.valid_referer(None), true);
         assert_eq!(s.valid_referer(Some("foobar")), true);
@@ -66,6 +643,7 @@ mod tests {
     fn valid_referer_no_referer() {
         let s = AudienceSettings {
             allowed_referers: Some(vec!["foo".into(), "bar".into(), "baz".into()]),
+            ..Default::default()
         };
         assert_eq!(s.valid_referer(None), false);
         assert_eq!(s.valid_referer(Some("http://foo")), true);
@@ -73,10 +651,48 @@ mod tests {
         assert_eq!(s.valid_referer(Some("https://quux")), false);
     }
 
+    #[test]
+    fn valid_referer_required() {
+        let s = AudienceSettings {
+            require_referer: true,
+            ..Default::default()
+        };
+        assert_eq!(s.valid_referer(None), false);
+        assert_eq!(s.valid_referer(Some("https://foo")), true);
+        assert_eq!(s.valid_referer(Some("http://foo/bar")), true);
+        assert_eq!(s.valid_referer(Some("foobar")), false);
+        assert_eq!(s.valid_referer(Some("null")), false);
+        assert_eq!(s.valid_referer(Some("")), false);
+        assert_eq!(s.valid_referer(Some("file:///etc/passwd")), false);
+        assert_eq!(s.valid_referer(Some("data:text/html,foo")), false);
+
+        let s = AudienceSettings {
+            allowed_referers: Some(vec!["foo".into()]),
+            require_referer: true,
+            ..Default::default()
+        };
+        assert_eq!(s.valid_referer(None), false);
+        assert_eq!(s.valid_referer(Some("https://foo")), true);
+        assert_eq!(s.valid_referer(Some("https://quux")), false);
+    }
+
+    #[test]
+    fn write_once_methods() {
+        let s = AudienceSettings {
+            worm: true,
+            ..Default::default()
+        };
+        assert!(s.write_once("PUT"));
+        assert!(s.write_once("DELETE"));
+        assert!(!s.write_once("GET"));
+        assert!(!AudienceSettings::default().write_once("PUT"));
+    }
+
     #[test]
     fn valid_referer_mask() {
         let s = AudienceSettings {
             allowed_referers: Some(vec!["*.foo".into()]),
+            ..Default::default()
         };
         assert_eq!(s.valid_referer(None), false);
         assert_eq!(s.valid_referer(Some("http://baz.foo")), true);
@@ -84,4 +700,556 @@ mod tests {
         assert_eq!(s.valid_referer(Some("http://qwe.quux")), false);
         assert_eq!(s.valid_referer(Some("http://foo")), false);
     }
+
+    #[test]
+    fn valid_content_type_allowed() {
+        let s = AudienceSettings {
+            allowed_content_types: Some(vec!["image/png".into(), "text/plain".into()]),
+            ..Default::default()
+        };
+        assert_eq!(s.valid_content_type(Some("image/png")), true);
+        assert_eq!(
+            s.valid_content_type(Some("Text/Plain; charset=utf-8")),
+            true
+        );
+    }
+
+    #[test]
+    fn valid_content_type_disallowed() {
+        let s = AudienceSettings {
+            allowed_content_types: Some(vec!["image/png".into()]),
+            ..Default::default()
+        };
+        assert_eq!(s.valid_content_type(None), false);
+        assert_eq!(s.valid_content_type(Some("text/html")), false);
+
+        let s = AudienceSettings::default();
+        assert_eq!(s.valid_content_type(Some("text/html")), true);
+    }
+
+    #[test]
+    fn valid_set_label_length() {
+        let s = AudienceSettings {
+            set_label: Some(SetLabelSettings {
+                max_length: 8,
+                allowed_symbols: SetLabelSettings::default_allowed_symbols(),
+                schemes: Vec::new(),
+            }),
+            ..Default::default()
+        };
+        assert_eq!(s.valid_set_label("foo-bar"), true);
+        assert_eq!(s.valid_set_label("foo-bar-baz"), false);
+        assert_eq!(s.valid_set_label(""), false);
+    }
+
+    #[test]
+    fn valid_set_label_charset() {
+        let s = AudienceSettings {
+            set_label: Some(SetLabelSettings {
+                max_length: SetLabelSettings::default_max_length(),
+                allowed_symbols: SetLabelSettings::default_allowed_symbols(),
+                schemes: Vec::new(),
+            }),
+            ..Default::default()
+        };
+        assert_eq!(s.valid_set_label("foo_123"), true);
+        assert_eq!(s.valid_set_label("foo/bar"), false);
+        assert_eq!(s.valid_set_label("foo.bar"), false);
+        assert_eq!(s.valid_set_label("фу"), false);
+
+        let s = AudienceSettings::default();
+        assert_eq!(s.valid_set_label("foo/bar"), true);
+    }
+
+    #[test]
+    fn valid_set_label_scheme() {
+        let settings = |schemes| AudienceSettings {
+            set_label: Some(SetLabelSettings {
+                max_length: SetLabelSettings::default_max_length(),
+                allowed_symbols: SetLabelSettings::default_allowed_symbols(),
+                schemes,
+            }),
+            ..Default::default()
+        };
+        let uuid = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        let uuid_suffix = "avatar-67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+        let s = settings(vec![SetIdScheme::Integer]);
+        assert_eq!(s.valid_set_label("123"), true);
+        assert_eq!(s.valid_set_label(uuid), false);
+        assert_eq!(s.valid_set_label("foo"), false);
+
+        let s = settings(vec![SetIdScheme::Uuid]);
+        assert_eq!(s.valid_set_label(uuid), true);
+        assert_eq!(s.valid_set_label("123"), false);
+        assert_eq!(s.valid_set_label(uuid_suffix), false);
+
+        let s = settings(vec![SetIdScheme::Integer, SetIdScheme::UuidSuffix]);
+        assert_eq!(s.valid_set_label("123"), true);
+        assert_eq!(s.valid_set_label(uuid_suffix), true);
+        assert_eq!(s.valid_set_label(uuid), false);
+
+        let s = settings(Vec::new());
+        assert_eq!(s.valid_set_label("foo"), true);
+    }
+
+    #[test]
+    fn default_object() {
+        let s = AudienceSettings {
+            default_object: Some("index.html".into()),
+            ..Default::default()
+        };
+        assert_eq!(s.default_object(), Some("index.html"));
+
+        let s = AudienceSettings::default();
+        assert_eq!(s.default_object(), None);
+    }
+
+    #[test]
+    fn valid_content_length_required() {
+        let s = AudienceSettings {
+            require_content_length: true,
+            ..Default::default()
+        };
+        assert_eq!(s.valid_content_length("PUT", Some(1024)), true);
+        assert_eq!(s.valid_content_length("PUT", None), false);
+        assert_eq!(s.valid_content_length("GET", None), true);
+
+        let s = AudienceSettings::default();
+        assert_eq!(s.valid_content_length("PUT", None), true);
+    }
+
+    #[test]
+    fn normalize_object_case() {
+        let s = AudienceSettings {
+            case_insensitive_objects: true,
+            ..Default::default()
+        };
+        assert_eq!(s.normalize_object("Foo.JPG"), s.normalize_object("foo.jpg"));
+
+        let s = AudienceSettings::default();
+        assert_eq!(s.normalize_object("Foo.JPG"), "Foo.JPG");
+    }
+
+    #[test]
+    fn valid_response_override() {
+        let s = AudienceSettings::default();
+        assert_eq!(s.valid_response_override("response-content-type"), true);
+        assert_eq!(s.valid_response_override("response-expires"), false);
+
+        let s = AudienceSettings {
+            allowed_response_overrides: Some(vec!["response-content-disposition".into()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            s.valid_response_override("response-content-disposition"),
+            true
+        );
+        assert_eq!(s.valid_response_override("response-content-type"), false);
+    }
+
+    #[test]
+    fn valid_response_overrides_count() {
+        let s = AudienceSettings::default();
+        assert_eq!(s.valid_response_overrides_count(16), true);
+
+        let s = AudienceSettings {
+            max_response_overrides: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(s.valid_response_overrides_count(0), true);
+        assert_eq!(s.valid_response_overrides_count(1), true);
+        assert_eq!(s.valid_response_overrides_count(2), false);
+    }
+
+    #[test]
+    fn inline_content_type() {
+        let s = AudienceSettings::default();
+        assert_eq!(s.inline_content_type("text/html"), true);
+
+        let s = AudienceSettings {
+            inline_content_types: Some(vec!["image/png".to_owned(), "text/plain".to_owned()]),
+            ..Default::default()
+        };
+        assert_eq!(s.inline_content_type("image/png"), true);
+        assert_eq!(s.inline_content_type("Text/Plain; charset=utf-8"), true);
+        assert_eq!(s.inline_content_type("text/html"), false);
+    }
+
+    #[test]
+    fn page_size_clamped() {
+        let s = AudienceSettings::default();
+        assert_eq!(s.page_size(None, Some(25), 25), Some(25));
+        assert_eq!(s.page_size(Some(100), Some(25), 25), Some(25));
+        assert_eq!(s.page_size(None, None, 1000), None);
+
+        let s = AudienceSettings {
+            page_size: Some(20),
+            max_page_size: Some(500),
+            ..Default::default()
+        };
+        assert_eq!(s.page_size(None, Some(25), 25), Some(20));
+        assert_eq!(s.page_size(Some(100), Some(25), 25), Some(100));
+        assert_eq!(s.page_size(Some(1000), Some(25), 25), Some(500));
+        assert_eq!(s.page_size(None, None, 1000), Some(20));
+    }
+
+    #[test]
+    fn normalize_object_encoding() {
+        let composed = "\u{0439}\u{0451}\u{0436}.txt";
+        let decomposed = "\u{0438}\u{0306}\u{0435}\u{0308}\u{0436}.txt";
+
+        let s = AudienceSettings::default();
+        assert_eq!(s.normalize_object(decomposed), decomposed);
+
+        let s = AudienceSettings {
+            object_key_encoding: ObjectKeyEncoding::Nfc,
+            ..Default::default()
+        };
+        assert_eq!(s.normalize_object(decomposed), composed);
+        assert_eq!(s.normalize_object(composed), composed);
+
+        let s = AudienceSettings {
+            object_key_encoding: ObjectKeyEncoding::Transliterate,
+            ..Default::default()
+        };
+        assert_eq!(s.normalize_object(composed), "yyezh.txt");
+        assert_eq!(s.normalize_object(decomposed), "yyezh.txt");
+        assert_eq!(
+            s.normalize_object("\u{041f}\u{0440}\u{0438}\u{0432}\u{0435}\u{0442} \u{1f642}.txt"),
+            "Privet %F0%9F%99%82.txt"
+        );
+        assert_eq!(s.normalize_object("caf\u{00e9}/foo.bar"), "cafe/foo.bar");
+    }
+
+    #[test]
+    fn normalize_object_separator() {
+        use percent_encoding::percent_decode_str;
+
+        let s = AudienceSettings {
+            escape_object_separator: true,
+            ..Default::default()
+        };
+
+        for (label, object) in &[("foo", "bar.baz"), ("foo.bar", "baz"), ("foo", "100%.txt")] {
+            let key = format!("{}.{}", label, s.normalize_object(object));
+            let mut parts = key.rsplitn(2, '.');
+            let escaped = parts.next().expect("Missing object");
+            assert_eq!(parts.next(), Some(*label));
+            assert_eq!(percent_decode_str(escaped).decode_utf8_lossy(), *object);
+        }
+
+        let s = AudienceSettings::default();
+        assert_eq!(s.normalize_object("bar.baz"), "bar.baz");
+    }
+
+    #[test]
+    fn valid_object_prefix_cross_set() {
+        let s = AudienceSettings {
+            reject_set_prefix: true,
+            ..Default::default()
+        };
+        assert!(!s.valid_object_prefix("bar.baz/qux"));
+        assert!(!s.valid_object_prefix("bar./qux"));
+        assert!(s.valid_object_prefix("readme.txt"));
+        assert!(s.valid_object_prefix("bar.baz"));
+        assert!(s.valid_object_prefix("bar"));
+        assert!(s.valid_object_prefix("bar/baz.png"));
+
+        // Keys starting with something that can't be a label of a set are kept
+        let s = AudienceSettings {
+            reject_set_prefix: true,
+            set_label: Some(SetLabelSettings {
+                max_length: 3,
+                allowed_symbols: SetLabelSettings::default_allowed_symbols(),
+                schemes: Vec::new(),
+            }),
+            ..Default::default()
+        };
+        assert!(!s.valid_object_prefix("bar.baz/qux"));
+        assert!(s.valid_object_prefix("readme.md/qux"));
+
+        let s = AudienceSettings {
+            reject_set_prefix: true,
+            escape_object_separator: true,
+            ..Default::default()
+        };
+        assert!(s.valid_object_prefix("bar.baz"));
+
+        let s = AudienceSettings::default();
+        assert!(s.valid_object_prefix("bar.baz"));
+    }
+
+    #[test]
+    fn storage_class() {
+        let s = AudienceSettings::default();
+        assert_eq!(s.storage_class(None), Ok(None));
+        assert_eq!(
+            s.storage_class(Some("STANDARD_IA")),
+            Ok(Some("STANDARD_IA".to_owned()))
+        );
+        assert_eq!(
+            s.storage_class(Some("standard_ia")),
+            Err("standard_ia".to_owned())
+        );
+
+        let s = AudienceSettings {
+            default_storage_class: Some("GLACIER_IR".to_owned()),
+            allowed_storage_classes: Some(vec!["STANDARD".to_owned(), "GLACIER_IR".to_owned()]),
+            ..Default::default()
+        };
+        assert_eq!(s.storage_class(None), Ok(Some("GLACIER_IR".to_owned())));
+        assert_eq!(
+            s.storage_class(Some("STANDARD")),
+            Ok(Some("STANDARD".to_owned()))
+        );
+        assert_eq!(
+            s.storage_class(Some("DEEP_ARCHIVE")),
+            Err("DEEP_ARCHIVE".to_owned())
+        );
+    }
+
+    #[test]
+    fn reserved_prefix() {
+        let s = AudienceSettings {
+            reserved_prefixes: vec!["system/".to_owned(), "_canary".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(s.reserved_prefix("system/tombstones"), Some("system/"));
+        assert_eq!(s.reserved_prefix("_canary.txt"), Some("_canary"));
+        assert_eq!(s.reserved_prefix("avatars/system/john.png"), None);
+        assert_eq!(s.reserved_prefix("System/tombstones"), None);
+
+        let s = AudienceSettings {
+            case_insensitive_objects: true,
+            ..s
+        };
+        assert_eq!(s.reserved_prefix("System/tombstones"), Some("system/"));
+
+        assert_eq!(
+            AudienceSettings::default().reserved_prefix("system/foo"),
+            None
+        );
+    }
+
+    #[test]
+    fn valid_object_pattern() {
+        let s = serde_json::from_str::<AudienceSettings>(
+            r#"{"object_pattern": "^[a-z0-9_-]+/[a-z0-9_-]+\\.(png|jpg)$"}"#,
+        )
+        .expect("Error parsing audience settings");
+        assert!(s.valid_object_pattern("avatars/john.png"));
+        assert!(!s.valid_object_pattern("avatars/John.png"));
+        assert!(!s.valid_object_pattern("avatars/john.exe"));
+
+        let s = AudienceSettings::default();
+        assert!(s.valid_object_pattern("avatars/John.exe"));
+
+        assert!(serde_json::from_str::<AudienceSettings>(r#"{"object_pattern": "("}"#).is_err());
+    }
+
+    #[test]
+    fn expires_in_below_minimum() {
+        let s = AudienceSettings {
+            min_expires_in: Some(60),
+            ..Default::default()
+        };
+        assert_eq!(s.expires_in(Some(5)), Ok(Some(60)));
+        assert_eq!(s.expires_in(Some(600)), Ok(Some(600)));
+        assert_eq!(s.expires_in(None), Ok(None));
+
+        let s = AudienceSettings {
+            min_expires_in: Some(60),
+            min_expires_in_policy: ExpiryPolicy::Reject,
+            ..Default::default()
+        };
+        assert_eq!(s.expires_in(Some(5)), Err(60));
+        assert_eq!(s.expires_in(Some(60)), Ok(Some(60)));
+
+        let s = AudienceSettings::default();
+        assert_eq!(s.expires_in(Some(5)), Ok(Some(5)));
+    }
+
+    #[test]
+    fn rewrite_object() {
+        let s = serde_json::from_str::<AudienceSettings>(
+            r#"{"rewrite_rules": [
+                {"pattern": "^avatars/([^/]+)\\.jpg$", "replacement": "users/$1/avatar.jpg"},
+                {"pattern": "^avatars/", "replacement": "legacy/avatars/"}
+            ]}"#,
+        )
+        .expect("Error parsing audience settings");
+        assert_eq!(
+            s.rewrite_object("avatars/john.jpg"),
+            "users/john/avatar.jpg"
+        );
+        assert_eq!(
+            s.rewrite_object("avatars/john.png"),
+            "legacy/avatars/john.png"
+        );
+        assert_eq!(s.rewrite_object("photos/john.jpg"), "photos/john.jpg");
+    }
+
+    #[test]
+    fn scope_object_by_subject() {
+        let s = AudienceSettings {
+            subject_scoped_objects: true,
+            ..Default::default()
+        };
+        let john = s.scope_object("john.usr.example.net", "avatar.png");
+        let jane = s.scope_object("jane.usr.example.net", "avatar.png");
+        assert_eq!(john, "john.usr.example.net/avatar.png");
+        assert_ne!(john, jane);
+
+        let s = AudienceSettings::default();
+        assert_eq!(
+            s.scope_object("john.usr.example.net", "avatar.png"),
+            "avatar.png"
+        );
+    }
+
+    #[test]
+    fn merge_subject_header() {
+        let subject = "john.usr.example.net";
+
+        let s = AudienceSettings::default();
+        let headers = s
+            .merge_subject_header(subject, BTreeMap::new())
+            .expect("Error merging headers");
+        assert!(headers.is_empty());
+
+        let s = AudienceSettings {
+            subject_header: Some("X-Amz-Meta-Actor".to_owned()),
+            ..Default::default()
+        };
+        let headers = s
+            .merge_subject_header(subject, BTreeMap::new())
+            .expect("Error merging headers");
+        assert_eq!(
+            headers.get("x-amz-meta-actor").map(String::as_str),
+            Some(subject)
+        );
+
+        let mut client_headers = BTreeMap::new();
+        client_headers.insert("x-amz-meta-actor".to_owned(), subject.to_owned());
+        assert!(s.merge_subject_header(subject, client_headers).is_ok());
+
+        let mut client_headers = BTreeMap::new();
+        client_headers.insert(
+            "x-amz-meta-actor".to_owned(),
+            "jane.usr.example.net".to_owned(),
+        );
+        assert_eq!(
+            s.merge_subject_header(subject, client_headers),
+            Err("x-amz-meta-actor".to_owned())
+        );
+    }
+
+    #[test]
+    fn merge_default_headers() {
+        let mut default_headers = BTreeMap::new();
+        default_headers.insert(
+            "X-Amz-Acl".to_owned(),
+            "bucket-owner-full-control".to_owned(),
+        );
+        let s = AudienceSettings {
+            default_headers,
+            ..Default::default()
+        };
+
+        let headers = s
+            .merge_default_headers(BTreeMap::new())
+            .expect("Error merging headers");
+        assert_eq!(
+            headers.get("x-amz-acl").map(String::as_str),
+            Some("bucket-owner-full-control")
+        );
+
+        let mut client_headers = BTreeMap::new();
+        client_headers.insert("x-amz-acl".to_owned(), "private".to_owned());
+        let headers = s
+            .merge_default_headers(client_headers.clone())
+            .expect("Error merging headers");
+        assert_eq!(
+            headers.get("x-amz-acl").map(String::as_str),
+            Some("private")
+        );
+
+        let s = AudienceSettings {
+            default_headers_conflict: HeaderConflictPolicy::Reject,
+            ..s
+        };
+        assert_eq!(
+            s.merge_default_headers(client_headers),
+            Err("x-amz-acl".to_owned())
+        );
+    }
+
+    #[test]
+    fn valid_caller_capability() {
+        use crate::app::util::CapabilityScope;
+        use hmac::{Hmac, Mac};
+        use sha2::Sha256;
+
+        let now = 1_600_000_000;
+        let mut mac = Hmac::<Sha256>::new_varkey(b"secret").expect("Invalid key");
+        mac.input(format!("{}\nGET\ndata.example.org\nfoo\nbar", now).as_bytes());
+        let capability = format!("{}:{}", now, hex::encode(mac.result().code()));
+
+        let s = AudienceSettings {
+            allowed_referers: Some(vec!["example.org".into()]),
+            capability_key: Some(Secret("secret".to_owned())),
+            ..Default::default()
+        };
+        let scope =
+            |method, object| CapabilityScope::new(method, "data.example.org", "foo", object);
+        assert_eq!(
+            s.valid_caller_at(&scope("GET", "bar"), None, Some(&capability), now),
+            true
+        );
+        assert_eq!(
+            s.valid_caller_at(&scope("GET", "bar"), None, Some("0:00"), now),
+            false
+        );
+        assert_eq!(
+            s.valid_caller_at(&scope("GET", "bar"), None, None, now),
+            false
+        );
+        assert_eq!(
+            s.valid_caller_at(
+                &scope("GET", "bar"),
+                Some("https://example.org/"),
+                None,
+                now
+            ),
+            true
+        );
+
+        assert!(!format!("{:?}", s).contains("secret"));
+
+        // Replaying the capability for another object or method fails
+        assert_eq!(
+            s.valid_caller_at(&scope("GET", "baz"), None, Some(&capability), now),
+            false
+        );
+        assert_eq!(
+            s.valid_caller_at(&scope("DELETE", "bar"), None, Some(&capability), now),
+            false
+        );
+    }
+
+    #[test]
+    fn expected_audience_matched() {
+        let s = AudienceSettings {
+            strict_audience: true,
+            ..Default::default()
+        };
+        assert!(s.valid_expected_audience("example.org", Some("example.org")));
+        assert!(!s.valid_expected_audience("example.org", Some("example.net")));
+        assert!(!s.valid_expected_audience("example.org", None));
+
+        let s = AudienceSettings::default();
+        assert!(s.valid_expected_audience("example.org", Some("example.net")));
+        assert!(s.valid_expected_audience("example.org", None));
+    }
 }
@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+
+use anyhow::{format_err, Context};
+use log::{info, warn};
+
+use crate::app::util::{refresh_s3_credentials, BackendConfig};
+use crate::app::S3ClientRef;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum CredentialsConfig {
+    // Variables prefixed with the name of the backend, e.g. "ALT_AWS_ACCESS_KEY_ID"
+    Env,
+    // JSON object of credentials by the name of the backend, e.g. mounted by a secret manager
+    File { path: PathBuf },
+}
+
+impl Default for CredentialsConfig {
+    fn default() -> Self {
+        CredentialsConfig::Env
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub(crate) struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+impl Credentials {
+    pub(crate) fn access_key_id(&self) -> &str {
+        &self.access_key_id
+    }
+
+    pub(crate) fn secret_access_key(&self) -> &str {
+        &self.secret_access_key
+    }
+}
+
+pub(crate) trait CredentialsProvider {
+    // The prefix is the one of environment variables of the backend
+    fn credentials(&self, backend: &str, prefix: &str) -> anyhow::Result<Credentials>;
+}
+
+pub(crate) fn provider(config: &CredentialsConfig) -> Box<dyn CredentialsProvider> {
+    match config {
+        CredentialsConfig::Env => Box::new(EnvProvider),
+        CredentialsConfig::File { path } => Box::new(FileProvider { path: path.clone() }),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct EnvProvider;
+
+impl CredentialsProvider for EnvProvider {
+    fn credentials(&self, _backend: &str, prefix: &str) -> anyhow::Result<Credentials> {
+        use std::env::var;
+
+        let access_key_id = var(&format!("{}AWS_ACCESS_KEY_ID", prefix))
+            .map_err(|_| format_err!("{}AWS_ACCESS_KEY_ID must be specified", prefix))?;
+        let secret_access_key = var(&format!("{}AWS_SECRET_ACCESS_KEY", prefix))
+            .map_err(|_| format_err!("{}AWS_SECRET_ACCESS_KEY must be specified", prefix))?;
+
+        Ok(Credentials {
+            access_key_id,
+            secret_access_key,
+        })
+    }
+}
+
+// The file is read on every call, so rotated secrets are picked up on refresh
+struct FileProvider {
+    path: PathBuf,
+}
+
+impl CredentialsProvider for FileProvider {
+    fn credentials(&self, backend: &str, _prefix: &str) -> anyhow::Result<Credentials> {
+        let data = fs::read(&self.path).with_context(|| {
+            format!(
+                "failed to read credentials file = '{}'",
+                self.path.display()
+            )
+        })?;
+        let mut credentials: BTreeMap<String, Credentials> = serde_json::from_slice(&data)
+            .with_context(|| {
+                format!(
+                    "failed to parse credentials file = '{}'",
+                    self.path.display()
+                )
+            })?;
+
+        credentials
+            .remove(backend)
+            .ok_or_else(|| format_err!("Missing credentials of backend = '{}'", backend))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Credentials of the clients are swapped in the background so that rotated secrets are used without a restart,
+// the clients themselves are kept along with the ones previously added through the API
+pub(crate) fn refresh(config: BackendConfig, s3: S3ClientRef) {
+    let interval = match config.credentials_refresh_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+
+        match refresh_s3_credentials(&config, &s3) {
+            Ok(()) => info!("Backend credentials refreshed"),
+            Err(err) => warn!("Error refreshing backend credentials: {}", err),
+        }
+    });
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn presigned_url(credentials: &Credentials) -> String {
+        crate::s3::Client::new(
+            credentials.access_key_id(),
+            credentials.secret_access_key(),
+            "us-east-1",
+            "https://s3.example.org",
+            Duration::from_secs(300),
+        )
+        .presigned_url("GET", "data.example.org", "foo.bar")
+        .expect("Error signing a request")
+    }
+
+    #[test]
+    fn env_credentials() {
+        std::env::set_var("CREDENTIALS_TEST_AWS_ACCESS_KEY_ID", "envkey");
+        std::env::set_var("CREDENTIALS_TEST_AWS_SECRET_ACCESS_KEY", "envsecret");
+
+        let credentials = provider(&CredentialsConfig::Env)
+            .credentials("alt", "CREDENTIALS_TEST_")
+            .expect("Error reading credentials");
+        assert_eq!(credentials.secret_access_key(), "envsecret");
+        assert!(presigned_url(&credentials).contains("X-Amz-Credential=envkey%2F"));
+
+        assert!(provider(&CredentialsConfig::Env)
+            .credentials("alt", "CREDENTIALS_MISSING_")
+            .is_err());
+    }
+
+    #[test]
+    fn file_credentials() {
+        let path =
+            std::env::temp_dir().join(format!("storage-credentials-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{"alt": {"access_key_id": "filekey", "secret_access_key": "filesecret"}}"#,
+        )
+        .expect("Error writing credentials file");
+
+        let config = CredentialsConfig::File { path: path.clone() };
+        let credentials = provider(&config)
+            .credentials("alt", "ALT_")
+            .expect("Error reading credentials");
+        assert_eq!(credentials.secret_access_key(), "filesecret");
+        assert!(presigned_url(&credentials).contains("X-Amz-Credential=filekey%2F"));
+        assert!(provider(&config).credentials("default", "").is_err());
+
+        fs::remove_file(&path).expect("Error removing credentials file");
+    }
+
+    #[test]
+    fn credentials_refreshed_in_place() {
+        use std::sync::{Arc, RwLock};
+
+        let path = std::env::temp_dir().join(format!(
+            "storage-credentials-refresh-{}.json",
+            std::process::id()
+        ));
+        let config: BackendConfig = serde_json::from_value(serde_json::json!({
+            "default": "alt",
+            "alt": { "alt": {} },
+            "credentials": { "type": "file", "path": path },
+        }))
+        .expect("Error parsing backend config");
+
+        let mut clients = crate::app::util::S3Clients::new();
+        for back in &["default", "alt"] {
+            clients.insert(
+                (*back).to_owned(),
+                Arc::new(crate::s3::Client::new(
+                    "oldkey",
+                    "oldsecret",
+                    "us-east-1",
+                    "https://s3.example.org",
+                    Duration::from_secs(300),
+                )),
+            );
+        }
+        let s3: S3ClientRef = Arc::new(RwLock::new(clients));
+        let before = s3.read().expect("Error acquiring s3 clients lock")["alt"].clone();
+
+        fs::write(
+            &path,
+            r#"{
+                "default": {"access_key_id": "newkey", "secret_access_key": "newsecret"},
+                "alt": {"access_key_id": "altkey", "secret_access_key": "altsecret"}
+            }"#,
+        )
+        .expect("Error writing credentials file");
+        refresh_s3_credentials(&config, &s3).expect("Error refreshing credentials");
+        fs::remove_file(&path).expect("Error removing credentials file");
+
+        let clients = s3.read().expect("Error acquiring s3 clients lock");
+        let url = |back: &str| {
+            clients[back]
+                .presigned_url("GET", "data.example.org", "foo.bar")
+                .expect("Error signing a request")
+        };
+        assert!(url("default").contains("X-Amz-Credential=newkey%2F"));
+        assert!(url("alt").contains("X-Amz-Credential=altkey%2F"));
+        // The client is the same, only its credentials are swapped
+        assert!(Arc::ptr_eq(&before, &clients["alt"]));
+    }
+}
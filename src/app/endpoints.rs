@@ -0,0 +1,298 @@
+use std::sync::Arc;
+
+use futures::{Async, Future, Poll};
+use http::{Request, Response, StatusCode};
+use tower_service::Service;
+use tower_web::middleware::Middleware;
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Routes of the disabled APIs respond with 404, the ones not listed here are always served
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct EndpointsConfig {
+    #[serde(default = "EndpointsConfig::enabled")]
+    pub(crate) object: bool,
+    #[serde(default = "EndpointsConfig::enabled")]
+    pub(crate) set: bool,
+    #[serde(default = "EndpointsConfig::enabled")]
+    pub(crate) tag: bool,
+    #[serde(default = "EndpointsConfig::enabled")]
+    pub(crate) sign: bool,
+    #[serde(default = "EndpointsConfig::enabled")]
+    pub(crate) backend: bool,
+}
+
+impl EndpointsConfig {
+    fn enabled() -> bool {
+        true
+    }
+}
+
+impl Default for EndpointsConfig {
+    fn default() -> Self {
+        Self {
+            object: true,
+            set: true,
+            tag: true,
+            sign: true,
+            backend: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Api {
+    Object,
+    Set,
+    Tag,
+    Sign,
+    Backend,
+}
+
+impl EndpointsConfig {
+    fn enabled_for(&self, path: &str) -> bool {
+        match api(path) {
+            Some(Api::Object) => self.object,
+            Some(Api::Set) => self.set,
+            Some(Api::Tag) => self.tag,
+            Some(Api::Sign) => self.sign,
+            Some(Api::Backend) => self.backend,
+            None => true,
+        }
+    }
+}
+
+// The API is told by the first segment following the version and the backend, if there's one.
+// Shares are a part of the sign API, backend creation and self-tests of the backend one
+fn api(path: &str) -> Option<Api> {
+    let mut segments = path.trim_start_matches('/').split('/');
+    let version = match (segments.next(), segments.next()) {
+        (Some("api"), Some(version)) => version,
+        _ => return None,
+    };
+    let mut segments = segments.peekable();
+    if segments.peek() == Some(&"backends") {
+        segments.next();
+        if matches!(
+            (version, segments.next(), segments.peek()),
+            ("v1", None, _) | ("v1", Some(_), Some(&"self_test"))
+        ) {
+            return Some(Api::Backend);
+        }
+    }
+
+    match (version, segments.next(), segments.nth(1)) {
+        ("v1", Some("buckets"), Some("sets")) => Some(Api::Set),
+        ("v1", Some("buckets"), _) => Some(Api::Object),
+        ("v1", Some("sign"), _) => Some(Api::Sign),
+        ("v2", Some("sets"), _) => Some(Api::Set),
+        ("v2", Some("tags"), _) => Some(Api::Tag),
+        ("v2", Some("sign"), _) | ("v2", Some("share"), _) | ("v2", Some("shares"), _) => {
+            Some(Api::Sign)
+        }
+        _ => None,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Every resource is registered, requests to the disabled ones are refused before they're routed
+#[derive(Debug)]
+pub(crate) struct EndpointsMiddleware {
+    config: Arc<EndpointsConfig>,
+}
+
+impl EndpointsMiddleware {
+    pub(crate) fn new(config: &EndpointsConfig) -> Self {
+        Self {
+            config: Arc::new(config.clone()),
+        }
+    }
+}
+
+impl<S, RequestBody, ResponseBody> Middleware<S> for EndpointsMiddleware
+where
+    S: Service<Request = Request<RequestBody>, Response = Response<ResponseBody>>,
+{
+    type Request = Request<RequestBody>;
+    type Response = Response<Option<ResponseBody>>;
+    type Error = S::Error;
+    type Service = EndpointsService<S>;
+
+    fn wrap(&self, inner: S) -> Self::Service {
+        EndpointsService {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct EndpointsService<S> {
+    inner: S,
+    config: Arc<EndpointsConfig>,
+}
+
+impl<S, RequestBody, ResponseBody> Service for EndpointsService<S>
+where
+    S: Service<Request = Request<RequestBody>, Response = Response<ResponseBody>>,
+{
+    type Request = Request<RequestBody>;
+    type Response = Response<Option<ResponseBody>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: Self::Request) -> Self::Future {
+        if self.config.enabled_for(req.uri().path()) {
+            ResponseFuture {
+                inner: Some(self.inner.call(req)),
+            }
+        } else {
+            ResponseFuture { inner: None }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ResponseFuture<F> {
+    inner: Option<F>,
+}
+
+impl<F, ResponseBody> Future for ResponseFuture<F>
+where
+    F: Future<Item = Response<ResponseBody>>,
+{
+    type Item = Response<Option<ResponseBody>>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.inner {
+            Some(ref mut inner) => match inner.poll()? {
+                Async::Ready(resp) => Ok(Async::Ready(resp.map(Some))),
+                Async::NotReady => Ok(Async::NotReady),
+            },
+            None => {
+                let mut resp = Response::new(None);
+                *resp.status_mut() = StatusCode::NOT_FOUND;
+                Ok(Async::Ready(resp))
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+
+    use super::*;
+
+    // Responds to every request right away
+    struct Handler;
+
+    impl Service for Handler {
+        type Request = Request<()>;
+        type Response = Response<()>;
+        type Error = ();
+        type Future = future::FutureResult<Response<()>, ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _req: Self::Request) -> Self::Future {
+            future::ok(Response::new(()))
+        }
+    }
+
+    #[test]
+    fn disabled_endpoints_not_found() {
+        let config: EndpointsConfig = serde_json::from_str(r#"{"set": false, "backend": false}"#)
+            .expect("Error parsing endpoints config");
+        let mut service = EndpointsMiddleware::new(&config).wrap(Handler);
+        let mut status = |path: &str| {
+            let req = Request::get(path)
+                .body(())
+                .expect("Error building a request");
+            service
+                .call(req)
+                .wait()
+                .expect("Error handling a request")
+                .status()
+        };
+
+        // Disabled
+        assert_eq!(
+            status("/api/v2/sets/foo/objects/bar"),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status("/api/v2/backends/alt/sets/foo/objects/bar"),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status("/api/v1/buckets/foo/sets/bar/objects/baz"),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(status("/api/v1/backends"), StatusCode::NOT_FOUND);
+        assert_eq!(
+            status("/api/v1/backends/alt/self_test"),
+            StatusCode::NOT_FOUND
+        );
+
+        // Enabled
+        assert_eq!(
+            status("/api/v1/buckets/foo/objects/sets/bar"),
+            StatusCode::OK
+        );
+        assert_eq!(status("/api/v2/sign"), StatusCode::OK);
+        assert_eq!(status("/api/v2/backends/alt/sign/batch"), StatusCode::OK);
+        assert_eq!(status("/api/v2/tags/foo/objects/bar"), StatusCode::OK);
+        assert_eq!(status("/healthz"), StatusCode::OK);
+        assert_eq!(
+            status("/api/v2/audiences/example.org/usage"),
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn endpoints_classified() {
+        assert_eq!(api("/api/v1/buckets/foo/objects/bar"), Some(Api::Object));
+        assert_eq!(
+            api("/api/v1/backends/alt/buckets/foo/objects/bar"),
+            Some(Api::Object)
+        );
+        assert_eq!(api("/api/v1/buckets/foo/sets/bar"), Some(Api::Set));
+        assert_eq!(api("/api/v2/sets/foo/objects/bar/restore"), Some(Api::Set));
+        assert_eq!(api("/api/v2/tags"), Some(Api::Tag));
+        assert_eq!(api("/api/v1/sign"), Some(Api::Sign));
+        assert_eq!(api("/api/v2/share"), Some(Api::Sign));
+        assert_eq!(api("/api/v2/shares/token"), Some(Api::Sign));
+        assert_eq!(api("/api/v1/backends"), Some(Api::Backend));
+        assert_eq!(api("/api/v1/backends/alt/self_test"), Some(Api::Backend));
+        assert_eq!(api("/api/v1/config"), None);
+        assert_eq!(api("/readyz"), None);
+    }
+
+    #[test]
+    fn endpoints_enabled_by_default() {
+        let config: EndpointsConfig = serde_json::from_str(
+            r#"{"object": false, "set": false, "tag": false, "backend": false}"#,
+        )
+        .expect("Error parsing endpoints config");
+        assert!(!config.object);
+        assert!(!config.set);
+        assert!(!config.tag);
+        assert!(!config.backend);
+        assert!(config.sign);
+
+        let config: EndpointsConfig =
+            serde_json::from_str("{}").expect("Error parsing endpoints config");
+        assert!(config.object && config.set && config.tag && config.sign && config.backend);
+    }
+}
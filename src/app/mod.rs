@@ -1,7 +1,8 @@
 use anyhow::format_err;
-use futures::{future, Future};
+use futures::{future, stream, Future, Stream};
 use http::{Response, StatusCode};
-use log::{error, info};
+use log::{error, info, warn};
+use regex::Regex;
 use std::collections::BTreeMap;
 use std::string::ToString;
 use std::sync::Arc;
@@ -10,38 +11,67 @@ use svc_authz::cache::Cache;
 use tower_web::Error;
 
 use self::config::AudienceSettings;
-use crate::db::{tag, ConnectionPool};
+use crate::db::{share, tag, tombstone, ConnectionPool};
 use util::Subject;
 
 ////////////////////////////////////////////////////////////////////////////////
 
 const MAX_LIMIT: i64 = 25;
+// Most keys ListObjectsV2 returns on a page
+const MAX_KEYS: u64 = 1000;
+const STORAGE_CLASS_HEADER: &str = "x-amz-storage-class";
+// Signatures of the S3 API expire in 7 days at most
+const MAX_SHARE_EXPIRES_IN: u64 = 604_800;
+const DEFAULT_SHARE_EXPIRES_IN: u64 = 86_400;
 
 ////////////////////////////////////////////////////////////////////////////////
 
-type S3ClientRef = ::std::sync::Arc<util::S3Clients>;
+type S3ClientRef = ::std::sync::Arc<::std::sync::RwLock<util::S3Clients>>;
+type AudiencesSettingsRef = Arc<BTreeMap<String, AudienceSettings>>;
+type MethodActionsRef = Arc<BTreeMap<String, String>>;
 
 #[derive(Debug)]
 struct ObjectState {
-    authz: svc_authz::ClientMap,
+    authz: authz_retry::AuthzClient,
     aud_estm: Arc<util::AudienceEstimator>,
     s3: S3ClientRef,
-    audiences_settings: BTreeMap<String, AudienceSettings>,
+    router: Arc<util::BackendRouter>,
+    audiences_settings: AudiencesSettingsRef,
+    error_detail: util::ErrorDetail,
+    usage: Arc<usage::UsageRecorder>,
+    db: Option<ConnectionPool>,
+    readiness: Arc<self_test::ReadinessMonitor>,
 }
 
 #[derive(Debug)]
 struct SetState {
-    authz: svc_authz::ClientMap,
+    authz: authz_retry::AuthzClient,
     aud_estm: Arc<util::AudienceEstimator>,
     s3: S3ClientRef,
-    audiences_settings: BTreeMap<String, AudienceSettings>,
+    router: Arc<util::BackendRouter>,
+    audiences_settings: AudiencesSettingsRef,
+    error_detail: util::ErrorDetail,
+    usage: Arc<usage::UsageRecorder>,
+    db: Option<ConnectionPool>,
+    readiness: Arc<self_test::ReadinessMonitor>,
+}
+
+struct UsageState {
+    authz: authz_retry::AuthzClient,
+    audiences_settings: AudiencesSettingsRef,
+    db: Option<ConnectionPool>,
 }
 
 struct TagState {
-    authz: svc_authz::ClientMap,
+    authz: authz_retry::AuthzClient,
     aud_estm: Arc<util::AudienceEstimator>,
     s3: S3ClientRef,
+    router: Arc<util::BackendRouter>,
+    audiences_settings: AudiencesSettingsRef,
     db: Option<ConnectionPool>,
+    error_detail: util::ErrorDetail,
+    usage: Arc<usage::UsageRecorder>,
+    readiness: Arc<self_test::ReadinessMonitor>,
 }
 
 #[derive(Debug, Extract)]
@@ -49,6 +79,42 @@ struct UpdateTagPayload {
     set: String,
 }
 
+#[derive(Debug, Extract)]
+struct ObjectListQueryString {
+    prefix: Option<String>,
+    filter: Option<String>,
+    continuation_token: Option<String>,
+    max_keys: Option<u64>,
+}
+
+#[derive(Debug, Response)]
+#[web(status = "200")]
+struct ObjectListResponse {
+    objects: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    continuation_token: Option<String>,
+}
+
+impl ObjectListResponse {
+    // Keys are listed by the prefix of the set and returned as names of its objects,
+    // a page may hold fewer objects than requested once filtered, the token tells if there are more
+    fn new(listing: crate::s3::Listing, set: &str, filter: Option<&Regex>) -> Self {
+        let prefix = s3_object(set, "");
+        let objects = listing
+            .keys
+            .into_iter()
+            .filter(|key| key.starts_with(&prefix))
+            .map(|key| key[prefix.len()..].to_owned())
+            .filter(|object| filter.map_or(true, |filter| filter.is_match(object)))
+            .collect();
+
+        Self {
+            objects,
+            continuation_token: listing.continuation_token,
+        }
+    }
+}
+
 #[derive(Debug, Extract)]
 struct TagListQueryString {
     filter: String,
@@ -62,13 +128,23 @@ struct TagListQueryString {
 #[web(status = "204")]
 struct TagEmptyResponse {}
 
-#[derive(Debug)]
+#[derive(Response)]
+#[web(status = "204")]
+struct ObjectEmptyResponse {}
+
+#[derive(Clone, Debug)]
 struct SignState {
     application_id: AccountId,
-    authz: svc_authz::ClientMap,
+    authz: authz_retry::AuthzClient,
     aud_estm: Arc<util::AudienceEstimator>,
     s3: S3ClientRef,
-    audiences_settings: BTreeMap<String, AudienceSettings>,
+    router: Arc<util::BackendRouter>,
+    audiences_settings: AudiencesSettingsRef,
+    db: Option<ConnectionPool>,
+    error_detail: util::ErrorDetail,
+    sign_limiter: Arc<util::SignLimiter>,
+    method_actions: MethodActionsRef,
+    max_batch_size: usize,
 }
 
 #[derive(Debug, Extract)]
@@ -77,6 +153,77 @@ struct SignPayload {
     object: String,
     method: String,
     headers: BTreeMap<String, String>,
+    request_payer: Option<bool>,
+    tagging: Option<bool>,
+    content_length: Option<u64>,
+    response_overrides: Option<BTreeMap<String, String>>,
+    checksum_algorithm: Option<String>,
+    checksum_value: Option<String>,
+    include_tags: Option<bool>,
+    expires_in: Option<u64>,
+    service: Option<String>,
+    echo_method: Option<bool>,
+    decoded_content_length: Option<u64>,
+    storage_class: Option<String>,
+}
+
+#[derive(Debug, Extract)]
+struct SignBatchPayload {
+    items: Vec<SignBatchItemPayload>,
+    fail_fast: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SignBatchItemPayload {
+    set: String,
+    object: String,
+    method: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    content_length: Option<u64>,
+    checksum_algorithm: Option<String>,
+    checksum_value: Option<String>,
+    storage_class: Option<String>,
+    echo_method: Option<bool>,
+}
+
+impl From<SignBatchItemPayload> for SignPayload {
+    fn from(value: SignBatchItemPayload) -> Self {
+        Self {
+            set: value.set,
+            object: value.object,
+            method: value.method,
+            headers: value.headers,
+            request_payer: None,
+            tagging: None,
+            content_length: value.content_length,
+            response_overrides: None,
+            checksum_algorithm: value.checksum_algorithm,
+            checksum_value: value.checksum_value,
+            include_tags: None,
+            expires_in: None,
+            service: None,
+            echo_method: value.echo_method,
+            decoded_content_length: None,
+            storage_class: value.storage_class,
+        }
+    }
+}
+
+#[derive(Debug, Extract)]
+struct SignListPayload {
+    set: String,
+    prefix: Option<String>,
+    continuation_token: Option<String>,
+    max_keys: Option<u64>,
+}
+
+#[derive(Debug, Extract)]
+struct SharePayload {
+    set: String,
+    object: String,
+    expires_in: Option<u64>,
+    max_downloads: Option<u64>,
 }
 
 // Backward compatibility with v1 API
@@ -87,58 +234,257 @@ struct SignPayloadV1 {
     object: String,
     method: String,
     headers: BTreeMap<String, String>,
+    content_length: Option<u64>,
+    response_overrides: Option<BTreeMap<String, String>>,
+    checksum_algorithm: Option<String>,
+    checksum_value: Option<String>,
+    expires_in: Option<u64>,
 }
 
 #[derive(Response)]
 #[web(status = "200")]
 struct SignResponse {
     uri: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    signed_headers: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    method: Option<String>,
+}
+
+impl SignResponse {
+    fn new(uri: String) -> Self {
+        Self {
+            signed_headers: util::signed_headers(&uri),
+            uri,
+            tags: None,
+            method: None,
+        }
+    }
+
+    // The method isn't a part of the URI, a request sent with another one fails the signature check
+    fn method(self, method: Option<&str>) -> Self {
+        Self {
+            method: method.map(ToOwned::to_owned),
+            ..self
+        }
+    }
+
+    fn tags(self, tags: Option<Vec<crate::db::Set>>) -> Self {
+        Self {
+            tags: tags.map(|tags| tags.iter().map(ToString::to_string).collect()),
+            ..self
+        }
+    }
+}
+
+#[derive(Response)]
+#[web(status = "200")]
+struct ShareResponse {
+    uri: String,
+    token: String,
+    expires_at: String,
+}
+
+#[derive(Response)]
+#[web(status = "200")]
+struct SignBatchResponse {
+    items: Vec<SignBatchItem>,
 }
 
+impl SignBatchResponse {
+    // Items are signed concurrently and failures are reported per item. With fail_fast items are
+    // signed one by one, the first failed item fails the whole batch and the following ones are
+    // never signed, nor even checked
+    fn sign<S, F>(
+        items: Vec<S>,
+        fail_fast: bool,
+    ) -> impl Future<Item = Result<Self, Error>, Error = ()>
+    where
+        S: FnOnce() -> F,
+        F: Future<Item = Result<SignResponse, Error>, Error = ()>,
+    {
+        if fail_fast {
+            let batch = stream::iter_ok(items)
+                .and_then(|sign| {
+                    sign().then(|result| match result {
+                        Ok(Ok(resp)) => Ok(SignBatchItem::from(Ok(resp))),
+                        Ok(Err(err)) => Err(Some(err)),
+                        Err(()) => Err(None),
+                    })
+                })
+                .collect()
+                .then(|result| match result {
+                    Ok(items) => Ok(Ok(Self { items })),
+                    Err(Some(err)) => Ok(Err(err)),
+                    Err(None) => Err(()),
+                });
+
+            return future::Either::A(batch);
+        }
+
+        let items = items.into_iter().map(|sign| sign()).collect::<Vec<_>>();
+        future::Either::B(future::join_all(items).map(|results| {
+            Ok(Self {
+                items: results.into_iter().map(SignBatchItem::from).collect(),
+            })
+        }))
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SignBatchItem {
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    signed_headers: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl From<Result<SignResponse, Error>> for SignBatchItem {
+    fn from(value: Result<SignResponse, Error>) -> Self {
+        match value {
+            Ok(resp) => Self {
+                status: StatusCode::OK.as_u16(),
+                uri: Some(resp.uri),
+                signed_headers: resp.signed_headers,
+                method: resp.method,
+                error: None,
+            },
+            Err(err) => Self {
+                status: err.status_code().as_u16(),
+                uri: None,
+                signed_headers: Vec::new(),
+                method: None,
+                error: Some(err.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ConfigState {
+    application_id: AccountId,
+    authz: authz_retry::AuthzClient,
+    config: Option<serde_json::Value>,
+}
+
+#[derive(Debug)]
+struct BackendState {
+    application_id: AccountId,
+    authz: authz_retry::AuthzClient,
+    s3: S3ClientRef,
+    service_hosts: Vec<String>,
+    self_test: Option<self_test::SelfTestConfig>,
+}
+
+#[derive(Debug, Extract)]
+struct CreateBackendPayload {
+    name: String,
+    access_key_id: String,
+    secret_access_key: String,
+    endpoint: String,
+    region: String,
+    // The same options as of the backends in the config
+    config: Option<util::AltBackendConfig>,
+}
+
+#[derive(Response)]
+#[web(status = "201")]
+struct BackendResponse {
+    name: String,
+}
+
+#[derive(Response)]
+#[web(status = "200")]
+struct UsageResponse {
+    objects: i64,
+    bytes: i64,
+    estimated_objects: i64,
+    estimated_bytes: i64,
+}
+
+impl UsageResponse {
+    fn new(usage: &crate::db::object_size::Usage, sample_rate: Option<f64>) -> Self {
+        Self {
+            objects: usage.objects(),
+            bytes: usage.bytes(),
+            estimated_objects: usage::estimate(usage.objects(), sample_rate),
+            estimated_bytes: usage::estimate(usage.bytes(), sample_rate),
+        }
+    }
+}
+
+// Probes are served whichever APIs are enabled
 #[derive(Debug)]
-struct Healthz {}
+struct Healthz {
+    readiness: Arc<self_test::ReadinessMonitor>,
+}
 
 impl_web! {
 
     impl ObjectState {
         // Backward compatibility with v1 API
         #[get("/api/v1/buckets/:bucket/objects/:object")]
-        fn read_v1(&self, bucket: String, object: String, sub: Subject, referer: Option<String>) -> impl Future<Item = Result<Response<&'static str>, Error>, Error = ()> {
-            self.read_v1_ns(String::from(crate::app::util::S3_DEFAULT_CLIENT), bucket, object, sub, referer)
+        fn read_v1(&self, bucket: String, object: String, sub: Subject, referer: util::Referer, mode: util::ReadMode, geo: util::GeoHint) -> impl Future<Item = Result<Response<Vec<u8>>, Error>, Error = ()> {
+            self.read_v1_ns(self.router.route_nearest(&bucket, &geo), bucket, object, sub, referer, mode)
         }
 
         #[get("/api/v1/backends/:back/buckets/:bucket/objects/:object")]
-        fn read_v1_ns(&self, back: String, bucket: String, object: String, sub: Subject,  referer: Option<String>) -> impl Future<Item = Result<Response<&'static str>, Error>, Error = ()> {
+        fn read_v1_ns(&self, back: String, bucket: String, object: String, sub: Subject,  referer: util::Referer, mode: util::ReadMode) -> impl Future<Item = Result<Response<Vec<u8>>, Error>, Error = ()> {
             let error = || Error::builder().kind("set_read_error", "Error reading an object by key");
+            let error_detail = self.error_detail;
 
-            if let Err(e) = self.valid_referer(&bucket, referer) {
+            if let Err(e) = self.valid_referer(util::CapabilityScope::new("GET", &bucket, "", &object), referer) {
                 return future::Either::A(wrap_error(e));
             }
 
             let zobj = vec!["buckets", &bucket, "objects", &object];
             let zact = "read";
-            let s3 = self.s3.clone();
-            let s3 = match s3.get(&back) {
+            let s3 = match self.s3.read().expect("Error acquiring s3 clients lock").get(&back) {
                 Some(val) => val.clone(),
                 None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", &back)).build()))
             };
 
             match self.aud_estm.estimate(&bucket) {
                 Ok(audience) => {
+                    let object = rewrite_object(&self.audiences_settings, audience, &object);
+                    if let Err(detail) = valid_object(&self.audiences_settings, audience, &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&detail).build()));
+                    }
+
+                    if let Err(detail) = valid_object_pattern(&self.audiences_settings, audience, &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&detail).build()));
+                    }
+
+                    let object = normalize_object(&self.audiences_settings, audience, &sub, &object);
+                    let aud_settings = self.audiences_settings.get(audience).cloned().unwrap_or_default();
+                    let (usage, usage_audience) = (self.usage.clone(), audience.to_owned());
+                    let (audiences_settings, db) = (self.audiences_settings.clone(), self.db.clone());
+                    let (mirror, unhealthy) = (mirror_client(&self.router, &self.s3, &back), self.readiness.unhealthy(&back));
+
                     future::Either::B(self
                         .authz
                         .authorize(audience, &sub, zobj, zact)
                         .and_then(move |zauth| match zauth {
                             Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
-                            Ok(_) => future::Either::B(
-                                future::ok(s3
-                                    .presigned_url("GET", &bucket, &object)
-                                    .map(|ref uri| redirect(uri))
-                                    .map_err(|err| error()
-                                        .status(StatusCode::UNPROCESSABLE_ENTITY)
-                                        .detail(&err.to_string())
-                                        .build())))
-                        }))
+                            Ok(_) => {
+                                if let Err(err) = valid_not_deleted(&audiences_settings, &usage_audience, "GET", &bucket, &object, |bucket, key| tombstone_exists(db.as_ref(), &back, bucket, key)) {
+                                    return future::Either::A(wrap_error(err));
+                                }
+
+                                future::Either::B(failover(s3, mirror, unhealthy, move |s3| read_response(s3, usage.clone(), mode, &usage_audience, &aud_settings, &bucket, &object))
+                                    .then(move |result| Ok(result
+                                        .map_err(|err| error()
+                                            .status(s3_error_status(&err))
+                                            .detail(&error_detail.describe(&back, &err))
+                                            .build()))))
+                        }}))
                 },
                 Err(err) => {
                     future::Either::A(wrap_error(err))
@@ -146,14 +492,18 @@ impl_web! {
             }
         }
 
-        fn valid_referer(&self, bucket: &str, referer: Option<String>) -> Result<(), Error> {
+        fn valid_referer(&self, scope: util::CapabilityScope, referer: util::Referer) -> Result<(), Error> {
             let error = || Error::builder().kind("set_read_error", "Error reading an object by key");
+            let bucket = scope.bucket();
 
             match self.aud_estm.estimate(&bucket) {
                 Ok(aud) => match self.audiences_settings.get(aud) {
-                    Some(aud_settings) => if !aud_settings.valid_referer(referer.as_deref()) {
+                    Some(aud_settings) => if !aud_settings.valid_caller(&scope, referer.referer(), referer.capability()) {
                         let e = error().status(StatusCode::FORBIDDEN).detail("Invalid request").build();
                         return Err(e);
+                    } else if !aud_settings.valid_expected_audience(aud, referer.expected_audience()) {
+                        let e = error().status(StatusCode::FORBIDDEN).detail(&format!("Bucket '{}' doesn't belong to the expected audience", &bucket)).build();
+                        return Err(e);
                     }
                     None => {
                         let e = error().status(StatusCode::NOT_FOUND).detail(&format!("Audience settings for bucket '{}' not found", &bucket)).build();
@@ -173,28 +523,47 @@ impl_web! {
 
     impl SetState {
         #[get("/api/v2/sets/:set/objects/:object")]
-        fn read(&self, set: String, object: String, sub: Subject, referer: Option<String>) -> impl Future<Item = Result<Response<&'static str>, Error>, Error = ()> {
-            self.read_ns(String::from(crate::app::util::S3_DEFAULT_CLIENT), set, object, sub, referer)
+        fn read(&self, set: String, object: String, sub: Subject, referer: util::Referer, mode: util::ReadMode, geo: util::GeoHint) -> impl Future<Item = Result<Response<Vec<u8>>, Error>, Error = ()> {
+            self.read_ns(self.router.route_set_nearest(&self.aud_estm, &set, &geo), set, object, sub, referer, mode)
         }
 
         #[get("/api/v2/backends/:back/sets/:set/objects/:object")]
-        fn read_ns(&self, back: String, set: String, object: String, sub: Subject, referer: Option<String>) -> impl Future<Item = Result<Response<&'static str>, Error>, Error = ()> {
+        fn read_ns(&self, back: String, set: String, object: String, sub: Subject, referer: util::Referer, mode: util::ReadMode) -> impl Future<Item = Result<Response<Vec<u8>>, Error>, Error = ()> {
             let error = || Error::builder().kind("set_read_error", "Error reading an object by set");
+            let error_detail = self.error_detail;
 
             let zobj = vec!["sets", &set];
             let zact = "read";
-            let s3 = self.s3.clone();
-            let s3 = match s3.get(&back) {
+            let s3 = match self.s3.read().expect("Error acquiring s3 clients lock").get(&back) {
                 Some(val) => val.clone(),
                 None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", &back)).build()))
             };
 
             match self.aud_estm.parse_set(&set) {
                 Ok(set_s) => {
-                    if let Err(e) = self.valid_referer(&set_s.bucket().to_string(), referer) {
+                    if let Err(e) = self.valid_referer(util::CapabilityScope::new("GET", &set_s.bucket().to_string(), set_s.label(), &object), referer) {
+                        return future::Either::A(wrap_error(e));
+                    }
+
+                    if let Err(e) = self.valid_set_label(&set_s.bucket().to_string(), set_s.label()) {
                         return future::Either::A(wrap_error(e));
                     }
 
+                    let object = rewrite_object(&self.audiences_settings, set_s.bucket().audience(), &object);
+                    if let Err(detail) = valid_object(&self.audiences_settings, set_s.bucket().audience(), &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&detail).build()));
+                    }
+
+                    if let Err(detail) = valid_object_pattern(&self.audiences_settings, set_s.bucket().audience(), &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&detail).build()));
+                    }
+
+                    let object = normalize_object(&self.audiences_settings, set_s.bucket().audience(), &sub, &object);
+                    let aud_settings = self.audiences_settings.get(set_s.bucket().audience()).cloned().unwrap_or_default();
+                    let usage = self.usage.clone();
+                    let (audiences_settings, db) = (self.audiences_settings.clone(), self.db.clone());
+                    let (mirror, unhealthy) = (mirror_client(&self.router, &self.s3, &back), self.readiness.unhealthy(&back));
+
                     future::Either::B(self
                         .authz
                         .authorize(set_s.bucket().audience(), &sub, zobj, zact)
@@ -204,13 +573,16 @@ impl_web! {
                                 let bucket = set_s.bucket().to_string();
                                 let object = s3_object(set_s.label(), &object);
 
-                                future::Either::B(future::ok(s3
-                                    .presigned_url("GET", &bucket, &object)
-                                    .map(|ref uri| redirect(uri))
-                                    .map_err(|err| error()
-                                        .status(StatusCode::UNPROCESSABLE_ENTITY)
-                                        .detail(&err.to_string())
-                                        .build())))
+                                if let Err(err) = valid_not_deleted(&audiences_settings, set_s.bucket().audience(), "GET", &bucket, &object, |bucket, key| tombstone_exists(db.as_ref(), &back, bucket, key)) {
+                                    return future::Either::A(wrap_error(err));
+                                }
+
+                                future::Either::B(failover(s3, mirror, unhealthy, move |s3| read_response(s3, usage.clone(), mode, set_s.bucket().audience(), &aud_settings, &bucket, &object))
+                                    .then(move |result| Ok(result
+                                        .map_err(|err| error()
+                                            .status(s3_error_status(&err))
+                                            .detail(&error_detail.describe(&back, &err))
+                                            .build()))))
                         }}))
                 },
                 Err(err) => {
@@ -219,45 +591,85 @@ impl_web! {
             }
         }
 
-        // Backward compatibility with v1 API
-        #[get("/api/v1/buckets/:bucket/sets/:set/objects/:object")]
-        fn read_v1(&self, bucket: String, set: String, object: String, sub: Subject, referer: Option<String>) -> impl Future<Item = Result<Response<&'static str>, Error>, Error = ()> {
-            self.read_v1_ns(String::from(crate::app::util::S3_DEFAULT_CLIENT), bucket, set, object, sub, referer)
+        #[get("/api/v2/sets/:set/objects/:object/download")]
+        fn download(&self, set: String, object: String, sub: Subject, referer: util::Referer, geo: util::GeoHint) -> impl Future<Item = Result<Response<Vec<u8>>, Error>, Error = ()> {
+            self.download_ns(self.router.route_set_nearest(&self.aud_estm, &set, &geo), set, object, sub, referer)
         }
 
-        #[get("/api/v1/backends/:back/buckets/:bucket/sets/:set/objects/:object")]
-        fn read_v1_ns(&self, back: String, bucket: String, set: String, object: String, sub: Subject, referer: Option<String>) -> impl Future<Item = Result<Response<&'static str>, Error>, Error = ()> {
-            let error = || Error::builder().kind("set_read_error", "Error reading an object by set");
-
-            if let Err(e) = self.valid_referer(&bucket, referer) {
-                return future::Either::A(wrap_error(e));
-            }
+        #[get("/api/v2/backends/:back/sets/:set/objects/:object/download")]
+        fn download_ns(&self, back: String, set: String, object: String, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<Response<Vec<u8>>, Error>, Error = ()> {
+            let error = || Error::builder().kind("set_download_error", "Error downloading an object by set");
+            let error_detail = self.error_detail;
 
-            let zobj = vec!["buckets", &bucket, "sets", &set];
+            let zobj = vec!["sets", &set];
             let zact = "read";
-            let s3 = self.s3.clone();
-            let s3 = match s3.get(&back) {
+            let s3 = match self.s3.read().expect("Error acquiring s3 clients lock").get(&back) {
                 Some(val) => val.clone(),
                 None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", &back)).build()))
             };
 
-            match self.aud_estm.estimate(&bucket) {
-                Ok(audience) => {
+            match self.aud_estm.parse_set(&set) {
+                Ok(set_s) => {
+                    if let Err(e) = self.valid_referer(util::CapabilityScope::new("GET", &set_s.bucket().to_string(), set_s.label(), &object), referer) {
+                        return future::Either::A(wrap_error(e));
+                    }
+
+                    if let Err(e) = self.valid_set_label(&set_s.bucket().to_string(), set_s.label()) {
+                        return future::Either::A(wrap_error(e));
+                    }
+
+                    let object = rewrite_object(&self.audiences_settings, set_s.bucket().audience(), &object);
+                    if let Err(detail) = valid_object(&self.audiences_settings, set_s.bucket().audience(), &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&detail).build()));
+                    }
+
+                    if let Err(detail) = valid_object_pattern(&self.audiences_settings, set_s.bucket().audience(), &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&detail).build()));
+                    }
+
+                    let object = normalize_object(&self.audiences_settings, set_s.bucket().audience(), &sub, &object);
+                    let aud_settings = self.audiences_settings.get(set_s.bucket().audience()).cloned().unwrap_or_default();
+                    let (max_size, sample_rate) = (aud_settings.download_max_size(), aud_settings.usage_sample_rate());
+                    let usage = self.usage.clone();
+                    let (audiences_settings, db) = (self.audiences_settings.clone(), self.db.clone());
+                    let (mirror, unhealthy) = (mirror_client(&self.router, &self.s3, &back), self.readiness.unhealthy(&back));
+
                     future::Either::B(self
                         .authz
-                        .authorize(audience, &sub, zobj, zact)
+                        .authorize(set_s.bucket().audience(), &sub, zobj, zact)
                         .and_then(move |zresp| match zresp {
                             Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
-                            Ok(_) =>
-                                future::Either::B(
-                                future::ok(s3
-                                    .presigned_url("GET", &bucket, &s3_object(&set, &object))
-                                    .map(|ref uri| redirect(uri))
-                                    .map_err(|err| error()
-                                        .status(StatusCode::UNPROCESSABLE_ENTITY)
-                                        .detail(&err.to_string())
-                                        .build())))
-                        }))
+                            Ok(_) => {
+                                let bucket = set_s.bucket().to_string();
+                                let object = s3_object(set_s.label(), &object);
+
+                                if let Err(err) = valid_not_deleted(&audiences_settings, set_s.bucket().audience(), "GET", &bucket, &object, |bucket, key| tombstone_exists(db.as_ref(), &back, bucket, key)) {
+                                    return future::Either::A(wrap_error(err));
+                                }
+
+                                let read = move |s3: Arc<crate::s3::Client>| {
+                                    let (usage, audience, aud_settings) = (usage.clone(), set_s.bucket().audience().to_owned(), aud_settings.clone());
+                                    let (bucket, object) = (bucket.clone(), object.clone());
+                                    s3.download(&bucket, &object, max_size)
+                                        .map(move |download| {
+                                            if let Some(size) = download.size() {
+                                                usage.record(sample_rate, &audience, &bucket, &object, size);
+                                            }
+                                            download
+                                        })
+                                        .map(move |download| match download {
+                                            crate::s3::Download::Inline { body, content_type } => inline(body, content_type, &aud_settings),
+                                            crate::s3::Download::Redirect { ref uri, .. } => redirect(uri).map(|_| Vec::new()),
+                                        })
+                                };
+
+                                future::Either::B(failover(s3, mirror, unhealthy, read)
+                                    .then(move |result| Ok(result
+                                        .map_err(|err| error()
+                                            .status(s3_error_status(&err))
+                                            .detail(&error_detail.describe(&back, &err))
+                                            .build()))))
+                        }}))
                 },
                 Err(err) => {
                     future::Either::A(wrap_error(err))
@@ -265,86 +677,73 @@ impl_web! {
             }
         }
 
-        fn valid_referer(&self, bucket: &str, referer: Option<String>) -> Result<(), Error> {
-            let error = || Error::builder().kind("set_read_error", "Error reading an object using Set API");
+        #[delete("/api/v2/sets/:set/objects/:object")]
+        fn delete(&self, set: String, object: String, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<ObjectEmptyResponse, Error>, Error = ()> {
+            self.delete_ns(self.router.route_set(&self.aud_estm, &set), set, object, sub, referer)
+        }
 
-            match self.aud_estm.estimate(&bucket) {
-                Ok(aud) => match self.audiences_settings.get(aud) {
-                    Some(aud_settings) => if !aud_settings.valid_referer(referer.as_deref()) {
-                        let e = error().status(StatusCode::FORBIDDEN).detail("Invalid request").build();
-                        return Err(e);
+        // Objects of audiences with soft deletes are tombstoned, they are purged from the backend once the retention has passed
+        #[delete("/api/v2/backends/:back/sets/:set/objects/:object")]
+        fn delete_ns(&self, back: String, set: String, object: String, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<ObjectEmptyResponse, Error>, Error = ()> {
+            let error = || Error::builder().kind("set_delete_error", "Error deleting an object by set");
+
+            let zobj = vec!["sets", &set];
+            let zact = "delete";
+            if !self.s3.read().expect("Error acquiring s3 clients lock").contains_key(&back) {
+                return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", &back)).build()));
+            }
+
+            match self.aud_estm.parse_set(&set) {
+                Ok(set_s) => {
+                    if let Err(e) = self.valid_referer(util::CapabilityScope::new("DELETE", &set_s.bucket().to_string(), set_s.label(), &object), referer) {
+                        return future::Either::A(wrap_error(e));
                     }
-                    None => {
-                        let e = error().status(StatusCode::NOT_FOUND).detail(&format!("Audience settings for bucket '{}' not found", &bucket)).build();
-                        return Err(e);
+
+                    if let Err(e) = self.valid_set_label(&set_s.bucket().to_string(), set_s.label()) {
+                        return future::Either::A(wrap_error(e));
                     }
-                }
-                Err(err) => {
-                    let e = error().status(StatusCode::NOT_FOUND).detail(&format!("Audience estimate for bucket '{}' not found, err = {}", &bucket, err)).build();
-                    return Err(e);
-                }
-            }
 
-            Ok(())
-        }
-    }
+                    let retention = match self.audiences_settings.get(set_s.bucket().audience()).and_then(|aud_settings| aud_settings.soft_delete_retention()) {
+                        Some(val) => val,
+                        None => return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&format!("Soft delete is disabled for the audience = '{}'", set_s.bucket().audience())).build()))
+                    };
 
-    impl TagState {
-        #[get("/api/v2/tags/:tag/objects/:object")]
-        fn read(&self, tag: String, object: String, sub: Subject) -> impl Future<Item = Result<Response<&'static str>, Error>, Error = ()> {
-            self.read_ns(String::from(crate::app::util::S3_DEFAULT_CLIENT), tag, object, sub)
-        }
+                    let object = rewrite_object(&self.audiences_settings, set_s.bucket().audience(), &object);
+                    if let Err(detail) = valid_object(&self.audiences_settings, set_s.bucket().audience(), &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&detail).build()));
+                    }
 
-        #[get("/api/v2/backends/:back/tags/:tag/objects/:object")]
-        fn read_ns(&self, back: String, tag: String, object: String, sub: Subject) -> impl Future<Item = Result<Response<&'static str>, Error>, Error = ()> {
-            let error = || Error::builder().kind("tag_read_error", "Error reading a tagged object");
+                    if let Err(detail) = valid_object_pattern(&self.audiences_settings, set_s.bucket().audience(), &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&detail).build()));
+                    }
 
-            let zobj = vec!["tags", &tag];
-            let zact = "read";
-            let s3 = self.s3.clone();
-            let s3 = match s3.get(&back) {
-                Some(val) => val.clone(),
-                None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", &back)).build()))
-            };
-            let db = match self.db.clone() {
-                Some(val) => val,
-                None => return future::Either::A(wrap_error(error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("Tag API is disabled").build()))
-            };
+                    let db = match self.db.clone() {
+                        Some(val) => val,
+                        None => return future::Either::A(wrap_error(error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("Soft delete requires the database").build()))
+                    };
 
-            match self.aud_estm.parse_set(&tag) {
-                Ok(tag_s) => {
-                    future::Either::B(self.authz.authorize(tag_s.bucket().audience(), &sub, zobj, zact).and_then(move |zresp| match zresp {
-                        Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
-                        Ok(_) => {
-                            let maybe_tag = db.get()
-                                .map_err(|_| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("db connection is unavailable").build())
-                                .and_then(|conn| {
-                                    tag::FindQuery::new()
-                                        .tag(&tag_s)
-                                        .execute(&conn)
-                                        .map_err(|err| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail(&err.to_string()).build())
-                                });
+                    let object = normalize_object(&self.audiences_settings, set_s.bucket().audience(), &sub, &object);
 
-                            future::Either::B(future::ok(match maybe_tag {
-                                Ok(Some(tag)) => {
-                                    let bucket = tag.set().bucket().to_string();
-                                    let object = s3_object(tag.set().label(), &object);
+                    future::Either::B(self
+                        .authz
+                        .authorize(set_s.bucket().audience(), &sub, zobj, zact)
+                        .and_then(move |zresp| match zresp {
+                            Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
+                            Ok(_) => {
+                                let bucket = set_s.bucket().to_string();
+                                let object = s3_object(set_s.label(), &object);
+                                let purge_at = chrono::Utc::now() + chrono::Duration::seconds(retention as i64);
 
-                                    s3.presigned_url("GET", &bucket, &object)
-                                        .map(|ref uri| redirect(uri))
-                                        .map_err(|err| error()
-                                            .status(StatusCode::UNPROCESSABLE_ENTITY)
-                                            .detail(&err.to_string())
-                                            .build())
-                                }
-                                Ok(None) => Err(error()
-                                    .status(StatusCode::NOT_FOUND)
-                                    .detail(&format!("the tag = '{}' is not found", &tag))
-                                    .build()),
-                                Err(err) => Err(err)
-                            }))
-                        }
-                    }))
+                                let result = db.get()
+                                    .map_err(|_| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("db connection is unavailable").build())
+                                    .and_then(|conn| {
+                                        tombstone::InsertQuery::new(set_s.bucket().audience(), &back, &bucket, &object, purge_at)
+                                            .execute(&conn)
+                                            .map_err(|err| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail(&err.to_string()).build())
+                                    });
+
+                                future::Either::B(future::ok(result.map(|_| ObjectEmptyResponse {})))
+                        }}))
                 },
                 Err(err) => {
                     future::Either::A(wrap_error(err))
@@ -352,21 +751,391 @@ impl_web! {
             }
         }
 
-        #[put("/api/v2/tags/:tag")]
-        #[content_type("json")]
-        fn update(&self, tag: String, body: UpdateTagPayload, sub: Subject) -> impl Future<Item = Result<TagEmptyResponse, Error>, Error = ()> {
-            let error = || Error::builder().kind("tag_update_error", "Error updating a tag");
+        #[post("/api/v2/sets/:set/objects/:object/restore")]
+        fn restore(&self, set: String, object: String, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<ObjectEmptyResponse, Error>, Error = ()> {
+            self.restore_ns(self.router.route_set(&self.aud_estm, &set), set, object, sub, referer)
+        }
 
-            let zobj = vec!["tags", &tag];
+        // Soft-deleted objects are restored until they are purged
+        #[post("/api/v2/backends/:back/sets/:set/objects/:object/restore")]
+        fn restore_ns(&self, back: String, set: String, object: String, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<ObjectEmptyResponse, Error>, Error = ()> {
+            let error = || Error::builder().kind("set_restore_error", "Error restoring an object by set");
+
+            let zobj = vec!["sets", &set];
             let zact = "update";
-            let db = match self.db.clone() {
-                Some(val) => val,
-                None => return future::Either::A(wrap_error(error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("Tag API is disabled").build()))
-            };
+            if !self.s3.read().expect("Error acquiring s3 clients lock").contains_key(&back) {
+                return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", &back)).build()));
+            }
 
-            match (self.aud_estm.parse_set(&body.set), self.aud_estm.parse_set(&tag)) {
-                (Ok(set_s), Ok(tag_s)) => {
-                    future::Either::B(self.authz.authorize(set_s.bucket().audience(), &sub, zobj, zact).and_then(move |zresp| match zresp {
+            match self.aud_estm.parse_set(&set) {
+                Ok(set_s) => {
+                    if let Err(e) = self.valid_referer(util::CapabilityScope::new("RESTORE", &set_s.bucket().to_string(), set_s.label(), &object), referer) {
+                        return future::Either::A(wrap_error(e));
+                    }
+
+                    if let Err(e) = self.valid_set_label(&set_s.bucket().to_string(), set_s.label()) {
+                        return future::Either::A(wrap_error(e));
+                    }
+
+                    let object = rewrite_object(&self.audiences_settings, set_s.bucket().audience(), &object);
+                    if let Err(detail) = valid_object(&self.audiences_settings, set_s.bucket().audience(), &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&detail).build()));
+                    }
+
+                    if let Err(detail) = valid_object_pattern(&self.audiences_settings, set_s.bucket().audience(), &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&detail).build()));
+                    }
+
+                    let db = match self.db.clone() {
+                        Some(val) => val,
+                        None => return future::Either::A(wrap_error(error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("Soft delete requires the database").build()))
+                    };
+
+                    let object = normalize_object(&self.audiences_settings, set_s.bucket().audience(), &sub, &object);
+
+                    future::Either::B(self
+                        .authz
+                        .authorize(set_s.bucket().audience(), &sub, zobj, zact)
+                        .and_then(move |zresp| match zresp {
+                            Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
+                            Ok(_) => {
+                                let bucket = set_s.bucket().to_string();
+                                let key = s3_object(set_s.label(), &object);
+
+                                let result = db.get()
+                                    .map_err(|_| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("db connection is unavailable").build())
+                                    .and_then(|conn| {
+                                        tombstone::DeleteQuery::new(&back, &bucket, &key)
+                                            .execute(&conn)
+                                            .map_err(|err| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail(&err.to_string()).build())
+                                    });
+
+                                future::Either::B(future::ok(match result {
+                                    Ok(0) => Err(error().status(StatusCode::NOT_FOUND).detail(&format!("the object = '{}' is not deleted", &object)).build()),
+                                    Ok(_) => Ok(ObjectEmptyResponse {}),
+                                    Err(err) => Err(err)
+                                }))
+                        }}))
+                },
+                Err(err) => {
+                    future::Either::A(wrap_error(err))
+                }
+            }
+        }
+
+        #[get("/api/v2/sets/:set")]
+        fn read_default(&self, set: String, sub: Subject, referer: util::Referer, mode: util::ReadMode, geo: util::GeoHint) -> impl Future<Item = Result<Response<Vec<u8>>, Error>, Error = ()> {
+            self.read_default_ns(self.router.route_set_nearest(&self.aud_estm, &set, &geo), set, sub, referer, mode)
+        }
+
+        #[get("/api/v2/backends/:back/sets/:set")]
+        fn read_default_ns(&self, back: String, set: String, sub: Subject, referer: util::Referer, mode: util::ReadMode) -> impl Future<Item = Result<Response<Vec<u8>>, Error>, Error = ()> {
+            let bucket = match self.aud_estm.parse_set(&set) {
+                Ok(set_s) => set_s.bucket().to_string(),
+                Err(err) => return future::Either::A(wrap_error(err)),
+            };
+
+            match self.default_object(&bucket, &set) {
+                Ok(object) => future::Either::B(self.read_ns(back, set, object, sub, referer, mode)),
+                Err(err) => future::Either::A(wrap_error(err)),
+            }
+        }
+
+        #[get("/api/v2/sets/:set/objects")]
+        #[content_type("json")]
+        fn list(&self, set: String, query_string: ObjectListQueryString, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<ObjectListResponse, Error>, Error = ()> {
+            self.list_ns(self.router.route_set(&self.aud_estm, &set), set, query_string, sub, referer)
+        }
+
+        // Objects of the set listed by the service itself, the filter is matched against their names
+        #[get("/api/v2/backends/:back/sets/:set/objects")]
+        #[content_type("json")]
+        fn list_ns(&self, back: String, set: String, query_string: ObjectListQueryString, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<ObjectListResponse, Error>, Error = ()> {
+            let error = || Error::builder().kind("set_list_error", "Error listing objects by set");
+            let error_detail = self.error_detail;
+
+            let filter = match query_string.filter.as_deref().map(Regex::new).transpose() {
+                Ok(val) => val,
+                Err(err) => return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&format!("Invalid filter: {}", err)).build()))
+            };
+
+            let zobj = vec!["sets", &set];
+            let zact = "list";
+            let s3 = match self.s3.read().expect("Error acquiring s3 clients lock").get(&back) {
+                Some(val) => val.clone(),
+                None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", &back)).build()))
+            };
+
+            match self.aud_estm.parse_set(&set) {
+                Ok(set_s) => {
+                    if let Err(e) = self.valid_referer(util::CapabilityScope::new("LIST", &set_s.bucket().to_string(), set_s.label(), query_string.prefix.as_deref().unwrap_or("")), referer) {
+                        return future::Either::A(wrap_error(e));
+                    }
+
+                    if let Err(e) = self.valid_set_label(&set_s.bucket().to_string(), set_s.label()) {
+                        return future::Either::A(wrap_error(e));
+                    }
+
+                    let prefix = rewrite_object(&self.audiences_settings, set_s.bucket().audience(), query_string.prefix.as_deref().unwrap_or(""));
+                    let prefix = normalize_object(&self.audiences_settings, set_s.bucket().audience(), &sub, &prefix);
+                    let max_keys = page_size(&self.audiences_settings, set_s.bucket().audience(), query_string.max_keys, None, MAX_KEYS);
+                    let continuation_token = query_string.continuation_token;
+
+                    future::Either::B(self
+                        .authz
+                        .authorize(set_s.bucket().audience(), &sub, zobj, zact)
+                        .and_then(move |zresp| match zresp {
+                            Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
+                            Ok(_) => {
+                                let bucket = set_s.bucket().to_string();
+                                let label = set_s.label().to_owned();
+
+                                future::Either::B(s3
+                                    .list(&bucket, &s3_object(&label, &prefix), continuation_token, max_keys)
+                                    .then(move |result| Ok(result
+                                        .map(|listing| ObjectListResponse::new(listing, &label, filter.as_ref()))
+                                        .map_err(|err| error()
+                                            .status(s3_error_status(&err))
+                                            .detail(&error_detail.describe(&back, &err))
+                                            .build()))))
+                        }}))
+                },
+                Err(err) => {
+                    future::Either::A(wrap_error(err))
+                }
+            }
+        }
+
+        // Backward compatibility with v1 API
+        #[get("/api/v1/buckets/:bucket/sets/:set")]
+        fn read_default_v1(&self, bucket: String, set: String, sub: Subject, referer: util::Referer, mode: util::ReadMode, geo: util::GeoHint) -> impl Future<Item = Result<Response<Vec<u8>>, Error>, Error = ()> {
+            self.read_default_v1_ns(self.router.route_nearest(&bucket, &geo), bucket, set, sub, referer, mode)
+        }
+
+        #[get("/api/v1/backends/:back/buckets/:bucket/sets/:set")]
+        fn read_default_v1_ns(&self, back: String, bucket: String, set: String, sub: Subject, referer: util::Referer, mode: util::ReadMode) -> impl Future<Item = Result<Response<Vec<u8>>, Error>, Error = ()> {
+            match self.default_object(&bucket, &set) {
+                Ok(object) => future::Either::B(self.read_v1_ns(back, bucket, set, object, sub, referer, mode)),
+                Err(err) => future::Either::A(wrap_error(err)),
+            }
+        }
+
+        // Backward compatibility with v1 API
+        #[get("/api/v1/buckets/:bucket/sets/:set/objects/:object")]
+        fn read_v1(&self, bucket: String, set: String, object: String, sub: Subject, referer: util::Referer, mode: util::ReadMode, geo: util::GeoHint) -> impl Future<Item = Result<Response<Vec<u8>>, Error>, Error = ()> {
+            self.read_v1_ns(self.router.route_nearest(&bucket, &geo), bucket, set, object, sub, referer, mode)
+        }
+
+        #[get("/api/v1/backends/:back/buckets/:bucket/sets/:set/objects/:object")]
+        fn read_v1_ns(&self, back: String, bucket: String, set: String, object: String, sub: Subject, referer: util::Referer, mode: util::ReadMode) -> impl Future<Item = Result<Response<Vec<u8>>, Error>, Error = ()> {
+            let error = || Error::builder().kind("set_read_error", "Error reading an object by set");
+            let error_detail = self.error_detail;
+
+            if let Err(e) = self.valid_referer(util::CapabilityScope::new("GET", &bucket, &set, &object), referer) {
+                return future::Either::A(wrap_error(e));
+            }
+
+            if let Err(e) = self.valid_set_label(&bucket, &set) {
+                return future::Either::A(wrap_error(e));
+            }
+
+            let zobj = vec!["buckets", &bucket, "sets", &set];
+            let zact = "read";
+            let s3 = match self.s3.read().expect("Error acquiring s3 clients lock").get(&back) {
+                Some(val) => val.clone(),
+                None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", &back)).build()))
+            };
+
+            match self.aud_estm.estimate(&bucket) {
+                Ok(audience) => {
+                    let object = rewrite_object(&self.audiences_settings, audience, &object);
+                    if let Err(detail) = valid_object(&self.audiences_settings, audience, &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&detail).build()));
+                    }
+
+                    if let Err(detail) = valid_object_pattern(&self.audiences_settings, audience, &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&detail).build()));
+                    }
+
+                    let object = normalize_object(&self.audiences_settings, audience, &sub, &object);
+                    let aud_settings = self.audiences_settings.get(audience).cloned().unwrap_or_default();
+                    let (usage, usage_audience) = (self.usage.clone(), audience.to_owned());
+                    let (audiences_settings, db) = (self.audiences_settings.clone(), self.db.clone());
+                    let (mirror, unhealthy) = (mirror_client(&self.router, &self.s3, &back), self.readiness.unhealthy(&back));
+
+                    future::Either::B(self
+                        .authz
+                        .authorize(audience, &sub, zobj, zact)
+                        .and_then(move |zresp| match zresp {
+                            Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
+                            Ok(_) => {
+                                let object = s3_object(&set, &object);
+                                if let Err(err) = valid_not_deleted(&audiences_settings, &usage_audience, "GET", &bucket, &object, |bucket, key| tombstone_exists(db.as_ref(), &back, bucket, key)) {
+                                    return future::Either::A(wrap_error(err));
+                                }
+
+                                future::Either::B(failover(s3, mirror, unhealthy, move |s3| read_response(s3, usage.clone(), mode, &usage_audience, &aud_settings, &bucket, &object))
+                                    .then(move |result| Ok(result
+                                        .map_err(|err| error()
+                                            .status(s3_error_status(&err))
+                                            .detail(&error_detail.describe(&back, &err))
+                                            .build()))))
+                        }}))
+                },
+                Err(err) => {
+                    future::Either::A(wrap_error(err))
+                }
+            }
+        }
+
+        fn valid_referer(&self, scope: util::CapabilityScope, referer: util::Referer) -> Result<(), Error> {
+            let error = || Error::builder().kind("set_read_error", "Error reading an object using Set API");
+            let bucket = scope.bucket();
+
+            match self.aud_estm.estimate(&bucket) {
+                Ok(aud) => match self.audiences_settings.get(aud) {
+                    Some(aud_settings) => if !aud_settings.valid_caller(&scope, referer.referer(), referer.capability()) {
+                        let e = error().status(StatusCode::FORBIDDEN).detail("Invalid request").build();
+                        return Err(e);
+                    } else if !aud_settings.valid_expected_audience(aud, referer.expected_audience()) {
+                        let e = error().status(StatusCode::FORBIDDEN).detail(&format!("Bucket '{}' doesn't belong to the expected audience", &bucket)).build();
+                        return Err(e);
+                    }
+                    None => {
+                        let e = error().status(StatusCode::NOT_FOUND).detail(&format!("Audience settings for bucket '{}' not found", &bucket)).build();
+                        return Err(e);
+                    }
+                }
+                Err(err) => {
+                    let e = error().status(StatusCode::NOT_FOUND).detail(&format!("Audience estimate for bucket '{}' not found, err = {}", &bucket, err)).build();
+                    return Err(e);
+                }
+            }
+
+            Ok(())
+        }
+
+        fn default_object(&self, bucket: &str, set: &str) -> Result<String, Error> {
+            let error = || Error::builder().kind("set_read_error", "Error reading an object using Set API");
+
+            match self.aud_estm.estimate(&bucket).ok().and_then(|aud| self.audiences_settings.get(aud)).and_then(|aud_settings| aud_settings.default_object()) {
+                Some(object) => Ok(object.to_owned()),
+                None => {
+                    let e = error().status(StatusCode::NOT_FOUND).detail(&format!("Default object for set '{}' is not configured", set)).build();
+                    Err(e)
+                }
+            }
+        }
+
+        fn valid_set_label(&self, bucket: &str, label: &str) -> Result<(), Error> {
+            let error = || Error::builder().kind("set_read_error", "Error reading an object using Set API");
+
+            match self.aud_estm.estimate(&bucket).ok().and_then(|aud| self.audiences_settings.get(aud)) {
+                Some(aud_settings) if !aud_settings.valid_set_label(label) => {
+                    let e = error().status(StatusCode::BAD_REQUEST).detail(&format!("Invalid set label '{}'", label)).build();
+                    Err(e)
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl TagState {
+        #[get("/api/v2/tags/:tag/objects/:object")]
+        fn read(&self, tag: String, object: String, sub: Subject, mode: util::ReadMode, geo: util::GeoHint) -> impl Future<Item = Result<Response<Vec<u8>>, Error>, Error = ()> {
+            self.read_ns(self.router.route_set_nearest(&self.aud_estm, &tag, &geo), tag, object, sub, mode)
+        }
+
+        #[get("/api/v2/backends/:back/tags/:tag/objects/:object")]
+        fn read_ns(&self, back: String, tag: String, object: String, sub: Subject, mode: util::ReadMode) -> impl Future<Item = Result<Response<Vec<u8>>, Error>, Error = ()> {
+            let error = || Error::builder().kind("tag_read_error", "Error reading a tagged object");
+            let error_detail = self.error_detail;
+
+            let zobj = vec!["tags", &tag];
+            let zact = "read";
+            let s3 = match self.s3.read().expect("Error acquiring s3 clients lock").get(&back) {
+                Some(val) => val.clone(),
+                None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", &back)).build()))
+            };
+            let db = match self.db.clone() {
+                Some(val) => val,
+                None => return future::Either::A(wrap_error(error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("Tag API is disabled").build()))
+            };
+
+            let audiences_settings = self.audiences_settings.clone();
+            let usage = self.usage.clone();
+            let subject = sub.clone();
+            let (mirror, unhealthy) = (mirror_client(&self.router, &self.s3, &back), self.readiness.unhealthy(&back));
+
+            match self.aud_estm.parse_set(&tag) {
+                Ok(tag_s) => {
+                    future::Either::B(self.authz.authorize(tag_s.bucket().audience(), &sub, zobj, zact).and_then(move |zresp| match zresp {
+                        Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
+                        Ok(_) => {
+                            let maybe_tag = db.get()
+                                .map_err(|_| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("db connection is unavailable").build())
+                                .and_then(|conn| {
+                                    tag::FindQuery::new()
+                                        .tag(&tag_s)
+                                        .execute(&conn)
+                                        .map_err(|err| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail(&err.to_string()).build())
+                                });
+
+                            future::Either::B(match maybe_tag {
+                                Ok(Some(tag)) => {
+                                    // The object is a part of the tagged set, the settings of its audience apply
+                                    let audience = tag.set().bucket().audience();
+                                    let object = rewrite_object(&audiences_settings, audience, &object);
+                                    if let Err((status, detail)) = valid_read_object(&audiences_settings, audience, &object) {
+                                        return future::Either::A(wrap_error(error().status(status).detail(&detail).build()));
+                                    }
+
+                                    let bucket = tag.set().bucket().to_string();
+                                    let object = normalize_object(&audiences_settings, audience, &subject, &object);
+                                    let object = s3_object(tag.set().label(), &object);
+                                    let aud_settings = audiences_settings.get(audience).cloned().unwrap_or_default();
+                                    let usage_audience = audience.to_owned();
+
+                                    match valid_not_deleted(&audiences_settings, audience, "GET", &bucket, &object, |bucket, key| tombstone_exists(Some(&db), &back, bucket, key)) {
+                                        Ok(()) => future::Either::B(failover(s3, mirror, unhealthy, move |s3| read_response(s3, usage.clone(), mode, &usage_audience, &aud_settings, &bucket, &object))
+                                            .then(move |result| Ok(result
+                                                .map_err(|err| error()
+                                                    .status(s3_error_status(&err))
+                                                    .detail(&error_detail.describe(&back, &err))
+                                                    .build())))),
+                                        Err(err) => future::Either::A(wrap_error(err)),
+                                    }
+                                }
+                                Ok(None) => future::Either::A(wrap_error(error()
+                                    .status(StatusCode::NOT_FOUND)
+                                    .detail(&format!("the tag = '{}' is not found", &tag))
+                                    .build())),
+                                Err(err) => future::Either::A(wrap_error(err))
+                            })
+                        }
+                    }))
+                },
+                Err(err) => {
+                    future::Either::A(wrap_error(err))
+                }
+            }
+        }
+
+        #[put("/api/v2/tags/:tag")]
+        #[content_type("json")]
+        fn update(&self, tag: String, body: UpdateTagPayload, sub: Subject) -> impl Future<Item = Result<TagEmptyResponse, Error>, Error = ()> {
+            let error = || Error::builder().kind("tag_update_error", "Error updating a tag");
+
+            let zobj = vec!["tags", &tag];
+            let zact = "update";
+            let db = match self.db.clone() {
+                Some(val) => val,
+                None => return future::Either::A(wrap_error(error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("Tag API is disabled").build()))
+            };
+
+            match (self.aud_estm.parse_set(&body.set), self.aud_estm.parse_set(&tag)) {
+                (Ok(set_s), Ok(tag_s)) => {
+                    future::Either::B(self.authz.authorize(set_s.bucket().audience(), &sub, zobj, zact).and_then(move |zresp| match zresp {
                         Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
                         Ok(_) => {
                             let resp = db.get()
@@ -453,7 +1222,9 @@ impl_web! {
                     let include = parse_sets(&query_string.include, filter_b.audience());
                     let exclude = parse_sets(&query_string.exclude.unwrap_or_else(|| String::from("")), filter_b.audience());
                     let offset = query_string.offset.unwrap_or_else(|| 0);
-                    let limit = std::cmp::min(query_string.limit.unwrap_or_else(|| MAX_LIMIT), MAX_LIMIT);
+                    let limit = query_string.limit.map(|val| val.max(0) as u64);
+                    let limit = page_size(&self.audiences_settings, filter_b.audience(), limit, Some(MAX_LIMIT as u64), MAX_LIMIT as u64)
+                        .map_or(MAX_LIMIT, |val| val as i64);
 
                     future::Either::B(self.authz.authorize(filter_b.audience(), &sub, zobj, zact).and_then(move |zresp| match zresp {
                         Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
@@ -482,119 +1253,589 @@ impl_web! {
     impl SignState {
         #[post("/api/v2/sign")]
         #[content_type("json")]
-        fn sign(&self, body: SignPayload, sub: Subject, referer: Option<String>) -> impl Future<Item = Result<SignResponse, Error>, Error = ()> {
-            self.sign_ns(String::from(crate::app::util::S3_DEFAULT_CLIENT), body, sub, referer)
+        fn sign(&self, body: SignPayload, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<SignResponse, Error>, Error = ()> {
+            self.sign_ns(self.router.route_set(&self.aud_estm, &body.set), body, sub, referer)
         }
 
         #[post("/api/v2/backends/:back/sign")]
         #[content_type("json")]
-        fn sign_ns(&self, back: String, body: SignPayload, sub: Subject, referer: Option<String>) -> impl Future<Item = Result<SignResponse, Error>, Error = ()> {
+        fn sign_ns(&self, back: String, body: SignPayload, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<SignResponse, Error>, Error = ()> {
             let error = || Error::builder().kind("sign_error", "Error signing a request");
+            let error_detail = self.error_detail;
+            let sign_limiter = self.sign_limiter.clone();
+
+            let headers = match util::normalize_headers(&body.headers) {
+                Ok(val) => val,
+                Err(err) => return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&err.to_string()).build()))
+            };
+            let headers = match self.aud_estm.parse_set(&body.set) {
+                Ok(set_s) => match self.with_default_headers(&set_s.bucket().to_string(), headers) {
+                    Ok(val) => val,
+                    Err(e) => return future::Either::A(wrap_error(e)),
+                },
+                Err(_) => headers,
+            };
+            let headers = match self.aud_estm.parse_set(&body.set) {
+                Ok(set_s) => match self.with_subject_header(&set_s.bucket().to_string(), &sub, headers) {
+                    Ok(val) => val,
+                    Err(e) => return future::Either::A(wrap_error(e)),
+                },
+                Err(_) => headers,
+            };
+            let expires_in = match self.aud_estm.parse_set(&body.set) {
+                Ok(set_s) => match self.valid_expires_in(&set_s.bucket().to_string(), body.expires_in) {
+                    Ok(val) => val,
+                    Err(e) => return future::Either::A(wrap_error(e)),
+                },
+                Err(_) => body.expires_in,
+            };
 
             if let Ok(set_s) = self.aud_estm.parse_set(&body.set) {
-                if let Err(e) = self.valid_referer(&set_s.bucket().to_string(), referer) {
+                if let Err(e) = self.valid_referer(util::CapabilityScope::new(&body.method, &set_s.bucket().to_string(), set_s.label(), &body.object), referer) {
+                    return future::Either::A(wrap_error(e));
+                }
+
+                if let Err(e) = self.valid_set_label(&set_s.bucket().to_string(), set_s.label()) {
+                    return future::Either::A(wrap_error(e));
+                }
+
+                if let Err(e) = self.valid_content_type(&set_s.bucket().to_string(), &body.method, &headers) {
+                    return future::Either::A(wrap_error(e));
+                }
+
+                if let Err(e) = self.valid_content_length(&set_s.bucket().to_string(), &body.method, body.content_length) {
                     return future::Either::A(wrap_error(e));
                 }
+
+                if let Some(ref overrides) = body.response_overrides {
+                    if let Err(e) = self.valid_response_overrides(&set_s.bucket().to_string(), &body.method, overrides) {
+                        return future::Either::A(wrap_error(e));
+                    }
+                }
             }
 
             let zobj = vec!["sets", &body.set];
-            let zact = match parse_action(&body.method) {
+            let zact = match parse_action(&body.method, &self.method_actions) {
                 Ok(val) => val,
                 Err(err) => return future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build()))
             };
-            let s3 = self.s3.clone();
-            let s3 = match s3.get(&back) {
+            let echo_method = if body.echo_method.unwrap_or(false) { Some(body.method.clone()) } else { None };
+            let tagging = body.tagging.unwrap_or(false);
+            if tagging && body.method != "GET" && body.method != "PUT" {
+                return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&format!("invalid method = {} for tagging", &body.method)).build()));
+            }
+            if body.decoded_content_length.is_some() && body.method != "PUT" {
+                return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&format!("invalid method = {} for streaming", &body.method)).build()));
+            }
+            let checksum = match checksum(&body.method, body.checksum_algorithm.as_deref(), body.checksum_value.as_deref(), body.decoded_content_length.is_some()) {
+                Ok(val) => val,
+                Err(detail) => return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&detail).build()))
+            };
+            let s3 = match self.s3.read().expect("Error acquiring s3 clients lock").get(&back) {
                 Some(val) => val.clone(),
                 None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", &back)).build()))
             };
 
             match self.aud_estm.parse_set(&body.set) {
                 Ok(set_s) => {
-                    future::Either::B(self.authz.authorize(set_s.bucket().audience(), &sub, zobj, zact).and_then(move |zresp| match zresp {
-                        Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
-                        Ok(_) => {
+                    let object = rewrite_object(&self.audiences_settings, set_s.bucket().audience(), &body.object);
+                    if let Err(detail) = valid_object(&self.audiences_settings, set_s.bucket().audience(), &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&detail).build()));
+                    }
+
+                    if let Err(detail) = valid_object_pattern(&self.audiences_settings, set_s.bucket().audience(), &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&detail).build()));
+                    }
+
+                    if let Err(detail) = valid_hard_delete(&self.audiences_settings, set_s.bucket().audience(), &body.method) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&detail).build()));
+                    }
+
+                    let storage_class = match storage_class(&self.audiences_settings, set_s.bucket().audience(), &body.method, body.storage_class.as_deref(), &headers) {
+                        Ok(val) => val,
+                        Err(detail) => return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&detail).build())),
+                    };
+
+                    let object = normalize_object(&self.audiences_settings, set_s.bucket().audience(), &sub, &object);
+                    let tags = self.set_tags(set_s.clone(), &body.set, &sub, zact, body.include_tags.unwrap_or(false));
+                    let (audiences_settings, db) = (self.audiences_settings.clone(), self.db.clone());
+
+                    future::Either::B(self.authz.authorize(set_s.bucket().audience(), &sub, zobj, zact).and_then(move |zresp| match zresp {
+                        Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
+                        Ok(_) => {
+                            let (bucket, s3_key) = (set_s.bucket().to_string(), s3_object(set_s.label(), &object));
+                            if let Err(err) = valid_not_deleted(&audiences_settings, set_s.bucket().audience(), &body.method, &bucket, &s3_key, |bucket, key| tombstone_exists(db.as_ref(), &back, bucket, key)) {
+                                return future::Either::A(wrap_error(err));
+                            }
+
                             // URI builder
                             let mut builder = util::S3SignedRequestBuilder::new()
+                                .error_detail(error_detail, &back)
                                 .method(&body.method)
                                 .bucket(&set_s.bucket().to_string())
-                                .object(&s3_object(set_s.label(), &body.object));
-                            for (key, val) in body.headers {
+                                .object(&s3_object(set_s.label(), &object))
+                                .request_payer(body.request_payer.unwrap_or(false));
+                            for (key, val) in headers {
                                 builder = builder.add_header(&key, &val);
                             }
+                            if let Some(content_length) = body.content_length {
+                                builder = builder.add_header("content-length", &content_length.to_string());
+                            }
+                            if tagging {
+                                builder = builder.add_param("tagging", None);
+                            }
+                            if let Some((header, Some(ref value))) = checksum {
+                                builder = builder.add_header(header, value);
+                            }
+                            if let Some(ref storage_class) = storage_class {
+                                builder = builder.add_header(STORAGE_CLASS_HEADER, storage_class);
+                            }
+                            if let Some(decoded_content_length) = body.decoded_content_length {
+                                let trailer = checksum.as_ref().map(|(header, _)| *header);
+                                builder = builder.streaming(decoded_content_length, trailer);
+                            }
+                            for (key, val) in body.response_overrides.unwrap_or_default() {
+                                builder = builder.add_param(&key, Some(&val));
+                            }
+                            if let Some(expires_in) = expires_in {
+                                builder = builder.expires_in(std::time::Duration::from_secs(expires_in));
+                            }
+                            if let Some(ref service) = body.service {
+                                builder = builder.service(service);
+                            }
+
+                            // Existing objects of write-once audiences are checked only once the subject is authorized
+                            let object_exists = { let s3 = s3.clone(); move || s3.object_exists(&bucket, &s3_key) };
+                            let write_once = valid_write_once(&audiences_settings, set_s.bucket().audience(), &body.method, error_detail, &back, object_exists);
 
-                            future::Either::B(future::ok(builder.build(&s3).map(|uri| SignResponse { uri })))
+                            future::Either::B(write_once.and_then(move |write_once| {
+                                let uri = match write_once {
+                                    Ok(()) => future::Either::A(sign_limiter.run(move || builder.build(&s3))),
+                                    Err(err) => future::Either::B(future::ok(Err(err))),
+                                };
+                                uri.join(tags).map(move |(uri, tags)| match (uri, tags) {
+                                    (Ok(uri), Ok(tags)) => Ok(SignResponse::new(uri).tags(tags).method(echo_method.as_deref())),
+                                    (Err(err), _) | (_, Err(err)) => Err(err),
+                                })
+                            }))
                     }}))
                 },
                 Err(err) => future::Either::A(wrap_error(err))
             }
         }
 
+        #[post("/api/v2/sign/batch")]
+        #[content_type("json")]
+        fn sign_batch(&self, body: SignBatchPayload, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<SignBatchResponse, Error>, Error = ()> {
+            self.sign_items(None, body, sub, referer)
+        }
+
+        // Every item is signed and authorized on its own, failures are reported per item
+        #[post("/api/v2/backends/:back/sign/batch")]
+        #[content_type("json")]
+        fn sign_batch_ns(&self, back: String, body: SignBatchPayload, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<SignBatchResponse, Error>, Error = ()> {
+            self.sign_items(Some(back), body, sub, referer)
+        }
+
+        // Items of a batch without a backend are routed by their buckets one by one
+        fn sign_items(&self, back: Option<String>, body: SignBatchPayload, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<SignBatchResponse, Error>, Error = ()> {
+            let error = || Error::builder().kind("sign_error", "Error signing a request");
+
+            if body.items.len() > self.max_batch_size {
+                return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&format!("batch size = {} exceeds the maximum = {}", body.items.len(), self.max_batch_size)).build()));
+            }
+
+            // Items are checked and signed once they're reached, so that fail_fast stops at the first failed one
+            let fail_fast = body.fail_fast.unwrap_or(false);
+            let state = Arc::new(self.clone());
+            let items = body.items
+                .into_iter()
+                .map(|item| {
+                    let (state, back, sub, referer) = (state.clone(), back.clone(), sub.clone(), referer.clone());
+                    move || {
+                        let back = back.unwrap_or_else(|| state.router.route_set(&state.aud_estm, &item.set));
+                        state.sign_ns(back, item.into(), sub, referer)
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            future::Either::B(SignBatchResponse::sign(items, fail_fast))
+        }
+
+        #[post("/api/v2/sign/list")]
+        #[content_type("json")]
+        fn sign_list(&self, body: SignListPayload, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<SignResponse, Error>, Error = ()> {
+            self.sign_list_ns(self.router.route_set(&self.aud_estm, &body.set), body, sub, referer)
+        }
+
+        #[post("/api/v2/backends/:back/sign/list")]
+        #[content_type("json")]
+        fn sign_list_ns(&self, back: String, body: SignListPayload, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<SignResponse, Error>, Error = ()> {
+            let error = || Error::builder().kind("sign_error", "Error signing a request");
+            let error_detail = self.error_detail;
+            let sign_limiter = self.sign_limiter.clone();
+
+            if let Ok(set_s) = self.aud_estm.parse_set(&body.set) {
+                if let Err(e) = self.valid_referer(util::CapabilityScope::new("LIST", &set_s.bucket().to_string(), set_s.label(), body.prefix.as_deref().unwrap_or("")), referer) {
+                    return future::Either::A(wrap_error(e));
+                }
+
+                if let Err(e) = self.valid_set_label(&set_s.bucket().to_string(), set_s.label()) {
+                    return future::Either::A(wrap_error(e));
+                }
+            }
+
+            let zobj = vec!["sets", &body.set];
+            let zact = "list";
+            let s3 = match self.s3.read().expect("Error acquiring s3 clients lock").get(&back) {
+                Some(val) => val.clone(),
+                None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", &back)).build()))
+            };
+
+            match self.aud_estm.parse_set(&body.set) {
+                Ok(set_s) => {
+                    let prefix = rewrite_object(&self.audiences_settings, set_s.bucket().audience(), body.prefix.as_deref().unwrap_or(""));
+                    let prefix = normalize_object(&self.audiences_settings, set_s.bucket().audience(), &sub, &prefix);
+                    let max_keys = page_size(&self.audiences_settings, set_s.bucket().audience(), body.max_keys, None, MAX_KEYS);
+                    let headers = match self.with_subject_header(&set_s.bucket().to_string(), &sub, BTreeMap::new()) {
+                        Ok(val) => val,
+                        Err(e) => return future::Either::A(wrap_error(e)),
+                    };
+
+                    future::Either::B(self.authz.authorize(set_s.bucket().audience(), &sub, zobj, zact).and_then(move |zresp| match zresp {
+                        Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
+                        Ok(_) => {
+                            // Bucket-level ListObjectsV2 request restricted to the set
+                            let prefix = s3_object(set_s.label(), &prefix);
+                            let mut builder = util::S3SignedRequestBuilder::new()
+                                .error_detail(error_detail, &back)
+                                .method("GET")
+                                .bucket(&set_s.bucket().to_string())
+                                .object("")
+                                .add_param("list-type", Some("2"))
+                                .add_param("prefix", Some(&prefix));
+                            if let Some(ref token) = body.continuation_token {
+                                builder = builder.add_param("continuation-token", Some(token));
+                            }
+                            if let Some(max_keys) = max_keys {
+                                builder = builder.add_param("max-keys", Some(&max_keys.to_string()));
+                            }
+                            for (key, val) in headers {
+                                builder = builder.add_header(&key, &val);
+                            }
+
+                            future::Either::B(sign_limiter.run(move || builder.build(&s3)).map(|uri| uri.map(SignResponse::new)))
+                    }}))
+                },
+                Err(err) => future::Either::A(wrap_error(err))
+            }
+        }
+
+        #[post("/api/v2/share")]
+        #[content_type("json")]
+        fn share(&self, body: SharePayload, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<ShareResponse, Error>, Error = ()> {
+            self.share_ns(self.router.route_set(&self.aud_estm, &body.set), body, sub, referer)
+        }
+
+        // The signed URI is standalone, the token is redeemed by anyone until it expires or runs out of downloads
+        #[post("/api/v2/backends/:back/share")]
+        #[content_type("json")]
+        fn share_ns(&self, back: String, body: SharePayload, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<ShareResponse, Error>, Error = ()> {
+            let error = || Error::builder().kind("share_error", "Error sharing an object");
+            let error_detail = self.error_detail;
+            let sign_limiter = self.sign_limiter.clone();
+
+            let zobj = vec!["sets", &body.set];
+            let zact = "read";
+            let s3 = match self.s3.read().expect("Error acquiring s3 clients lock").get(&back) {
+                Some(val) => val.clone(),
+                None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", &back)).build()))
+            };
+            let db = match self.db.clone() {
+                Some(val) => val,
+                None => return future::Either::A(wrap_error(error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("Sharing requires the database").build()))
+            };
+            let max_downloads = match body.max_downloads {
+                Some(0) => return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail("max_downloads must be positive").build())),
+                Some(val) => Some(val.min(i64::max_value() as u64) as i64),
+                None => None,
+            };
+
+            match self.aud_estm.parse_set(&body.set) {
+                Ok(set_s) => {
+                    if let Err(e) = self.valid_referer(util::CapabilityScope::new("SHARE", &set_s.bucket().to_string(), set_s.label(), &body.object), referer) {
+                        return future::Either::A(wrap_error(e));
+                    }
+
+                    if let Err(e) = self.valid_set_label(&set_s.bucket().to_string(), set_s.label()) {
+                        return future::Either::A(wrap_error(e));
+                    }
+
+                    if !self.audiences_settings.get(set_s.bucket().audience()).map(|aud_settings| aud_settings.sharing()).unwrap_or(false) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&format!("Sharing is disabled for the audience = '{}'", set_s.bucket().audience())).build()));
+                    }
+
+                    let expires_in = match self.valid_expires_in(&set_s.bucket().to_string(), body.expires_in) {
+                        Ok(val) => val.unwrap_or(DEFAULT_SHARE_EXPIRES_IN),
+                        Err(e) => return future::Either::A(wrap_error(e)),
+                    };
+                    if expires_in > MAX_SHARE_EXPIRES_IN {
+                        return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&format!("expires_in is greater than the maximum = {}", MAX_SHARE_EXPIRES_IN)).build()));
+                    }
+
+                    let object = rewrite_object(&self.audiences_settings, set_s.bucket().audience(), &body.object);
+                    if let Err(detail) = valid_object(&self.audiences_settings, set_s.bucket().audience(), &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&detail).build()));
+                    }
+
+                    if let Err(detail) = valid_object_pattern(&self.audiences_settings, set_s.bucket().audience(), &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&detail).build()));
+                    }
+
+                    let object = normalize_object(&self.audiences_settings, set_s.bucket().audience(), &sub, &object);
+
+                    future::Either::B(self.authz.authorize(set_s.bucket().audience(), &sub, zobj, zact).and_then(move |zresp| match zresp {
+                        Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
+                        Ok(_) => {
+                            let bucket = set_s.bucket().to_string();
+                            let object = s3_object(set_s.label(), &object);
+                            let token = uuid::Uuid::new_v4().simple().to_string();
+                            let expires_at = chrono::Utc::now() + chrono::Duration::seconds(expires_in as i64);
+
+                            let builder = util::S3SignedRequestBuilder::new()
+                                .error_detail(error_detail, &back)
+                                .method("GET")
+                                .bucket(&bucket)
+                                .object(&object)
+                                .expires_in(std::time::Duration::from_secs(expires_in));
+
+                            future::Either::B(sign_limiter.run(move || builder.build(&s3)).map(move |uri| uri.and_then(|uri| {
+                                db.get()
+                                    .map_err(|_| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("db connection is unavailable").build())
+                                    .and_then(|conn| {
+                                        share::InsertQuery::new(&token, &back, &bucket, &object, &sub.to_string(), max_downloads, expires_at)
+                                            .execute(&conn)
+                                            .map_err(|err| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail(&err.to_string()).build())
+                                    })
+                                    .map(|_| ShareResponse { uri, token, expires_at: expires_at.to_rfc3339() })
+                            })))
+                    }}))
+                },
+                Err(err) => future::Either::A(wrap_error(err))
+            }
+        }
+
+        // Public, the token itself grants the download
+        #[get("/api/v2/shares/:token")]
+        fn redeem_share(&self, token: String) -> impl Future<Item = Result<Response<&'static str>, Error>, Error = ()> {
+            let error = || Error::builder().kind("share_error", "Error redeeming a shared object");
+            let error_detail = self.error_detail;
+
+            let db = match self.db.clone() {
+                Some(val) => val,
+                None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail("the share is not found").build()))
+            };
+
+            let now = chrono::Utc::now();
+            let conn = || db.get().map_err(|_| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("db connection is unavailable").build());
+            let redemption = redeem_checked(
+                || conn().and_then(|conn| {
+                    share::FindQuery::new(&token, now)
+                        .execute(&conn)
+                        .map_err(|err| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail(&err.to_string()).build())
+                }).and_then(redeemed),
+                |shared| {
+                    if !self.s3.read().expect("Error acquiring s3 clients lock").contains_key(shared.backend()) {
+                        return Err(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", shared.backend())).build());
+                    }
+
+                    self.aud_estm.estimate(shared.bucket()).and_then(|audience| {
+                        valid_not_deleted(&self.audiences_settings, audience, "GET", shared.bucket(), shared.object(), |bucket, key| tombstone_exists(Some(&db), shared.backend(), bucket, key))
+                    })
+                },
+                || conn().and_then(|conn| {
+                    share::RedeemQuery::new(&token, now)
+                        .execute(&conn)
+                        .map_err(|err| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail(&err.to_string()).build())
+                }).and_then(redeemed),
+            );
+
+            let shared = match redemption {
+                Ok(val) => val,
+                Err(e) => return future::Either::A(wrap_error(e)),
+            };
+            let s3 = match self.s3.read().expect("Error acquiring s3 clients lock").get(shared.backend()) {
+                Some(val) => val.clone(),
+                None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", shared.backend())).build()))
+            };
+
+            let builder = util::S3SignedRequestBuilder::new()
+                .error_detail(error_detail, shared.backend())
+                .method("GET")
+                .bucket(shared.bucket())
+                .object(shared.object());
+            future::Either::B(self.sign_limiter.run(move || builder.build(&s3)).map(|uri| uri.map(|ref uri| redirect(uri))))
+        }
+
         // Backward compatibility with v1 API
         #[post("/api/v1/sign")]
         #[content_type("json")]
-        fn sign_v1(&self, body: SignPayloadV1, sub: Subject, referer: Option<String>) -> impl Future<Item = Result<SignResponse, Error>, Error = ()> {
-            self.sign_v1_ns(String::from(crate::app::util::S3_DEFAULT_CLIENT), body, sub, referer)
+        fn sign_v1(&self, body: SignPayloadV1, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<SignResponse, Error>, Error = ()> {
+            self.sign_v1_ns(self.router.route(&body.bucket), body, sub, referer)
         }
 
         #[post("/api/v1/backends/:back/sign")]
         #[content_type("json")]
-        fn sign_v1_ns(&self, back: String, body: SignPayloadV1, sub: Subject, referer: Option<String>) -> impl Future<Item = Result<SignResponse, Error>, Error = ()> {
+        fn sign_v1_ns(&self, back: String, body: SignPayloadV1, sub: Subject, referer: util::Referer) -> impl Future<Item = Result<SignResponse, Error>, Error = ()> {
             let error = || Error::builder().kind("sign_error", "Error signing a request");
+            let error_detail = self.error_detail;
+            let sign_limiter = self.sign_limiter.clone();
+
+            let headers = match util::normalize_headers(&body.headers) {
+                Ok(val) => val,
+                Err(err) => return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&err.to_string()).build()))
+            };
+            let headers = match self.with_default_headers(&body.bucket, headers) {
+                Ok(val) => val,
+                Err(e) => return future::Either::A(wrap_error(e)),
+            };
+            let headers = match self.with_subject_header(&body.bucket, &sub, headers) {
+                Ok(val) => val,
+                Err(e) => return future::Either::A(wrap_error(e)),
+            };
+
+            if let Err(e) = self.valid_referer(util::CapabilityScope::new(&body.method, &body.bucket, body.set.as_deref().unwrap_or(""), &body.object), referer) {
+                return future::Either::A(wrap_error(e));
+            }
+
+            if let Some(ref set) = body.set {
+                if let Err(e) = self.valid_set_label(&body.bucket, set) {
+                    return future::Either::A(wrap_error(e));
+                }
+            }
+
+            if let Err(e) = self.valid_content_type(&body.bucket, &body.method, &headers) {
+                return future::Either::A(wrap_error(e));
+            }
 
-            if let Err(e) = self.valid_referer(&body.bucket, referer) {
+            if let Err(e) = self.valid_content_length(&body.bucket, &body.method, body.content_length) {
                 return future::Either::A(wrap_error(e));
             }
 
+            if let Some(ref overrides) = body.response_overrides {
+                if let Err(e) = self.valid_response_overrides(&body.bucket, &body.method, overrides) {
+                    return future::Either::A(wrap_error(e));
+                }
+            }
+
+            let expires_in = match self.valid_expires_in(&body.bucket, body.expires_in) {
+                Ok(val) => val,
+                Err(e) => return future::Either::A(wrap_error(e)),
+            };
+            let checksum = match checksum(&body.method, body.checksum_algorithm.as_deref(), body.checksum_value.as_deref(), false) {
+                Ok(val) => val,
+                Err(detail) => return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&detail).build()))
+            };
+
             // Authz subject, object, and action
-            let (object, zobj) = match body.set {
-                Some(ref set) => (
-                    s3_object(&set, &body.object),
-                    vec!["buckets", &body.bucket, "sets", set]
-                ),
-                None => (
-                    body.object.to_owned(),
-                    vec!["buckets", &body.bucket, "objects", &body.object]
-                )
-            };
-            let zact = match parse_action(&body.method) {
+            let zobj = match body.set {
+                Some(ref set) => vec!["buckets", &body.bucket, "sets", set],
+                None => vec!["buckets", &body.bucket, "objects", &body.object],
+            };
+            let zact = match parse_action(&body.method, &self.method_actions) {
                 Ok(val) => val,
                 Err(err) => return future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build()))
             };
-            let s3 = self.s3.clone();
-            let s3 = match s3.get(&back) {
+            let s3 = match self.s3.read().expect("Error acquiring s3 clients lock").get(&back) {
                 Some(val) => val.clone(),
                 None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", &back)).build()))
             };
 
             match self.aud_estm.estimate(&body.bucket) {
                 Ok(audience) => {
+                    let object = rewrite_object(&self.audiences_settings, audience, &body.object);
+                    if let Err(detail) = valid_object(&self.audiences_settings, audience, &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&detail).build()));
+                    }
+
+                    if let Err(detail) = valid_object_pattern(&self.audiences_settings, audience, &object) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&detail).build()));
+                    }
+
+                    if let Err(detail) = valid_hard_delete(&self.audiences_settings, audience, &body.method) {
+                        return future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&detail).build()));
+                    }
+
+                    // The v1 payload has no storage class, the one of the client is told by the header
+                    let storage_class = match storage_class(&self.audiences_settings, audience, &body.method, None, &headers) {
+                        Ok(val) => val,
+                        Err(detail) => return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&detail).build())),
+                    };
+
+                    let object = normalize_object(&self.audiences_settings, audience, &sub, &object);
+                    let object = match body.set {
+                        Some(ref set) => s3_object(&set, &object),
+                        None => object,
+                    };
+                    let (audiences_settings, write_once_audience) = (self.audiences_settings.clone(), audience.to_owned());
+                    let db = self.db.clone();
+
                     future::Either::B(self.authz.authorize(audience, &sub, zobj, zact).and_then(move |zresp| match zresp {
                         Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
                         Ok(_) => {
+                            if let Err(err) = valid_not_deleted(&audiences_settings, &write_once_audience, &body.method, &body.bucket, &object, |bucket, key| tombstone_exists(db.as_ref(), &back, bucket, key)) {
+                                return future::Either::A(wrap_error(err));
+                            }
+
                             // URI builder
                             let mut builder = util::S3SignedRequestBuilder::new()
+                                .error_detail(error_detail, &back)
                                 .method(&body.method)
                                 .bucket(&body.bucket)
                                 .object(&object);
-                            for (key, val) in body.headers {
+                            for (key, val) in headers {
                                 builder = builder.add_header(&key, &val);
                             }
+                            if let Some(content_length) = body.content_length {
+                                builder = builder.add_header("content-length", &content_length.to_string());
+                            }
+                            if let Some((header, Some(ref value))) = checksum {
+                                builder = builder.add_header(header, value);
+                            }
+                            if let Some(ref storage_class) = storage_class {
+                                builder = builder.add_header(STORAGE_CLASS_HEADER, storage_class);
+                            }
+                            for (key, val) in body.response_overrides.clone().unwrap_or_default() {
+                                builder = builder.add_param(&key, Some(&val));
+                            }
+                            if let Some(expires_in) = expires_in {
+                                builder = builder.expires_in(std::time::Duration::from_secs(expires_in));
+                            }
 
-                            future::Either::B(future::ok(builder.build(&s3).map(|uri| SignResponse { uri })))
+                            let object_exists = { let (s3, bucket) = (s3.clone(), body.bucket.clone()); move || s3.object_exists(&bucket, &object) };
+                            let write_once = valid_write_once(&audiences_settings, &write_once_audience, &body.method, error_detail, &back, object_exists);
+                            future::Either::B(write_once.and_then(move |write_once| match write_once {
+                                Ok(()) => future::Either::A(sign_limiter.run(move || builder.build(&s3)).map(|uri| uri.map(SignResponse::new))),
+                                Err(err) => future::Either::B(future::ok(Err(err))),
+                            }))
                     }}))
                 },
                 Err(err) => future::Either::A(wrap_error(err))
             }
         }
 
-        fn valid_referer(&self, bucket: &str, referer: Option<String>) -> Result<(), Error> {
+        fn valid_referer(&self, scope: util::CapabilityScope, referer: util::Referer) -> Result<(), Error> {
             let error = || Error::builder().kind("sign_error", "Error signing a request");
+            let bucket = scope.bucket();
 
             match self.aud_estm.estimate(&bucket) {
                 Ok(aud) => match self.audiences_settings.get(aud) {
-                    Some(aud_settings) => if !aud_settings.valid_referer(referer.as_deref()) {
+                    Some(aud_settings) => if !aud_settings.valid_caller(&scope, referer.referer(), referer.capability()) {
                         let e = error().status(StatusCode::FORBIDDEN).detail("Invalid request").build();
                         return Err(e);
+                    } else if !aud_settings.valid_expected_audience(aud, referer.expected_audience()) {
+                        let e = error().status(StatusCode::FORBIDDEN).detail(&format!("Bucket '{}' doesn't belong to the expected audience", &bucket)).build();
+                        return Err(e);
                     }
                     None => {
                         let e = error().status(StatusCode::NOT_FOUND).detail(&format!("Audience settings for bucket '{}' not found", &bucket)).build();
@@ -609,6 +1850,270 @@ impl_web! {
 
             Ok(())
         }
+
+        fn valid_set_label(&self, bucket: &str, label: &str) -> Result<(), Error> {
+            let error = || Error::builder().kind("sign_error", "Error signing a request");
+
+            match self.aud_estm.estimate(&bucket).ok().and_then(|aud| self.audiences_settings.get(aud)) {
+                Some(aud_settings) if !aud_settings.valid_set_label(label) => {
+                    let e = error().status(StatusCode::BAD_REQUEST).detail(&format!("Invalid set label '{}'", label)).build();
+                    Err(e)
+                }
+                _ => Ok(()),
+            }
+        }
+
+        // Reading tags of a set requires the read permission on it regardless of the signed method
+        fn set_tags(&self, set: crate::db::Set, set_id: &str, sub: &Subject, zact: &str, include: bool) -> impl Future<Item = Result<Option<Vec<crate::db::Set>>, Error>, Error = ()> {
+            let error = || Error::builder().kind("sign_error", "Error signing a request");
+
+            if !include {
+                return future::Either::A(future::ok(Ok(None)));
+            }
+            let db = match self.db.clone() {
+                Some(val) => val,
+                None => return future::Either::A(wrap_error(error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("Tag API is disabled").build()))
+            };
+
+            let zresp = if zact == "read" {
+                future::Either::A(future::ok(Ok(())))
+            } else {
+                let zobj = vec!["sets", set_id];
+                future::Either::B(self.authz.authorize(set.bucket().audience(), sub, zobj, "read").map(|zresp| zresp.map(|_| ()).map_err(|err| err.to_string())))
+            };
+
+            future::Either::B(zresp.map(move |zresp| match zresp {
+                Err(err) => Err(error().status(StatusCode::FORBIDDEN).detail(&err).build()),
+                Ok(()) => db.get()
+                    .map_err(|_| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("db connection is unavailable").build())
+                    .and_then(|conn| {
+                        tag::SetTagsQuery::new(&set)
+                            .execute(&conn)
+                            .map_err(|err| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail(&err.to_string()).build())
+                    })
+                    .map(Some),
+            }))
+        }
+
+        fn valid_response_overrides(&self, bucket: &str, method: &str, overrides: &BTreeMap<String, String>) -> Result<(), Error> {
+            let error = || Error::builder().kind("sign_error", "Error signing a request");
+
+            if method != "GET" && method != "HEAD" && !overrides.is_empty() {
+                let e = error().status(StatusCode::BAD_REQUEST).detail(&format!("invalid method = {} for response overrides", method)).build();
+                return Err(e);
+            }
+
+            if let Some(key) = overrides.keys().find(|key| !key.starts_with("response-")) {
+                let e = error().status(StatusCode::BAD_REQUEST).detail(&format!("Invalid response override '{}'", key)).build();
+                return Err(e);
+            }
+
+            let aud_settings = self.aud_estm.estimate(&bucket).ok().and_then(|aud| self.audiences_settings.get(aud));
+            if let Some(aud_settings) = aud_settings {
+                if !aud_settings.valid_response_overrides_count(overrides.len()) {
+                    let e = error().status(StatusCode::BAD_REQUEST).detail(&format!("Too many response overrides = {}", overrides.len())).build();
+                    return Err(e);
+                }
+
+                if let Some(key) = overrides.keys().find(|key| !aud_settings.valid_response_override(key)) {
+                    let e = error().status(StatusCode::FORBIDDEN).detail(&format!("Response override '{}' is not allowed", key)).build();
+                    return Err(e);
+                }
+            }
+
+            Ok(())
+        }
+
+        fn valid_content_length(&self, bucket: &str, method: &str, content_length: Option<u64>) -> Result<(), Error> {
+            let error = || Error::builder().kind("sign_error", "Error signing a request");
+
+            match self.aud_estm.estimate(&bucket).ok().and_then(|aud| self.audiences_settings.get(aud)) {
+                Some(aud_settings) if !aud_settings.valid_content_length(method, content_length) => {
+                    let e = error().status(StatusCode::BAD_REQUEST).detail("Content length is required").build();
+                    Err(e)
+                }
+                _ => Ok(()),
+            }
+        }
+
+        fn with_default_headers(&self, bucket: &str, headers: BTreeMap<String, String>) -> Result<BTreeMap<String, String>, Error> {
+            let error = || Error::builder().kind("sign_error", "Error signing a request");
+
+            match self.aud_estm.estimate(&bucket).ok().and_then(|aud| self.audiences_settings.get(aud)) {
+                Some(aud_settings) => aud_settings.merge_default_headers(headers).map_err(|key| {
+                    error().status(StatusCode::FORBIDDEN).detail(&format!("Header '{}' conflicts with the default value", key)).build()
+                }),
+                None => Ok(headers),
+            }
+        }
+
+        fn with_subject_header(&self, bucket: &str, sub: &Subject, headers: BTreeMap<String, String>) -> Result<BTreeMap<String, String>, Error> {
+            let error = || Error::builder().kind("sign_error", "Error signing a request");
+
+            match self.aud_estm.estimate(&bucket).ok().and_then(|aud| self.audiences_settings.get(aud)) {
+                Some(aud_settings) => aud_settings.merge_subject_header(&sub.to_string(), headers).map_err(|key| {
+                    error().status(StatusCode::FORBIDDEN).detail(&format!("Header '{}' doesn't match the subject", key)).build()
+                }),
+                None => Ok(headers),
+            }
+        }
+
+        fn valid_expires_in(&self, bucket: &str, expires_in: Option<u64>) -> Result<Option<u64>, Error> {
+            let error = || Error::builder().kind("sign_error", "Error signing a request");
+
+            match self.aud_estm.estimate(&bucket).ok().and_then(|aud| self.audiences_settings.get(aud)) {
+                Some(aud_settings) => aud_settings.expires_in(expires_in).map_err(|min| {
+                    error().status(StatusCode::BAD_REQUEST).detail(&format!("expires_in is less than the minimum = {}", min)).build()
+                }),
+                None => Ok(expires_in),
+            }
+        }
+
+        fn valid_content_type(&self, bucket: &str, method: &str, headers: &BTreeMap<String, String>) -> Result<(), Error> {
+            let error = || Error::builder().kind("sign_error", "Error signing a request");
+
+            if method != "PUT" {
+                return Ok(());
+            }
+
+            let content_type = headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case("content-type"))
+                .map(|(_, val)| val.as_str());
+
+            match self.aud_estm.estimate(&bucket).ok().and_then(|aud| self.audiences_settings.get(aud)) {
+                Some(aud_settings) if !aud_settings.valid_content_type(content_type) => {
+                    let e = error().status(StatusCode::FORBIDDEN).detail(&format!("Content type '{}' is not allowed", content_type.unwrap_or(""))).build();
+                    Err(e)
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl BackendState {
+        #[post("/api/v1/backends")]
+        #[content_type("json")]
+        fn create(&self, body: CreateBackendPayload, sub: Subject) -> impl Future<Item = Result<BackendResponse, Error>, Error = ()> {
+            let error = || Error::builder().kind("backend_create_error", "Error creating a backend");
+
+            let zobj = vec!["backends"];
+            let zact = "create";
+            let s3 = self.s3.clone();
+            let service_hosts = self.service_hosts.clone();
+
+            self.authz.authorize(self.application_id.audience(), &sub, zobj, zact).and_then(move |zresp| match zresp {
+                Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
+                Ok(_) => {
+                    let config = body.config.clone().unwrap_or_default();
+                    if let Some(option) = config.startup_only() {
+                        return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&format!("Option '{}' is only applied on startup", option)).build()));
+                    }
+                    let client = match util::build_s3_client(&body.access_key_id, &body.secret_access_key, &body.region, &body.endpoint, &config, &service_hosts) {
+                        Ok(val) => val,
+                        Err(err) => return future::Either::A(wrap_error(error().status(StatusCode::BAD_REQUEST).detail(&err.to_string()).build())),
+                    };
+
+                    let mut s3 = s3.write().expect("Error acquiring s3 clients lock");
+                    let resp = util::insert_s3_client(&mut s3, &body.name, client)
+                        .map(|_| BackendResponse { name: body.name.clone() })
+                        .map_err(|err| error().status(StatusCode::CONFLICT).detail(&err.to_string()).build());
+
+                    future::Either::B(future::ok(resp))
+            }})
+        }
+
+        // Signs a URI of the configured canary object and requests it, the outcome is reported in the body
+        #[get("/api/v1/backends/:back/self_test")]
+        #[content_type("json")]
+        fn self_test(&self, back: String, sub: Subject) -> impl Future<Item = Result<self_test::SelfTestReport, Error>, Error = ()> {
+            let error = || Error::builder().kind("self_test_error", "Error running a self-test");
+
+            let canary = match self.self_test.as_ref().and_then(|config| config.canary(&back)) {
+                Some(val) => val.clone(),
+                None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Self-test is not configured for the backend = '{}'", &back)).build()))
+            };
+            let s3 = match self.s3.read().expect("Error acquiring s3 clients lock").get(&back) {
+                Some(val) => val.clone(),
+                None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail(&format!("Backend '{}' is not found", &back)).build()))
+            };
+
+            let zobj = vec!["backends", &back];
+            let zact = "read";
+
+            future::Either::B(self.authz.authorize(self.application_id.audience(), &sub, zobj, zact).and_then(move |zresp| match zresp {
+                Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
+                Ok(_) => {
+                    let uri = util::S3SignedRequestBuilder::new()
+                        .method("GET")
+                        .bucket(canary.bucket())
+                        .object(canary.object())
+                        .build(&s3)
+                        .map_err(|err| err.to_string());
+
+                    future::Either::B(self_test::run(uri, self_test::fetch).map(Ok))
+            }}))
+        }
+    }
+
+    impl UsageState {
+        // Totals of the sampled objects, estimates of the whole audience are scaled by its sample rate
+        #[get("/api/v2/audiences/:audience/usage")]
+        #[content_type("json")]
+        fn usage(&self, audience: String, sub: Subject) -> impl Future<Item = Result<UsageResponse, Error>, Error = ()> {
+            let error = || Error::builder().kind("usage_read_error", "Error reading usage of an audience");
+
+            let zobj = vec!["audiences", &audience, "usage"];
+            let zact = "read";
+            let db = match self.db.clone() {
+                Some(val) => val,
+                None => return future::Either::A(wrap_error(error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("Usage API is disabled").build()))
+            };
+            let sample_rate = self.audiences_settings.get(&audience).and_then(|aud_settings| aud_settings.usage_sample_rate());
+
+            future::Either::B(self.authz.authorize(&audience, &sub, zobj, zact).and_then(move |zresp| match zresp {
+                Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
+                Ok(_) => {
+                    let usage = db.get()
+                        .map_err(|_| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail("db connection is unavailable").build())
+                        .and_then(|conn| {
+                            crate::db::object_size::UsageQuery::new(&audience)
+                                .execute(&conn)
+                                .map(|usage| UsageResponse::new(&usage, sample_rate))
+                                .map_err(|err| error().status(StatusCode::UNPROCESSABLE_ENTITY).detail(&err.to_string()).build())
+                        });
+
+                    future::Either::B(future::ok(usage))
+            }}))
+        }
+    }
+
+    impl ConfigState {
+        // Served only with the expose_config flag, the configuration is never exposed by default
+        #[get("/api/v1/config")]
+        fn read(&self, sub: Subject) -> impl Future<Item = Result<Response<String>, Error>, Error = ()> {
+            let error = || Error::builder().kind("config_read_error", "Error reading the configuration");
+
+            let config = match self.config.clone() {
+                Some(val) => val,
+                None => return future::Either::A(wrap_error(error().status(StatusCode::NOT_FOUND).detail("Configuration endpoint is disabled").build()))
+            };
+
+            let zobj = vec!["config"];
+            let zact = "read";
+
+            future::Either::B(self.authz.authorize(self.application_id.audience(), &sub, zobj, zact).and_then(move |zresp| match zresp {
+                Err(err) => future::Either::A(wrap_error(error().status(StatusCode::FORBIDDEN).detail(&err.to_string()).build())),
+                Ok(_) => {
+                    let resp = Response::builder()
+                        .header("content-type", "application/json")
+                        .status(StatusCode::OK)
+                        .body(config.to_string())
+                        .unwrap();
+
+                    future::Either::B(future::ok(Ok(resp)))
+            }}))
+        }
     }
 
     impl Healthz {
@@ -619,6 +2124,17 @@ impl_web! {
                 .body("")
                 .unwrap())
         }
+
+        // Backends are self-tested in the background, the service stays ready while any of them works
+        #[get("/readyz")]
+        fn readyz(&self) -> Result<Response<Vec<u8>>, ()> {
+            let readiness = self.readiness.readiness();
+            Ok(Response::builder()
+                .header("content-type", "application/json")
+                .status(readiness.status_code())
+                .body(serde_json::to_vec(&readiness).expect("Error serializing readiness"))
+                .unwrap())
+        }
     }
 }
 
@@ -626,6 +2142,34 @@ impl_web! {
 pub(crate) struct HttpConfig {
     listener_address: String,
     cors: Cors,
+    #[serde(default)]
+    access_log: access_log::AccessLogConfig,
+    #[serde(default)]
+    public_hosts: Vec<String>,
+    #[serde(default)]
+    timeout: timeout::TimeoutConfig,
+    #[serde(default)]
+    retry_after: Vec<retry_after::RetryAfterRule>,
+    #[serde(default)]
+    endpoints: endpoints::EndpointsConfig,
+    #[serde(default)]
+    vary: Vec<String>,
+    #[serde(default)]
+    error_detail: util::ErrorDetail,
+    max_concurrent_signs: Option<usize>,
+    #[serde(default = "HttpConfig::default_max_batch_size")]
+    max_batch_size: usize,
+    max_concurrent_streams: Option<usize>,
+    #[serde(default)]
+    echo_request_id: bool,
+    #[serde(default)]
+    expose_config: bool,
+}
+
+impl HttpConfig {
+    fn default_max_batch_size() -> usize {
+        100
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -636,17 +2180,401 @@ pub(crate) struct Cors {
     #[serde(deserialize_with = "crate::serde::duration")]
     #[serde(default)]
     pub(crate) max_age: std::time::Duration,
+    #[serde(deserialize_with = "crate::serde::header_names")]
+    #[serde(default)]
+    pub(crate) allow_headers: Vec<http::header::HeaderName>,
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
-fn parse_action(method: &str) -> anyhow::Result<&str> {
+const STANDARD_METHODS: &[&str] = &["HEAD", "GET", "PUT", "DELETE"];
+const METHOD_ACTIONS: &[&str] = &["read", "update", "delete"];
+
+fn parse_action<'a>(
+    method: &str,
+    method_actions: &'a BTreeMap<String, String>,
+) -> anyhow::Result<&'a str> {
     match method {
         "HEAD" => Ok("read"),
         "GET" => Ok("read"),
         "PUT" => Ok("update"),
         "DELETE" => Ok("delete"),
-        _ => Err(format_err!("invalid method = {}", method)),
+        _ => method_actions
+            .get(method)
+            .map(String::as_str)
+            .ok_or_else(|| format_err!("invalid method = {}", method)),
+    }
+}
+
+// Custom methods may only extend the standard ones with actions authz already knows of
+fn valid_method_actions(method_actions: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    for (method, action) in method_actions {
+        if STANDARD_METHODS.contains(&method.as_str()) {
+            return Err(format_err!(
+                "standard method = {} can't be remapped",
+                method
+            ));
+        }
+        if http::Method::from_bytes(method.as_bytes()).is_err() {
+            return Err(format_err!("invalid method = {}", method));
+        }
+        if !METHOD_ACTIONS.contains(&action.as_str()) {
+            return Err(format_err!(
+                "unknown action = {} of method = {}",
+                action,
+                method
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn valid_object(
+    audiences_settings: &AudiencesSettingsRef,
+    audience: &str,
+    object: &str,
+) -> Result<(), String> {
+    // An empty name would address the set itself as "label."
+    if object.is_empty() {
+        return Err(String::from("Object is empty"));
+    }
+
+    valid_object_depth(audiences_settings, audience, object)?;
+
+    let aud_settings = audiences_settings
+        .get(audience)
+        .cloned()
+        .unwrap_or_default();
+    if aud_settings.valid_object_prefix(object) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Object '{}' starts with a prefix of another set",
+            object
+        ))
+    }
+}
+
+fn valid_object_pattern(
+    audiences_settings: &AudiencesSettingsRef,
+    audience: &str,
+    object: &str,
+) -> Result<(), String> {
+    let aud_settings = match audiences_settings.get(audience) {
+        Some(val) => val,
+        None => return Ok(()),
+    };
+
+    if let Some(prefix) = aud_settings.reserved_prefix(object) {
+        return Err(format!(
+            "Object '{}' is under the reserved prefix '{}'",
+            object, prefix
+        ));
+    }
+
+    if aud_settings.valid_object_pattern(object) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Object '{}' doesn't match the allowed pattern",
+            object
+        ))
+    }
+}
+
+// Tag reads find the set to check the object against after authz, the checks are the ones of set reads
+fn valid_read_object(
+    audiences_settings: &AudiencesSettingsRef,
+    audience: &str,
+    object: &str,
+) -> Result<(), (StatusCode, String)> {
+    valid_object(audiences_settings, audience, object)
+        .map_err(|detail| (StatusCode::BAD_REQUEST, detail))?;
+    valid_object_pattern(audiences_settings, audience, object)
+        .map_err(|detail| (StatusCode::FORBIDDEN, detail))
+}
+
+// The backend is only asked about the object for writes to write-once audiences
+fn valid_write_once<F, R>(
+    audiences_settings: &AudiencesSettingsRef,
+    audience: &str,
+    method: &str,
+    error_detail: util::ErrorDetail,
+    backend: &str,
+    object_exists: F,
+) -> impl Future<Item = Result<(), Error>, Error = ()>
+where
+    F: FnOnce() -> R,
+    R: Future<Item = bool, Error = anyhow::Error>,
+{
+    let error = || Error::builder().kind("sign_error", "Error signing a request");
+    let backend = backend.to_owned();
+
+    match audiences_settings.get(audience) {
+        Some(aud_settings) if aud_settings.write_once(method) => {
+            future::Either::A(object_exists().then(move |result| {
+                Ok(match result {
+                    Ok(false) => Ok(()),
+                    Ok(true) => Err(error()
+                        .status(StatusCode::FORBIDDEN)
+                        .detail("Object is write-once and already exists")
+                        .build()),
+                    Err(err) => Err(error()
+                        .status(s3_error_status(&err))
+                        .detail(&error_detail.describe(&backend, &err))
+                        .build()),
+                })
+            }))
+        }
+        _ => future::Either::B(future::ok(Ok(()))),
+    }
+}
+
+fn valid_object_depth(
+    audiences_settings: &AudiencesSettingsRef,
+    audience: &str,
+    object: &str,
+) -> Result<(), String> {
+    let max_depth = audiences_settings
+        .get(audience)
+        .cloned()
+        .unwrap_or_default()
+        .max_object_depth();
+
+    if object.matches('/').count() > max_depth {
+        Err(format!(
+            "Object '{}' is nested deeper than the maximum = {}",
+            object, max_depth
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// Objects of audiences with soft deletes are never deleted by clients, the purge does it once the retention has passed
+fn valid_hard_delete(
+    audiences_settings: &AudiencesSettingsRef,
+    audience: &str,
+    method: &str,
+) -> Result<(), String> {
+    match audiences_settings.get(audience) {
+        Some(aud_settings)
+            if method == "DELETE" && aud_settings.soft_delete_retention().is_some() =>
+        {
+            Err(format!(
+                "objects of the audience = '{}' are soft-deleted, DELETE is not signed",
+                audience
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Storage classes only apply to uploads, a class passed as a header is checked the same way
+fn storage_class(
+    audiences_settings: &AudiencesSettingsRef,
+    audience: &str,
+    method: &str,
+    requested: Option<&str>,
+    headers: &BTreeMap<String, String>,
+) -> Result<Option<String>, String> {
+    let header = headers.get(STORAGE_CLASS_HEADER).map(String::as_str);
+    let requested = match (requested, header) {
+        (Some(val), Some(header)) if val != header => {
+            return Err(format!(
+                "storage_class = '{}' conflicts with the {} header",
+                val, STORAGE_CLASS_HEADER
+            ))
+        }
+        (requested, header) => requested.or(header),
+    };
+
+    if method != "PUT" {
+        return match requested {
+            Some(_) => Err(format!("invalid method = {} for storage class", method)),
+            None => Ok(None),
+        };
+    }
+
+    let aud_settings = audiences_settings
+        .get(audience)
+        .cloned()
+        .unwrap_or_default();
+    aud_settings
+        .storage_class(requested)
+        .map_err(|class| format!("storage class = '{}' is not allowed", class))
+}
+
+// Checksums are only signed into uploads, the ones of streamed uploads are computed along with the body and sent in the trailer
+fn checksum(
+    method: &str,
+    algorithm: Option<&str>,
+    value: Option<&str>,
+    streaming: bool,
+) -> Result<Option<(&'static str, Option<String>)>, String> {
+    match (algorithm, value) {
+        (None, None) => Ok(None),
+        (Some(_), Some(_)) if method != "PUT" => {
+            Err(format!("invalid method = {} for checksum", method))
+        }
+        (Some(algorithm), Some(value)) => util::checksum_header(algorithm)
+            .map(|header| Some((header, Some(value.to_owned()))))
+            .map_err(|err| err.to_string()),
+        (Some(algorithm), None) if streaming => util::checksum_header(algorithm)
+            .map(|header| Some((header, None)))
+            .map_err(|err| err.to_string()),
+        _ => Err(String::from(
+            "checksum_algorithm and checksum_value must be set together",
+        )),
+    }
+}
+
+// Tombstoned objects are missing for readers until they are restored. Writers are refused,
+// the purge would otherwise delete the new object along with the deleted one
+fn valid_tombstone<F>(soft_delete: bool, method: &str, tombstoned: F) -> Result<(), Error>
+where
+    F: FnOnce() -> Result<bool, String>,
+{
+    let reading = method == "GET" || method == "HEAD";
+    let title = if reading {
+        "Error reading a deleted object"
+    } else {
+        "Error writing a deleted object"
+    };
+    let error = || Error::builder().kind("object_tombstoned", title);
+
+    if !soft_delete {
+        return Ok(());
+    }
+
+    match tombstoned() {
+        Ok(false) => Ok(()),
+        Ok(true) if reading => Err(error()
+            .status(StatusCode::NOT_FOUND)
+            .detail("the object is deleted")
+            .build()),
+        Ok(true) => Err(error()
+            .status(StatusCode::CONFLICT)
+            .detail("the object is deleted, restore it or wait for the purge")
+            .build()),
+        Err(err) => Err(error()
+            .status(StatusCode::UNPROCESSABLE_ENTITY)
+            .detail(&err)
+            .build()),
+    }
+}
+
+// Reads and signed requests of soft-deleted objects are refused alike, whichever API addresses them
+fn valid_not_deleted<F>(
+    audiences_settings: &AudiencesSettingsRef,
+    audience: &str,
+    method: &str,
+    bucket: &str,
+    key: &str,
+    tombstoned: F,
+) -> Result<(), Error>
+where
+    F: FnOnce(&str, &str) -> Result<bool, String>,
+{
+    let soft_delete = audiences_settings
+        .get(audience)
+        .and_then(|aud_settings| aud_settings.soft_delete_retention())
+        .is_some();
+    valid_tombstone(soft_delete, method, || tombstoned(bucket, key))
+}
+
+// Expired and exhausted shares are gone for good, unlike unknown ones
+fn redeemed(redemption: share::Redemption) -> Result<share::Share, Error> {
+    let error = || Error::builder().kind("share_error", "Error redeeming a shared object");
+
+    match redemption {
+        share::Redemption::Redeemed(shared) => Ok(shared),
+        share::Redemption::Expired => Err(error()
+            .status(StatusCode::GONE)
+            .detail("the share is expired")
+            .build()),
+        share::Redemption::Exhausted => Err(error()
+            .status(StatusCode::GONE)
+            .detail("the share has no downloads left")
+            .build()),
+        share::Redemption::NotFound => Err(error()
+            .status(StatusCode::NOT_FOUND)
+            .detail("the share is not found")
+            .build()),
+    }
+}
+
+// A download of the share is used up only once the object is known to be served, the share is
+// checked again while it's locked for the redemption
+fn redeem_checked<T, P, C, R>(peek: P, check: C, redeem: R) -> Result<T, Error>
+where
+    P: FnOnce() -> Result<T, Error>,
+    C: FnOnce(&T) -> Result<(), Error>,
+    R: FnOnce() -> Result<T, Error>,
+{
+    let shared = peek()?;
+    check(&shared)?;
+    redeem()
+}
+
+// Without the database, nothing could have been tombstoned
+fn tombstone_exists(
+    db: Option<&ConnectionPool>,
+    backend: &str,
+    bucket: &str,
+    object: &str,
+) -> Result<bool, String> {
+    match db {
+        Some(db) => db
+            .get()
+            .map_err(|_| String::from("db connection is unavailable"))
+            .and_then(|conn| {
+                tombstone::FindQuery::new(backend, bucket, object)
+                    .execute(&conn)
+                    .map_err(|err| err.to_string())
+            }),
+        None => Ok(false),
+    }
+}
+
+fn page_size(
+    audiences_settings: &AudiencesSettingsRef,
+    audience: &str,
+    requested: Option<u64>,
+    default: Option<u64>,
+    max: u64,
+) -> Option<u64> {
+    match audiences_settings.get(audience) {
+        Some(aud_settings) => aud_settings.page_size(requested, default, max),
+        None => AudienceSettings::default().page_size(requested, default, max),
+    }
+}
+
+fn normalize_object(
+    audiences_settings: &AudiencesSettingsRef,
+    audience: &str,
+    sub: &Subject,
+    object: &str,
+) -> String {
+    match audiences_settings.get(audience) {
+        Some(aud_settings) => {
+            aud_settings.normalize_object(&aud_settings.scope_object(&sub.to_string(), object))
+        }
+        None => object.to_owned(),
+    }
+}
+
+// Keys are rewritten as the client sent them, before they're checked, scoped and normalized, so that rules
+// match the logical key and the rewritten one is checked the same way. Authz is checked against the set
+// and the logical key, rewriting the stored key doesn't affect it
+fn rewrite_object(
+    audiences_settings: &AudiencesSettingsRef,
+    audience: &str,
+    object: &str,
+) -> String {
+    match audiences_settings.get(audience) {
+        Some(aud_settings) => aud_settings.rewrite_object(object),
+        None => object.to_owned(),
     }
 }
 
@@ -654,14 +2582,163 @@ fn s3_object(set: &str, object: &str) -> String {
     format!("{set}.{object}", set = set, object = object)
 }
 
+fn s3_error_status(err: &anyhow::Error) -> StatusCode {
+    if err.is::<crate::s3::UrlTooLong>() {
+        StatusCode::URI_TOO_LONG
+    } else if err.is::<crate::s3::RedirectLoop>() {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    }
+}
+
+// The signed URI is redirected to, returned as JSON or fetched by the service itself, as the client accepts
+fn read_response(
+    s3: Arc<crate::s3::Client>,
+    usage: Arc<usage::UsageRecorder>,
+    mode: util::ReadMode,
+    audience: &str,
+    aud_settings: &AudienceSettings,
+    bucket: &str,
+    object: &str,
+) -> impl Future<Item = Response<Vec<u8>>, Error = anyhow::Error> {
+    let (max_size, sample_rate) = (
+        aud_settings.download_max_size(),
+        aud_settings.usage_sample_rate(),
+    );
+    let uri = {
+        let (s3, bucket, object) = (s3.clone(), bucket.to_owned(), object.to_owned());
+        move || s3.presigned_url("GET", &bucket, &object)
+    };
+    let (audience, bucket, object) = (audience.to_owned(), bucket.to_owned(), object.to_owned());
+    negotiate(mode, aud_settings, uri, move || {
+        s3.download(&bucket, &object, max_size)
+            .map(move |download| {
+                if let Some(size) = download.size() {
+                    usage.record(sample_rate, &audience, &bucket, &object, size);
+                }
+                download
+            })
+    })
+}
+
+// Reads of backends failing their self-test are served by the mirror right away, the ones failing
+// to reach the backend fall back to it. Either way the response is negotiated the same way,
+// reads of the mirror itself never fail over, so that a read does once at most
+fn failover<R, F>(
+    s3: Arc<crate::s3::Client>,
+    mirror: Option<Arc<crate::s3::Client>>,
+    unhealthy: bool,
+    read: R,
+) -> impl Future<Item = Response<Vec<u8>>, Error = anyhow::Error>
+where
+    R: Fn(Arc<crate::s3::Client>) -> F,
+    F: Future<Item = Response<Vec<u8>>, Error = anyhow::Error>,
+{
+    match mirror {
+        Some(mirror) if unhealthy => {
+            warn!("Reading from the mirror of an unhealthy backend");
+            future::Either::A(read(mirror))
+        }
+        mirror => future::Either::B(read(s3).or_else(move |err| match mirror {
+            Some(mirror) if err.is::<crate::s3::Unreachable>() => {
+                warn!("Reading from the mirror: {}", err);
+                future::Either::A(read(mirror))
+            }
+            _ => future::Either::B(future::err(err)),
+        })),
+    }
+}
+
+fn mirror_client(
+    router: &util::BackendRouter,
+    s3: &S3ClientRef,
+    backend: &str,
+) -> Option<Arc<crate::s3::Client>> {
+    router.mirror(backend).and_then(|mirror| {
+        s3.read()
+            .expect("Error acquiring s3 clients lock")
+            .get(mirror)
+            .cloned()
+    })
+}
+
+fn negotiate<U, D, R>(
+    mode: util::ReadMode,
+    aud_settings: &AudienceSettings,
+    uri: U,
+    download: D,
+) -> impl Future<Item = Response<Vec<u8>>, Error = anyhow::Error>
+where
+    U: FnOnce() -> anyhow::Result<String>,
+    D: FnOnce() -> R,
+    R: Future<Item = crate::s3::Download, Error = anyhow::Error>,
+{
+    let resp = match mode {
+        util::ReadMode::Proxy if aud_settings.proxy_reads() => {
+            let aud_settings = aud_settings.clone();
+            future::Either::A(download().map(move |download| match download {
+                crate::s3::Download::Inline { body, content_type } => {
+                    inline(body, content_type, &aud_settings)
+                }
+                crate::s3::Download::Redirect { ref uri, .. } => redirect(uri).map(|_| Vec::new()),
+            }))
+        }
+        util::ReadMode::Json => {
+            future::Either::B(future::result(uri().map(|ref uri| uri_json(uri))))
+        }
+        _ => future::Either::B(future::result(
+            uri().map(|ref uri| redirect(uri).map(|_| Vec::new())),
+        )),
+    };
+    resp.map(vary_accept)
+}
+
+// Every variant of a negotiated read tells caches it depends on the Accept header,
+// headers configured with the vary setting are appended to it
+fn vary_accept(mut resp: Response<Vec<u8>>) -> Response<Vec<u8>> {
+    resp.headers_mut().append(
+        http::header::VARY,
+        http::header::HeaderValue::from_static("Accept"),
+    );
+    resp
+}
+
+// The length of the empty body is told explicitly, so that strict clients don't wait for one on kept-alive connections
 fn redirect(uri: &str) -> Response<&'static str> {
     Response::builder()
         .header("location", uri)
+        .header("content-length", "0")
         .status(StatusCode::SEE_OTHER)
         .body("")
         .unwrap()
 }
 
+// Content served from the origin of the service may run scripts on it, e.g. HTML uploaded by users,
+// content types not allowed to be shown inline are served as attachments
+fn inline(
+    body: Vec<u8>,
+    content_type: Option<String>,
+    aud_settings: &AudienceSettings,
+) -> Response<Vec<u8>> {
+    let content_type = content_type.unwrap_or_else(|| String::from("application/octet-stream"));
+    let mut resp = Response::builder();
+    resp.header("content-type", content_type.as_str());
+    if !aud_settings.inline_content_type(&content_type) {
+        resp.header("content-disposition", "attachment");
+        resp.header("x-content-type-options", "nosniff");
+    }
+    resp.status(StatusCode::OK).body(body).unwrap()
+}
+
+fn uri_json(uri: &str) -> Response<Vec<u8>> {
+    Response::builder()
+        .header("content-type", "application/json")
+        .status(StatusCode::OK)
+        .body(serde_json::json!({ "uri": uri }).to_string().into_bytes())
+        .unwrap()
+}
+
 fn wrap_error<T>(err: Error) -> impl Future<Item = Result<T, Error>, Error = ()> {
     error!("{}", err);
     future::ok(Err(err))
@@ -669,19 +2746,13 @@ fn wrap_error<T>(err: Error) -> impl Future<Item = Result<T, Error>, Error = ()>
 
 ////////////////////////////////////////////////////////////////////////////////
 
-pub(crate) fn run(db: Option<ConnectionPool>, cache: Option<Cache>) {
+// Custom headers of clients are allowed in addition to the ones the service itself reads
+fn cors(config: &Cors) -> tower_web::middleware::cors::CorsMiddleware {
     use http::{header, Method};
     use std::collections::HashSet;
     use tower_web::middleware::cors::CorsBuilder;
-    use tower_web::middleware::log::LogMiddleware;
-    use tower_web::ServiceBuilder;
-
-    // Config
-    let config = config::load().expect("Failed to load config");
-    info!("App config: {:?}", config);
 
-    // Middleware
-    let allow_headers: HashSet<header::HeaderName> = [
+    let mut allow_headers: HashSet<header::HeaderName> = [
         header::AUTHORIZATION,
         header::CACHE_CONTROL,
         header::CONTENT_LENGTH,
@@ -691,78 +2762,1583 @@ pub(crate) fn run(db: Option<ConnectionPool>, cache: Option<Cache>) {
         header::IF_NONE_MATCH,
         header::IF_UNMODIFIED_SINCE,
         header::RANGE,
+        header::HeaderName::from_static("x-request-id"),
+        header::HeaderName::from_static("x-storage-audience"),
+        header::HeaderName::from_static("x-storage-capability"),
     ]
     .iter()
     .cloned()
     .collect();
+    allow_headers.extend(config.allow_headers.iter().cloned());
 
-    let cors = CorsBuilder::new()
-        .allow_origins(config.http.cors.allow_origins.clone())
+    CorsBuilder::new()
+        .allow_origins(config.allow_origins.clone())
         .allow_methods(vec![Method::GET, Method::POST, Method::PUT, Method::DELETE])
         .allow_headers(allow_headers)
         .allow_credentials(true)
-        .max_age(config.http.cors.max_age)
-        .build();
+        .max_age(config.max_age)
+        .build()
+}
+
+pub(crate) fn run(db: Option<ConnectionPool>, cache: Option<Cache>) {
+    use tower_web::ServiceBuilder;
+
+    // Config
+    let config = config::load().expect("Failed to load config");
+    // Secrets of the configuration never reach the logs
+    let redacted_config = config::load_redacted().expect("Failed to load config");
+    info!("App config: {}", redacted_config);
+    valid_method_actions(&config.method_actions).expect("Error reading method actions");
 
-    let log = LogMiddleware::new("storage::http");
+    // Middleware
+    let cors = cors(&config.http.cors);
 
     // Resources
-    let s3_clients =
-        util::read_s3_config(config.backend.as_ref()).expect("Error reading s3 config");
+    let s3_clients = util::read_s3_config(config.backend.as_ref(), &config.http.public_hosts)
+        .expect("Error reading s3 config");
 
-    let s3 = S3ClientRef::new(s3_clients);
+    let s3 = S3ClientRef::new(::std::sync::RwLock::new(s3_clients));
+    if let Some(ref backend) = config.backend {
+        credentials::refresh(backend.clone(), s3.clone());
+    }
+    let router = Arc::new(
+        util::BackendRouter::new(config.backend.as_ref()).expect("Error reading backend routes"),
+    );
 
     // Authz
-    let aud_estm = Arc::new(util::AudienceEstimator::new(&config.authz));
+    let mut aud_estm = util::AudienceEstimator::new(&config.authz);
+    if let Some(ref audiences) = config.allowed_audiences {
+        aud_estm.set_allowed_audiences(audiences);
+    }
+    let aud_estm = Arc::new(aud_estm);
+    let log = access_log::AccessLogMiddleware::new(
+        config.http.access_log.clone(),
+        aud_estm.clone(),
+        config.authn.clone(),
+        config.id.audience(),
+    );
+    let endpoints = endpoints::EndpointsMiddleware::new(&config.http.endpoints);
+    let timeout = timeout::TimeoutMiddleware::new(config.http.timeout.clone());
+    let retry_after = retry_after::RetryAfterMiddleware::new(&config.http.retry_after);
+    let vary = vary::VaryMiddleware::new(&config.http.vary);
+    let streams = streams::StreamsMiddleware::new(config.http.max_concurrent_streams);
+    let request_id = request_id::RequestIdMiddleware::new(config.http.echo_request_id);
     let authz = svc_authz::ClientMap::new(&config.id, cache, config.authz.clone())
         .expect("Error converting authz config to clients");
+    if let Some(ref warmup) = config.authz_warmup {
+        warmup::run(warmup, &authz);
+    }
+    let mut authz =
+        authz_retry::AuthzClient::new(authz, config.authz_retry.clone().unwrap_or_default());
+    authz.set_audiences(&config.authz_audiences);
 
+    let audiences_settings = AudiencesSettingsRef::new(config.audiences_settings.clone());
+    let recorder = Arc::new(usage::UsageRecorder::new(db.clone()));
+    if let Some(ref db) = db {
+        let soft_delete = audiences_settings
+            .values()
+            .any(|aud_settings| aud_settings.soft_delete_retention().is_some());
+        if soft_delete {
+            soft_delete::spawn_purge(db.clone(), s3.clone());
+        }
+    }
+    let readiness = self_test::spawn_readiness(config.self_test.clone(), s3.clone());
     let object = ObjectState {
         authz: authz.clone(),
         aud_estm: aud_estm.clone(),
         s3: s3.clone(),
-        audiences_settings: config.audiences_settings.clone(),
+        router: router.clone(),
+        audiences_settings: audiences_settings.clone(),
+        error_detail: config.http.error_detail,
+        usage: recorder.clone(),
+        db: db.clone(),
+        readiness: readiness.clone(),
     };
     let set = SetState {
         authz: authz.clone(),
         aud_estm: aud_estm.clone(),
         s3: s3.clone(),
-        audiences_settings: config.audiences_settings.clone(),
+        router: router.clone(),
+        audiences_settings: audiences_settings.clone(),
+        error_detail: config.http.error_detail,
+        usage: recorder.clone(),
+        db: db.clone(),
+        readiness: readiness.clone(),
     };
     let sign = SignState {
         application_id: config.id.clone(),
         authz: authz.clone(),
         aud_estm: aud_estm.clone(),
         s3: s3.clone(),
-        audiences_settings: config.audiences_settings.clone(),
+        router: router.clone(),
+        audiences_settings: audiences_settings.clone(),
+        db: db.clone(),
+        error_detail: config.http.error_detail,
+        sign_limiter: Arc::new(util::SignLimiter::new(config.http.max_concurrent_signs)),
+        method_actions: MethodActionsRef::new(config.method_actions.clone()),
+        max_batch_size: config.http.max_batch_size,
+    };
+    let backend = BackendState {
+        application_id: config.id.clone(),
+        authz: authz.clone(),
+        s3: s3.clone(),
+        service_hosts: config.http.public_hosts.clone(),
+        self_test: config.self_test.clone(),
+    };
+    let healthz = Healthz {
+        readiness: readiness.clone(),
+    };
+    let usage = UsageState {
+        authz: authz.clone(),
+        audiences_settings: audiences_settings.clone(),
+        db: db.clone(),
+    };
+    let config_state = ConfigState {
+        application_id: config.id.clone(),
+        authz: authz.clone(),
+        config: if config.http.expose_config {
+            Some(redacted_config)
+        } else {
+            None
+        },
     };
     let tag = TagState {
         authz,
         aud_estm,
         s3,
+        router,
+        audiences_settings,
         db,
+        error_detail: config.http.error_detail,
+        usage: recorder,
+        readiness,
     };
-    let healthz = Healthz {};
 
     let addr = config
         .http
         .listener_address
         .parse()
         .expect("Error parsing HTTP listener address");
+
     ServiceBuilder::new()
         .config(config)
+        .resource(usage)
+        .resource(config_state)
+        .resource(healthz)
         .resource(object)
         .resource(set)
         .resource(tag)
         .resource(sign)
-        .resource(healthz)
+        .resource(backend)
+        .middleware(endpoints)
+        .middleware(streams)
+        .middleware(timeout)
+        .middleware(retry_after)
+        .middleware(vary)
         .middleware(log)
         .middleware(cors)
+        .middleware(request_id)
         .run(&addr)
         .expect("Error running the HTTP listener");
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+mod access_log;
+mod authz_retry;
 mod config;
+mod credentials;
+mod endpoints;
+mod request_id;
+mod retry_after;
+mod self_test;
+mod soft_delete;
+mod streams;
+mod timeout;
+mod usage;
 pub(crate) mod util;
+mod vary;
+mod warmup;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sets of "example.org" are authorized for anyone, sets of "example.net" for no one
+    fn authz_config() -> svc_authz::ConfigMap {
+        serde_json::from_value(serde_json::json!({
+            "example.org": { "type": "none" },
+            "example.net": { "type": "local", "trusted": [] },
+        }))
+        .expect("Error parsing authz config")
+    }
+
+    fn authz() -> authz_retry::AuthzClient {
+        let id = AccountId::new("storage", "svc.example.org");
+        let inner = svc_authz::ClientMap::new(&id, None, authz_config())
+            .expect("Error creating authz clients");
+        authz_retry::AuthzClient::new(inner, Default::default())
+    }
+
+    // Settings of "example.org", the ones of "example.net" are the default
+    fn settings(json: &str) -> AudiencesSettingsRef {
+        let mut settings = BTreeMap::new();
+        settings.insert(
+            "example.org".to_owned(),
+            serde_json::from_str::<AudienceSettings>(json)
+                .expect("Error parsing audience settings"),
+        );
+        settings.insert("example.net".to_owned(), AudienceSettings::default());
+        AudiencesSettingsRef::new(settings)
+    }
+
+    fn s3_clients() -> S3ClientRef {
+        s3_clients_at("https://s3.example.org")
+    }
+
+    fn s3_clients_at(endpoint: &str) -> S3ClientRef {
+        let mut clients = util::S3Clients::new();
+        clients.insert(
+            util::S3_DEFAULT_CLIENT.to_owned(),
+            Arc::new(crate::s3::Client::new(
+                "key",
+                "secret",
+                "us-east-1",
+                endpoint,
+                std::time::Duration::from_secs(300),
+            )),
+        );
+        Arc::new(std::sync::RwLock::new(clients))
+    }
+
+    // Answers every request with the object, closing the connection after each one
+    pub(crate) fn backend(body: &'static [u8]) -> String {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let listener =
+            std::net::TcpListener::bind("127.0.0.1:0").expect("Error binding a listener");
+        let addr = listener
+            .local_addr()
+            .expect("Error reading the local address");
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().filter_map(Result::ok) {
+                let mut reader =
+                    BufReader::new(stream.try_clone().expect("Error cloning a stream"));
+                let mut line = String::new();
+                let _ = reader.read_line(&mut line);
+                let head = line.starts_with("HEAD ");
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    if reader.read_line(&mut header).unwrap_or(0) <= 2 {
+                        break;
+                    }
+                    let mut parts = header.splitn(2, ':');
+                    if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap_or(0);
+                        }
+                    }
+                }
+                // The payload is read out, so that closing the connection doesn't reset it
+                let _ = (&mut reader)
+                    .take(content_length)
+                    .read_to_end(&mut Vec::new());
+
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    body.len()
+                );
+                if !head {
+                    let _ = stream.write_all(body);
+                }
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    fn set_state(audiences_settings: AudiencesSettingsRef, endpoint: &str) -> SetState {
+        SetState {
+            authz: authz(),
+            aud_estm: Arc::new(util::AudienceEstimator::new(&authz_config())),
+            s3: s3_clients_at(endpoint),
+            router: Arc::new(util::BackendRouter::new(None).expect("Error creating a router")),
+            audiences_settings,
+            error_detail: util::ErrorDetail::default(),
+            usage: Arc::new(usage::UsageRecorder::new(None)),
+            db: None,
+            readiness: self_test::spawn_readiness(None, s3_clients()),
+        }
+    }
+
+    fn sign_state(audiences_settings: AudiencesSettingsRef) -> SignState {
+        SignState {
+            application_id: AccountId::new("storage", "svc.example.org"),
+            authz: authz(),
+            aud_estm: Arc::new(util::AudienceEstimator::new(&authz_config())),
+            s3: s3_clients(),
+            router: Arc::new(util::BackendRouter::new(None).expect("Error creating a router")),
+            audiences_settings,
+            db: None,
+            error_detail: util::ErrorDetail::default(),
+            sign_limiter: Arc::new(util::SignLimiter::new(None)),
+            method_actions: MethodActionsRef::new(BTreeMap::new()),
+            max_batch_size: 3,
+        }
+    }
+
+    fn subject() -> Subject {
+        Subject::new(AccountId::new("john", "usr.example.org"))
+    }
+
+    fn block_on<F>(fut: F) -> F::Item
+    where
+        F: Future<Error = ()>,
+    {
+        tokio::runtime::current_thread::Runtime::new()
+            .expect("Error creating a runtime")
+            .block_on(fut)
+            .expect("Error running a handler")
+    }
+
+    fn sign_v1_payload(method: &str) -> SignPayloadV1 {
+        SignPayloadV1 {
+            bucket: "data.example.org".to_owned(),
+            set: Some("foo".to_owned()),
+            object: "bar".to_owned(),
+            method: method.to_owned(),
+            headers: BTreeMap::new(),
+            content_length: None,
+            response_overrides: None,
+            checksum_algorithm: None,
+            checksum_value: None,
+            expires_in: None,
+        }
+    }
+
+    #[test]
+    fn sign_v1_checked() {
+        let state = sign_state(settings(
+            r#"{"require_content_length": true, "min_expires_in": 600, "min_expires_in_policy": "reject"}"#,
+        ));
+        let sign = |body| {
+            block_on(state.sign_v1_ns(
+                util::S3_DEFAULT_CLIENT.to_owned(),
+                body,
+                subject(),
+                util::Referer::default(),
+            ))
+        };
+        let status = |body| sign(body).err().map(|err| err.status_code());
+        let put = || SignPayloadV1 {
+            content_length: Some(1024),
+            ..sign_v1_payload("PUT")
+        };
+        let overrides = |key: &str| {
+            let mut overrides = BTreeMap::new();
+            overrides.insert(key.to_owned(), "text/plain".to_owned());
+            Some(overrides)
+        };
+
+        // Content length
+        assert_eq!(
+            status(sign_v1_payload("PUT")),
+            Some(StatusCode::BAD_REQUEST)
+        );
+        let resp = sign(put()).expect("Error signing a request");
+        assert!(resp.signed_headers.contains(&"content-length".to_owned()));
+
+        // Response overrides
+        let body = SignPayloadV1 {
+            response_overrides: overrides("response-content-type"),
+            ..put()
+        };
+        assert_eq!(status(body), Some(StatusCode::BAD_REQUEST));
+        let body = SignPayloadV1 {
+            response_overrides: overrides("response-expires"),
+            ..sign_v1_payload("GET")
+        };
+        assert_eq!(status(body), Some(StatusCode::FORBIDDEN));
+        let body = SignPayloadV1 {
+            response_overrides: overrides("response-content-type"),
+            ..sign_v1_payload("GET")
+        };
+        let resp = sign(body).expect("Error signing a request");
+        assert!(
+            resp.uri.contains("response-content-type=text%2Fplain"),
+            "{}",
+            resp.uri
+        );
+
+        // Checksum
+        let body = SignPayloadV1 {
+            checksum_algorithm: Some("MD5".to_owned()),
+            checksum_value: Some("XrY7u+Ae7tCTyyK7j1rNww==".to_owned()),
+            ..put()
+        };
+        assert_eq!(status(body), Some(StatusCode::BAD_REQUEST));
+        let body = SignPayloadV1 {
+            checksum_algorithm: Some("SHA256".to_owned()),
+            ..put()
+        };
+        assert_eq!(status(body), Some(StatusCode::BAD_REQUEST));
+        let body = SignPayloadV1 {
+            checksum_algorithm: Some("SHA256".to_owned()),
+            checksum_value: Some("n4bQgYhMfWWaL+qgxVrQFaO/TxsrC4Is0V1sFbDwCgg=".to_owned()),
+            ..put()
+        };
+        let resp = sign(body).expect("Error signing a request");
+        assert!(resp
+            .signed_headers
+            .contains(&"x-amz-checksum-sha256".to_owned()));
+
+        // Minimum expiration time
+        let body = SignPayloadV1 {
+            expires_in: Some(60),
+            ..sign_v1_payload("GET")
+        };
+        assert_eq!(status(body), Some(StatusCode::BAD_REQUEST));
+        let body = SignPayloadV1 {
+            expires_in: Some(900),
+            ..sign_v1_payload("GET")
+        };
+        let resp = sign(body).expect("Error signing a request");
+        assert!(resp.uri.contains("X-Amz-Expires=900"), "{}", resp.uri);
+    }
+
+    #[test]
+    fn sign_v1_storage_class() {
+        let state = sign_state(settings(
+            r#"{"default_storage_class": "STANDARD_IA", "allowed_storage_classes": ["STANDARD", "STANDARD_IA"]}"#,
+        ));
+        let sign = |body| {
+            block_on(state.sign_v1_ns(
+                util::S3_DEFAULT_CLIENT.to_owned(),
+                body,
+                subject(),
+                util::Referer::default(),
+            ))
+        };
+        let put = |class: Option<&str>| {
+            let mut headers = BTreeMap::new();
+            if let Some(class) = class {
+                headers.insert(STORAGE_CLASS_HEADER.to_owned(), class.to_owned());
+            }
+            SignPayloadV1 {
+                headers,
+                ..sign_v1_payload("PUT")
+            }
+        };
+
+        // The default one, or the one of the header
+        let resp = sign(put(None)).expect("Error signing a request");
+        assert!(resp
+            .signed_headers
+            .contains(&STORAGE_CLASS_HEADER.to_owned()));
+        let resp = sign(put(Some("STANDARD"))).expect("Error signing a request");
+        assert!(resp
+            .signed_headers
+            .contains(&STORAGE_CLASS_HEADER.to_owned()));
+        assert_eq!(
+            sign(put(Some("GLACIER")))
+                .err()
+                .map(|err| err.status_code()),
+            Some(StatusCode::BAD_REQUEST)
+        );
+
+        // Reads are signed without it
+        let resp = sign(sign_v1_payload("GET")).expect("Error signing a request");
+        assert!(!resp
+            .signed_headers
+            .contains(&STORAGE_CLASS_HEADER.to_owned()));
+    }
+
+    #[test]
+    fn sign_batch_items() {
+        let state = sign_state(settings("{}"));
+        let sign = |items: serde_json::Value| {
+            let body = SignBatchPayload {
+                items: serde_json::from_value(items).expect("Error parsing batch items"),
+                fail_fast: None,
+            };
+            block_on(state.sign_batch_ns(
+                util::S3_DEFAULT_CLIENT.to_owned(),
+                body,
+                subject(),
+                util::Referer::default(),
+            ))
+        };
+
+        let resp = sign(serde_json::json!([
+            {"set": "data.example.org::foo", "object": "a", "method": "GET"},
+            {"set": "data.example.net::foo", "object": "b", "method": "PUT", "content_length": 1024},
+            {"set": "data.example.org::foo", "object": "c", "method": "DELETE"},
+        ]))
+        .expect("Error signing a batch");
+        let statuses: Vec<u16> = resp.items.iter().map(|item| item.status).collect();
+        assert_eq!(statuses, vec![200, 403, 200]);
+        let uri = |idx: usize| resp.items[idx].uri.clone().unwrap_or_default();
+        assert!(uri(0).contains("/data.example.org/foo.a?"), "{}", uri(0));
+        assert_eq!(resp.items[1].uri, None);
+        assert!(resp.items[1].error.is_some());
+        assert!(uri(2).contains("/data.example.org/foo.c?"), "{}", uri(2));
+
+        // Items are signed with their content length, checksum and storage class
+        let resp = sign(serde_json::json!([{
+            "set": "data.example.org::foo",
+            "object": "a",
+            "method": "PUT",
+            "content_length": 1024,
+            "checksum_algorithm": "SHA256",
+            "checksum_value": "n4bQgYhMfWWaL+qgxVrQFaO/TxsrC4Is0V1sFbDwCgg=",
+            "storage_class": "STANDARD",
+            "echo_method": true,
+        }]))
+        .expect("Error signing a batch");
+        let signed_headers = &resp.items[0].signed_headers;
+        assert_eq!(
+            signed_headers,
+            &util::signed_headers(&resp.items[0].uri.clone().unwrap_or_default())
+        );
+        assert!(signed_headers.contains(&"content-length".to_owned()));
+        assert!(signed_headers.contains(&"x-amz-checksum-sha256".to_owned()));
+        assert!(signed_headers.contains(&STORAGE_CLASS_HEADER.to_owned()));
+        assert_eq!(resp.items[0].method.as_deref(), Some("PUT"));
+        assert_eq!(resp.items[0].error, None);
+
+        let item =
+            serde_json::json!({"set": "data.example.org::foo", "object": "a", "method": "GET"});
+        let err = sign(serde_json::json!([item, item, item, item]))
+            .err()
+            .expect("Expected the batch to be rejected");
+        assert_eq!(err.status_code(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn sign_batch_fail_fast() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let signed = Arc::new(AtomicUsize::new(0));
+        let items = || {
+            let results = vec![
+                Ok(SignResponse::new(
+                    "https://s3.example.org/data.example.org/foo.bar?get".to_owned(),
+                )),
+                Err(Error::builder()
+                    .kind("sign_error", "Error signing a request")
+                    .status(StatusCode::FORBIDDEN)
+                    .detail("access denied")
+                    .build()),
+                Err(Error::builder()
+                    .kind("sign_error", "Error signing a request")
+                    .status(StatusCode::BAD_REQUEST)
+                    .detail("invalid method")
+                    .build()),
+            ];
+
+            results
+                .into_iter()
+                .map(|result| {
+                    let signed = signed.clone();
+                    move || {
+                        signed.fetch_add(1, Ordering::SeqCst);
+                        future::ok::<_, ()>(result)
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let err = block_on(SignBatchResponse::sign(items(), true))
+            .err()
+            .expect("Expected the batch to fail");
+        assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+        // The item following the failed one is never signed
+        assert_eq!(signed.swap(0, Ordering::SeqCst), 2);
+
+        let resp =
+            block_on(SignBatchResponse::sign(items(), false)).expect("Error signing a batch");
+        let statuses: Vec<u16> = resp.items.iter().map(|item| item.status).collect();
+        assert_eq!(statuses, vec![200, 403, 400]);
+        assert_eq!(signed.swap(0, Ordering::SeqCst), 3);
+
+        let resp = block_on(SignBatchResponse::sign(
+            items().into_iter().take(1).collect(),
+            true,
+        ))
+        .expect("Error signing a batch");
+        assert_eq!(resp.items.len(), 1);
+        assert_eq!(resp.items[0].status, 200);
+    }
+
+    #[test]
+    fn healthz_responsive_while_signing() {
+        use std::time::{Duration, Instant};
+
+        // A single worker of the pool, signing operations running on it would hold the probe back
+        let mut runtime = tokio::runtime::Builder::new()
+            .core_threads(1)
+            .build()
+            .expect("Error creating a runtime");
+        let limiter = util::SignLimiter::new(Some(8));
+        for _ in 0..8 {
+            let sign = limiter.run(|| {
+                std::thread::sleep(Duration::from_millis(500));
+                Ok(())
+            });
+            runtime.spawn(sign.map(|_| ()));
+        }
+
+        let healthz = Healthz {
+            readiness: self_test::spawn_readiness(None, s3_clients()),
+        };
+        let started = Instant::now();
+        let resp = runtime
+            .block_on(future::lazy(move || healthz.healthz()))
+            .expect("Error running a handler");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(started.elapsed() < Duration::from_millis(250));
+    }
+
+    #[test]
+    fn sign_batch_payload_mixed_methods() {
+        let body = r#"[
+            {"set": "data.example.org::foo", "object": "a", "method": "GET"},
+            {"set": "data.example.org::foo", "object": "b", "method": "PUT", "headers": {"content-type": "text/plain"}},
+            {"set": "data.example.org::bar", "object": "c", "method": "DELETE"}
+        ]"#;
+        let items: Vec<SignBatchItemPayload> =
+            serde_json::from_str(body).expect("Error parsing batch items");
+
+        let payloads: Vec<SignPayload> = items.into_iter().map(SignPayload::from).collect();
+        let methods: Vec<&str> = payloads.iter().map(|p| p.method.as_str()).collect();
+        assert_eq!(methods, vec!["GET", "PUT", "DELETE"]);
+        assert_eq!(
+            payloads[1].headers.get("content-type").map(String::as_str),
+            Some("text/plain")
+        );
+        assert!(payloads[2].headers.is_empty());
+    }
+
+    #[test]
+    fn object_depth_exceeded() {
+        let audiences_settings = AudiencesSettingsRef::new(BTreeMap::new());
+
+        let object = "a/".repeat(32) + "b";
+        assert!(valid_object_depth(&audiences_settings, "example.org", &object).is_ok());
+
+        let object = "a/".repeat(33) + "b";
+        assert!(valid_object_depth(&audiences_settings, "example.org", &object).is_err());
+    }
+
+    #[test]
+    fn storage_class_signed() {
+        let audiences_settings = settings(
+            r#"{"default_storage_class": "STANDARD_IA", "allowed_storage_classes": ["STANDARD", "STANDARD_IA"]}"#,
+        );
+        let check = |method: &str, requested: Option<&str>| {
+            storage_class(
+                &audiences_settings,
+                "example.org",
+                method,
+                requested,
+                &BTreeMap::new(),
+            )
+        };
+
+        assert_eq!(check("PUT", None), Ok(Some("STANDARD_IA".to_owned())));
+        assert_eq!(
+            check("PUT", Some("STANDARD")),
+            Ok(Some("STANDARD".to_owned()))
+        );
+        assert!(check("PUT", Some("GLACIER")).is_err());
+        assert_eq!(check("GET", None), Ok(None));
+        assert!(check("GET", Some("STANDARD")).is_err());
+
+        // Headers of the client don't bypass the allowed classes
+        let mut headers = BTreeMap::new();
+        headers.insert(STORAGE_CLASS_HEADER.to_owned(), "GLACIER".to_owned());
+        assert!(storage_class(&audiences_settings, "example.org", "PUT", None, &headers).is_err());
+
+        let client = crate::s3::Client::new(
+            "key",
+            "secret",
+            "us-east-1",
+            "https://s3.example.org",
+            std::time::Duration::from_secs(300),
+        );
+        let uri = util::S3SignedRequestBuilder::new()
+            .method("PUT")
+            .bucket("data.example.org")
+            .object("foo.bar")
+            .add_header(STORAGE_CLASS_HEADER, "STANDARD_IA")
+            .build(&client)
+            .expect("Error signing a request");
+        assert!(util::signed_headers(&uri).contains(&STORAGE_CLASS_HEADER.to_owned()));
+    }
+
+    #[test]
+    fn reserved_prefix_rejected() {
+        let audiences_settings = settings(r#"{"reserved_prefixes": ["system/"]}"#);
+
+        assert!(
+            valid_object_pattern(&audiences_settings, "example.org", "system/foo.bar").is_err()
+        );
+        assert!(
+            valid_object_pattern(&audiences_settings, "example.org", "avatars/foo.bar").is_ok()
+        );
+        assert!(valid_object_pattern(&audiences_settings, "example.net", "system/foo.bar").is_ok());
+    }
+
+    #[test]
+    fn empty_object_rejected() {
+        let audiences_settings = AudiencesSettingsRef::new(BTreeMap::new());
+        assert!(valid_object(&audiences_settings, "example.org", "").is_err());
+        assert!(valid_object(&audiences_settings, "example.org", "foo").is_ok());
+    }
+
+    #[test]
+    fn cross_set_object_rejected() {
+        let audiences_settings = settings(r#"{"reject_set_prefix": true}"#);
+
+        assert!(valid_object(&audiences_settings, "example.org", "bar.baz/qux").is_err());
+        assert!(valid_object(&audiences_settings, "example.org", "readme.txt").is_ok());
+        assert!(valid_object(&audiences_settings, "example.org", "bar/baz.txt").is_ok());
+        assert!(valid_object(&audiences_settings, "example.net", "bar.baz/qux").is_ok());
+    }
+
+    #[test]
+    fn rewritten_object_key() {
+        let audiences_settings =
+            settings(r#"{"rewrite_rules": [{"pattern": "^v1/", "replacement": "v2/"}]}"#);
+        let sub = Subject::new(AccountId::new("john", "usr.example.net"));
+        let key = |audience, object| {
+            let object = rewrite_object(&audiences_settings, audience, object);
+            s3_object(
+                "foo",
+                &normalize_object(&audiences_settings, audience, &sub, &object),
+            )
+        };
+
+        assert_eq!(key("example.org", "v1/foo.bar"), "foo.v2/foo.bar");
+        assert_eq!(key("example.net", "v1/foo.bar"), "foo.v1/foo.bar");
+    }
+
+    #[test]
+    fn rewritten_object_key_checked() {
+        let audiences_settings = settings(
+            r#"{
+                "reserved_prefixes": ["system/"],
+                "rewrite_rules": [{"pattern": "^legacy/", "replacement": "system/"}]
+            }"#,
+        );
+
+        // The rule matches the logical key, the rewritten one falls under the reserved prefix
+        let object = rewrite_object(&audiences_settings, "example.org", "legacy/foo.bar");
+        assert_eq!(object, "system/foo.bar");
+        let err = valid_read_object(&audiences_settings, "example.org", &object)
+            .expect_err("Expected a rewritten reserved key to be rejected");
+        assert_eq!(err.0, StatusCode::FORBIDDEN);
+
+        let object = rewrite_object(&audiences_settings, "example.org", "avatars/foo.bar");
+        assert!(valid_read_object(&audiences_settings, "example.org", &object).is_ok());
+    }
+
+    #[test]
+    fn nfc_object_key() {
+        let audiences_settings = settings(r#"{"object_key_encoding": "nfc"}"#);
+        let sub = Subject::new(AccountId::new("john", "usr.example.net"));
+        let key = |audience, object| {
+            s3_object(
+                "foo",
+                &normalize_object(&audiences_settings, audience, &sub, object),
+            )
+        };
+
+        // Written composed, read decomposed
+        assert_eq!(key("example.org", "caf\u{00e9}.txt"), "foo.caf\u{00e9}.txt");
+        assert_eq!(
+            key("example.org", "cafe\u{0301}.txt"),
+            "foo.caf\u{00e9}.txt"
+        );
+
+        // Exact bytes are kept by default
+        assert_eq!(
+            key("example.net", "cafe\u{0301}.txt"),
+            "foo.cafe\u{0301}.txt"
+        );
+    }
+
+    #[test]
+    fn write_once_object_locked() {
+        let audiences_settings = settings(r#"{"worm": true}"#);
+        let check = |audience: &str, method: &str, exists: Option<bool>| {
+            valid_write_once(
+                &audiences_settings,
+                audience,
+                method,
+                util::ErrorDetail::default(),
+                "default",
+                || match exists {
+                    Some(exists) => future::ok(exists),
+                    None => panic!("Unexpected request to the backend"),
+                },
+            )
+            .wait()
+            .expect("Error checking an object")
+        };
+
+        for method in &["PUT", "DELETE"] {
+            let err = check("example.org", method, Some(true))
+                .expect_err("Expected an existing object to be locked");
+            assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+            assert!(check("example.org", method, Some(false)).is_ok());
+        }
+        assert!(check("example.org", "GET", None).is_ok());
+        assert!(check("example.net", "PUT", None).is_ok());
+
+        let err = valid_write_once(
+            &audiences_settings,
+            "example.org",
+            "PUT",
+            util::ErrorDetail::Verbose,
+            "media",
+            || future::err(anyhow::format_err!("failed to get object metadata")),
+        )
+        .wait()
+        .expect("Error checking an object")
+        .expect_err("Expected a failed request to be an error");
+        assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[test]
+    fn object_list_filtered() {
+        let listing = || crate::s3::Listing {
+            keys: vec![
+                "foo.avatars/1.png".to_owned(),
+                "foo.avatars/2.jpg".to_owned(),
+                "foo.docs/readme.txt".to_owned(),
+                "foobar.avatars/3.png".to_owned(),
+            ],
+            continuation_token: Some("token".to_owned()),
+        };
+
+        let resp = ObjectListResponse::new(listing(), "foo", None);
+        assert_eq!(
+            resp.objects,
+            vec!["avatars/1.png", "avatars/2.jpg", "docs/readme.txt"]
+        );
+
+        let filter = Regex::new(r"\.png$").expect("Error compiling a filter");
+        let resp = ObjectListResponse::new(listing(), "foo", Some(&filter));
+        assert_eq!(resp.objects, vec!["avatars/1.png"]);
+        assert_eq!(resp.continuation_token.as_deref(), Some("token"));
+
+        let filter = Regex::new("^docs/").expect("Error compiling a filter");
+        let resp = ObjectListResponse::new(listing(), "foo", Some(&filter));
+        assert_eq!(resp.objects, vec!["docs/readme.txt"]);
+    }
+
+    #[test]
+    fn failover_to_mirror() {
+        let client = |endpoint: &str| {
+            Arc::new(crate::s3::Client::new(
+                "key",
+                "secret",
+                "us-east-1",
+                endpoint,
+                std::time::Duration::from_secs(300),
+            ))
+        };
+        let (primary, mirror) = (
+            client("https://s3.example.org"),
+            client("https://mirror.example.org"),
+        );
+
+        // The primary backend is unreachable, reads are negotiated whichever backend serves them
+        let tried = std::cell::RefCell::new(Vec::new());
+        let read = |mode: util::ReadMode| {
+            let tried = &tried;
+            move |s3: Arc<crate::s3::Client>| {
+                let uri = s3
+                    .presigned_url("GET", "data.example.org", "foo.bar")
+                    .expect("Error signing a URI");
+                let primary = uri.starts_with("https://s3.example.org/");
+                tried.borrow_mut().push(primary);
+
+                let resp = negotiate(
+                    mode,
+                    &AudienceSettings::default(),
+                    move || Ok(uri),
+                    || future::empty::<crate::s3::Download, anyhow::Error>(),
+                );
+                if primary {
+                    future::Either::A(future::err(anyhow::Error::new(
+                        crate::s3::Unreachable::new("connection refused"),
+                    )))
+                } else {
+                    future::Either::B(resp)
+                }
+            }
+        };
+        let vary = |resp: &Response<Vec<u8>>| {
+            resp.headers()
+                .get(http::header::VARY)
+                .and_then(|val| val.to_str().ok())
+                .map(ToOwned::to_owned)
+        };
+
+        let resp = failover(
+            primary.clone(),
+            Some(mirror.clone()),
+            false,
+            read(util::ReadMode::Redirect),
+        )
+        .wait()
+        .expect("Expected a mirror redirect");
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let location = resp
+            .headers()
+            .get("location")
+            .and_then(|val| val.to_str().ok())
+            .unwrap_or("");
+        assert!(
+            location.starts_with("https://mirror.example.org/"),
+            "{}",
+            location
+        );
+        assert_eq!(vary(&resp).as_deref(), Some("Accept"));
+        assert_eq!(*tried.borrow(), vec![true, false]);
+
+        let resp = failover(
+            primary.clone(),
+            Some(mirror.clone()),
+            false,
+            read(util::ReadMode::Json),
+        )
+        .wait()
+        .expect("Expected a mirror URI");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(String::from_utf8_lossy(resp.body()).contains("mirror.example.org"));
+        assert_eq!(vary(&resp).as_deref(), Some("Accept"));
+
+        // Unhealthy backends aren't tried at all
+        tried.borrow_mut().clear();
+        let resp = failover(
+            primary.clone(),
+            Some(mirror.clone()),
+            true,
+            read(util::ReadMode::Redirect),
+        )
+        .wait()
+        .expect("Expected a mirror redirect");
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(*tried.borrow(), vec![false]);
+
+        // Errors told by the backend itself aren't failed over
+        let failed = |_: Arc<crate::s3::Client>| {
+            future::err::<Response<Vec<u8>>, anyhow::Error>(anyhow::format_err!(
+                "failed to get object metadata: 404"
+            ))
+        };
+        assert!(failover(primary.clone(), Some(mirror), false, failed)
+            .wait()
+            .is_err());
+
+        assert!(
+            failover(primary, None, true, read(util::ReadMode::Redirect))
+                .wait()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn redirect_without_body() {
+        let uri = "https://s3.example.org/data.example.org/foo.bar?signature";
+        let header = |resp: &Response<Vec<u8>>, name: &str| {
+            resp.headers()
+                .get(name)
+                .and_then(|val| val.to_str().ok())
+                .map(ToOwned::to_owned)
+        };
+
+        // Redirects of the v1 endpoints and the ones of negotiated reads
+        let resp = redirect(uri).map(|body| body.as_bytes().to_vec());
+        let negotiated = negotiate(
+            util::ReadMode::Redirect,
+            &AudienceSettings::default(),
+            || Ok(uri.to_owned()),
+            || future::err(anyhow::format_err!("Unexpected download")),
+        )
+        .wait()
+        .expect("Error reading an object");
+
+        for resp in &[resp, negotiated] {
+            assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+            assert_eq!(header(resp, "location").as_deref(), Some(uri));
+            assert_eq!(header(resp, "content-length").as_deref(), Some("0"));
+            assert!(resp.body().is_empty());
+        }
+    }
+
+    #[test]
+    fn soft_deleted_object_not_found() {
+        let tombstones = std::cell::RefCell::new(std::collections::BTreeSet::new());
+        let read = || valid_tombstone(true, "GET", || Ok(tombstones.borrow().contains("foo.bar")));
+
+        assert!(read().is_ok());
+
+        tombstones.borrow_mut().insert("foo.bar");
+        let err = read().expect_err("Expected a deleted object to be missing");
+        assert_eq!(err.status_code(), StatusCode::NOT_FOUND);
+
+        // Restored
+        tombstones.borrow_mut().remove("foo.bar");
+        assert!(read().is_ok());
+
+        // Tombstones aren't looked up for audiences without soft deletes
+        assert!(valid_tombstone(false, "GET", || panic!("Unexpected lookup")).is_ok());
+
+        let err = valid_tombstone(true, "GET", || {
+            Err("db connection is unavailable".to_owned())
+        })
+        .expect_err("Expected a failed lookup to be an error");
+        assert_eq!(err.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[test]
+    fn deleted_object_refused_on_every_path() {
+        let audiences_settings = settings(r#"{"soft_delete_retention": 60}"#);
+        let tombstones = vec![("data.example.org", "foo.bar")];
+        let status = |audience: &str, method: &str, bucket: &str, key: &str| {
+            valid_not_deleted(
+                &audiences_settings,
+                audience,
+                method,
+                bucket,
+                key,
+                |bucket, key| Ok(tombstones.iter().any(|&(b, k)| b == bucket && k == key)),
+            )
+            .err()
+            .map(|err| err.status_code())
+        };
+
+        // v1 reads of objects, v1 signatures and shares address the object by its key
+        assert_eq!(
+            status("example.org", "GET", "data.example.org", "foo.bar"),
+            Some(StatusCode::NOT_FOUND)
+        );
+        assert_eq!(
+            status("example.org", "HEAD", "data.example.org", "foo.bar"),
+            Some(StatusCode::NOT_FOUND)
+        );
+        // v1 reads of sets, tag reads and signatures address it by the label of the set it's in
+        let key = s3_object("foo", "bar");
+        assert_eq!(
+            status("example.org", "GET", "data.example.org", &key),
+            Some(StatusCode::NOT_FOUND)
+        );
+        assert_eq!(
+            status("example.org", "GET", "data.example.org", "foo.baz"),
+            None
+        );
+
+        // Writes are refused until the object is restored, the purge would delete them
+        assert_eq!(
+            status("example.org", "PUT", "data.example.org", "foo.bar"),
+            Some(StatusCode::CONFLICT)
+        );
+
+        // Tombstones aren't looked up for audiences without soft deletes
+        assert!(valid_not_deleted(
+            &audiences_settings,
+            "example.net",
+            "GET",
+            "data.example.net",
+            "foo.bar",
+            |_, _| panic!("Unexpected lookup")
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn tag_read_reserved_object_refused() {
+        let audiences_settings = settings(r#"{"reserved_prefixes": ["system/"]}"#);
+        let status = |audience: &str, object: &str| {
+            valid_read_object(&audiences_settings, audience, object)
+                .err()
+                .map(|(status, _)| status)
+        };
+
+        assert_eq!(
+            status("example.org", "system/secret"),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(status("example.org", ""), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(status("example.org", "bar"), None);
+        assert_eq!(status("example.net", "system/secret"), None);
+    }
+
+    #[test]
+    fn sign_put_after_delete_refused() {
+        let audiences_settings = settings(r#"{"soft_delete_retention": 60}"#);
+        let tombstones = std::cell::RefCell::new(vec![(
+            util::S3_DEFAULT_CLIENT,
+            "data.example.org",
+            "foo.bar",
+        )]);
+        let status = |back: &str, method: &str| {
+            valid_not_deleted(
+                &audiences_settings,
+                "example.org",
+                method,
+                "data.example.org",
+                "foo.bar",
+                |bucket, key| {
+                    Ok(tombstones
+                        .borrow()
+                        .iter()
+                        .any(|&(t, b, k)| t == back && b == bucket && k == key))
+                },
+            )
+            .err()
+            .map(|err| err.status_code())
+        };
+
+        assert_eq!(
+            status(util::S3_DEFAULT_CLIENT, "PUT"),
+            Some(StatusCode::CONFLICT)
+        );
+        assert_eq!(
+            status(util::S3_DEFAULT_CLIENT, "GET"),
+            Some(StatusCode::NOT_FOUND)
+        );
+
+        // Tombstones are kept per backend
+        assert_eq!(status("alt", "PUT"), None);
+        assert_eq!(status("alt", "GET"), None);
+
+        // Restored
+        tombstones.borrow_mut().clear();
+        assert_eq!(status(util::S3_DEFAULT_CLIENT, "PUT"), None);
+    }
+
+    #[test]
+    fn soft_delete_referer_checked() {
+        let state = |json: &str| set_state(settings(json), "https://s3.example.org");
+        let delete = |state: &SetState, object: &str| {
+            block_on(state.delete_ns(
+                util::S3_DEFAULT_CLIENT.to_owned(),
+                "data.example.org::foo".to_owned(),
+                object.to_owned(),
+                subject(),
+                util::Referer::default(),
+            ))
+            .err()
+            .expect("Expected the delete to be refused")
+            .status_code()
+        };
+        let restore = |state: &SetState, object: &str| {
+            block_on(state.restore_ns(
+                util::S3_DEFAULT_CLIENT.to_owned(),
+                "data.example.org::foo".to_owned(),
+                object.to_owned(),
+                subject(),
+                util::Referer::default(),
+            ))
+            .err()
+            .expect("Expected the restore to be refused")
+            .status_code()
+        };
+
+        let strict = state(r#"{"soft_delete_retention": 60, "require_referer": true}"#);
+        assert_eq!(delete(&strict, "bar"), StatusCode::FORBIDDEN);
+        assert_eq!(restore(&strict, "bar"), StatusCode::FORBIDDEN);
+
+        // The referer is checked before soft deletes are
+        let strict = state(r#"{"require_referer": true}"#);
+        assert_eq!(delete(&strict, "bar"), StatusCode::FORBIDDEN);
+
+        let labeled = state(r#"{"soft_delete_retention": 60, "set_label": {"max_length": 2}}"#);
+        assert_eq!(delete(&labeled, "bar"), StatusCode::BAD_REQUEST);
+        assert_eq!(restore(&labeled, "bar"), StatusCode::BAD_REQUEST);
+
+        let patterned =
+            state(r#"{"soft_delete_retention": 60, "object_pattern": "^[a-z]+\\.png$"}"#);
+        assert_eq!(delete(&patterned, "bar"), StatusCode::FORBIDDEN);
+        assert_eq!(restore(&patterned, "bar"), StatusCode::FORBIDDEN);
+
+        // Allowed requests get as far as the database
+        assert_eq!(
+            delete(&patterned, "bar.png"),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            restore(&patterned, "bar.png"),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[test]
+    fn share_redemption_status() {
+        let status = |redemption| {
+            redeemed(redemption)
+                .expect_err("Expected a share to be refused")
+                .status_code()
+        };
+
+        assert_eq!(status(share::Redemption::Expired), StatusCode::GONE);
+        assert_eq!(status(share::Redemption::Exhausted), StatusCode::GONE);
+        assert_eq!(status(share::Redemption::NotFound), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn failed_redemption_not_counted() {
+        let redemptions = std::cell::Cell::new(0);
+        let redeem = |check: Result<(), StatusCode>| {
+            redeem_checked(
+                || Ok("data.example.org/foo.bar"),
+                |_| {
+                    check.map_err(|status| {
+                        Error::builder()
+                            .kind("share_error", "Error redeeming a shared object")
+                            .status(status)
+                            .build()
+                    })
+                },
+                || {
+                    redemptions.set(redemptions.get() + 1);
+                    Ok("data.example.org/foo.bar")
+                },
+            )
+            .map_err(|err| err.status_code())
+        };
+
+        // The object is deleted or its backend is gone
+        assert_eq!(
+            redeem(Err(StatusCode::NOT_FOUND)),
+            Err(StatusCode::NOT_FOUND)
+        );
+        assert_eq!(redemptions.get(), 0);
+
+        assert_eq!(redeem(Ok(())), Ok("data.example.org/foo.bar"));
+        assert_eq!(redemptions.get(), 1);
+
+        // Shares refused on their own aren't redeemed either
+        let result = redeem_checked(
+            || redeemed(share::Redemption::Exhausted).map(|_| "data.example.org/foo.bar"),
+            |_| panic!("Unexpected check"),
+            || panic!("Unexpected redemption"),
+        );
+        assert_eq!(
+            result.map_err(|err| err.status_code()),
+            Err(StatusCode::GONE)
+        );
+    }
+
+    #[test]
+    fn hard_delete_not_signed() {
+        let audiences_settings = settings(r#"{"soft_delete_retention": 86400}"#);
+
+        assert!(valid_hard_delete(&audiences_settings, "example.org", "DELETE").is_err());
+        assert!(valid_hard_delete(&audiences_settings, "example.org", "PUT").is_ok());
+        assert!(valid_hard_delete(&audiences_settings, "example.net", "DELETE").is_ok());
+    }
+
+    #[test]
+    fn read_negotiated() {
+        let read = |mode, proxy_reads: bool| {
+            let aud_settings = serde_json::from_value::<AudienceSettings>(
+                serde_json::json!({ "proxy_reads": proxy_reads }),
+            )
+            .expect("Error parsing audience settings");
+            negotiate(
+                mode,
+                &aud_settings,
+                || Ok("https://s3.example.org/data.example.org/foo.bar?signature".to_owned()),
+                || {
+                    future::ok(crate::s3::Download::Inline {
+                        body: b"content".to_vec(),
+                        content_type: Some("text/plain".to_owned()),
+                    })
+                },
+            )
+            .wait()
+            .expect("Error reading an object")
+        };
+        let content_type = |resp: &Response<Vec<u8>>| {
+            resp.headers()
+                .get("content-type")
+                .and_then(|val| val.to_str().ok())
+                .map(ToOwned::to_owned)
+        };
+
+        let vary = |resp: &Response<Vec<u8>>| {
+            resp.headers()
+                .get("vary")
+                .and_then(|val| val.to_str().ok())
+                .map(ToOwned::to_owned)
+        };
+
+        let resp = read(util::ReadMode::Redirect, true);
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert!(resp.headers().contains_key("location"));
+        assert_eq!(vary(&resp).as_deref(), Some("Accept"));
+
+        let resp = read(util::ReadMode::Json, true);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(content_type(&resp).as_deref(), Some("application/json"));
+        assert_eq!(vary(&resp).as_deref(), Some("Accept"));
+        let body: serde_json::Value =
+            serde_json::from_slice(resp.body()).expect("Error parsing the response");
+        assert_eq!(
+            body["uri"],
+            "https://s3.example.org/data.example.org/foo.bar?signature"
+        );
+
+        let resp = read(util::ReadMode::Proxy, true);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(content_type(&resp).as_deref(), Some("text/plain"));
+        assert_eq!(resp.body(), b"content");
+        assert_eq!(vary(&resp).as_deref(), Some("Accept"));
+
+        // Proxying is opted into by the audience
+        let resp = read(util::ReadMode::Proxy, false);
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    }
+
+    #[test]
+    fn download_inline_or_redirected() {
+        let endpoint = backend(b"content");
+        let download = |max_size: u64| {
+            let state = set_state(
+                settings(&format!(r#"{{"download_max_size": {}}}"#, max_size)),
+                &endpoint,
+            );
+            block_on(state.download_ns(
+                util::S3_DEFAULT_CLIENT.to_owned(),
+                "data.example.org::foo".to_owned(),
+                "bar".to_owned(),
+                subject(),
+                util::Referer::default(),
+            ))
+            .expect("Error downloading an object")
+        };
+
+        let resp = download(16);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()
+                .get("content-type")
+                .and_then(|val| val.to_str().ok()),
+            Some("text/plain")
+        );
+        assert_eq!(resp.body(), b"content");
+
+        // Objects over the maximum size are redirected to, their metadata is fetched only
+        let resp = download(4);
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let location = resp
+            .headers()
+            .get("location")
+            .and_then(|val| val.to_str().ok())
+            .expect("Error reading the location");
+        assert!(
+            location.starts_with(&format!("{}/data.example.org/foo.bar?", endpoint)),
+            "{}",
+            location
+        );
+        assert!(resp.body().is_empty());
+    }
+
+    #[test]
+    fn read_v1_negotiated() {
+        let endpoint = backend(b"content");
+        let state = set_state(settings(r#"{"proxy_reads": true}"#), &endpoint);
+        let read = |mode| {
+            block_on(state.read_v1_ns(
+                util::S3_DEFAULT_CLIENT.to_owned(),
+                "data.example.org".to_owned(),
+                "foo".to_owned(),
+                "bar".to_owned(),
+                subject(),
+                util::Referer::default(),
+                mode,
+            ))
+            .expect("Error reading an object")
+        };
+        let vary = |resp: &Response<Vec<u8>>| {
+            resp.headers()
+                .get("vary")
+                .and_then(|val| val.to_str().ok())
+                .map(ToOwned::to_owned)
+        };
+
+        let resp = read(util::ReadMode::Redirect);
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(vary(&resp).as_deref(), Some("Accept"));
+
+        let resp = read(util::ReadMode::Json);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(vary(&resp).as_deref(), Some("Accept"));
+        let body: serde_json::Value =
+            serde_json::from_slice(resp.body()).expect("Error parsing the response");
+        let uri = body["uri"].as_str().expect("Error reading the uri");
+        assert!(
+            uri.starts_with(&format!("{}/data.example.org/foo.bar?", endpoint)),
+            "{}",
+            uri
+        );
+
+        let resp = read(util::ReadMode::Proxy);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), b"content");
+        assert_eq!(vary(&resp).as_deref(), Some("Accept"));
+    }
+
+    #[test]
+    fn html_not_served_inline() {
+        let aud_settings = serde_json::from_str::<AudienceSettings>(
+            r#"{"proxy_reads": true, "inline_content_types": ["image/png", "text/plain"]}"#,
+        )
+        .expect("Error parsing audience settings");
+        let read = |content_type: &str| {
+            let content_type = content_type.to_owned();
+            negotiate(
+                util::ReadMode::Proxy,
+                &aud_settings,
+                || Ok("https://s3.example.org/data.example.org/foo.html?signature".to_owned()),
+                || {
+                    future::ok(crate::s3::Download::Inline {
+                        body: b"<script></script>".to_vec(),
+                        content_type: Some(content_type),
+                    })
+                },
+            )
+            .wait()
+            .expect("Error reading an object")
+        };
+
+        let resp = read("text/html; charset=utf-8");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()
+                .get("content-disposition")
+                .and_then(|val| val.to_str().ok()),
+            Some("attachment")
+        );
+        assert!(resp.headers().contains_key("x-content-type-options"));
+        assert_eq!(resp.body(), b"<script></script>");
+
+        let resp = read("text/plain");
+        assert!(!resp.headers().contains_key("content-disposition"));
+    }
+
+    #[test]
+    fn custom_method_action() {
+        let mut method_actions = BTreeMap::new();
+        method_actions.insert("PURGE".to_owned(), "delete".to_owned());
+        assert!(valid_method_actions(&method_actions).is_ok());
+
+        assert_eq!(parse_action("PURGE", &method_actions).ok(), Some("delete"));
+        assert_eq!(parse_action("GET", &method_actions).ok(), Some("read"));
+        assert!(parse_action("PURGE", &BTreeMap::new()).is_err());
+        assert!(parse_action("PATCH", &method_actions).is_err());
+
+        for (method, action) in &[
+            ("PURGE", "destroy"),
+            ("GET", "delete"),
+            ("BAD METHOD", "read"),
+        ] {
+            let mut method_actions = BTreeMap::new();
+            method_actions.insert((*method).to_owned(), (*action).to_owned());
+            assert!(valid_method_actions(&method_actions).is_err(), "{}", method);
+        }
+    }
+
+    #[test]
+    fn sign_response_tags() {
+        use crate::db::{Bucket, Set};
+
+        let uri = "https://s3.example.org/data.example.org/foo.bar?get".to_owned();
+        let resp = serde_json::to_value(SignResponse::new(uri.clone()).tags(None))
+            .expect("Error serializing a response");
+        assert_eq!(resp, serde_json::json!({ "uri": uri }));
+
+        let bucket = Bucket::new("data", "example.org");
+        let tags = vec![
+            Set::new("ref.v1", bucket.clone()),
+            Set::new("ref.v2", bucket),
+        ];
+        let resp = serde_json::to_value(SignResponse::new(uri.clone()).tags(Some(tags)))
+            .expect("Error serializing a response");
+        assert_eq!(resp["uri"], serde_json::json!(uri));
+        assert_eq!(resp["tags"].as_array().map(Vec::len), Some(2));
+    }
+
+    #[test]
+    fn sign_response_method() {
+        let uri = "https://s3.example.org/data.example.org/foo.bar?put".to_owned();
+        let resp = serde_json::to_value(SignResponse::new(uri.clone()).method(Some("PUT")))
+            .expect("Error serializing a response");
+        assert_eq!(resp, serde_json::json!({ "uri": uri, "method": "PUT" }));
+
+        let resp = serde_json::to_value(SignResponse::new(uri.clone()).method(None))
+            .expect("Error serializing a response");
+        assert_eq!(resp, serde_json::json!({ "uri": uri }));
+    }
+
+    #[test]
+    fn custom_header_preflight() {
+        use http::header::ACCESS_CONTROL_ALLOW_HEADERS;
+        use http::Request;
+        use tower_service::Service;
+        use tower_web::middleware::Middleware;
+
+        struct Handler;
+
+        impl Service for Handler {
+            type Request = Request<()>;
+            type Response = Response<()>;
+            type Error = ();
+            type Future = future::FutureResult<Response<()>, ()>;
+
+            fn poll_ready(&mut self) -> futures::Poll<(), Self::Error> {
+                Ok(futures::Async::Ready(()))
+            }
+
+            fn call(&mut self, _req: Self::Request) -> Self::Future {
+                future::ok(Response::new(()))
+            }
+        }
+
+        let config = serde_json::from_str::<Cors>(
+            r#"{"allow_origins": ["https://example.org"], "allow_headers": ["x-client-version"]}"#,
+        )
+        .expect("Error parsing cors config");
+        let mut service = cors(&config).wrap(Handler);
+
+        let mut preflight = |header: &str| {
+            let req = Request::options("/api/v2/sign")
+                .header("origin", "https://example.org")
+                .header("access-control-request-method", "POST")
+                .header("access-control-request-headers", header)
+                .body(())
+                .expect("Error building a request");
+            service.call(req).wait().expect("Error handling a request")
+        };
+
+        for header in &["x-client-version", "x-storage-capability"] {
+            let resp = preflight(header);
+            assert!(resp.status().is_success(), "{}", header);
+            let allowed = resp
+                .headers()
+                .get(ACCESS_CONTROL_ALLOW_HEADERS)
+                .and_then(|val| val.to_str().ok())
+                .unwrap_or_default();
+            assert!(allowed.contains(header), "{}", allowed);
+        }
+
+        assert!(!preflight("x-unknown").status().is_success());
+    }
+}
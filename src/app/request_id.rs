@@ -0,0 +1,177 @@
+use futures::{Async, Future, Poll};
+use http::header::HeaderValue;
+use http::{Request, Response};
+use tower_service::Service;
+use tower_web::middleware::Middleware;
+use uuid::Uuid;
+
+const X_REQUEST_ID: &str = "x-request-id";
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+pub(crate) struct RequestIdMiddleware {
+    enabled: bool,
+}
+
+impl RequestIdMiddleware {
+    pub(crate) fn new(enabled: bool) -> Self {
+        Self { enabled }
+    }
+}
+
+impl<S, RequestBody, ResponseBody> Middleware<S> for RequestIdMiddleware
+where
+    S: Service<Request = Request<RequestBody>, Response = Response<ResponseBody>>,
+{
+    type Request = Request<RequestBody>;
+    type Response = Response<ResponseBody>;
+    type Error = S::Error;
+    type Service = RequestIdService<S>;
+
+    fn wrap(&self, inner: S) -> Self::Service {
+        RequestIdService {
+            inner,
+            enabled: self.enabled,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RequestIdService<S> {
+    inner: S,
+    enabled: bool,
+}
+
+impl<S, RequestBody, ResponseBody> Service for RequestIdService<S>
+where
+    S: Service<Request = Request<RequestBody>, Response = Response<ResponseBody>>,
+{
+    type Request = Request<RequestBody>;
+    type Response = Response<ResponseBody>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, mut req: Self::Request) -> Self::Future {
+        if !self.enabled {
+            return ResponseFuture {
+                inner: self.inner.call(req),
+                request_id: None,
+            };
+        }
+
+        // A generated id is added to the request as well, so that the access log records it
+        let request_id = match req.headers().get(X_REQUEST_ID) {
+            Some(val) => val.clone(),
+            None => {
+                let val = HeaderValue::from_str(&Uuid::new_v4().to_string())
+                    .expect("Error converting a request id to a header value");
+                req.headers_mut().insert(X_REQUEST_ID, val.clone());
+                val
+            }
+        };
+
+        ResponseFuture {
+            inner: self.inner.call(req),
+            request_id: Some(request_id),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ResponseFuture<F> {
+    inner: F,
+    request_id: Option<HeaderValue>,
+}
+
+impl<F, ResponseBody> Future for ResponseFuture<F>
+where
+    F: Future<Item = Response<ResponseBody>>,
+{
+    type Item = Response<ResponseBody>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut resp = match self.inner.poll()? {
+            Async::Ready(resp) => resp,
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+
+        if let Some(request_id) = self.request_id.take() {
+            resp.headers_mut().insert(X_REQUEST_ID, request_id);
+        }
+
+        Ok(Async::Ready(resp))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+
+    use super::*;
+
+    // Responds with the request id seen by the handler
+    struct Handler;
+
+    impl Service for Handler {
+        type Request = Request<()>;
+        type Response = Response<Option<String>>;
+        type Error = ();
+        type Future = future::FutureResult<Response<Option<String>>, ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, req: Self::Request) -> Self::Future {
+            let request_id = req
+                .headers()
+                .get(X_REQUEST_ID)
+                .and_then(|val| val.to_str().ok())
+                .map(ToOwned::to_owned);
+            future::ok(Response::new(request_id))
+        }
+    }
+
+    fn call(enabled: bool, request_id: Option<&str>) -> Response<Option<String>> {
+        let mut service = RequestIdMiddleware::new(enabled).wrap(Handler);
+        let mut req = Request::get("/api/v2/sets/data.example.org::foo/objects/bar");
+        if let Some(request_id) = request_id {
+            req.header(X_REQUEST_ID, request_id);
+        }
+        let req = req.body(()).expect("Error building a request");
+        service.call(req).wait().expect("Error handling a request")
+    }
+
+    fn response_id(resp: &Response<Option<String>>) -> Option<&str> {
+        resp.headers()
+            .get(X_REQUEST_ID)
+            .map(|val| val.to_str().expect("Invalid header value"))
+    }
+
+    #[test]
+    fn supplied_request_id_echoed() {
+        let resp = call(true, Some("req-123"));
+        assert_eq!(response_id(&resp), Some("req-123"));
+        assert_eq!(resp.body().as_deref(), Some("req-123"));
+    }
+
+    #[test]
+    fn missing_request_id_generated() {
+        let resp = call(true, None);
+        let request_id = response_id(&resp).expect("Missing request id");
+        assert!(Uuid::parse_str(request_id).is_ok(), "{}", request_id);
+        assert_eq!(resp.body().as_deref(), Some(request_id));
+
+        let resp = call(false, None);
+        assert_eq!(response_id(&resp), None);
+        assert_eq!(resp.body().as_deref(), None);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use futures::{Async, Future, Poll};
+use http::header::{HeaderValue, RETRY_AFTER};
+use http::{Request, Response};
+use tower_service::Service;
+use tower_web::middleware::Middleware;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct RetryAfterRule {
+    status: u16,
+    seconds: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct RetryAfterMiddleware {
+    rules: Arc<BTreeMap<u16, HeaderValue>>,
+}
+
+impl RetryAfterMiddleware {
+    pub(crate) fn new(rules: &[RetryAfterRule]) -> Self {
+        let rules = rules
+            .iter()
+            .map(|rule| (rule.status, HeaderValue::from(rule.seconds)))
+            .collect();
+
+        Self {
+            rules: Arc::new(rules),
+        }
+    }
+}
+
+impl<S, RequestBody, ResponseBody> Middleware<S> for RetryAfterMiddleware
+where
+    S: Service<Request = Request<RequestBody>, Response = Response<ResponseBody>>,
+{
+    type Request = Request<RequestBody>;
+    type Response = Response<ResponseBody>;
+    type Error = S::Error;
+    type Service = RetryAfterService<S>;
+
+    fn wrap(&self, inner: S) -> Self::Service {
+        RetryAfterService {
+            inner,
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RetryAfterService<S> {
+    inner: S,
+    rules: Arc<BTreeMap<u16, HeaderValue>>,
+}
+
+impl<S, RequestBody, ResponseBody> Service for RetryAfterService<S>
+where
+    S: Service<Request = Request<RequestBody>, Response = Response<ResponseBody>>,
+{
+    type Request = Request<RequestBody>;
+    type Response = Response<ResponseBody>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: Self::Request) -> Self::Future {
+        ResponseFuture {
+            inner: self.inner.call(req),
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ResponseFuture<F> {
+    inner: F,
+    rules: Arc<BTreeMap<u16, HeaderValue>>,
+}
+
+impl<F, ResponseBody> Future for ResponseFuture<F>
+where
+    F: Future<Item = Response<ResponseBody>>,
+{
+    type Item = Response<ResponseBody>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut resp = match self.inner.poll()? {
+            Async::Ready(resp) => resp,
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+
+        // A value set by the handler itself is more precise than the configured one
+        if let Some(val) = self.rules.get(&resp.status().as_u16()) {
+            if !resp.headers().contains_key(RETRY_AFTER) {
+                resp.headers_mut().insert(RETRY_AFTER, val.clone());
+            }
+        }
+
+        Ok(Async::Ready(resp))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use http::StatusCode;
+
+    use super::*;
+
+    struct Handler;
+
+    impl Service for Handler {
+        type Request = Request<StatusCode>;
+        type Response = Response<()>;
+        type Error = ();
+        type Future = future::FutureResult<Response<()>, ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, req: Self::Request) -> Self::Future {
+            let mut resp = Response::new(());
+            *resp.status_mut() = *req.body();
+            future::ok(resp)
+        }
+    }
+
+    fn retry_after(status: StatusCode) -> Option<String> {
+        let rules = vec![
+            RetryAfterRule {
+                status: 429,
+                seconds: 1,
+            },
+            RetryAfterRule {
+                status: 503,
+                seconds: 30,
+            },
+        ];
+        let mut service = RetryAfterMiddleware::new(&rules).wrap(Handler);
+        let req = Request::new(status);
+
+        service
+            .call(req)
+            .wait()
+            .expect("Error handling a request")
+            .headers()
+            .get(RETRY_AFTER)
+            .map(|val| val.to_str().expect("Invalid header value").to_owned())
+    }
+
+    #[test]
+    fn retry_after_configured() {
+        assert_eq!(
+            retry_after(StatusCode::TOO_MANY_REQUESTS),
+            Some("1".to_owned())
+        );
+        assert_eq!(
+            retry_after(StatusCode::SERVICE_UNAVAILABLE),
+            Some("30".to_owned())
+        );
+        assert_eq!(retry_after(StatusCode::GATEWAY_TIMEOUT), None);
+    }
+}
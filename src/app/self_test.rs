@@ -0,0 +1,434 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use http::StatusCode;
+use log::warn;
+use tokio::runtime::Runtime;
+use tokio_timer::Timeout;
+
+use super::util::{S3Clients, S3SignedRequestBuilder};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(3);
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Backends may host different buckets, each one is checked with its own canary object
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct SelfTestConfig {
+    #[serde(default)]
+    backends: BTreeMap<String, Canary>,
+}
+
+impl SelfTestConfig {
+    pub(crate) fn canary(&self, backend: &str) -> Option<&Canary> {
+        self.backends.get(backend)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct Canary {
+    bucket: String,
+    object: String,
+}
+
+impl Canary {
+    pub(crate) fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    pub(crate) fn object(&self) -> &str {
+        &self.object
+    }
+}
+
+#[derive(Debug, Response)]
+#[web(status = "200")]
+pub(crate) struct SelfTestReport {
+    ok: bool,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReadinessStatus {
+    Healthy,
+    Degraded,
+    Down,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Readiness {
+    status: ReadinessStatus,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    unhealthy: Vec<String>,
+}
+
+impl Readiness {
+    // Some of the backends being down doesn't take the service out of rotation, only all of them do
+    pub(crate) fn status_code(&self) -> StatusCode {
+        match self.status {
+            ReadinessStatus::Healthy | ReadinessStatus::Degraded => StatusCode::OK,
+            ReadinessStatus::Down => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+// Readiness of the last round of checks, probes are served it without waiting for the backends
+#[derive(Debug)]
+pub(crate) struct ReadinessMonitor {
+    last: RwLock<Readiness>,
+}
+
+impl ReadinessMonitor {
+    // Backends are reported healthy until the first round of checks is done
+    fn new() -> Self {
+        Self {
+            last: RwLock::new(readiness(Vec::new())),
+        }
+    }
+
+    pub(crate) fn readiness(&self) -> Readiness {
+        self.last
+            .read()
+            .expect("Error acquiring readiness lock")
+            .clone()
+    }
+
+    // Reads of unhealthy backends are served by their mirrors
+    pub(crate) fn unhealthy(&self, backend: &str) -> bool {
+        self.last
+            .read()
+            .expect("Error acquiring readiness lock")
+            .unhealthy
+            .iter()
+            .any(|val| val == backend)
+    }
+
+    fn update(&self, readiness: Readiness) {
+        *self.last.write().expect("Error acquiring readiness lock") = readiness;
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Backends are checked on a timer, without the self-test configuration there is nothing to check
+// and the service is always reported healthy
+pub(crate) fn spawn_readiness(
+    config: Option<SelfTestConfig>,
+    s3: super::S3ClientRef,
+) -> Arc<ReadinessMonitor> {
+    let monitor = Arc::new(ReadinessMonitor::new());
+    let config = match config {
+        Some(val) => val,
+        None => return monitor,
+    };
+
+    let last = monitor.clone();
+    thread::spawn(move || {
+        let mut runtime = Runtime::new().expect("Error creating a readiness runtime");
+
+        loop {
+            let clients = s3.read().expect("Error acquiring s3 clients lock").clone();
+            let checks = check(&config, clients, fetch, CHECK_TIMEOUT);
+            match runtime.block_on(checks) {
+                Ok(readiness) => last.update(readiness),
+                Err(()) => warn!("Error checking readiness of backends"),
+            }
+
+            thread::sleep(CHECK_INTERVAL);
+        }
+    });
+
+    monitor
+}
+
+// Every backend with a canary is self-tested at once, a check exceeding the timeout fails.
+// Backends without one aren't checked, they are neither healthy nor unhealthy
+fn check<F, R>(
+    config: &SelfTestConfig,
+    clients: S3Clients,
+    fetch: F,
+    timeout: Duration,
+) -> impl Future<Item = Readiness, Error = ()>
+where
+    F: FnOnce(String) -> R + Clone,
+    R: Future<Item = (), Error = String>,
+{
+    let checks = clients
+        .into_iter()
+        .filter_map(|(back, s3)| {
+            config
+                .canary(&back)
+                .cloned()
+                .map(|canary| (back, s3, canary))
+        })
+        .map(|(back, s3, canary)| {
+            let uri = S3SignedRequestBuilder::new()
+                .method("GET")
+                .bucket(canary.bucket())
+                .object(canary.object())
+                .build(&s3)
+                .map_err(|err| err.to_string());
+            let latency_ms = timeout.as_secs() * 1000 + u64::from(timeout.subsec_millis());
+
+            Timeout::new(run(uri, fetch.clone()), timeout)
+                .or_else(move |_| {
+                    warn!("Self-test timed out");
+                    Ok(SelfTestReport {
+                        ok: false,
+                        latency_ms,
+                        error: Some(String::from("Self-test timed out")),
+                    })
+                })
+                .map(move |report| (back, report))
+        })
+        .collect::<Vec<_>>();
+
+    future::join_all(checks).map(readiness)
+}
+
+// The signed URI of the canary object is requested the same way as a client would do
+pub(crate) fn run<F, R>(
+    uri: Result<String, String>,
+    fetch: F,
+) -> impl Future<Item = SelfTestReport, Error = ()>
+where
+    F: FnOnce(String) -> R,
+    R: Future<Item = (), Error = String>,
+{
+    let start = Instant::now();
+    let result = match uri {
+        Ok(uri) => future::Either::A(fetch(uri).then(Ok)),
+        Err(err) => future::Either::B(future::ok(Err(err))),
+    };
+
+    result.map(move |result| {
+        let elapsed = start.elapsed();
+        let latency_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        match result {
+            Ok(()) => SelfTestReport {
+                ok: true,
+                latency_ms,
+                error: None,
+            },
+            Err(err) => {
+                warn!("Self-test failed: {}", err);
+                SelfTestReport {
+                    ok: false,
+                    latency_ms,
+                    error: Some(err),
+                }
+            }
+        }
+    })
+}
+
+// Backends are reported by their names, the ones failing their self-test are listed as unhealthy
+pub(crate) fn readiness(reports: Vec<(String, SelfTestReport)>) -> Readiness {
+    let total = reports.len();
+    let unhealthy = reports
+        .into_iter()
+        .filter(|(_, report)| !report.ok)
+        .map(|(back, _)| back)
+        .collect::<Vec<String>>();
+
+    let status = if unhealthy.is_empty() {
+        ReadinessStatus::Healthy
+    } else if unhealthy.len() < total {
+        ReadinessStatus::Degraded
+    } else {
+        ReadinessStatus::Down
+    };
+
+    Readiness { status, unhealthy }
+}
+
+// A canary object the backend doesn't serve within the timeout fails the self-test
+pub(crate) fn fetch(uri: String) -> impl Future<Item = (), Error = String> {
+    let client = match reqwest::r#async::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+    {
+        Ok(val) => val,
+        Err(err) => {
+            return future::Either::A(future::err(format!(
+                "Error creating a self-test client: {}",
+                err
+            )))
+        }
+    };
+
+    let resp = client
+        .get(&uri)
+        .send()
+        .map_err(|err| format!("Error requesting the canary object: {}", err))
+        .and_then(|resp| {
+            if resp.status().is_success() {
+                Ok(())
+            } else {
+                Err(format!(
+                    "Unexpected status of the canary object = {}",
+                    resp.status()
+                ))
+            }
+        });
+    future::Either::B(resp)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn working_backend() {
+        let uri = "https://s3.example.org/data.example.org/canary?signature".to_owned();
+        let report = run(Ok(uri), |uri| {
+            assert!(uri.ends_with("canary?signature"));
+            future::ok(())
+        })
+        .wait()
+        .expect("Error running a self-test");
+
+        assert!(report.ok);
+        assert_eq!(report.error, None);
+    }
+
+    #[test]
+    fn broken_backend() {
+        let uri = "https://s3.example.org/data.example.org/canary?signature".to_owned();
+        let report = run(Ok(uri), |_| {
+            future::err("Unexpected status of the canary object = 403 Forbidden".to_owned())
+        })
+        .wait()
+        .expect("Error running a self-test");
+
+        assert!(!report.ok);
+        assert_eq!(
+            report.error.as_deref(),
+            Some("Unexpected status of the canary object = 403 Forbidden")
+        );
+
+        let report = run(Err("missing bucket".to_owned()), |_| future::ok(()))
+            .wait()
+            .expect("Error running a self-test");
+        assert!(!report.ok);
+        assert_eq!(report.error.as_deref(), Some("missing bucket"));
+    }
+
+    fn report(ok: bool) -> SelfTestReport {
+        SelfTestReport {
+            ok,
+            latency_ms: 0,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn readiness_healthy() {
+        let readiness = readiness(vec![
+            ("default".to_owned(), report(true)),
+            ("media".to_owned(), report(true)),
+        ]);
+        assert_eq!(readiness.status, ReadinessStatus::Healthy);
+        assert!(readiness.unhealthy.is_empty());
+        assert_eq!(readiness.status_code(), StatusCode::OK);
+
+        // Nothing to check
+        assert_eq!(readiness(Vec::new()).status, ReadinessStatus::Healthy);
+    }
+
+    #[test]
+    fn readiness_degraded() {
+        let readiness = readiness(vec![
+            ("default".to_owned(), report(true)),
+            ("media".to_owned(), report(false)),
+        ]);
+        assert_eq!(readiness.status, ReadinessStatus::Degraded);
+        assert_eq!(readiness.unhealthy, vec!["media".to_owned()]);
+        assert_eq!(readiness.status_code(), StatusCode::OK);
+    }
+
+    #[test]
+    fn readiness_checked_with_timeout() {
+        let canary = |bucket: &str| Canary {
+            bucket: bucket.to_owned(),
+            object: "canary.txt".to_owned(),
+        };
+        let mut backends = BTreeMap::new();
+        backends.insert("default".to_owned(), canary("canary.example.org"));
+        backends.insert("media".to_owned(), canary("canary.media.example.org"));
+        let config = SelfTestConfig { backends };
+
+        // The partner backend has no canary, it's never requested
+        let mut clients = S3Clients::new();
+        for (back, endpoint) in &[
+            ("default", "https://s3.example.org"),
+            ("media", "https://media.example.org"),
+            ("partner", "https://partner.example.org"),
+        ] {
+            let client = crate::s3::Client::new(
+                "key",
+                "secret",
+                "us-east-1",
+                endpoint,
+                Duration::from_secs(300),
+            );
+            clients.insert((*back).to_owned(), Arc::new(client));
+        }
+
+        // The media backend never answers
+        let fetch = |uri: String| {
+            assert!(!uri.starts_with("https://partner.example.org/"));
+            if uri.starts_with("https://media.example.org/") {
+                future::Either::A(future::empty())
+            } else {
+                future::Either::B(future::ok(()))
+            }
+        };
+        let readiness = tokio::runtime::current_thread::Runtime::new()
+            .expect("Error creating a runtime")
+            .block_on(check(&config, clients, fetch, Duration::from_millis(100)))
+            .expect("Error checking readiness");
+        assert_eq!(readiness.status, ReadinessStatus::Degraded);
+        assert_eq!(readiness.unhealthy, vec!["media".to_owned()]);
+    }
+
+    #[test]
+    fn readiness_without_self_test() {
+        let monitor = spawn_readiness(None, Arc::new(RwLock::new(S3Clients::new())));
+        assert_eq!(monitor.readiness().status, ReadinessStatus::Healthy);
+        assert!(!monitor.unhealthy("default"));
+    }
+
+    #[test]
+    fn unhealthy_backends_listed() {
+        let monitor = ReadinessMonitor::new();
+        monitor.update(readiness(vec![
+            ("default".to_owned(), report(true)),
+            ("media".to_owned(), report(false)),
+        ]));
+        assert!(!monitor.unhealthy("default"));
+        assert!(monitor.unhealthy("media"));
+        assert!(!monitor.unhealthy("partner"));
+    }
+
+    #[test]
+    fn readiness_down() {
+        let readiness = readiness(vec![
+            ("default".to_owned(), report(false)),
+            ("media".to_owned(), report(false)),
+        ]);
+        assert_eq!(readiness.status, ReadinessStatus::Down);
+        assert_eq!(readiness.unhealthy.len(), 2);
+        assert_eq!(readiness.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt::Debug;
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::runtime::current_thread::Runtime;
+
+use crate::db::tombstone;
+use crate::db::ConnectionPool;
+
+// Tombstones past their retention are purged in batches, the ones left wait for the next run
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+const PURGE_BATCH_SIZE: i64 = 100;
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) fn spawn_purge(db: ConnectionPool, s3: super::S3ClientRef) {
+    thread::spawn(move || {
+        let mut runtime = Runtime::new().expect("Error creating a purge runtime");
+
+        loop {
+            thread::sleep(PURGE_INTERVAL);
+
+            let expired = db.get().map_err(|err| err.to_string()).and_then(|conn| {
+                tombstone::ExpiredQuery::new(PURGE_BATCH_SIZE)
+                    .execute(&conn)
+                    .map_err(|err| err.to_string())
+            });
+            let expired = match expired {
+                Ok(val) => val,
+                Err(err) => {
+                    warn!("Error listing expired tombstones: {}", err);
+                    continue;
+                }
+            };
+
+            let purged = purge(
+                expired,
+                |object| {
+                    let client = s3
+                        .read()
+                        .expect("Error acquiring s3 clients lock")
+                        .get(object.backend())
+                        .cloned()
+                        .ok_or_else(|| format!("backend = '{}' is not found", object.backend()))?;
+                    runtime
+                        .block_on(client.delete(object.bucket(), object.object()))
+                        .map_err(|err| err.to_string())
+                },
+                |object| {
+                    db.get()
+                        .map_err(|err| err.to_string())
+                        .and_then(|conn| {
+                            tombstone::DeleteQuery::new(
+                                object.backend(),
+                                object.bucket(),
+                                object.object(),
+                            )
+                            .execute(&conn)
+                            .map_err(|err| err.to_string())
+                        })
+                        .map(|_| ())
+                },
+            );
+            if purged > 0 {
+                info!("Purged {} soft-deleted objects", purged);
+            }
+        }
+    });
+}
+
+// A tombstone is removed only once its object is deleted from the backend,
+// so that failed deletes are retried by the next purge
+fn purge<T, D, F>(expired: Vec<T>, mut delete: D, mut forget: F) -> usize
+where
+    T: Debug,
+    D: FnMut(&T) -> Result<(), String>,
+    F: FnMut(&T) -> Result<(), String>,
+{
+    let mut purged = 0;
+    for object in expired {
+        match delete(&object).and_then(|()| forget(&object)) {
+            Ok(()) => purged += 1,
+            Err(err) => warn!("Error purging the object = {:?}: {}", object, err),
+        }
+    }
+    purged
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[test]
+    fn failed_deletes_kept() {
+        let tombstones = RefCell::new(vec!["foo", "bar", "baz"]);
+        let expired = tombstones.borrow().clone();
+
+        let purged = purge(
+            expired,
+            |object| match *object {
+                "bar" => Err("backend is unavailable".to_owned()),
+                _ => Ok(()),
+            },
+            |object| {
+                tombstones.borrow_mut().retain(|val| val != object);
+                Ok(())
+            },
+        );
+
+        assert_eq!(purged, 2);
+        assert_eq!(*tombstones.borrow(), vec!["bar"]);
+    }
+}
@@ -0,0 +1,198 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::{Async, Future, Poll};
+use http::{Request, Response, StatusCode};
+use tower_service::Service;
+use tower_web::middleware::Middleware;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+pub(crate) struct StreamsMiddleware {
+    max: Option<usize>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl StreamsMiddleware {
+    pub(crate) fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl<S, RequestBody, ResponseBody> Middleware<S> for StreamsMiddleware
+where
+    S: Service<Request = Request<RequestBody>, Response = Response<ResponseBody>>,
+{
+    type Request = Request<RequestBody>;
+    type Response = Response<Option<ResponseBody>>;
+    type Error = S::Error;
+    type Service = StreamsService<S>;
+
+    // The service is wrapped for every accepted connection, the in-flight requests are counted
+    // across all of them, so that opening more connections doesn't get a client past the limit
+    fn wrap(&self, inner: S) -> Self::Service {
+        StreamsService {
+            inner,
+            max: self.max,
+            in_flight: self.in_flight.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct StreamsService<S> {
+    inner: S,
+    max: Option<usize>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl<S, RequestBody, ResponseBody> Service for StreamsService<S>
+where
+    S: Service<Request = Request<RequestBody>, Response = Response<ResponseBody>>,
+{
+    type Request = Request<RequestBody>;
+    type Response = Response<Option<ResponseBody>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: Self::Request) -> Self::Future {
+        let permit = StreamPermit::acquire(self.in_flight.clone(), self.max);
+        match permit {
+            Some(permit) => ResponseFuture {
+                inner: Some((self.inner.call(req), permit)),
+            },
+            None => ResponseFuture { inner: None },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ResponseFuture<F> {
+    inner: Option<(F, StreamPermit)>,
+}
+
+impl<F, ResponseBody> Future for ResponseFuture<F>
+where
+    F: Future<Item = Response<ResponseBody>>,
+{
+    type Item = Response<Option<ResponseBody>>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.inner {
+            Some((ref mut inner, _)) => match inner.poll()? {
+                Async::Ready(resp) => Ok(Async::Ready(resp.map(Some))),
+                Async::NotReady => Ok(Async::NotReady),
+            },
+            None => {
+                let mut resp = Response::new(None);
+                *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                Ok(Async::Ready(resp))
+            }
+        }
+    }
+}
+
+// Released once the response future is either resolved and dropped or abandoned by the client
+#[derive(Debug)]
+pub(crate) struct StreamPermit {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl StreamPermit {
+    fn acquire(in_flight: Arc<AtomicUsize>, max: Option<usize>) -> Option<Self> {
+        let count = in_flight.fetch_add(1, Ordering::SeqCst);
+        let permit = Self { in_flight };
+        match max {
+            Some(max) if count >= max => None,
+            _ => Some(permit),
+        }
+    }
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+
+    use super::*;
+
+    // Never responds, keeping the request in flight
+    struct Handler;
+
+    impl Service for Handler {
+        type Request = Request<()>;
+        type Response = Response<()>;
+        type Error = ();
+        type Future = future::Empty<Response<()>, ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _req: Self::Request) -> Self::Future {
+            future::empty()
+        }
+    }
+
+    fn call<S>(service: &mut S) -> S::Future
+    where
+        S: Service<Request = Request<()>>,
+    {
+        service.call(Request::new(()))
+    }
+
+    fn refused(fut: &mut ResponseFuture<future::Empty<Response<()>, ()>>) -> bool {
+        match fut.poll().expect("Error handling a request") {
+            Async::Ready(resp) => resp.status() == StatusCode::SERVICE_UNAVAILABLE,
+            Async::NotReady => false,
+        }
+    }
+
+    #[test]
+    fn streams_over_limit_refused() {
+        let middleware = StreamsMiddleware::new(Some(2));
+        let mut conn = middleware.wrap(Handler);
+        let mut first = call(&mut conn);
+        let mut second = call(&mut conn);
+        assert!(!refused(&mut first));
+        assert!(!refused(&mut second));
+        assert!(refused(&mut call(&mut conn)));
+
+        // Requests of other connections count towards the same limit
+        let mut other = middleware.wrap(Handler);
+        assert!(refused(&mut call(&mut other)));
+
+        // Completed or abandoned requests free their slots, for any connection
+        drop(first);
+        let mut third = call(&mut other);
+        assert!(!refused(&mut third));
+        assert!(refused(&mut call(&mut conn)));
+
+        // Limits of other listeners are their own
+        let mut unrelated = StreamsMiddleware::new(Some(2)).wrap(Handler);
+        assert!(!refused(&mut call(&mut unrelated)));
+    }
+
+    #[test]
+    fn streams_unlimited() {
+        let mut conn = StreamsMiddleware::new(None).wrap(Handler);
+        let mut pending = (0..64).map(|_| call(&mut conn)).collect::<Vec<_>>();
+        assert!(pending.iter_mut().all(|fut| !refused(fut)));
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Async, Future, Poll};
+use http::{Request, Response, StatusCode};
+use log::warn;
+use tokio_timer::Timeout;
+use tower_service::Service;
+use tower_web::middleware::Middleware;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct TimeoutConfig {
+    #[serde(default = "TimeoutConfig::default_ms")]
+    default_ms: u64,
+    #[serde(default)]
+    routes: BTreeMap<String, u64>,
+}
+
+impl TimeoutConfig {
+    fn default_ms() -> u64 {
+        30000
+    }
+
+    // The override with the longest matching path prefix wins
+    fn deadline(&self, path: &str) -> Duration {
+        let ms = self
+            .routes
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, ms)| *ms)
+            .unwrap_or(self.default_ms);
+
+        Duration::from_millis(ms)
+    }
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            default_ms: Self::default_ms(),
+            routes: BTreeMap::new(),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+pub(crate) struct TimeoutMiddleware {
+    config: Arc<TimeoutConfig>,
+}
+
+impl TimeoutMiddleware {
+    pub(crate) fn new(config: TimeoutConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl<S, RequestBody, ResponseBody> Middleware<S> for TimeoutMiddleware
+where
+    S: Service<Request = Request<RequestBody>, Response = Response<ResponseBody>>,
+{
+    type Request = Request<RequestBody>;
+    type Response = Response<Option<ResponseBody>>;
+    type Error = S::Error;
+    type Service = TimeoutService<S>;
+
+    fn wrap(&self, inner: S) -> Self::Service {
+        TimeoutService {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct TimeoutService<S> {
+    inner: S,
+    config: Arc<TimeoutConfig>,
+}
+
+impl<S, RequestBody, ResponseBody> Service for TimeoutService<S>
+where
+    S: Service<Request = Request<RequestBody>, Response = Response<ResponseBody>>,
+{
+    type Request = Request<RequestBody>;
+    type Response = Response<Option<ResponseBody>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: Self::Request) -> Self::Future {
+        let path = req.uri().path().to_owned();
+        let deadline = self.config.deadline(&path);
+
+        ResponseFuture {
+            inner: Timeout::new(self.inner.call(req), deadline),
+            path,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ResponseFuture<F> {
+    inner: Timeout<F>,
+    path: String,
+}
+
+impl<F, ResponseBody> Future for ResponseFuture<F>
+where
+    F: Future<Item = Response<ResponseBody>>,
+{
+    type Item = Response<Option<ResponseBody>>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.inner.poll() {
+            Ok(Async::Ready(resp)) => Ok(Async::Ready(resp.map(Some))),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(err) => {
+                if err.is_inner() {
+                    return Err(err.into_inner().expect("Expected an inner error"));
+                }
+
+                let status = if err.is_elapsed() {
+                    warn!("Request deadline exceeded, path = '{}'", self.path);
+                    StatusCode::GATEWAY_TIMEOUT
+                } else {
+                    warn!("Request timer failed, path = '{}'", self.path);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                };
+
+                let mut resp = Response::new(None);
+                *resp.status_mut() = status;
+                Ok(Async::Ready(resp))
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use tokio::runtime::current_thread::Runtime;
+
+    struct Handler {
+        delay: Option<Duration>,
+    }
+
+    impl Service for Handler {
+        type Request = Request<()>;
+        type Response = Response<()>;
+        type Error = ();
+        type Future = Box<dyn Future<Item = Response<()>, Error = ()>>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _req: Self::Request) -> Self::Future {
+            match self.delay {
+                Some(delay) => Box::new(
+                    tokio_timer::sleep(delay)
+                        .map_err(|_| ())
+                        .map(|()| Response::new(())),
+                ),
+                None => Box::new(future::ok(Response::new(()))),
+            }
+        }
+    }
+
+    fn call(delay: Option<Duration>, config: TimeoutConfig) -> StatusCode {
+        let mut service = TimeoutMiddleware::new(config).wrap(Handler { delay });
+        let req = Request::get("/api/v2/sets/foo/objects/bar")
+            .body(())
+            .expect("Error building a request");
+
+        Runtime::new()
+            .expect("Error creating a runtime")
+            .block_on(service.call(req))
+            .expect("Error handling a request")
+            .status()
+    }
+
+    #[test]
+    fn deadline_exceeded() {
+        let config = TimeoutConfig {
+            default_ms: 50,
+            ..Default::default()
+        };
+        let status = call(Some(Duration::from_secs(5)), config);
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn deadline_met() {
+        let config = TimeoutConfig {
+            default_ms: 5000,
+            ..Default::default()
+        };
+        assert_eq!(call(None, config), StatusCode::OK);
+    }
+
+    #[test]
+    fn route_deadline() {
+        let mut routes = BTreeMap::new();
+        routes.insert("/api/v2/sets".to_owned(), 100);
+        routes.insert("/api/v2/sets/foo".to_owned(), 200);
+        let config = TimeoutConfig {
+            default_ms: 1000,
+            routes,
+        };
+
+        assert_eq!(
+            config.deadline("/api/v2/sets/foo/objects/bar"),
+            Duration::from_millis(200)
+        );
+        assert_eq!(
+            config.deadline("/api/v2/sets/bar/objects/bar"),
+            Duration::from_millis(100)
+        );
+        assert_eq!(config.deadline("/api/v2/sign"), Duration::from_millis(1000));
+    }
+}
@@ -0,0 +1,197 @@
+use std::iter;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+
+use log::warn;
+
+use crate::db::object_size;
+use crate::db::ConnectionPool;
+
+// Records waiting to be written, the ones over the limit are dropped
+const QUEUE_SIZE: usize = 1024;
+const SAMPLE_SCALE: u64 = 10_000;
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct ObjectSize {
+    audience: String,
+    bucket: String,
+    object: String,
+    size: i64,
+}
+
+// Sizes of fetched objects are written to the database in the background, so reads never wait for it
+#[derive(Debug)]
+pub(crate) struct UsageRecorder {
+    tx: Option<Mutex<SyncSender<ObjectSize>>>,
+}
+
+impl UsageRecorder {
+    pub(crate) fn new(db: Option<ConnectionPool>) -> Self {
+        let db = match db {
+            Some(db) => db,
+            None => return Self { tx: None },
+        };
+
+        let (recorder, rx) = Self::channel();
+        thread::spawn(move || {
+            for record in rx {
+                let result = db.get().map_err(|err| err.to_string()).and_then(|conn| {
+                    object_size::RecordQuery::new(
+                        &record.audience,
+                        &record.bucket,
+                        &record.object,
+                        record.size,
+                    )
+                    .execute(&conn)
+                    .map_err(|err| err.to_string())
+                });
+
+                if let Err(err) = result {
+                    warn!("Error recording the size of an object: {}", err);
+                }
+            }
+        });
+        recorder
+    }
+
+    fn channel() -> (Self, Receiver<ObjectSize>) {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let recorder = Self {
+            tx: Some(Mutex::new(tx)),
+        };
+        (recorder, rx)
+    }
+
+    // Nothing is recorded for audiences without a sample rate
+    pub(crate) fn record(
+        &self,
+        sample_rate: Option<f64>,
+        audience: &str,
+        bucket: &str,
+        object: &str,
+        size: i64,
+    ) {
+        let tx = match (&self.tx, sample_rate) {
+            (Some(tx), Some(rate)) if sampled(bucket, object, rate) => tx,
+            _ => return,
+        };
+
+        let record = ObjectSize {
+            audience: audience.to_owned(),
+            bucket: bucket.to_owned(),
+            object: object.to_owned(),
+            size,
+        };
+        if tx
+            .lock()
+            .expect("Error acquiring usage recorder lock")
+            .try_send(record)
+            .is_err()
+        {
+            warn!("Usage recorder queue is full, the size of an object is dropped");
+        }
+    }
+}
+
+// Totals of the whole audience estimated from the recorded ones, which are a sample of its objects
+pub(crate) fn estimate(recorded: i64, sample_rate: Option<f64>) -> i64 {
+    match sample_rate {
+        Some(rate) if rate > 0.0 && rate < 1.0 => (recorded as f64 / rate).round() as i64,
+        _ => recorded,
+    }
+}
+
+// Objects are sampled by their names rather than at random, so that a sampled object stays sampled
+// and totals scale by the rate. The hash is stable, so the sample survives restarts and upgrades
+fn sampled(bucket: &str, object: &str, rate: f64) -> bool {
+    // Buckets never contain "/", so that names are hashed unambiguously
+    let name = bucket.bytes().chain(iter::once(b'/')).chain(object.bytes());
+    let threshold = (rate.max(0.0).min(1.0) * SAMPLE_SCALE as f64) as u64;
+    fnv1a(name) % SAMPLE_SCALE < threshold
+}
+
+fn fnv1a(bytes: impl Iterator<Item = u8>) -> u64 {
+    bytes.fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_recorded_per_audience() {
+        let (recorder, rx) = UsageRecorder::channel();
+        recorder.record(Some(1.0), "example.org", "data.example.org", "foo", 10);
+        recorder.record(Some(1.0), "example.org", "data.example.org", "bar", 20);
+        recorder.record(None, "example.net", "data.example.net", "foo", 30);
+        drop(recorder);
+
+        let records = rx.iter().collect::<Vec<ObjectSize>>();
+        assert_eq!(records.len(), 2);
+        assert!(records
+            .iter()
+            .all(|record| record.audience == "example.org"));
+        assert_eq!(records.iter().map(|record| record.size).sum::<i64>(), 30);
+    }
+
+    #[test]
+    fn totals_estimated_by_rate() {
+        let (recorder, rx) = UsageRecorder::channel();
+        for idx in 0..1000 {
+            let object = idx.to_string();
+            recorder.record(Some(0.25), "example.org", "data.example.org", &object, 10);
+        }
+        drop(recorder);
+
+        let records = rx.iter().collect::<Vec<ObjectSize>>();
+        let (objects, bytes) = (
+            records.len() as i64,
+            records.iter().map(|record| record.size).sum::<i64>(),
+        );
+        assert!(objects > 150 && objects < 350, "{}", objects);
+
+        let (objects, bytes) = (estimate(objects, Some(0.25)), estimate(bytes, Some(0.25)));
+        assert!(objects > 800 && objects < 1200, "{}", objects);
+        assert_eq!(bytes, objects * 10);
+
+        // Totals of fully recorded audiences are exact
+        assert_eq!(estimate(1000, Some(1.0)), 1000);
+        assert_eq!(estimate(1000, None), 1000);
+    }
+
+    #[test]
+    fn objects_sampled_by_rate() {
+        let objects = (0..1000)
+            .map(|idx| idx.to_string())
+            .collect::<Vec<String>>();
+        let count = |rate| {
+            objects
+                .iter()
+                .filter(|object| sampled("data.example.org", object, rate))
+                .count()
+        };
+
+        assert_eq!(count(0.0), 0);
+        assert_eq!(count(1.0), 1000);
+        let half = count(0.5);
+        assert!(half > 400 && half < 600, "{}", half);
+
+        // The same objects are sampled every time
+        assert_eq!(count(0.5), half);
+    }
+
+    #[test]
+    fn sample_hash_stable() {
+        assert_eq!(fnv1a("".bytes()), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a("a".bytes()), 0xaf63_dc4c_8601_ec8c);
+    }
+}
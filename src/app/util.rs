@@ -1,9 +1,16 @@
 use anyhow::format_err;
+use futures::{future, Async, Future};
+use log::{error, warn};
 use radix_trie::Trie;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
 use svc_authn::{AccountId, Authenticable};
+use tokio_sync::semaphore::{Permit, Semaphore};
 
+use crate::app::credentials::{CredentialsConfig, CredentialsProvider};
 use crate::db::{Bucket, Set};
 use crate::s3::Client;
 use crate::tower_web::Error;
@@ -15,69 +22,385 @@ pub(crate) type S3Clients = BTreeMap<String, ::std::sync::Arc<crate::s3::Client>
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub(crate) struct BackendConfig {
     default: String,
     alt: BTreeMap<String, AltBackendConfig>,
+    #[serde(default)]
+    routes: Vec<BackendRoute>,
+    #[serde(default)]
+    credentials: CredentialsConfig,
+    credentials_refresh_interval: Option<u64>,
+    geo: Option<GeoConfig>,
 }
 
-#[derive(Debug, Deserialize)]
+impl BackendConfig {
+    pub(crate) fn credentials_refresh_interval(&self) -> Option<Duration> {
+        self.credentials_refresh_interval.map(Duration::from_secs)
+    }
+
+    pub(crate) fn geo_header(&self) -> Option<&str> {
+        self.geo.as_ref().map(|geo| geo.header.as_str())
+    }
+}
+
+// Replicas of the backend by the region of clients, as told by them or by a CDN in front of the service
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct GeoConfig {
+    #[serde(default = "GeoConfig::default_header")]
+    header: String,
+    regions: BTreeMap<String, String>,
+}
+
+impl GeoConfig {
+    fn default_header() -> String {
+        String::from("x-geo-region")
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct BackendRoute {
+    bucket: String,
+    backend: String,
+}
+
+impl BackendRoute {
+    fn matches(&self, bucket: &str) -> bool {
+        if self.bucket.starts_with('*') {
+            bucket.ends_with(&self.bucket.replace('*', ""))
+        } else {
+            self.bucket == bucket
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
 pub(crate) struct AltBackendConfig {
     proxy_host: Option<String>,
+    #[serde(default)]
+    request_payer: bool,
+    max_url_length: Option<usize>,
+    region_probe_bucket: Option<String>,
+    extra_query: Option<String>,
+    #[serde(default)]
+    extra_query_signed: bool,
+    service_name: Option<String>,
+    #[serde(default)]
+    anonymous: bool,
+    #[serde(default)]
+    services: BTreeMap<String, String>,
+    expected_bucket_owner: Option<String>,
+    user_agent: Option<String>,
+    mirror: Option<String>,
+    existence_cache: Option<ExistenceCacheConfig>,
+    listing_cache: Option<ListingCacheConfig>,
+    tls: Option<BackendTlsConfig>,
+}
+
+// Seconds the answers to existence checks are remembered for, positive ones aren't by default
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ExistenceCacheConfig {
+    missing_ttl: u64,
+    #[serde(default)]
+    found_ttl: u64,
+}
+
+// Seconds pages of listings are remembered for
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ListingCacheConfig {
+    ttl: u64,
 }
 
 impl AltBackendConfig {
-    fn new() -> Self {
-        AltBackendConfig { proxy_host: None }
+    fn insecure_skip_verify(&self) -> bool {
+        self.tls
+            .as_ref()
+            .map_or(false, |tls| tls.insecure_skip_verify)
+    }
+
+    // Mirrors are routed and regions are probed once on startup, backends added later can't have them
+    pub(crate) fn startup_only(&self) -> Option<&'static str> {
+        if self.mirror.is_some() {
+            Some("mirror")
+        } else if self.region_probe_bucket.is_some() {
+            Some("region_probe_bucket")
+        } else {
+            None
+        }
+    }
+}
+
+// Files of the private CA and the PKCS #12 identity used for connections of the service itself
+#[derive(Clone, Deserialize)]
+pub(crate) struct BackendTlsConfig {
+    ca_path: Option<String>,
+    identity_path: Option<String>,
+    #[serde(default)]
+    identity_password: String,
+    #[serde(default)]
+    insecure_skip_verify: bool,
+}
+
+impl BackendTlsConfig {
+    fn tls(&self) -> anyhow::Result<crate::s3::Tls> {
+        let read = |path: &str| {
+            std::fs::read(path).map_err(|err| format_err!("Error reading '{}': {}", path, err))
+        };
+        let ca = self.ca_path.as_deref().map(read).transpose()?;
+        let identity = self.identity_path.as_deref().map(read).transpose()?;
+
+        crate::s3::Tls::new(
+            ca.as_deref(),
+            identity
+                .as_deref()
+                .map(|identity| (identity, self.identity_password.as_str())),
+            self.insecure_skip_verify,
+        )
+    }
+}
+
+// The password of the identity is never printed
+impl fmt::Debug for BackendTlsConfig {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("BackendTlsConfig")
+            .field("ca_path", &self.ca_path)
+            .field("identity_path", &self.identity_path)
+            .field("insecure_skip_verify", &self.insecure_skip_verify)
+            .finish()
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
-pub(crate) fn read_s3_config(config: Option<&BackendConfig>) -> anyhow::Result<S3Clients> {
+// Picks a backend by the bucket name for requests that don't specify one
+#[derive(Debug)]
+pub(crate) struct BackendRouter {
+    routes: Vec<BackendRoute>,
+    geo_regions: BTreeMap<String, String>,
+    mirrors: BTreeMap<String, String>,
+}
+
+impl BackendRouter {
+    pub(crate) fn new(config: Option<&BackendConfig>) -> anyhow::Result<Self> {
+        let routes = match config {
+            Some(config) => config.routes.clone(),
+            None => Vec::new(),
+        };
+
+        for route in &routes {
+            let known = config.map_or(false, |config| config.alt.contains_key(&route.backend));
+            if !known {
+                return Err(format_err!(
+                    "Backend '{}' of the route for bucket = '{}' is not configured",
+                    route.backend,
+                    route.bucket
+                ));
+            }
+        }
+
+        let geo_regions = config
+            .and_then(|config| config.geo.as_ref())
+            .map(|geo| {
+                geo.regions
+                    .iter()
+                    .map(|(region, backend)| (region.to_lowercase(), backend.to_owned()))
+                    .collect::<BTreeMap<String, String>>()
+            })
+            .unwrap_or_default();
+
+        for (region, backend) in &geo_regions {
+            let known = backend == S3_DEFAULT_CLIENT
+                || config.map_or(false, |config| config.alt.contains_key(backend));
+            if !known {
+                return Err(format_err!(
+                    "Backend '{}' of the geo region = '{}' is not configured",
+                    backend,
+                    region
+                ));
+            }
+        }
+
+        let mut mirrors = BTreeMap::new();
+        if let Some(config) = config {
+            for (backend, alt) in &config.alt {
+                if let Some(ref mirror) = alt.mirror {
+                    let known = mirror == S3_DEFAULT_CLIENT || config.alt.contains_key(mirror);
+                    if !known || mirror == backend {
+                        return Err(format_err!(
+                            "Mirror '{}' of the backend '{}' is not configured",
+                            mirror,
+                            backend
+                        ));
+                    }
+                    if backend == &config.default {
+                        mirrors.insert(String::from(S3_DEFAULT_CLIENT), mirror.to_owned());
+                    }
+                    mirrors.insert(backend.to_owned(), mirror.to_owned());
+                }
+            }
+        }
+
+        Ok(Self {
+            routes,
+            geo_regions,
+            mirrors,
+        })
+    }
+
+    // Reads failing to reach the backend are redirected to its mirror
+    pub(crate) fn mirror(&self, backend: &str) -> Option<&str> {
+        self.mirrors.get(backend).map(String::as_str)
+    }
+
+    pub(crate) fn route(&self, bucket: &str) -> String {
+        self.routes
+            .iter()
+            .find(|route| route.matches(bucket))
+            .map(|route| route.backend.to_owned())
+            .unwrap_or_else(|| String::from(S3_DEFAULT_CLIENT))
+    }
+
+    pub(crate) fn route_set(&self, aud_estm: &AudienceEstimator, set: &str) -> String {
+        match aud_estm.parse_set(set) {
+            Ok(set_s) => self.route(&set_s.bucket().to_string()),
+            Err(_) => String::from(S3_DEFAULT_CLIENT),
+        }
+    }
+
+    // Reads are served by the replica of the client's region, unknown regions are routed as usual
+    pub(crate) fn route_nearest(&self, bucket: &str, geo: &GeoHint) -> String {
+        geo.region()
+            .and_then(|region| self.geo_regions.get(region))
+            .cloned()
+            .unwrap_or_else(|| self.route(bucket))
+    }
+
+    pub(crate) fn route_set_nearest(
+        &self,
+        aud_estm: &AudienceEstimator,
+        set: &str,
+        geo: &GeoHint,
+    ) -> String {
+        geo.region()
+            .and_then(|region| self.geo_regions.get(region))
+            .cloned()
+            .unwrap_or_else(|| self.route_set(aud_estm, set))
+    }
+}
+
+pub(crate) fn read_s3_config(
+    config: Option<&BackendConfig>,
+    service_hosts: &[String],
+) -> anyhow::Result<S3Clients> {
     let mut acc = S3Clients::new();
 
     if let Some(back) = config {
+        let credentials = crate::app::credentials::provider(&back.credentials);
         read_s3(
             &String::from(S3_DEFAULT_CLIENT),
             &format!("{}_", back.default.to_uppercase()),
             back.alt
                 .get(&back.default)
                 .ok_or_else(|| format_err!("Missing default backend configuration"))?,
+            credentials.as_ref(),
+            service_hosts,
             &mut acc,
-        );
+        )?;
 
         for (back, config) in back.alt.iter() {
-            read_s3(back, &format!("{}_", back.to_uppercase()), config, &mut acc);
+            read_s3(
+                back,
+                &format!("{}_", back.to_uppercase()),
+                config,
+                credentials.as_ref(),
+                service_hosts,
+                &mut acc,
+            )?;
         }
     } else {
+        let credentials = crate::app::credentials::provider(&CredentialsConfig::Env);
         read_s3(
             &String::from(S3_DEFAULT_CLIENT),
             "",
-            &AltBackendConfig::new(),
+            &AltBackendConfig::default(),
+            credentials.as_ref(),
+            service_hosts,
             &mut acc,
-        );
+        )?;
     }
 
     Ok(acc)
 }
 
-fn read_s3(back: &str, prefix: &str, alt: &AltBackendConfig, acc: &mut S3Clients) {
+fn read_s3(
+    back: &str,
+    prefix: &str,
+    alt: &AltBackendConfig,
+    credentials: &dyn CredentialsProvider,
+    service_hosts: &[String],
+    acc: &mut S3Clients,
+) -> anyhow::Result<()> {
     use std::env::var;
-    let key = var(&format!("{}AWS_ACCESS_KEY_ID", prefix))
-        .unwrap_or_else(|_| panic!("{}AWS_ACCESS_KEY_ID must be specified", prefix));
-    let secret = var(&format!("{}AWS_SECRET_ACCESS_KEY", prefix))
-        .unwrap_or_else(|_| panic!("{}AWS_SECRET_ACCESS_KEY must be specified", prefix));
+    let credentials = credentials.credentials(back, prefix)?;
+    let key = credentials.access_key_id();
+    let secret = credentials.secret_access_key();
     let endpoint = var(&format!("{}AWS_ENDPOINT", prefix))
-        .unwrap_or_else(|_| panic!("{}AWS_ENDPOINT must be specified", prefix));
+        .map_err(|_| format_err!("{}AWS_ENDPOINT must be specified", prefix))?;
     let region = var(&format!("{}AWS_REGION", prefix))
-        .unwrap_or_else(|_| panic!("{}AWS_REGION must be specified", prefix));
+        .map_err(|_| format_err!("{}AWS_REGION must be specified", prefix))?;
+
+    let client = build_s3_client(key, secret, &region, &endpoint, alt, service_hosts)
+        .map_err(|err| format_err!("Error configuring backend '{}': {}", back, err))?;
+    acc.insert(back.to_owned(), ::std::sync::Arc::new(client));
+    Ok(())
+}
+
+// Only the credentials of the clients are swapped, they keep their caches and the regions of buckets they found
+pub(crate) fn refresh_s3_credentials(
+    config: &BackendConfig,
+    s3: &crate::app::S3ClientRef,
+) -> anyhow::Result<()> {
+    let credentials = crate::app::credentials::provider(&config.credentials);
+    let clients = s3.read().expect("Error acquiring s3 clients lock");
+    let backends = std::iter::once((S3_DEFAULT_CLIENT, &config.default))
+        .chain(config.alt.keys().map(|back| (back.as_str(), back)));
+
+    for (client, back) in backends {
+        let credentials = credentials.credentials(client, &format!("{}_", back.to_uppercase()))?;
+        if let Some(client) = clients.get(client) {
+            client.set_credentials(credentials.access_key_id(), credentials.secret_access_key());
+        }
+    }
+
+    Ok(())
+}
+
+// Backends read from the config and added at runtime are configured the same way
+pub(crate) fn build_s3_client(
+    key: &str,
+    secret: &str,
+    region: &str,
+    endpoint: &str,
+    alt: &AltBackendConfig,
+    service_hosts: &[String],
+) -> anyhow::Result<Client> {
+    valid_endpoint(endpoint)?;
+    valid_region(region)?;
+
+    let tls = alt.tls.as_ref().map(BackendTlsConfig::tls).transpose()?;
+    let region = match alt.region_probe_bucket {
+        Some(ref bucket) => crate::s3::select_region(
+            region,
+            crate::s3::probe_region(key, secret, region, endpoint, bucket, tls.as_ref()),
+        ),
+        None => region.to_owned(),
+    };
 
     let mut client = crate::s3::Client::new(
-        &key,
-        &secret,
+        key,
+        secret,
         &region,
-        &endpoint,
+        endpoint,
         ::std::time::Duration::from_secs(300),
     );
 
@@ -85,17 +408,334 @@ fn read_s3(back: &str, prefix: &str, alt: &AltBackendConfig, acc: &mut S3Clients
         client.set_proxy_host(proxy_host);
     }
 
+    client.set_request_payer(alt.request_payer);
+    client.set_anonymous(alt.anonymous);
+    client.set_services(&alt.services);
+
+    if let Some(max_url_length) = alt.max_url_length {
+        client.set_max_url_length(max_url_length);
+    }
+
+    if let Some(ref extra_query) = alt.extra_query {
+        client.set_extra_query(extra_query, alt.extra_query_signed);
+    }
+
+    if let Some(ref service_name) = alt.service_name {
+        client.set_service_name(service_name);
+    }
+
+    if let Some(ref owner) = alt.expected_bucket_owner {
+        client.set_expected_bucket_owner(owner);
+    }
+
+    if let Some(ref user_agent) = alt.user_agent {
+        client.set_user_agent(user_agent);
+    }
+
+    if let Some(tls) = tls {
+        if alt.insecure_skip_verify() {
+            warn!(
+                "Certificates of the backend at endpoint = '{}' aren't verified",
+                endpoint
+            );
+        }
+        client.set_tls(tls);
+    }
+
+    if let Some(ref cache) = alt.existence_cache {
+        client.set_existence_cache(
+            Duration::from_secs(cache.found_ttl),
+            Duration::from_secs(cache.missing_ttl),
+        );
+    }
+
+    if let Some(ref cache) = alt.listing_cache {
+        client.set_listing_cache(Duration::from_secs(cache.ttl));
+    }
+
+    client.set_service_hosts(service_hosts);
+    Ok(client)
+}
+
+// Signed URIs are built on top of the endpoint, it must be an absolute HTTP(S) URL with a host
+fn valid_endpoint(endpoint: &str) -> anyhow::Result<()> {
+    let url = url::Url::parse(endpoint)
+        .map_err(|err| format_err!("endpoint = '{}' is invalid: {}", endpoint, err))?;
+    match (url.scheme(), url.host_str()) {
+        ("http", Some(_)) | ("https", Some(_)) => Ok(()),
+        _ => Err(format_err!(
+            "endpoint = '{}' must be an http or https URL with a host",
+            endpoint
+        )),
+    }
+}
+
+fn valid_region(region: &str) -> anyhow::Result<()> {
+    let valid = !region.is_empty()
+        && region
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format_err!("region = '{}' is invalid", region))
+    }
+}
+
+pub(crate) fn insert_s3_client(
+    acc: &mut S3Clients,
+    back: &str,
+    client: Client,
+) -> anyhow::Result<()> {
+    if back.is_empty() {
+        return Err(format_err!("Backend name must not be empty"));
+    }
+    if acc.contains_key(back) {
+        return Err(format_err!("Backend '{}' already exists", back));
+    }
+
     acc.insert(back.to_owned(), ::std::sync::Arc::new(client));
+    Ok(())
+}
+
+// Header names are case-insensitive, differently-cased duplicates must agree on the value
+pub(crate) fn normalize_headers(
+    headers: &BTreeMap<String, String>,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut acc = BTreeMap::new();
+    for (key, val) in headers {
+        let key = key.to_lowercase();
+        match acc.get(&key) {
+            Some(prev) if prev != val => {
+                return Err(format_err!("conflicting values of the header = '{}'", key));
+            }
+            Some(_) => (),
+            None => {
+                acc.insert(key, val.to_owned());
+            }
+        }
+    }
+    Ok(acc)
+}
+
+// Cyrillic letters are romanized, Latin letters lose their diacritics and
+// the remaining non-ASCII characters are percent-encoded, so the result is always ASCII
+pub(crate) fn transliterate(value: &str) -> String {
+    use percent_encoding::{utf8_percent_encode, CONTROLS};
+    use unicode_normalization::char::is_combining_mark;
+    use unicode_normalization::UnicodeNormalization;
+
+    let mut acc = String::with_capacity(value.len());
+    for c in value.nfc() {
+        match romanize(c) {
+            Some(val) => acc.push_str(val),
+            None if c.is_ascii() => acc.push(c),
+            None => {
+                let stripped = std::iter::once(c)
+                    .nfkd()
+                    .filter(|c| !is_combining_mark(*c))
+                    .collect::<String>();
+                acc.extend(utf8_percent_encode(&stripped, CONTROLS));
+            }
+        }
+    }
+    acc
+}
+
+fn romanize(c: char) -> Option<&'static str> {
+    let val = match c {
+        'а' => "a",
+        'б' => "b",
+        'в' => "v",
+        'г' => "g",
+        'д' => "d",
+        'е' | 'э' => "e",
+        'ё' => "ye",
+        'ж' => "zh",
+        'з' => "z",
+        'и' => "i",
+        'й' | 'ы' => "y",
+        'к' => "k",
+        'л' => "l",
+        'м' => "m",
+        'н' => "n",
+        'о' => "o",
+        'п' => "p",
+        'р' => "r",
+        'с' => "s",
+        'т' => "t",
+        'у' => "u",
+        'ф' => "f",
+        'х' => "kh",
+        'ц' => "ts",
+        'ч' => "ch",
+        'ш' => "sh",
+        'щ' => "shch",
+        'ъ' | 'ь' => "",
+        'ю' => "yu",
+        'я' => "ya",
+        'А' => "A",
+        'Б' => "B",
+        'В' => "V",
+        'Г' => "G",
+        'Д' => "D",
+        'Е' | 'Э' => "E",
+        'Ё' => "Ye",
+        'Ж' => "Zh",
+        'З' => "Z",
+        'И' => "I",
+        'Й' | 'Ы' => "Y",
+        'К' => "K",
+        'Л' => "L",
+        'М' => "M",
+        'Н' => "N",
+        'О' => "O",
+        'П' => "P",
+        'Р' => "R",
+        'С' => "S",
+        'Т' => "T",
+        'У' => "U",
+        'Ф' => "F",
+        'Х' => "Kh",
+        'Ц' => "Ts",
+        'Ч' => "Ch",
+        'Ш' => "Sh",
+        'Щ' => "Shch",
+        'Ъ' | 'Ь' => "",
+        'Ю' => "Yu",
+        'Я' => "Ya",
+        _ => return None,
+    };
+    Some(val)
+}
+
+// The request a capability is issued for, so that it can't be replayed for another object or method.
+// Methods are the signed ones, with "LIST" for listings, whose object is the prefix, and "SHARE" for shares
+#[derive(Debug)]
+pub(crate) struct CapabilityScope<'a> {
+    method: &'a str,
+    bucket: &'a str,
+    set: &'a str,
+    object: &'a str,
+}
+
+impl<'a> CapabilityScope<'a> {
+    pub(crate) fn new(method: &'a str, bucket: &'a str, set: &'a str, object: &'a str) -> Self {
+        Self {
+            method,
+            bucket,
+            set,
+            object,
+        }
+    }
+
+    pub(crate) fn bucket(&self) -> &'a str {
+        self.bucket
+    }
+
+    fn message(&self, timestamp: i64) -> String {
+        format!(
+            "{}\n{}\n{}\n{}\n{}",
+            timestamp, self.method, self.bucket, self.set, self.object
+        )
+    }
+}
+
+// A capability is `${TIMESTAMP}:${SIGNATURE}`, where the signature is hex-encoded HMAC-SHA256
+// of `${TIMESTAMP}\n${METHOD}\n${BUCKET}\n${SET}\n${OBJECT}` keyed with the secret of the audience
+pub(crate) fn verify_capability(
+    key: &str,
+    scope: &CapabilityScope,
+    capability: &str,
+    now: i64,
+) -> bool {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    let mut parts = capability.splitn(2, ':');
+    let timestamp = match parts.next().and_then(|val| val.parse::<i64>().ok()) {
+        Some(val) => val,
+        None => return false,
+    };
+    let signature = match parts.next().and_then(|val| hex::decode(val).ok()) {
+        Some(val) => val,
+        None => return false,
+    };
+
+    if (now - timestamp).abs() > CAPABILITY_MAX_SKEW {
+        return false;
+    }
+
+    match Hmac::<Sha256>::new_varkey(key.as_bytes()) {
+        Ok(mut mac) => {
+            mac.input(scope.message(timestamp).as_bytes());
+            mac.verify(&signature).is_ok()
+        }
+        Err(_) => false,
+    }
+}
+
+const CAPABILITY_MAX_SKEW: i64 = 300;
+
+const STREAMING_PAYLOAD: &str = "STREAMING-AWS4-HMAC-SHA256-PAYLOAD";
+const STREAMING_PAYLOAD_TRAILER: &str = "STREAMING-AWS4-HMAC-SHA256-PAYLOAD-TRAILER";
+
+// Additional integrity checksums are passed to the backend as signed headers
+pub(crate) fn checksum_header(algorithm: &str) -> anyhow::Result<&'static str> {
+    match algorithm.to_uppercase().as_ref() {
+        "CRC32" => Ok("x-amz-checksum-crc32"),
+        "CRC32C" => Ok("x-amz-checksum-crc32c"),
+        "SHA1" => Ok("x-amz-checksum-sha1"),
+        "SHA256" => Ok("x-amz-checksum-sha256"),
+        _ => Err(format_err!(
+            "unsupported checksum algorithm = '{}'",
+            algorithm
+        )),
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ErrorDetail {
+    // Messages of backend errors are returned to the client as is
+    Verbose,
+    // Messages of backend errors are only logged, the client gets a generic one
+    Terse,
+}
+
+impl ErrorDetail {
+    // The backend is named along with the message, so that clients of several backends tell them apart
+    pub(crate) fn describe(self, backend: &str, err: &dyn fmt::Display) -> String {
+        match self {
+            ErrorDetail::Verbose => format!("{}, backend = '{}'", err, backend),
+            ErrorDetail::Terse => {
+                error!("Backend '{}' request failed: {}", backend, err);
+                String::from("Backend request failed")
+            }
+        }
+    }
+}
+
+impl Default for ErrorDetail {
+    fn default() -> Self {
+        ErrorDetail::Verbose
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct S3SignedRequestBuilder {
     method: Option<String>,
     bucket: Option<String>,
     object: Option<String>,
     headers: BTreeMap<String, String>,
+    params: BTreeMap<String, Option<String>>,
+    request_payer: bool,
+    expires_in: Option<Duration>,
+    error_detail: ErrorDetail,
+    backend: String,
+    service: Option<String>,
 }
 
 impl S3SignedRequestBuilder {
@@ -105,6 +745,12 @@ impl S3SignedRequestBuilder {
             bucket: None,
             object: None,
             headers: BTreeMap::new(),
+            params: BTreeMap::new(),
+            request_payer: false,
+            expires_in: None,
+            error_detail: ErrorDetail::default(),
+            backend: String::from(S3_DEFAULT_CLIENT),
+            service: None,
         }
     }
 
@@ -135,6 +781,67 @@ impl S3SignedRequestBuilder {
         Self { headers, ..self }
     }
 
+    pub(crate) fn add_param(self, key: &str, value: Option<&str>) -> Self {
+        let mut params = self.params;
+        params.insert(key.to_string(), value.map(ToString::to_string));
+        Self { params, ..self }
+    }
+
+    pub(crate) fn request_payer(self, value: bool) -> Self {
+        Self {
+            request_payer: value,
+            ..self
+        }
+    }
+
+    pub(crate) fn expires_in(self, value: Duration) -> Self {
+        Self {
+            expires_in: Some(value),
+            ..self
+        }
+    }
+
+    pub(crate) fn error_detail(self, value: ErrorDetail, backend: &str) -> Self {
+        Self {
+            error_detail: value,
+            backend: backend.to_owned(),
+            ..self
+        }
+    }
+
+    // Uploads of a body not hashed in advance are sent in aws-chunked encoding, every chunk is signed
+    // by the client chaining the signature of the request, a checksum may follow the chunks in the trailer
+    pub(crate) fn streaming(self, decoded_content_length: u64, trailer: Option<&str>) -> Self {
+        let content_sha256 = match trailer {
+            Some(_) => STREAMING_PAYLOAD_TRAILER,
+            None => STREAMING_PAYLOAD,
+        };
+        let content_encoding = match self.headers.get("content-encoding") {
+            Some(val) => format!("aws-chunked,{}", val),
+            None => String::from("aws-chunked"),
+        };
+
+        let builder = self
+            .add_header("x-amz-content-sha256", content_sha256)
+            .add_header(
+                "x-amz-decoded-content-length",
+                &decoded_content_length.to_string(),
+            )
+            .add_header("content-encoding", &content_encoding);
+        match trailer {
+            Some(trailer) => builder.add_header("x-amz-trailer", trailer),
+            None => builder,
+        }
+    }
+
+    // Another SigV4 service of the backend, it's S3 when not specified
+    pub(crate) fn service(self, value: &str) -> Self {
+        Self {
+            service: Some(value.to_string()),
+            ..self
+        }
+    }
+
     pub(crate) fn build(self, client: &Client) -> Result<String, Error> {
         let unproc_error = || {
             Error::builder()
@@ -145,42 +852,166 @@ impl S3SignedRequestBuilder {
                 .status(http::StatusCode::INTERNAL_SERVER_ERROR)
         };
 
-        let mut req = client.create_request(
-            &self
-                .method
-                .ok_or_else(|| unproc_error().detail("missing method").build())?,
-            &self
-                .bucket
-                .ok_or_else(|| unproc_error().detail("missing bucket").build())?,
-            &self
-                .object
-                .ok_or_else(|| unproc_error().detail("missing object").build())?,
-        );
+        let method = self
+            .method
+            .ok_or_else(|| unproc_error().detail("missing method").build())?;
+        let bucket = self
+            .bucket
+            .ok_or_else(|| unproc_error().detail("missing bucket").build())?;
+        let object = self
+            .object
+            .ok_or_else(|| unproc_error().detail("missing object").build())?;
+        let mut req = match self.service {
+            Some(ref service) => client
+                .create_service_request(service, &method, &bucket, &object)
+                .map_err(|err| {
+                    unproc_error()
+                        .status(http::StatusCode::FORBIDDEN)
+                        .detail(&err.to_string())
+                        .build()
+                })?,
+            None => client.create_request(&method, &bucket, &object),
+        };
         for (key, val) in self.headers {
             req.add_header(&key, &val);
         }
+        for (key, val) in self.params {
+            req.params.insert(key, val);
+        }
+        if self.request_payer {
+            crate::s3::add_request_payer(&mut req);
+        }
 
-        client
-            .sign_request(&mut req)
-            .map_err(|err| unproc_error().detail(&err.to_string()).build())
+        let (error_detail, backend) = (self.error_detail, self.backend);
+        let result = match self.expires_in {
+            Some(ref expires_in) => client.sign_request_expiring(&mut req, expires_in),
+            None => client.sign_request(&mut req),
+        };
+        result.map_err(|err| {
+            let status = if err.is::<crate::s3::UrlTooLong>() {
+                http::StatusCode::URI_TOO_LONG
+            } else {
+                http::StatusCode::INTERNAL_SERVER_ERROR
+            };
+            unproc_error()
+                .status(status)
+                .detail(&error_detail.describe(&backend, &err))
+                .build()
+        })
     }
 }
 
+// Names of the headers a client has to send along with the signed URI, unsigned URIs have none
+pub(crate) fn signed_headers(uri: &str) -> Vec<String> {
+    let query = uri.splitn(2, '?').nth(1).unwrap_or("");
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "X-Amz-SignedHeaders")
+        .map(|(_, val)| val.split(';').map(ToOwned::to_owned).collect())
+        .unwrap_or_default()
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
+// Signing is CPU-bound, it runs on blocking threads of the pool instead of starving the event loop,
+// excess operations are rejected rather than queued
+#[derive(Debug)]
+pub(crate) struct SignLimiter {
+    semaphore: Option<Arc<Semaphore>>,
+}
+
+impl SignLimiter {
+    pub(crate) fn new(max: Option<usize>) -> Self {
+        Self {
+            semaphore: max.map(|max| Arc::new(Semaphore::new(max))),
+        }
+    }
+
+    pub(crate) fn run<T, F>(&self, sign: F) -> impl Future<Item = Result<T, Error>, Error = ()>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        let permit = match self.acquire() {
+            Ok(permit) => permit,
+            Err(err) => return future::Either::A(future::ok(Err(err))),
+        };
+
+        let mut sign = Some(sign);
+        let signed = future::poll_fn(move || {
+            let run = || sign.take().expect("Signing operation already run")();
+            match tokio_threadpool::blocking(run) {
+                Ok(Async::Ready(result)) => Ok(Async::Ready(result)),
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                // Off the thread pool, e.g. on a current thread runtime, there is no one to block but the caller
+                Err(_) => Ok(Async::Ready(sign
+                    .take()
+                    .expect("Signing operation already run")(
+                ))),
+            }
+        });
+
+        // The permit is held until the operation is done or dropped
+        future::Either::B(signed.then(move |result| {
+            drop(permit);
+            result
+        }))
+    }
+
+    fn acquire(&self) -> Result<Option<SignPermit>, Error> {
+        let semaphore = match self.semaphore {
+            Some(ref semaphore) => semaphore.clone(),
+            None => return Ok(None),
+        };
+
+        let mut permit = Permit::new();
+        match permit.try_acquire(&semaphore) {
+            Ok(()) => Ok(Some(SignPermit { semaphore, permit })),
+            Err(_) => Err(Error::builder()
+                .kind("sign_limit_error", "Error signing a request")
+                .status(http::StatusCode::SERVICE_UNAVAILABLE)
+                .detail("too many concurrent signing operations")
+                .build()),
+        }
+    }
+}
+
+struct SignPermit {
+    semaphore: Arc<Semaphore>,
+    permit: Permit,
+}
+
+impl Drop for SignPermit {
+    fn drop(&mut self) {
+        self.permit.release(&self.semaphore);
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct AudienceEstimator {
     inner: Trie<String, String>,
+    allowed_audiences: Option<BTreeSet<String>>,
 }
 
 impl AudienceEstimator {
     pub(crate) fn new(config: &svc_authz::ConfigMap) -> Self {
+        Self::from_audiences(config.iter().map(|(key, _val)| key))
+    }
+
+    fn from_audiences<'a>(audiences: impl Iterator<Item = &'a String>) -> Self {
         let mut inner = Trie::new();
-        config.iter().for_each(|(key, _val)| {
+        audiences.for_each(|key| {
             let rkey = key.split('.').rev().collect::<Vec<&str>>().join(".");
             inner.insert(rkey, key.clone());
         });
-        Self { inner }
+        Self {
+            inner,
+            allowed_audiences: None,
+        }
+    }
+
+    // Buckets of audiences missing in the list are rejected even if authz is configured for them
+    pub(crate) fn set_allowed_audiences(&mut self, audiences: &[String]) -> &mut Self {
+        self.allowed_audiences = Some(audiences.iter().cloned().collect());
+        self
     }
 
     pub(crate) fn estimate(&self, bucket: &str) -> Result<&str, Error> {
@@ -193,20 +1024,36 @@ impl AudienceEstimator {
                 .status(http::StatusCode::INTERNAL_SERVER_ERROR)
         };
 
+        if bucket.is_empty() {
+            return Err(unproc_error()
+                .status(http::StatusCode::BAD_REQUEST)
+                .detail("bucket is empty")
+                .build());
+        }
+
         let rbucket = bucket.split('.').rev().collect::<Vec<&str>>().join(".");
-        self.inner
+        let audience = self
+            .inner
             .get_ancestor_value(&rbucket)
             .map(|aud| aud.as_ref())
             .ok_or_else(|| {
                 unproc_error()
                     .detail(&format!("invalid bucket = '{}'", bucket))
                     .build()
-            })
+            })?;
+
+        match self.allowed_audiences {
+            Some(ref allowed) if !allowed.contains(audience) => Err(unproc_error()
+                .status(http::StatusCode::FORBIDDEN)
+                .detail(&format!("audience = '{}' is not served", audience))
+                .build()),
+            _ => Ok(audience),
+        }
     }
 
     pub(crate) fn parse_bucket(&self, value: &str) -> Result<Bucket, Error> {
-        self.estimate(value)
-            .map(|audience| Bucket::new(Self::bucket_label(value, audience), audience))
+        let audience = self.estimate(value)?;
+        Ok(Bucket::new(Self::bucket_label(value, audience)?, audience))
     }
 
     pub(crate) fn parse_set(&self, value: &str) -> Result<Set, Error> {
@@ -223,20 +1070,94 @@ impl AudienceEstimator {
 
         let bucket_value = parts[0];
         let label = parts[1];
-        self.estimate(bucket_value).map(|audience| {
-            let bucket = Bucket::new(Self::bucket_label(bucket_value, audience), audience);
-            Set::new(label, bucket)
-        })
+        if label.is_empty() {
+            return Err(unproc_error()
+                .status(http::StatusCode::BAD_REQUEST)
+                .detail(&format!("set = '{}', the label is empty", value))
+                .build());
+        }
+
+        let audience = self.estimate(bucket_value)?;
+        let bucket = Bucket::new(Self::bucket_label(bucket_value, audience)?, audience);
+        Ok(Set::new(label, bucket))
     }
 
-    fn bucket_label<'a>(bucket: &'a str, audience: &str) -> &'a str {
-        let (val, _) = bucket.split_at(bucket.len() - (audience.len() + 1));
-        val
+    // The label precedes the audience, a bucket named after the audience has none
+    fn bucket_label<'a>(bucket: &'a str, audience: &str) -> Result<&'a str, Error> {
+        match bucket.len().checked_sub(audience.len() + 1) {
+            Some(len) if len > 0 => Ok(&bucket[..len]),
+            _ => Err(Error::builder()
+                .kind("audience_estimator_parsing_error", "Error parsing a bucket")
+                .status(http::StatusCode::BAD_REQUEST)
+                .detail(&format!("bucket = '{}', the label is empty", bucket))
+                .build()),
+        }
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+// Origin of a request: either a browser telling its referer or a trusted backend holding a capability
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Referer {
+    referer: Option<String>,
+    origin: Option<String>,
+    capability: Option<String>,
+    audience: Option<String>,
+}
+
+impl Referer {
+    // Browsers omitting the referer by policy still send the origin of cross-origin requests
+    pub(crate) fn referer(&self) -> Option<&str> {
+        self.referer.as_deref().or_else(|| self.origin.as_deref())
+    }
+
+    pub(crate) fn capability(&self) -> Option<&str> {
+        self.capability.as_deref()
+    }
+
+    pub(crate) fn expected_audience(&self) -> Option<&str> {
+        self.audience.as_deref()
+    }
+}
+
+// Region of the client, read from the header configured for the backend
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GeoHint {
+    region: Option<String>,
+}
+
+impl GeoHint {
+    pub(crate) fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+}
+
+// What a client reading an object expects in response, as told by the Accept header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ReadMode {
+    Redirect,
+    Json,
+    Proxy,
+}
+
+impl ReadMode {
+    // The first media type known to the service wins, quality values aren't weighed
+    pub(crate) fn from_accept(accept: Option<&str>) -> Self {
+        accept
+            .unwrap_or("")
+            .split(',')
+            .filter_map(|media| match media.split(';').next().unwrap_or("").trim() {
+                "application/json" => Some(ReadMode::Json),
+                "application/octet-stream" => Some(ReadMode::Proxy),
+                "text/html" => Some(ReadMode::Redirect),
+                _ => None,
+            })
+            .next()
+            .unwrap_or(ReadMode::Redirect)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Subject {
     inner: AccountId,
@@ -262,6 +1183,34 @@ impl Authenticable for Subject {
     }
 }
 
+pub(crate) fn extract_subject<B>(
+    request: &http::Request<B>,
+    authn: &svc_authn::jose::ConfigMap,
+    anonymous_audience: &str,
+) -> Result<Subject, String> {
+    use svc_authn::token::jws_compact::extract::{
+        decode_jws_compact_with_config, extract_jws_compact,
+    };
+
+    let h = request.headers().get(http::header::AUTHORIZATION);
+    let q = url::form_urlencoded::parse(request.uri().query().unwrap_or_else(|| "").as_bytes())
+        .find(|(key, _)| key == "access_token")
+        .map(|(_, val)| val);
+
+    match (h, q) {
+        (Some(header), _) => extract_jws_compact(header, authn)
+            .map(|data| data.claims.into())
+            .map_err(|err| err.to_string()),
+        (_, Some(token)) => decode_jws_compact_with_config::<String>(&token, authn)
+            .map(|data| data.claims.into())
+            .map_err(|err| err.to_string()),
+        (None, None) => {
+            let anonymous = AccountId::new("anonymous", anonymous_audience);
+            Ok(Subject::new(anonymous))
+        }
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 mod jose {
@@ -280,21 +1229,17 @@ mod jose {
 ////////////////////////////////////////////////////////////////////////////////
 
 mod tower_web {
-    use super::{S3SignedRequestBuilder, Subject};
+    use super::{GeoHint, ReadMode, Referer, S3SignedRequestBuilder, Subject};
 
     mod extract {
         use http::StatusCode;
         use tower_web::extract::{Context, Error, Extract, Immediate};
         use tower_web::util::BufStream;
 
-        use svc_authn::token::jws_compact::extract::{
-            decode_jws_compact_with_config, extract_jws_compact,
-        };
-        use svc_authn::AccountId;
-
         use crate::app::config::Config;
+        use crate::app::util::extract_subject;
 
-        use super::{S3SignedRequestBuilder, Subject};
+        use super::{GeoHint, ReadMode, Referer, S3SignedRequestBuilder, Subject};
 
         impl<B: BufStream> Extract<B> for S3SignedRequestBuilder {
             type Future = Immediate<S3SignedRequestBuilder>;
@@ -312,43 +1257,64 @@ mod tower_web {
             }
         }
 
+        impl<B: BufStream> Extract<B> for Referer {
+            type Future = Immediate<Referer>;
+
+            fn extract(context: &Context) -> Self::Future {
+                let headers = context.request().headers();
+                let header = |name: &str| {
+                    headers
+                        .get(name)
+                        .and_then(|val| val.to_str().ok())
+                        .map(ToOwned::to_owned)
+                };
+
+                Immediate::ok(Referer {
+                    referer: header("referer"),
+                    origin: header("origin"),
+                    capability: header("x-storage-capability"),
+                    audience: header("x-storage-audience"),
+                })
+            }
+        }
+
+        impl<B: BufStream> Extract<B> for GeoHint {
+            type Future = Immediate<GeoHint>;
+
+            fn extract(context: &Context) -> Self::Future {
+                let config = context.config::<Config>().expect("missing config");
+                let region = config
+                    .backend
+                    .as_ref()
+                    .and_then(|backend| backend.geo_header())
+                    .and_then(|header| context.request().headers().get(header))
+                    .and_then(|val| val.to_str().ok())
+                    .map(|val| val.trim().to_lowercase());
+                Immediate::ok(GeoHint { region })
+            }
+        }
+
+        impl<B: BufStream> Extract<B> for ReadMode {
+            type Future = Immediate<ReadMode>;
+
+            fn extract(context: &Context) -> Self::Future {
+                let accept = context
+                    .request()
+                    .headers()
+                    .get(http::header::ACCEPT)
+                    .and_then(|val| val.to_str().ok());
+                Immediate::ok(ReadMode::from_accept(accept))
+            }
+        }
+
         impl<B: BufStream> Extract<B> for Subject {
             type Future = Immediate<Subject>;
 
             fn extract(context: &Context) -> Self::Future {
                 let config = context.config::<Config>().expect("missing config");
-                let h = context.request().headers().get(http::header::AUTHORIZATION);
-                let q = url::form_urlencoded::parse(
-                    context
-                        .request()
-                        .uri()
-                        .query()
-                        .unwrap_or_else(|| "")
-                        .as_bytes(),
-                )
-                .find(|(key, _)| key == "access_token")
-                .map(|(_, val)| val);
-
-                match (h, q) {
-                    (Some(header), _) => match extract_jws_compact(header, &config.authn) {
-                        Ok(data) => Immediate::ok(data.claims.into()),
-                        Err(ref err) => {
-                            Immediate::err(error(&err.to_string(), StatusCode::UNAUTHORIZED))
-                        }
-                    },
-                    (_, Some(token)) => {
-                        match decode_jws_compact_with_config::<String>(&token, &config.authn) {
-                            Ok(data) => Immediate::ok(data.claims.into()),
-                            Err(ref err) => {
-                                Immediate::err(error(&err.to_string(), StatusCode::UNAUTHORIZED))
-                            }
-                        }
-                    }
-                    (None, None) => {
-                        let audience = config.id.audience();
-                        let anonymous = AccountId::new("anonymous", audience);
-                        Immediate::ok(Subject::new(anonymous))
-                    }
+                match extract_subject(context.request(), &config.authn, config.id.audience()) {
+                    Ok(sub) => Immediate::ok(sub),
+                    Err(ref err) => Immediate::err(error(err, StatusCode::UNAUTHORIZED)),
                 }
             }
         }
@@ -364,3 +1330,617 @@ mod tower_web {
         }
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> Client {
+        Client::new(
+            "key",
+            "secret",
+            "us-east-1",
+            "https://s3.example.org",
+            ::std::time::Duration::from_secs(300),
+        )
+    }
+
+    #[test]
+    fn insert_s3_client_usable() {
+        let mut acc = S3Clients::new();
+        insert_s3_client(&mut acc, "alt", client()).expect("Error inserting a client");
+
+        let uri = S3SignedRequestBuilder::new()
+            .method("GET")
+            .bucket("data.example.org")
+            .object("foo.bar")
+            .build(acc.get("alt").expect("Missing inserted client"))
+            .expect("Error signing a request");
+        assert!(uri.starts_with("https://s3.example.org/data.example.org/foo.bar?"));
+    }
+
+    #[test]
+    fn signed_headers_listed() {
+        let uri = S3SignedRequestBuilder::new()
+            .method("PUT")
+            .bucket("data.example.org")
+            .object("foo.bar")
+            .add_header("Content-MD5", "XrY7u+Ae7tCTyyK7j1rNww==")
+            .add_header("x-amz-meta-author", "john")
+            .request_payer(true)
+            .build(&client())
+            .expect("Error signing a request");
+        assert_eq!(
+            signed_headers(&uri),
+            vec![
+                "content-md5",
+                "host",
+                "x-amz-meta-author",
+                "x-amz-request-payer"
+            ]
+        );
+
+        assert!(signed_headers("https://s3.example.org/data.example.org/foo.bar").is_empty());
+    }
+
+    #[test]
+    fn read_mode_accepted() {
+        assert_eq!(ReadMode::from_accept(None), ReadMode::Redirect);
+        assert_eq!(
+            ReadMode::from_accept(Some("application/json")),
+            ReadMode::Json
+        );
+        assert_eq!(
+            ReadMode::from_accept(Some(
+                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+            )),
+            ReadMode::Redirect
+        );
+        assert_eq!(
+            ReadMode::from_accept(Some("application/octet-stream")),
+            ReadMode::Proxy
+        );
+        assert_eq!(
+            ReadMode::from_accept(Some("image/webp, application/json; charset=utf-8")),
+            ReadMode::Json
+        );
+        assert_eq!(ReadMode::from_accept(Some("*/*")), ReadMode::Redirect);
+    }
+
+    #[test]
+    fn tagging_subresource_signed() {
+        for method in &["GET", "PUT"] {
+            let uri = S3SignedRequestBuilder::new()
+                .method(method)
+                .bucket("data.example.org")
+                .object("foo.bar")
+                .add_param("tagging", None)
+                .build(&client())
+                .expect("Error signing a request");
+            let query = uri.splitn(2, '?').nth(1).expect("Missing query string");
+            assert!(
+                query.split('&').any(|p| p == "tagging" || p == "tagging="),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[test]
+    fn list_objects_signed() {
+        let uri = S3SignedRequestBuilder::new()
+            .method("GET")
+            .bucket("data.example.org")
+            .object("")
+            .add_param("list-type", Some("2"))
+            .add_param("prefix", Some("foo."))
+            .build(&client())
+            .expect("Error signing a request");
+        assert!(uri.starts_with("https://s3.example.org/data.example.org/?"));
+
+        let query = uri.splitn(2, '?').nth(1).expect("Missing query string");
+        let params: Vec<&str> = query.split('&').collect();
+        assert!(params.contains(&"list-type=2"), "{}", uri);
+        assert!(params.contains(&"prefix=foo."), "{}", uri);
+    }
+
+    #[test]
+    fn normalize_headers_case_folding() {
+        let mut headers = BTreeMap::new();
+        headers.insert("Content-Type".to_owned(), "text/plain".to_owned());
+        headers.insert("content-type".to_owned(), "text/plain".to_owned());
+        headers.insert("X-Amz-Meta-Foo".to_owned(), "bar".to_owned());
+
+        let headers = normalize_headers(&headers).expect("Error normalizing headers");
+        assert_eq!(headers.len(), 2);
+        assert_eq!(
+            headers.get("content-type").map(String::as_str),
+            Some("text/plain")
+        );
+        assert_eq!(
+            headers.get("x-amz-meta-foo").map(String::as_str),
+            Some("bar")
+        );
+    }
+
+    #[test]
+    fn normalize_headers_conflict() {
+        let mut headers = BTreeMap::new();
+        headers.insert("Content-Type".to_owned(), "text/plain".to_owned());
+        headers.insert("content-type".to_owned(), "image/png".to_owned());
+        assert!(normalize_headers(&headers).is_err());
+    }
+
+    #[test]
+    fn build_s3_client_configured() {
+        let alt: AltBackendConfig = serde_json::from_str(r#"{"proxy_host": "cdn.example.org"}"#)
+            .expect("Error parsing a backend config");
+        let client = build_s3_client(
+            "key",
+            "secret",
+            "us-east-1",
+            "https://s3.example.org",
+            &alt,
+            &[],
+        )
+        .expect("Error building a client");
+
+        let uri = S3SignedRequestBuilder::new()
+            .method("GET")
+            .bucket("data.example.org")
+            .object("foo.bar")
+            .build(&client)
+            .expect("Error signing a request");
+        assert!(
+            uri.starts_with("https://cdn.example.org/data.example.org/foo.bar?"),
+            "{}",
+            uri
+        );
+    }
+
+    #[test]
+    fn build_s3_client_invalid() {
+        let alt = AltBackendConfig::default();
+        let build =
+            |region, endpoint| build_s3_client("key", "secret", region, endpoint, &alt, &[]);
+
+        assert!(build("us-east-1", "https://s3.example.org").is_ok());
+        assert!(build("us-east-1", "s3.example.org").is_err());
+        assert!(build("us-east-1", "ftp://s3.example.org").is_err());
+        assert!(build("us-east-1", "").is_err());
+        assert!(build("", "https://s3.example.org").is_err());
+        assert!(build("us east/1", "https://s3.example.org").is_err());
+    }
+
+    #[test]
+    fn build_s3_client_custom_ca() {
+        let alt = serde_json::from_value::<AltBackendConfig>(serde_json::json!({
+            "tls": {
+                "ca_path": concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata/ca.pem"),
+                "identity_path": concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata/client.p12"),
+                "identity_password": "storage",
+            }
+        }))
+        .expect("Error parsing a backend config");
+        let build = |alt| {
+            build_s3_client(
+                "key",
+                "secret",
+                "us-east-1",
+                "https://s3.example.org",
+                alt,
+                &[],
+            )
+        };
+        assert!(build(&alt).is_ok());
+        assert!(!format!("{:?}", alt).contains("storage\""));
+
+        let alt = serde_json::from_value::<AltBackendConfig>(serde_json::json!({
+            "tls": { "ca_path": "/nonexistent/ca.pem" }
+        }))
+        .expect("Error parsing a backend config");
+        assert!(build(&alt).is_err());
+    }
+
+    #[test]
+    fn startup_only_options() {
+        let parse = |json| {
+            serde_json::from_str::<AltBackendConfig>(json).expect("Error parsing a backend config")
+        };
+
+        assert_eq!(parse("{}").startup_only(), None);
+        assert_eq!(
+            parse(r#"{"mirror": "default"}"#).startup_only(),
+            Some("mirror")
+        );
+        assert_eq!(
+            parse(r#"{"region_probe_bucket": "data.example.org"}"#).startup_only(),
+            Some("region_probe_bucket")
+        );
+    }
+
+    #[test]
+    fn insert_s3_client_duplicate() {
+        let mut acc = S3Clients::new();
+        insert_s3_client(&mut acc, "alt", client()).expect("Error inserting a client");
+        assert!(insert_s3_client(&mut acc, "alt", client()).is_err());
+        assert!(insert_s3_client(&mut acc, "", client()).is_err());
+    }
+
+    #[test]
+    fn checksum_header_signed() {
+        let header = checksum_header("sha256").expect("Error getting a checksum header");
+        assert_eq!(header, "x-amz-checksum-sha256");
+        assert!(checksum_header("MD5").is_err());
+
+        let uri = S3SignedRequestBuilder::new()
+            .method("PUT")
+            .bucket("data.example.org")
+            .object("foo.bar")
+            .add_header(header, "n4bQgYhMfWWaL+qgxVrQFaO/TxsrC4Is0V1sFbDwCgg=")
+            .build(&client())
+            .expect("Error signing a request");
+
+        let query = uri.splitn(2, '?').nth(1).expect("Missing query string");
+        let signed_headers = query
+            .split('&')
+            .find(|p| p.starts_with("X-Amz-SignedHeaders="))
+            .expect("Missing signed headers");
+        assert!(signed_headers.contains("x-amz-checksum-sha256"), "{}", uri);
+    }
+
+    #[test]
+    fn streaming_payload_signed() {
+        let builder = S3SignedRequestBuilder::new()
+            .method("PUT")
+            .bucket("data.example.org")
+            .object("foo.bar")
+            .streaming(66560, None);
+        assert_eq!(
+            builder
+                .headers
+                .get("x-amz-content-sha256")
+                .map(String::as_str),
+            Some("STREAMING-AWS4-HMAC-SHA256-PAYLOAD")
+        );
+        assert_eq!(
+            builder
+                .headers
+                .get("x-amz-decoded-content-length")
+                .map(String::as_str),
+            Some("66560")
+        );
+        assert!(!builder.headers.contains_key("x-amz-trailer"));
+
+        let uri = builder.build(&client()).expect("Error signing a request");
+        let headers = signed_headers(&uri);
+        for header in &[
+            "content-encoding",
+            "x-amz-content-sha256",
+            "x-amz-decoded-content-length",
+        ] {
+            assert!(headers.iter().any(|val| val == header), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn streaming_payload_trailer() {
+        let builder = S3SignedRequestBuilder::new()
+            .method("PUT")
+            .bucket("data.example.org")
+            .object("foo.bar")
+            .add_header("content-encoding", "gzip")
+            .streaming(66560, Some("x-amz-checksum-crc32"));
+        let header = |name: &str| builder.headers.get(name).map(String::as_str);
+        assert_eq!(
+            header("x-amz-content-sha256"),
+            Some("STREAMING-AWS4-HMAC-SHA256-PAYLOAD-TRAILER")
+        );
+        assert_eq!(header("x-amz-trailer"), Some("x-amz-checksum-crc32"));
+        assert_eq!(header("content-encoding"), Some("aws-chunked,gzip"));
+    }
+
+    #[test]
+    fn special_characters_encoded_once() {
+        for (object, encoded) in &[
+            ("foo bar.txt", "foo%20bar.txt"),
+            ("foo+bar.txt", "foo%2Bbar.txt"),
+            ("100%.txt", "100%25.txt"),
+            ("foo%20bar.txt", "foo%2520bar.txt"),
+        ] {
+            let expected = format!("https://s3.example.org/data.example.org/{}", encoded);
+
+            // Signed for the sign endpoint
+            let uri = S3SignedRequestBuilder::new()
+                .method("GET")
+                .bucket("data.example.org")
+                .object(object)
+                .build(&client())
+                .expect("Error signing a request");
+            assert_eq!(uri.splitn(2, '?').next(), Some(expected.as_str()));
+
+            // Signed for the redirect of the read endpoint
+            let uri = client()
+                .presigned_url("GET", "data.example.org", object)
+                .expect("Error signing a request");
+            assert_eq!(uri.splitn(2, '?').next(), Some(expected.as_str()));
+        }
+    }
+
+    #[test]
+    fn non_ascii_object_encoded() {
+        for object in &[
+            "\u{043f}\u{0440}\u{0438}\u{0432}\u{0435}\u{0442}.txt",
+            "\u{1f642}.png",
+        ] {
+            let sign = || {
+                S3SignedRequestBuilder::new()
+                    .method("GET")
+                    .bucket("data.example.org")
+                    .object(object)
+                    .build(&client())
+                    .expect("Error signing a request")
+            };
+
+            let uri = sign();
+            let path = uri.splitn(2, '?').next().expect("Missing path");
+            assert!(path.is_ascii(), "{}", uri);
+            assert!(path.contains('%'), "{}", uri);
+            assert_eq!(path, sign().splitn(2, '?').next().expect("Missing path"));
+        }
+    }
+
+    #[test]
+    fn backend_routes() {
+        let router = BackendRouter {
+            routes: vec![
+                BackendRoute {
+                    bucket: "media.example.org".to_owned(),
+                    backend: "media".to_owned(),
+                },
+                BackendRoute {
+                    bucket: "*.example.net".to_owned(),
+                    backend: "archive".to_owned(),
+                },
+            ],
+            geo_regions: BTreeMap::new(),
+            mirrors: BTreeMap::new(),
+        };
+
+        assert_eq!(router.route("media.example.org"), "media");
+        assert_eq!(router.route("data.example.net"), "archive");
+        assert_eq!(router.route("data.example.org"), S3_DEFAULT_CLIENT);
+    }
+
+    #[test]
+    fn geo_routes() {
+        let mut geo_regions = BTreeMap::new();
+        geo_regions.insert("eu".to_owned(), "eu-replica".to_owned());
+        let router = BackendRouter {
+            routes: vec![BackendRoute {
+                bucket: "media.example.org".to_owned(),
+                backend: "media".to_owned(),
+            }],
+            geo_regions,
+            mirrors: BTreeMap::new(),
+        };
+        let geo = |region: Option<&str>| GeoHint {
+            region: region.map(ToOwned::to_owned),
+        };
+
+        assert_eq!(
+            router.route_nearest("data.example.org", &geo(Some("eu"))),
+            "eu-replica"
+        );
+        assert_eq!(
+            router.route_nearest("data.example.org", &geo(Some("ap"))),
+            S3_DEFAULT_CLIENT
+        );
+        assert_eq!(
+            router.route_nearest("media.example.org", &geo(Some("ap"))),
+            "media"
+        );
+        assert_eq!(
+            router.route_nearest("data.example.org", &geo(None)),
+            S3_DEFAULT_CLIENT
+        );
+    }
+
+    #[test]
+    fn mirror_routes() {
+        let config = serde_json::from_value::<BackendConfig>(serde_json::json!({
+            "default": "primary",
+            "alt": {
+                "primary": { "mirror": "secondary" },
+                "secondary": {},
+            },
+        }))
+        .expect("Error parsing backend config");
+        let router = BackendRouter::new(Some(&config)).expect("Error creating a router");
+
+        assert_eq!(router.mirror("primary"), Some("secondary"));
+        assert_eq!(router.mirror(S3_DEFAULT_CLIENT), Some("secondary"));
+        assert_eq!(router.mirror("secondary"), None);
+
+        let config = serde_json::from_value::<BackendConfig>(serde_json::json!({
+            "default": "primary",
+            "alt": { "primary": { "mirror": "primary" } },
+        }))
+        .expect("Error parsing backend config");
+        assert!(BackendRouter::new(Some(&config)).is_err());
+    }
+
+    #[test]
+    fn capability_verified() {
+        use hmac::{Hmac, Mac};
+        use sha2::Sha256;
+
+        let scope = |method, bucket, object| CapabilityScope::new(method, bucket, "foo", object);
+        let sign = |key: &str, timestamp: i64, scope: CapabilityScope| {
+            let mut mac = Hmac::<Sha256>::new_varkey(key.as_bytes()).expect("Invalid key");
+            mac.input(scope.message(timestamp).as_bytes());
+            format!("{}:{}", timestamp, hex::encode(mac.result().code()))
+        };
+        let verify = |key, scope: CapabilityScope, capability: &str, now| {
+            verify_capability(key, &scope, capability, now)
+        };
+
+        let now = 1_600_000_000;
+        let capability = sign("secret", now, scope("GET", "data.example.org", "bar"));
+        assert!(verify(
+            "secret",
+            scope("GET", "data.example.org", "bar"),
+            &capability,
+            now + 10
+        ));
+        assert!(!verify(
+            "other",
+            scope("GET", "data.example.org", "bar"),
+            &capability,
+            now
+        ));
+        assert!(!verify(
+            "secret",
+            scope("GET", "data.example.net", "bar"),
+            &capability,
+            now
+        ));
+        assert!(!verify(
+            "secret",
+            scope("GET", "data.example.org", "bar"),
+            &capability,
+            now + 3600
+        ));
+        assert!(!verify(
+            "secret",
+            scope("GET", "data.example.org", "bar"),
+            "garbage",
+            now
+        ));
+
+        // Replayed for another object, method or set
+        assert!(!verify(
+            "secret",
+            scope("GET", "data.example.org", "baz"),
+            &capability,
+            now
+        ));
+        assert!(!verify(
+            "secret",
+            scope("PUT", "data.example.org", "bar"),
+            &capability,
+            now
+        ));
+        assert!(!verify(
+            "secret",
+            CapabilityScope::new("GET", "data.example.org", "other", "bar"),
+            &capability,
+            now
+        ));
+    }
+
+    #[test]
+    fn terse_error_detail() {
+        let err = format_err!("InternalError: We encountered an internal error");
+        assert_eq!(
+            ErrorDetail::Verbose.describe("media", &err),
+            "InternalError: We encountered an internal error, backend = 'media'"
+        );
+        assert_eq!(
+            ErrorDetail::Terse.describe("media", &err),
+            "Backend request failed"
+        );
+    }
+
+    #[test]
+    fn allowed_audiences() {
+        let audiences = vec!["example.org".to_owned(), "example.net".to_owned()];
+        let mut aud_estm = AudienceEstimator::from_audiences(audiences.iter());
+        assert_eq!(
+            aud_estm.estimate("data.example.net").ok(),
+            Some("example.net")
+        );
+
+        aud_estm.set_allowed_audiences(&["example.org".to_owned()]);
+        assert_eq!(
+            aud_estm.estimate("data.example.org").ok(),
+            Some("example.org")
+        );
+        let err = aud_estm
+            .estimate("data.example.net")
+            .err()
+            .expect("Expected the audience to be rejected");
+        assert_eq!(err.status_code(), http::StatusCode::FORBIDDEN);
+        assert!(aud_estm.parse_set("data.example.net::foo").is_err());
+    }
+
+    #[test]
+    fn empty_bucket_and_set_rejected() {
+        let audiences = vec!["example.org".to_owned()];
+        let aud_estm = AudienceEstimator::from_audiences(audiences.iter());
+        let status = |result: Result<_, Error>| {
+            result
+                .err()
+                .expect("Expected an empty name to be rejected")
+                .status_code()
+        };
+
+        assert_eq!(status(aud_estm.estimate("")), http::StatusCode::BAD_REQUEST);
+        assert_eq!(
+            status(aud_estm.parse_bucket("example.org")),
+            http::StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(aud_estm.parse_set("::foo")),
+            http::StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(aud_estm.parse_set("data.example.org::")),
+            http::StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(aud_estm.parse_set("example.org::foo")),
+            http::StatusCode::BAD_REQUEST
+        );
+
+        let set = aud_estm
+            .parse_set("data.example.org::foo")
+            .expect("Error parsing a set");
+        assert_eq!(set.to_string(), "data.example.org:foo");
+    }
+
+    #[test]
+    fn sign_limiter_overflow() {
+        let limiter = SignLimiter::new(Some(1));
+        // The permit is taken as soon as the operation is started and held until it's done
+        let in_flight = limiter.run(|| Ok(()));
+        let status = limiter
+            .run(|| Ok(()))
+            .wait()
+            .expect("Error running a signing operation")
+            .err()
+            .expect("Expected the concurrent operation to be rejected")
+            .status_code();
+        assert_eq!(status, http::StatusCode::SERVICE_UNAVAILABLE);
+
+        assert!(in_flight
+            .wait()
+            .expect("Error running a signing operation")
+            .is_ok());
+        assert!(limiter
+            .run(|| Ok(()))
+            .wait()
+            .expect("Error running a signing operation")
+            .is_ok());
+
+        let limiter = SignLimiter::new(None);
+        let _in_flight = limiter.run(|| Ok(()));
+        assert!(limiter
+            .run(|| Ok(()))
+            .wait()
+            .expect("Error running a signing operation")
+            .is_ok());
+    }
+}
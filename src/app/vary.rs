@@ -0,0 +1,181 @@
+use std::sync::Arc;
+
+use futures::{Async, Future, Poll};
+use http::header::{HeaderValue, VARY};
+use http::{Request, Response};
+use tower_service::Service;
+use tower_web::middleware::Middleware;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+pub(crate) struct VaryMiddleware {
+    headers: Arc<Vec<String>>,
+}
+
+impl VaryMiddleware {
+    pub(crate) fn new(headers: &[String]) -> Self {
+        Self {
+            headers: Arc::new(headers.to_vec()),
+        }
+    }
+}
+
+impl<S, RequestBody, ResponseBody> Middleware<S> for VaryMiddleware
+where
+    S: Service<Request = Request<RequestBody>, Response = Response<ResponseBody>>,
+{
+    type Request = Request<RequestBody>;
+    type Response = Response<ResponseBody>;
+    type Error = S::Error;
+    type Service = VaryService<S>;
+
+    fn wrap(&self, inner: S) -> Self::Service {
+        VaryService {
+            inner,
+            headers: self.headers.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct VaryService<S> {
+    inner: S,
+    headers: Arc<Vec<String>>,
+}
+
+impl<S, RequestBody, ResponseBody> Service for VaryService<S>
+where
+    S: Service<Request = Request<RequestBody>, Response = Response<ResponseBody>>,
+{
+    type Request = Request<RequestBody>;
+    type Response = Response<ResponseBody>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: Self::Request) -> Self::Future {
+        ResponseFuture {
+            inner: self.inner.call(req),
+            headers: self.headers.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ResponseFuture<F> {
+    inner: F,
+    headers: Arc<Vec<String>>,
+}
+
+impl<F, ResponseBody> Future for ResponseFuture<F>
+where
+    F: Future<Item = Response<ResponseBody>>,
+{
+    type Item = Response<ResponseBody>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut resp = match self.inner.poll()? {
+            Async::Ready(resp) => resp,
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+
+        if !self.headers.is_empty() {
+            let mut vary = resp
+                .headers()
+                .get_all(VARY)
+                .iter()
+                .filter_map(|val| val.to_str().ok())
+                .flat_map(|val| val.split(','))
+                .map(|val| val.trim().to_owned())
+                .filter(|val| !val.is_empty())
+                .collect::<Vec<String>>();
+
+            // Values set by the handler are kept, the configured ones are appended if missing
+            for header in self.headers.iter() {
+                if !vary.iter().any(|val| val.eq_ignore_ascii_case(header)) {
+                    vary.push(header.to_owned());
+                }
+            }
+
+            if let Ok(val) = HeaderValue::from_str(&vary.join(", ")) {
+                resp.headers_mut().insert(VARY, val);
+            }
+        }
+
+        Ok(Async::Ready(resp))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use http::header::{ACCEPT, CONTENT_TYPE, LOCATION};
+    use http::StatusCode;
+
+    use super::*;
+
+    // Responds with JSON or with a redirect depending on the Accept header, as negotiated reads do
+    struct Handler;
+
+    impl Service for Handler {
+        type Request = Request<()>;
+        type Response = Response<()>;
+        type Error = ();
+        type Future = future::FutureResult<Response<()>, ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, req: Self::Request) -> Self::Future {
+            let json = req
+                .headers()
+                .get(ACCEPT)
+                .map_or(false, |val| val == "application/json");
+
+            let resp = if json {
+                Response::builder()
+                    .header(CONTENT_TYPE, "application/json")
+                    .header(VARY, "Accept")
+                    .body(())
+            } else {
+                Response::builder()
+                    .status(StatusCode::SEE_OTHER)
+                    .header(LOCATION, "https://s3.example.org/data.example.org/foo.bar")
+                    .header(VARY, "Accept")
+                    .body(())
+            };
+            future::ok(resp.expect("Error building a response"))
+        }
+    }
+
+    fn vary(accept: &str) -> Option<String> {
+        let headers = vec!["Origin".to_owned(), "accept".to_owned()];
+        let mut service = VaryMiddleware::new(&headers).wrap(Handler);
+        let req = Request::get("/api/v2/sets/data.example.org::foo/objects/bar")
+            .header(ACCEPT, accept)
+            .body(())
+            .expect("Error building a request");
+
+        service
+            .call(req)
+            .wait()
+            .expect("Error handling a request")
+            .headers()
+            .get(VARY)
+            .map(|val| val.to_str().expect("Invalid header value").to_owned())
+    }
+
+    #[test]
+    fn negotiated_response_varies() {
+        assert_eq!(vary("application/json"), Some("Accept, Origin".to_owned()));
+        assert_eq!(vary("text/html"), Some("Accept, Origin".to_owned()));
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt::Display;
+
+use futures::{stream, Future, Stream};
+use log::{info, warn};
+use svc_authn::AccountId;
+
+use crate::app::util::Subject;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct WarmupConfig {
+    #[serde(default = "WarmupConfig::default_concurrency")]
+    concurrency: usize,
+    entries: Vec<WarmupEntry>,
+}
+
+impl WarmupConfig {
+    fn default_concurrency() -> usize {
+        4
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct WarmupEntry {
+    audience: String,
+    subject: AccountId,
+    object: Vec<String>,
+    action: String,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Authz decisions are cached by the client, so authorizing the configured entries
+// once on startup saves the first requests from a cold cache
+pub(crate) fn run(config: &WarmupConfig, authz: &svc_authz::ClientMap) {
+    let mut runtime = tokio::runtime::Runtime::new().expect("Error creating a warmup runtime");
+    if let Ok(count) = runtime.block_on(preload(config, authz)) {
+        info!(
+            "Authz cache warmed up with {} of {} entries",
+            count,
+            config.entries.len()
+        );
+    }
+}
+
+fn preload(
+    config: &WarmupConfig,
+    authz: &svc_authz::ClientMap,
+) -> impl Future<Item = usize, Error = ()> {
+    let authz = authz.clone();
+    warmup(config.entries.clone(), config.concurrency, move |entry| {
+        let sub = Subject::new(entry.subject.clone());
+        let object = entry.object.iter().map(String::as_str).collect();
+        authz.authorize(&entry.audience, &sub, object, &entry.action)
+    })
+}
+
+fn warmup<F, R, T, E>(
+    entries: Vec<WarmupEntry>,
+    concurrency: usize,
+    authorize: F,
+) -> impl Future<Item = usize, Error = ()>
+where
+    F: Fn(&WarmupEntry) -> R,
+    R: Future<Item = Result<T, E>, Error = ()>,
+    E: Display,
+{
+    stream::iter_ok(entries)
+        .map(move |entry| {
+            authorize(&entry).map(move |result| match result {
+                Ok(_) => true,
+                Err(err) => {
+                    warn!(
+                        "Authz warmup failed, audience = '{}', subject = '{}': {}",
+                        entry.audience, entry.subject, err
+                    );
+                    false
+                }
+            })
+        })
+        .buffer_unordered(concurrency.max(1))
+        .fold(0, |acc, ok| Ok(if ok { acc + 1 } else { acc }))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(audience: &str, subject: &str, set: &str) -> WarmupEntry {
+        WarmupEntry {
+            audience: audience.to_owned(),
+            subject: AccountId::new(subject, "usr.example.net"),
+            object: vec!["sets".to_owned(), set.to_owned()],
+            action: "read".to_owned(),
+        }
+    }
+
+    // Without the cache, the entries are authorized by the client the service uses
+    #[test]
+    fn warmup_authorizes_entries() {
+        let authz_config = serde_json::from_value(serde_json::json!({
+            "example.org": { "type": "none" },
+            "example.net": { "type": "local", "trusted": [] },
+        }))
+        .expect("Error parsing authz config");
+        let authz = svc_authz::ClientMap::new(
+            &AccountId::new("storage", "svc.example.org"),
+            None,
+            authz_config,
+        )
+        .expect("Error creating authz clients");
+        let config = WarmupConfig {
+            concurrency: 2,
+            entries: vec![
+                entry("example.org", "john", "data.example.org::foo"),
+                entry("example.org", "jane", "data.example.org::foo"),
+                entry("example.org", "john", "data.example.org::bar"),
+                entry("example.net", "john", "data.example.net::foo"),
+            ],
+        };
+
+        let count = preload(&config, &authz).wait().expect("Error warming up");
+        assert_eq!(count, 3);
+    }
+
+    // Needs Redis at CACHE_URL, run with `cargo test -- --ignored`. Once warmed up, decisions
+    // are served from the cache even by a client of an unreachable authz
+    #[test]
+    #[ignore]
+    fn warmup_populates_cache() {
+        use svc_authz::cache::{create_pool2, Cache};
+
+        let url = std::env::var("CACHE_URL").expect("Missing CACHE_URL variable");
+        let id = AccountId::new("storage", "svc.example.org");
+        let authz = |uri: &str| {
+            let authz_config = serde_json::from_value(serde_json::json!({
+                "example.org": {
+                    "type": "http",
+                    "uri": uri,
+                    "algorithm": "ES256",
+                    "key": "data/keys/svc.private_key.p8.der.sample",
+                },
+            }))
+            .expect("Error parsing authz config");
+            let cache = Cache::new(create_pool2(&url, 1, None, 5), 300);
+            svc_authz::ClientMap::new(&id, Some(cache), authz_config)
+                .expect("Error creating authz clients")
+        };
+        let mut runtime = tokio::runtime::Runtime::new().expect("Error creating a runtime");
+
+        // Subjects are unique to the run, so that decisions of previous runs aren't found
+        let (john, jane) = (
+            format!("john-{}", uuid::Uuid::new_v4()),
+            format!("jane-{}", uuid::Uuid::new_v4()),
+        );
+        let config = WarmupConfig {
+            concurrency: 2,
+            entries: vec![
+                entry("example.org", &john, "data.example.org::foo"),
+                entry("example.org", &john, "data.example.org::bar"),
+            ],
+        };
+        let reachable = authz(&format!(
+            "{}/authz",
+            crate::app::tests::backend(br#"["read"]"#)
+        ));
+        let count = runtime
+            .block_on(preload(&config, &reachable))
+            .expect("Error warming up");
+        assert_eq!(count, 2);
+
+        let unreachable = authz("http://127.0.0.1:1/authz");
+        let mut cached = |subject: &str, set: &str| {
+            let sub = Subject::new(AccountId::new(subject, "usr.example.net"));
+            runtime
+                .block_on(unreachable.authorize("example.org", &sub, vec!["sets", set], "read"))
+                .expect("Error authorizing")
+                .is_ok()
+        };
+        assert!(cached(&john, "data.example.org::foo"));
+        assert!(cached(&john, "data.example.org::bar"));
+        assert!(!cached(&john, "data.example.org::baz"));
+        assert!(!cached(&jane, "data.example.org::foo"));
+    }
+}
@@ -127,4 +127,7 @@ pub mod sql {
     }
 }
 
+pub(crate) mod object_size;
+pub(crate) mod share;
 pub(crate) mod tag;
+pub(crate) mod tombstone;
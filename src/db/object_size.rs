@@ -0,0 +1,86 @@
+use diesel::pg::PgConnection;
+use diesel::result::Error;
+use diesel::sql_types::BigInt;
+
+use crate::schema::object_size;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, QueryableByName)]
+pub(crate) struct Usage {
+    #[sql_type = "BigInt"]
+    objects: i64,
+    #[sql_type = "BigInt"]
+    bytes: i64,
+}
+
+impl Usage {
+    pub(crate) fn objects(&self) -> i64 {
+        self.objects
+    }
+
+    pub(crate) fn bytes(&self) -> i64 {
+        self.bytes
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Sizes are kept per object, so an object fetched many times is counted once with its latest size
+#[derive(Debug, Insertable)]
+#[table_name = "object_size"]
+pub(crate) struct RecordQuery<'a> {
+    audience: &'a str,
+    bucket: &'a str,
+    object: &'a str,
+    size: i64,
+}
+
+impl<'a> RecordQuery<'a> {
+    pub(crate) fn new(audience: &'a str, bucket: &'a str, object: &'a str, size: i64) -> Self {
+        Self {
+            audience,
+            bucket,
+            object,
+            size,
+        }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<usize, Error> {
+        use diesel::prelude::*;
+
+        diesel::insert_into(object_size::table)
+            .values(self)
+            .on_conflict((object_size::bucket, object_size::object))
+            .do_update()
+            .set((
+                object_size::size.eq(self.size),
+                object_size::updated_at.eq(diesel::dsl::now),
+            ))
+            .execute(conn)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) struct UsageQuery<'a> {
+    audience: &'a str,
+}
+
+impl<'a> UsageQuery<'a> {
+    pub(crate) fn new(audience: &'a str) -> Self {
+        Self { audience }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<Usage, Error> {
+        use diesel::sql_types::Text;
+        use diesel::RunQueryDsl;
+
+        diesel::sql_query(
+            "select count(*) as objects, coalesce(sum(size), 0)::int8 as bytes \
+             from object_size where audience = $1",
+        )
+        .bind::<Text, _>(self.audience)
+        .get_result(conn)
+    }
+}
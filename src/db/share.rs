@@ -0,0 +1,234 @@
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::result::Error;
+
+use crate::schema::share;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Queryable)]
+pub(crate) struct Share {
+    backend: String,
+    bucket: String,
+    object: String,
+    max_downloads: Option<i64>,
+    downloads: i64,
+    expires_at: DateTime<Utc>,
+}
+
+impl Share {
+    pub(crate) fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    pub(crate) fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    pub(crate) fn object(&self) -> &str {
+        &self.object
+    }
+
+    fn redeem(self, now: DateTime<Utc>) -> Redemption {
+        if self.expires_at <= now {
+            return Redemption::Expired;
+        }
+
+        match self.max_downloads {
+            Some(max) if self.downloads >= max => Redemption::Exhausted,
+            _ => Redemption::Redeemed(self),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Redemption {
+    Redeemed(Share),
+    Expired,
+    Exhausted,
+    NotFound,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Insertable)]
+#[table_name = "share"]
+pub(crate) struct InsertQuery<'a> {
+    token: &'a str,
+    backend: &'a str,
+    bucket: &'a str,
+    object: &'a str,
+    created_by: &'a str,
+    max_downloads: Option<i64>,
+    expires_at: DateTime<Utc>,
+}
+
+impl<'a> InsertQuery<'a> {
+    pub(crate) fn new(
+        token: &'a str,
+        backend: &'a str,
+        bucket: &'a str,
+        object: &'a str,
+        created_by: &'a str,
+        max_downloads: Option<i64>,
+        expires_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            token,
+            backend,
+            bucket,
+            object,
+            created_by,
+            max_downloads,
+            expires_at,
+        }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<usize, Error> {
+        use diesel::prelude::*;
+
+        diesel::insert_into(share::table).values(self).execute(conn)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// The share is checked without being redeemed, so that requests failing on other grounds don't use up downloads
+pub(crate) struct FindQuery<'a> {
+    token: &'a str,
+    now: DateTime<Utc>,
+}
+
+impl<'a> FindQuery<'a> {
+    pub(crate) fn new(token: &'a str, now: DateTime<Utc>) -> Self {
+        Self { token, now }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<Redemption, Error> {
+        use diesel::prelude::*;
+
+        let share = share::table
+            .find(self.token)
+            .select((
+                share::backend,
+                share::bucket,
+                share::object,
+                share::max_downloads,
+                share::downloads,
+                share::expires_at,
+            ))
+            .first::<Share>(conn)
+            .optional()?;
+
+        Ok(match share {
+            Some(share) => share.redeem(self.now),
+            None => Redemption::NotFound,
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// The share is locked while it's checked, so that concurrent downloads never exceed the limit
+pub(crate) struct RedeemQuery<'a> {
+    token: &'a str,
+    now: DateTime<Utc>,
+}
+
+impl<'a> RedeemQuery<'a> {
+    pub(crate) fn new(token: &'a str, now: DateTime<Utc>) -> Self {
+        Self { token, now }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<Redemption, Error> {
+        use diesel::prelude::*;
+
+        conn.transaction::<_, Error, _>(|| {
+            let share = share::table
+                .find(self.token)
+                .select((
+                    share::backend,
+                    share::bucket,
+                    share::object,
+                    share::max_downloads,
+                    share::downloads,
+                    share::expires_at,
+                ))
+                .for_update()
+                .first::<Share>(conn)
+                .optional()?;
+
+            let redemption = match share {
+                Some(share) => share.redeem(self.now),
+                None => Redemption::NotFound,
+            };
+
+            if let Redemption::Redeemed(_) = redemption {
+                diesel::update(share::table.find(self.token))
+                    .set(share::downloads.eq(share::downloads + 1))
+                    .execute(conn)?;
+            }
+
+            Ok(redemption)
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn share(max_downloads: Option<i64>, downloads: i64, expires_at: DateTime<Utc>) -> Share {
+        Share {
+            backend: String::from("default"),
+            bucket: String::from("data.example.org"),
+            object: String::from("foo.bar"),
+            max_downloads,
+            downloads,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn downloads_limited() {
+        let now = Utc::now();
+        let expires_at = now + Duration::hours(1);
+
+        assert!(matches!(
+            share(Some(2), 1, expires_at).redeem(now),
+            Redemption::Redeemed(_)
+        ));
+        assert!(matches!(
+            share(Some(2), 2, expires_at).redeem(now),
+            Redemption::Exhausted
+        ));
+        assert!(matches!(
+            share(None, 1000, expires_at).redeem(now),
+            Redemption::Redeemed(_)
+        ));
+    }
+
+    #[test]
+    fn expired_share_refused() {
+        let now = Utc::now();
+
+        assert!(matches!(
+            share(None, 0, now - Duration::seconds(1)).redeem(now),
+            Redemption::Expired
+        ));
+        assert!(matches!(
+            share(None, 0, now).redeem(now),
+            Redemption::Expired
+        ));
+
+        // Expiry is told before the limit
+        assert!(matches!(
+            share(Some(1), 1, now - Duration::seconds(1)).redeem(now),
+            Redemption::Expired
+        ));
+    }
+}
@@ -62,6 +62,28 @@ impl<'a> FindQuery<'a> {
 
 ////////////////////////////////////////////////////////////////////////////////
 
+pub(crate) struct SetTagsQuery<'a> {
+    set: &'a Set,
+}
+
+impl<'a> SetTagsQuery<'a> {
+    pub(crate) fn new(set: &'a Set) -> Self {
+        Self { set }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<Vec<Set>, Error> {
+        use diesel::prelude::*;
+
+        set_tag::table
+            .filter(set_tag::set.eq(self.set))
+            .order_by(set_tag::created_at.asc())
+            .select(set_tag::tag)
+            .get_results(conn)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
 pub(crate) struct DeleteQuery<'a> {
     id: Option<Uuid>,
     tag: Option<&'a Set>,
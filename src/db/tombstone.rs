@@ -0,0 +1,151 @@
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::result::Error;
+
+use crate::schema::tombstone;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Queryable)]
+pub(crate) struct Object {
+    backend: String,
+    bucket: String,
+    object: String,
+}
+
+impl Object {
+    pub(crate) fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    pub(crate) fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    pub(crate) fn object(&self) -> &str {
+        &self.object
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Deleting a tombstoned object again postpones its purge
+#[derive(Debug, Insertable)]
+#[table_name = "tombstone"]
+pub(crate) struct InsertQuery<'a> {
+    audience: &'a str,
+    backend: &'a str,
+    bucket: &'a str,
+    object: &'a str,
+    purge_at: DateTime<Utc>,
+}
+
+impl<'a> InsertQuery<'a> {
+    pub(crate) fn new(
+        audience: &'a str,
+        backend: &'a str,
+        bucket: &'a str,
+        object: &'a str,
+        purge_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            audience,
+            backend,
+            bucket,
+            object,
+            purge_at,
+        }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<usize, Error> {
+        use diesel::prelude::*;
+
+        diesel::insert_into(tombstone::table)
+            .values(self)
+            .on_conflict((tombstone::backend, tombstone::bucket, tombstone::object))
+            .do_update()
+            .set((
+                tombstone::deleted_at.eq(diesel::dsl::now),
+                tombstone::purge_at.eq(self.purge_at),
+            ))
+            .execute(conn)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) struct FindQuery<'a> {
+    backend: &'a str,
+    bucket: &'a str,
+    object: &'a str,
+}
+
+impl<'a> FindQuery<'a> {
+    pub(crate) fn new(backend: &'a str, bucket: &'a str, object: &'a str) -> Self {
+        Self {
+            backend,
+            bucket,
+            object,
+        }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<bool, Error> {
+        use diesel::dsl::exists;
+        use diesel::prelude::*;
+
+        diesel::select(exists(tombstone::table.find((
+            self.backend,
+            self.bucket,
+            self.object,
+        ))))
+        .get_result(conn)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) struct DeleteQuery<'a> {
+    backend: &'a str,
+    bucket: &'a str,
+    object: &'a str,
+}
+
+impl<'a> DeleteQuery<'a> {
+    pub(crate) fn new(backend: &'a str, bucket: &'a str, object: &'a str) -> Self {
+        Self {
+            backend,
+            bucket,
+            object,
+        }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<usize, Error> {
+        use diesel::prelude::*;
+
+        diesel::delete(tombstone::table.find((self.backend, self.bucket, self.object)))
+            .execute(conn)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) struct ExpiredQuery {
+    limit: i64,
+}
+
+impl ExpiredQuery {
+    pub(crate) fn new(limit: i64) -> Self {
+        Self { limit }
+    }
+
+    pub(crate) fn execute(&self, conn: &PgConnection) -> Result<Vec<Object>, Error> {
+        use diesel::prelude::*;
+
+        tombstone::table
+            .filter(tombstone::purge_at.le(diesel::dsl::now))
+            .select((tombstone::backend, tombstone::bucket, tombstone::object))
+            .order(tombstone::purge_at.asc())
+            .limit(self.limit)
+            .load(conn)
+    }
+}
@@ -1,19 +1,235 @@
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
+use futures::{future, Future, Stream};
+use log::warn;
 use rusoto_core::credential::AwsCredentials;
+use rusoto_core::request::{DispatchSignedRequest, HttpDispatchError, HttpResponse};
 use rusoto_core::signature::SignedRequest;
 use rusoto_core::Region;
 use url::Url;
 
+pub(crate) const DEFAULT_USER_AGENT: &str = concat!("storage/", env!("CARGO_PKG_VERSION"));
+
 #[derive(Debug)]
 pub(crate) struct Client {
-    credentials: AwsCredentials,
+    credentials: RwLock<AwsCredentials>,
     region: Region,
     expires_in: Duration,
     proxy_host: Option<String>,
+    request_payer: bool,
+    max_url_length: Option<usize>,
+    service_hosts: Vec<String>,
+    extra_query: Option<ExtraQuery>,
+    service_name: String,
+    anonymous: bool,
+    bucket_regions: Arc<RwLock<BTreeMap<String, String>>>,
+    services: BTreeMap<String, String>,
+    expected_bucket_owner: Option<String>,
+    user_agent: String,
+    existence_ttl: Option<ExistenceTtl>,
+    existence: TtlCache<(String, String), bool>,
+    listing_ttl: Option<Duration>,
+    listings: TtlCache<ListingKey, Listing>,
+    signed_writes: TtlCache<(String, String), ()>,
+    tls: Option<Tls>,
+    core: Core,
+}
+
+// Requests of the service itself share the connections and resolver threads of a single client,
+// it's built on first use, so that every option of the backend is set by then
+#[derive(Default)]
+struct Core(Mutex<Option<rusoto_core::Client>>);
+
+impl fmt::Debug for Core {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("Core")
+    }
+}
+
+// Trust roots and the identity of connections the service opens to the backend itself, signed URIs aren't affected
+#[derive(Clone)]
+pub(crate) struct Tls {
+    connector: native_tls::TlsConnector,
+}
+
+impl Tls {
+    // The CA is trusted in addition to the system roots, the identity is a PKCS #12 archive
+    pub(crate) fn new(
+        ca_pem: Option<&[u8]>,
+        identity: Option<(&[u8], &str)>,
+        insecure_skip_verify: bool,
+    ) -> Result<Self> {
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(pem) = ca_pem {
+            let ca = native_tls::Certificate::from_pem(pem)
+                .context("failed to parse the CA certificate")?;
+            builder.add_root_certificate(ca);
+        }
+        if let Some((pkcs12, password)) = identity {
+            let identity = native_tls::Identity::from_pkcs12(pkcs12, password)
+                .context("failed to parse the client identity")?;
+            builder.identity(identity);
+        }
+        builder
+            .danger_accept_invalid_certs(insecure_skip_verify)
+            .danger_accept_invalid_hostnames(insecure_skip_verify);
+
+        let connector = builder
+            .build()
+            .context("failed to create a TLS connector")?;
+        Ok(Self { connector })
+    }
+}
+
+impl fmt::Debug for Tls {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("Tls")
+    }
+}
+
+fn http_client(tls: Option<&Tls>) -> Result<rusoto_core::HttpClient> {
+    use rusoto_core::HttpClient;
+
+    match tls {
+        Some(tls) => {
+            let mut http = hyper::client::HttpConnector::new(4);
+            http.enforce_http(false);
+            let https = hyper_tls::HttpsConnector::from((http, tls.connector.clone()));
+            Ok(HttpClient::from_connector(https))
+        }
+        None => HttpClient::new()
+            .map_err(|err| anyhow::format_err!("failed to create an http client: {}", err)),
+    }
+}
+
+// Bucket, prefix, continuation token and page size of a listing
+type ListingKey = (String, String, Option<String>, Option<u64>);
+
+#[derive(Debug, Clone, Copy)]
+struct ExistenceTtl {
+    found: Duration,
+    missing: Duration,
+}
+
+// Answers of the backend kept until they expire, so that repeated requests aren't sent to it
+#[derive(Debug)]
+struct TtlCache<K, V> {
+    entries: RwLock<BTreeMap<K, (Instant, V)>>,
+}
+
+impl<K: Ord, V: Clone> TtlCache<K, V> {
+    fn new() -> Self {
+        Self {
+            entries: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        let entries = self.entries.read().expect("Error acquiring cache lock");
+        match entries.get(key) {
+            Some((expires_at, val)) if *expires_at > Instant::now() => Some(val.clone()),
+            _ => None,
+        }
+    }
+
+    // Expired entries are dropped on every insert, so the cache never outgrows the keys requested within the TTL
+    fn insert(&self, key: K, val: V, ttl: Duration) {
+        let now = Instant::now();
+        let mut entries = self.entries.write().expect("Error acquiring cache lock");
+        entries.retain(|_, (expires_at, _)| *expires_at > now);
+        entries.insert(key, (now + ttl, val));
+    }
+
+    fn contains_where<F>(&self, pred: F) -> bool
+    where
+        F: Fn(&K) -> bool,
+    {
+        let now = Instant::now();
+        self.entries
+            .read()
+            .expect("Error acquiring cache lock")
+            .iter()
+            .any(|(key, (expires_at, _))| *expires_at > now && pred(key))
+    }
+
+    fn remove_where<F>(&self, pred: F)
+    where
+        F: Fn(&K) -> bool,
+    {
+        self.entries
+            .write()
+            .expect("Error acquiring cache lock")
+            .retain(|key, _| !pred(key));
+    }
+}
+
+#[derive(Debug)]
+struct ExtraQuery {
+    query: String,
+    signed: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct UrlTooLong {
+    length: usize,
+    max_length: usize,
+}
+
+impl fmt::Display for UrlTooLong {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "signed uri length = {} exceeds the maximum = {}",
+            self.length, self.max_length
+        )
+    }
+}
+
+impl std::error::Error for UrlTooLong {}
+
+// The backend wasn't reached at all, as opposed to answering with an error
+#[derive(Debug)]
+pub(crate) struct Unreachable {
+    message: String,
+}
+
+impl Unreachable {
+    pub(crate) fn new(message: &str) -> Self {
+        Self {
+            message: message.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Unreachable {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "backend is unreachable: {}", self.message)
+    }
+}
+
+impl std::error::Error for Unreachable {}
+
+#[derive(Debug)]
+pub(crate) struct RedirectLoop {
+    host: String,
+}
+
+impl fmt::Display for RedirectLoop {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "signed uri points back to the service host = '{}', check the backend endpoint configuration",
+            self.host
+        )
+    }
 }
 
+impl std::error::Error for RedirectLoop {}
+
 impl Client {
     pub(crate) fn new(
         key: &str,
@@ -29,10 +245,27 @@ impl Client {
         let credentials = AwsCredentials::new(key, secret, None, None);
 
         Self {
-            credentials,
+            credentials: RwLock::new(credentials),
             region,
             expires_in,
             proxy_host: None,
+            request_payer: false,
+            max_url_length: None,
+            service_hosts: Vec::new(),
+            extra_query: None,
+            service_name: String::from("s3"),
+            anonymous: false,
+            bucket_regions: Arc::new(RwLock::new(BTreeMap::new())),
+            services: BTreeMap::new(),
+            expected_bucket_owner: None,
+            user_agent: String::from(DEFAULT_USER_AGENT),
+            existence_ttl: None,
+            existence: TtlCache::new(),
+            listing_ttl: None,
+            listings: TtlCache::new(),
+            signed_writes: TtlCache::new(),
+            tls: None,
+            core: Core::default(),
         }
     }
 
@@ -41,24 +274,231 @@ impl Client {
         self
     }
 
+    pub(crate) fn set_request_payer(&mut self, value: bool) -> &mut Self {
+        self.request_payer = value;
+        self
+    }
+
+    pub(crate) fn set_max_url_length(&mut self, value: usize) -> &mut Self {
+        self.max_url_length = Some(value);
+        self
+    }
+
+    pub(crate) fn set_service_hosts(&mut self, hosts: &[String]) -> &mut Self {
+        self.service_hosts = hosts.to_vec();
+        self
+    }
+
+    pub(crate) fn set_extra_query(&mut self, query: &str, signed: bool) -> &mut Self {
+        self.extra_query = Some(ExtraQuery {
+            query: query.trim_start_matches('?').to_owned(),
+            signed,
+        });
+        self
+    }
+
+    pub(crate) fn set_service_name(&mut self, value: &str) -> &mut Self {
+        self.service_name = value.to_owned();
+        self
+    }
+
+    pub(crate) fn set_anonymous(&mut self, value: bool) -> &mut Self {
+        self.anonymous = value;
+        self
+    }
+
+    // Endpoints of other SigV4 services allowed to be signed with the credentials of the backend
+    pub(crate) fn set_services(&mut self, services: &BTreeMap<String, String>) -> &mut Self {
+        self.services = services.clone();
+        self
+    }
+
+    // Account id of the bucket owner, S3 rejects signed requests to buckets owned by anyone else
+    pub(crate) fn set_expected_bucket_owner(&mut self, value: &str) -> &mut Self {
+        self.expected_bucket_owner = Some(value.to_owned());
+        self
+    }
+
+    // Sent with requests of the service itself, signed URIs are requested by clients with their own
+    pub(crate) fn set_user_agent(&mut self, value: &str) -> &mut Self {
+        self.user_agent = value.to_owned();
+        self.core = Core::default();
+        self
+    }
+
+    // Existing objects are remembered for the first TTL, missing ones for the second, zero disables either
+    pub(crate) fn set_existence_cache(&mut self, found: Duration, missing: Duration) -> &mut Self {
+        self.existence_ttl = Some(ExistenceTtl { found, missing });
+        self
+    }
+
+    // Pages of listings are remembered for the TTL, writes of objects under their prefixes forget them.
+    // Only writes signed by this client are known to it, the ones signed by other instances of the service aren't
+    pub(crate) fn set_listing_cache(&mut self, ttl: Duration) -> &mut Self {
+        self.listing_ttl = Some(ttl);
+        self
+    }
+
+    // Rotated credentials are swapped in place, so that the client keeps what it knows about buckets and objects.
+    // Requests of the service itself are sent by a client built with the new ones
+    pub(crate) fn set_credentials(&self, key: &str, secret: &str) {
+        let mut credentials = self
+            .credentials
+            .write()
+            .expect("Error acquiring credentials lock");
+        if credentials.aws_access_key_id() == key && credentials.aws_secret_access_key() == secret {
+            return;
+        }
+        *credentials = AwsCredentials::new(key, secret, None, None);
+        *self.core.0.lock().expect("Error acquiring core lock") = None;
+    }
+
+    pub(crate) fn set_tls(&mut self, tls: Tls) -> &mut Self {
+        self.tls = Some(tls);
+        self.core = Core::default();
+        self
+    }
+
+    // The object is expected decoded, as captured from the path or passed in a payload,
+    // the signer percent-encodes it once, so "+" and "%" are never taken for an encoding
     pub(crate) fn create_request(&self, method: &str, bucket: &str, object: &str) -> SignedRequest {
         let uri = format!("/{bucket}/{object}", bucket = bucket, object = object);
-        SignedRequest::new(method, "s3", &self.region, &uri)
+        let mut req = SignedRequest::new(
+            method,
+            &self.service_name,
+            &self.bucket_region(bucket),
+            &uri,
+        );
+        if self.request_payer {
+            add_request_payer(&mut req);
+        }
+        if let Some(ref owner) = self.expected_bucket_owner {
+            req.add_header("x-amz-expected-bucket-owner", owner);
+        }
+        if let Some(ExtraQuery {
+            ref query,
+            signed: true,
+        }) = self.extra_query
+        {
+            for (key, val) in url::form_urlencoded::parse(query.as_bytes()) {
+                req.params.insert(key.into_owned(), Some(val.into_owned()));
+            }
+        }
+        req
+    }
+
+    // Requests to other services are addressed the same way as to the backend, within its region
+    pub(crate) fn create_service_request(
+        &self,
+        service: &str,
+        method: &str,
+        bucket: &str,
+        object: &str,
+    ) -> Result<SignedRequest> {
+        if service == self.service_name {
+            return Ok(self.create_request(method, bucket, object));
+        }
+
+        let endpoint = self.services.get(service).ok_or_else(|| {
+            anyhow::format_err!("service = '{}' is not allowed to be signed", service)
+        })?;
+        let region = Region::Custom {
+            name: self.region.name().to_owned(),
+            endpoint: endpoint.to_owned(),
+        };
+        let uri = format!("/{bucket}/{object}", bucket = bucket, object = object);
+        Ok(SignedRequest::new(method, service, &region, &uri))
     }
 
     pub(crate) fn sign_request(&self, req: &mut SignedRequest) -> Result<String> {
-        let url = req.generate_presigned_url(&self.credentials, &self.expires_in, false);
+        self.sign_request_expiring(req, &self.expires_in)
+    }
 
-        if let Some(ref proxy_host) = self.proxy_host {
-            let mut parsed_url = Url::parse(&url).context("failed to parse generated uri")?;
+    pub(crate) fn sign_request_expiring(
+        &self,
+        req: &mut SignedRequest,
+        expires_in: &Duration,
+    ) -> Result<String> {
+        let credentials = self
+            .credentials
+            .read()
+            .expect("Error acquiring credentials lock")
+            .clone();
+        let url = req.generate_presigned_url(&credentials, expires_in, false);
+        // The proxy, the public access and the CDN token are the ones of the backend
+        let backend = req.service == self.service_name;
 
-            parsed_url
-                .set_host(Some(&proxy_host))
-                .context("failed to set proxy backend")?;
+        // The object is about to be written or deleted by the client, what the service knows about it becomes stale.
+        // The request may be sent at any time until the signature expires, answers are only remembered after that
+        if backend && req.method != "GET" && req.method != "HEAD" {
+            let mut path = req.path.trim_start_matches('/').splitn(2, '/');
+            if let (Some(bucket), Some(object)) = (path.next(), path.next()) {
+                self.invalidate(bucket, object);
+                if self.existence_ttl.is_some() || self.listing_ttl.is_some() {
+                    self.signed_writes.insert(
+                        (bucket.to_owned(), object.to_owned()),
+                        (),
+                        *expires_in,
+                    );
+                }
+            }
+        }
 
-            Ok(parsed_url.to_string())
+        // Objects of public buckets are requested without authentication, so they may be cached indefinitely
+        let url = if backend && self.anonymous {
+            strip_query_auth(&url)?
         } else {
-            Ok(url)
+            url
+        };
+
+        let url = match self.proxy_host {
+            Some(ref proxy_host) if backend => {
+                let mut parsed_url = Url::parse(&url).context("failed to parse generated uri")?;
+
+                parsed_url
+                    .set_host(Some(&proxy_host))
+                    .context("failed to set proxy backend")?;
+
+                parsed_url.to_string()
+            }
+            _ => url,
+        };
+
+        // Tokens of CDNs in front of the backend are usually checked outside of the signature
+        let url = match self.extra_query {
+            Some(ExtraQuery {
+                ref query,
+                signed: false,
+            }) if backend && !query.is_empty() => {
+                let separator = if url.contains('?') { '&' } else { '?' };
+                format!("{}{}{}", url, separator, query)
+            }
+            _ => url,
+        };
+
+        if !self.service_hosts.is_empty() {
+            let parsed_url = Url::parse(&url).context("failed to parse generated uri")?;
+            if let Some(host) = parsed_url.host_str() {
+                if self
+                    .service_hosts
+                    .iter()
+                    .any(|val| val.eq_ignore_ascii_case(host))
+                {
+                    return Err(RedirectLoop {
+                        host: host.to_owned(),
+                    }
+                    .into());
+                }
+            }
+        }
+
+        match self.max_url_length {
+            Some(max_length) if url.len() > max_length => Err(UrlTooLong {
+                length: url.len(),
+                max_length,
+            }
+            .into()),
+            _ => Ok(url),
         }
     }
 
@@ -70,4 +510,1129 @@ impl Client {
     ) -> Result<String> {
         self.sign_request(&mut self.create_request(method, bucket, object))
     }
+
+    // Objects up to the maximum size are fetched by the service, larger ones are redirected to
+    pub(crate) fn download(
+        self: Arc<Self>,
+        bucket: &str,
+        object: &str,
+        max_size: u64,
+    ) -> impl Future<Item = Download, Error = anyhow::Error> {
+        use rusoto_core::RusotoError;
+        use rusoto_s3::{GetObjectRequest, HeadObjectRequest, S3Client, S3};
+
+        let core = match self.core() {
+            Ok(val) => val,
+            Err(err) => return future::Either::A(future::err(err)),
+        };
+        let request_payer = self.request_payer_value();
+
+        let head_req = HeadObjectRequest {
+            bucket: bucket.to_owned(),
+            key: object.to_owned(),
+            request_payer: request_payer.clone(),
+            ..Default::default()
+        };
+        let get_req = GetObjectRequest {
+            bucket: bucket.to_owned(),
+            key: object.to_owned(),
+            request_payer,
+            ..Default::default()
+        };
+
+        let head_core = core.clone();
+        let fut = self
+            .retry_in_region(
+                bucket,
+                move |region| {
+                    S3Client::new_with_client(head_core.clone(), region.clone())
+                        .head_object(head_req.clone())
+                        .map(move |head| (head, region))
+                },
+                |err| match *err {
+                    RusotoError::Unknown(ref resp) => redirect_region(
+                        resp.status,
+                        resp.headers.get(BUCKET_REGION_HEADER).map(String::as_str),
+                    ),
+                    _ => None,
+                },
+            )
+            .map_err(|err| match err {
+                RusotoError::HttpDispatch(err) => {
+                    anyhow::Error::new(Unreachable::new(&err.to_string()))
+                }
+                err => anyhow::format_err!("failed to get object metadata: {}", err),
+            })
+            .and_then(move |(head, region)| {
+                if !inline(head.content_length, max_size) {
+                    let size = head.content_length;
+                    let uri = self.presigned_url("GET", &get_req.bucket, &get_req.key);
+                    return future::Either::A(future::result(
+                        uri.map(|uri| Download::Redirect { uri, size }),
+                    ));
+                }
+
+                let fut = S3Client::new_with_client(core, region)
+                    .get_object(get_req)
+                    .map_err(|err| match err {
+                        RusotoError::HttpDispatch(err) => {
+                            anyhow::Error::new(Unreachable::new(&err.to_string()))
+                        }
+                        err => anyhow::format_err!("failed to get object: {}", err),
+                    })
+                    .and_then(|output| {
+                        let content_type = output.content_type;
+                        let body = match output.body {
+                            Some(body) => future::Either::A(body.concat2().map(|val| val.to_vec())),
+                            None => future::Either::B(future::ok(Vec::new())),
+                        };
+
+                        body.map(|body| Download::Inline { body, content_type })
+                            .map_err(|err| anyhow::format_err!("failed to read object: {}", err))
+                    });
+                future::Either::B(fut)
+            });
+        future::Either::B(fut)
+    }
+
+    // Missing objects are told apart from failed requests, so that the latter are never taken for a missing object
+    pub(crate) fn object_exists(
+        self: Arc<Self>,
+        bucket: &str,
+        object: &str,
+    ) -> impl Future<Item = bool, Error = anyhow::Error> {
+        use rusoto_core::RusotoError;
+        use rusoto_s3::{HeadObjectRequest, S3Client, S3};
+
+        let key = (bucket.to_owned(), object.to_owned());
+        if let Some(exists) = self.existence.get(&key) {
+            return future::Either::A(future::ok(exists));
+        }
+
+        let core = match self.core() {
+            Ok(val) => val,
+            Err(err) => return future::Either::A(future::err(err)),
+        };
+        let head_req = HeadObjectRequest {
+            bucket: bucket.to_owned(),
+            key: object.to_owned(),
+            request_payer: self.request_payer_value(),
+            ..Default::default()
+        };
+
+        let fut = self
+            .retry_in_region(
+                bucket,
+                move |region| {
+                    S3Client::new_with_client(core.clone(), region).head_object(head_req.clone())
+                },
+                |err| match *err {
+                    RusotoError::Unknown(ref resp) => redirect_region(
+                        resp.status,
+                        resp.headers.get(BUCKET_REGION_HEADER).map(String::as_str),
+                    ),
+                    _ => None,
+                },
+            )
+            .then(|result| match result {
+                Ok(_) => Ok(true),
+                Err(ref err) if object_missing(err) => Ok(false),
+                Err(err) => Err(anyhow::format_err!(
+                    "failed to get object metadata: {}",
+                    err
+                )),
+            })
+            .map(move |exists| {
+                self.remember_existence(key, exists);
+                exists
+            });
+        future::Either::B(fut)
+    }
+
+    pub(crate) fn delete(
+        self: Arc<Self>,
+        bucket: &str,
+        object: &str,
+    ) -> impl Future<Item = (), Error = anyhow::Error> {
+        use rusoto_core::RusotoError;
+        use rusoto_s3::{DeleteObjectRequest, S3Client, S3};
+
+        let core = match self.core() {
+            Ok(val) => val,
+            Err(err) => return future::Either::A(future::err(err)),
+        };
+        let key = (bucket.to_owned(), object.to_owned());
+        let delete_req = DeleteObjectRequest {
+            bucket: bucket.to_owned(),
+            key: object.to_owned(),
+            request_payer: self.request_payer_value(),
+            ..Default::default()
+        };
+
+        let fut = self
+            .retry_in_region(
+                bucket,
+                move |region| {
+                    S3Client::new_with_client(core.clone(), region)
+                        .delete_object(delete_req.clone())
+                },
+                |err| match *err {
+                    RusotoError::Unknown(ref resp) => redirect_region(
+                        resp.status,
+                        resp.headers.get(BUCKET_REGION_HEADER).map(String::as_str),
+                    ),
+                    _ => None,
+                },
+            )
+            .map(move |_| self.invalidate(&key.0, &key.1))
+            .map_err(|err| anyhow::format_err!("failed to delete object: {}", err));
+        future::Either::B(fut)
+    }
+
+    // Failures to get an answer are never remembered, nor are answers about objects with a signed write pending
+    fn remember_existence(&self, key: (String, String), exists: bool) {
+        if self.signed_writes.get(&key).is_some() {
+            return;
+        }
+        let ttl = match self.existence_ttl {
+            Some(ttl) if exists => ttl.found,
+            Some(ttl) => ttl.missing,
+            None => return,
+        };
+        if ttl > Duration::from_secs(0) {
+            self.existence.insert(key, exists, ttl);
+        }
+    }
+
+    fn invalidate(&self, bucket: &str, object: &str) {
+        self.existence
+            .remove_where(|(b, o)| b == bucket && o == object);
+        self.listings
+            .remove_where(|(b, prefix, _, _)| b == bucket && object.starts_with(prefix.as_str()));
+    }
+
+    // Pages of prefixes with a signed write pending aren't remembered, the object may show up any time
+    fn remember_listing(&self, key: ListingKey, listing: &Listing) {
+        let (ref bucket, ref prefix, _, _) = key;
+        if self
+            .signed_writes
+            .contains_where(|(b, o)| b == bucket && o.starts_with(prefix.as_str()))
+        {
+            return;
+        }
+        match self.listing_ttl {
+            Some(ttl) if ttl > Duration::from_secs(0) => {
+                self.listings.insert(key, listing.clone(), ttl)
+            }
+            _ => (),
+        }
+    }
+
+    // A single page of the listing is requested, so that the work per request stays bounded
+    pub(crate) fn list(
+        self: Arc<Self>,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+        max_keys: Option<u64>,
+    ) -> impl Future<Item = Listing, Error = anyhow::Error> {
+        use rusoto_core::RusotoError;
+        use rusoto_s3::{ListObjectsV2Request, S3Client, S3};
+
+        let key = (
+            bucket.to_owned(),
+            prefix.to_owned(),
+            continuation_token.clone(),
+            max_keys,
+        );
+        if let Some(listing) = self.listings.get(&key) {
+            return future::Either::A(future::ok(listing));
+        }
+
+        let core = match self.core() {
+            Ok(val) => val,
+            Err(err) => return future::Either::A(future::err(err)),
+        };
+        let list_req = ListObjectsV2Request {
+            bucket: bucket.to_owned(),
+            prefix: Some(prefix.to_owned()),
+            continuation_token,
+            max_keys: max_keys.map(|val| val as i64),
+            request_payer: self.request_payer_value(),
+            ..Default::default()
+        };
+
+        let fut = self
+            .retry_in_region(
+                bucket,
+                move |region| {
+                    S3Client::new_with_client(core.clone(), region)
+                        .list_objects_v2(list_req.clone())
+                },
+                |err| match *err {
+                    RusotoError::Unknown(ref resp) => redirect_region(
+                        resp.status,
+                        resp.headers.get(BUCKET_REGION_HEADER).map(String::as_str),
+                    ),
+                    _ => None,
+                },
+            )
+            .map(|output| Listing {
+                keys: output
+                    .contents
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|object| object.key)
+                    .collect(),
+                continuation_token: output.next_continuation_token,
+            })
+            .map(move |listing| {
+                self.remember_listing(key, &listing);
+                listing
+            })
+            .map_err(|err| anyhow::format_err!("failed to list objects: {}", err));
+        future::Either::B(fut)
+    }
+
+    fn core(&self) -> Result<rusoto_core::Client> {
+        use rusoto_core::credential::StaticProvider;
+
+        let mut core = self.core.0.lock().expect("Error acquiring core lock");
+        if let Some(ref core) = *core {
+            return Ok(core.clone());
+        }
+
+        let dispatcher = http_client(self.tls.as_ref())?;
+        let credentials = {
+            let credentials = self
+                .credentials
+                .read()
+                .expect("Error acquiring credentials lock");
+            StaticProvider::new_minimal(
+                credentials.aws_access_key_id().to_owned(),
+                credentials.aws_secret_access_key().to_owned(),
+            )
+        };
+        let dispatcher = UserAgentDispatcher {
+            inner: dispatcher,
+            user_agent: self.user_agent.clone(),
+        };
+        let client = rusoto_core::Client::new_with(credentials, dispatcher);
+        *core = Some(client.clone());
+        Ok(client)
+    }
+
+    fn request_payer_value(&self) -> Option<String> {
+        if self.request_payer {
+            Some(String::from("requester"))
+        } else {
+            None
+        }
+    }
+
+    // The region a bucket was redirected to is remembered, so that following requests and signatures use it
+    fn bucket_region(&self, bucket: &str) -> Region {
+        let regions = self
+            .bucket_regions
+            .read()
+            .expect("Error acquiring bucket regions lock");
+
+        match (regions.get(bucket), &self.region) {
+            (Some(name), Region::Custom { endpoint, .. }) => Region::Custom {
+                name: name.to_owned(),
+                endpoint: endpoint.to_owned(),
+            },
+            _ => self.region.clone(),
+        }
+    }
+
+    // A bucket living in another region is answered with a redirect naming it, the request is retried there once
+    fn retry_in_region<T, E, F, R, H>(
+        &self,
+        bucket: &str,
+        request: F,
+        region_hint: H,
+    ) -> impl Future<Item = T, Error = E>
+    where
+        F: Fn(Region) -> R,
+        R: Future<Item = T, Error = E>,
+        H: FnOnce(&E) -> Option<String>,
+    {
+        let bucket = bucket.to_owned();
+        let regions = self.bucket_regions.clone();
+        let region = self.bucket_region(&bucket);
+
+        request(region.clone()).or_else(move |err| {
+            let redirected = match (region_hint(&err), region) {
+                (
+                    Some(name),
+                    Region::Custom {
+                        name: current,
+                        endpoint,
+                    },
+                ) if name != current => Some(Region::Custom { name, endpoint }),
+                _ => None,
+            };
+
+            match redirected {
+                Some(region) => {
+                    warn!(
+                        "Bucket = '{}' is redirected to region = '{}'",
+                        bucket,
+                        region.name()
+                    );
+                    regions
+                        .write()
+                        .expect("Error acquiring bucket regions lock")
+                        .insert(bucket, region.name().to_owned());
+                    future::Either::A(request(region))
+                }
+                None => future::Either::B(future::err(err)),
+            }
+        })
+    }
+}
+
+const BUCKET_REGION_HEADER: &str = "x-amz-bucket-region";
+
+// S3 redirects requests for buckets of another region either permanently or temporarily
+fn redirect_region(status: http::StatusCode, region: Option<&str>) -> Option<String> {
+    match (status, region) {
+        (http::StatusCode::MOVED_PERMANENTLY, Some(region))
+        | (http::StatusCode::TEMPORARY_REDIRECT, Some(region)) => Some(region.to_owned()),
+        _ => None,
+    }
+}
+
+// Responses to HEAD requests have no body, so a missing object is only told by the status
+fn object_missing(err: &rusoto_core::RusotoError<rusoto_s3::HeadObjectError>) -> bool {
+    use rusoto_core::RusotoError;
+    use rusoto_s3::HeadObjectError;
+
+    match *err {
+        RusotoError::Service(HeadObjectError::NoSuchKey(_)) => true,
+        RusotoError::Unknown(ref resp) => resp.status == http::StatusCode::NOT_FOUND,
+        _ => false,
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Download {
+    Inline {
+        body: Vec<u8>,
+        content_type: Option<String>,
+    },
+    Redirect {
+        uri: String,
+        size: Option<i64>,
+    },
+}
+
+impl Download {
+    // Size of the object as told by its metadata
+    pub(crate) fn size(&self) -> Option<i64> {
+        match self {
+            Download::Inline { body, .. } => Some(body.len() as i64),
+            Download::Redirect { size, .. } => *size,
+        }
+    }
+}
+
+// The user agent is set once the request is signed, it isn't a part of the signature
+struct UserAgentDispatcher<D> {
+    inner: D,
+    user_agent: String,
+}
+
+impl<D> DispatchSignedRequest for UserAgentDispatcher<D>
+where
+    D: DispatchSignedRequest,
+{
+    type Future = D::Future;
+
+    fn dispatch(&self, mut request: SignedRequest, timeout: Option<Duration>) -> Self::Future {
+        request.headers.insert(
+            String::from("user-agent"),
+            vec![self.user_agent.as_bytes().to_vec()],
+        );
+        self.inner.dispatch(request, timeout)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Listing {
+    pub(crate) keys: Vec<String>,
+    pub(crate) continuation_token: Option<String>,
+}
+
+// Objects of unknown size are never fetched
+fn inline(content_length: Option<i64>, max_size: u64) -> bool {
+    match content_length {
+        Some(size) if size >= 0 => size as u64 <= max_size,
+        _ => false,
+    }
+}
+
+fn strip_query_auth(url: &str) -> Result<String> {
+    let mut parsed_url = Url::parse(url).context("failed to parse generated uri")?;
+    let pairs = parsed_url
+        .query_pairs()
+        .filter(|(key, _)| !key.starts_with("X-Amz-"))
+        .map(|(key, val)| (key.into_owned(), val.into_owned()))
+        .collect::<Vec<(String, String)>>();
+
+    if pairs.is_empty() {
+        parsed_url.set_query(None);
+    } else {
+        parsed_url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    Ok(parsed_url.to_string())
+}
+
+// Requester-pays buckets only accept object reads and writes with the header signed
+pub(crate) fn add_request_payer(req: &mut SignedRequest) {
+    if req.headers.contains_key("x-amz-request-payer") {
+        return;
+    }
+
+    match req.method.as_ref() {
+        "GET" | "HEAD" | "PUT" => req.add_header("x-amz-request-payer", "requester"),
+        _ => (),
+    }
+}
+
+// Asks the backend for the region of the bucket, the result is only used to derive signatures
+pub(crate) fn probe_region(
+    key: &str,
+    secret: &str,
+    region: &str,
+    endpoint: &str,
+    bucket: &str,
+    tls: Option<&Tls>,
+) -> Result<String> {
+    use rusoto_core::credential::StaticProvider;
+    use rusoto_s3::{GetBucketLocationRequest, S3Client, S3};
+
+    let dispatcher = http_client(tls)?;
+    let credentials = StaticProvider::new_minimal(key.to_owned(), secret.to_owned());
+    let region = Region::Custom {
+        name: region.to_string(),
+        endpoint: endpoint.to_string(),
+    };
+
+    let req = GetBucketLocationRequest {
+        bucket: bucket.to_owned(),
+    };
+    let output = S3Client::new_with(dispatcher, credentials, region)
+        .get_bucket_location(req)
+        .sync()
+        .map_err(|err| anyhow::format_err!("failed to get bucket location: {}", err))?;
+
+    Ok(location_region(output.location_constraint.as_deref()))
+}
+
+// An empty location constraint stands for the classic region, "EU" is its legacy alias
+fn location_region(location_constraint: Option<&str>) -> String {
+    match location_constraint {
+        None | Some("") => String::from("us-east-1"),
+        Some("EU") => String::from("eu-west-1"),
+        Some(val) => val.to_owned(),
+    }
+}
+
+pub(crate) fn select_region(configured: &str, probed: Result<String>) -> String {
+    match probed {
+        Ok(region) => region,
+        Err(err) => {
+            warn!(
+                "Falling back to the configured region = '{}': {}",
+                configured, err
+            );
+            configured.to_owned()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> Client {
+        Client::new(
+            "key",
+            "secret",
+            "us-east-1",
+            "https://s3.example.org",
+            Duration::from_secs(300),
+        )
+    }
+
+    #[test]
+    fn request_payer_signed() {
+        let mut client = client();
+        client.set_request_payer(true);
+
+        for method in &["GET", "HEAD", "PUT"] {
+            let uri = client
+                .presigned_url(method, "data.example.org", "foo.bar")
+                .expect("Error signing a request");
+            assert!(uri.contains("x-amz-request-payer"), "{}", uri);
+        }
+
+        let uri = client
+            .presigned_url("DELETE", "data.example.org", "foo.bar")
+            .expect("Error signing a request");
+        assert!(!uri.contains("x-amz-request-payer"), "{}", uri);
+    }
+
+    #[test]
+    fn expected_bucket_owner_signed() {
+        let uri = client()
+            .presigned_url("GET", "data.example.org", "foo.bar")
+            .expect("Error signing a request");
+        assert!(!uri.contains("x-amz-expected-bucket-owner"), "{}", uri);
+
+        let mut client = client();
+        client.set_expected_bucket_owner("111122223333");
+        for method in &["GET", "HEAD", "PUT", "DELETE"] {
+            let mut req = client.create_request(method, "data.example.org", "foo.bar");
+            assert!(req.headers.contains_key("x-amz-expected-bucket-owner"));
+            let uri = client
+                .sign_request(&mut req)
+                .expect("Error signing a request");
+            assert!(uri.contains("x-amz-expected-bucket-owner"), "{}", uri);
+        }
+    }
+
+    // Captures headers of dispatched requests, never responds
+    struct MockDispatcher {
+        headers: Arc<std::sync::Mutex<Vec<BTreeMap<String, Vec<Vec<u8>>>>>>,
+    }
+
+    impl DispatchSignedRequest for MockDispatcher {
+        type Future = future::FutureResult<HttpResponse, HttpDispatchError>;
+
+        fn dispatch(&self, request: SignedRequest, _timeout: Option<Duration>) -> Self::Future {
+            self.headers
+                .lock()
+                .expect("Error acquiring headers lock")
+                .push(request.headers);
+            future::err(HttpDispatchError::new(String::from("mock")))
+        }
+    }
+
+    #[test]
+    fn user_agent_sent() {
+        let headers = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let dispatcher = UserAgentDispatcher {
+            inner: MockDispatcher {
+                headers: headers.clone(),
+            },
+            user_agent: String::from("storage-media/1.0"),
+        };
+
+        let req = client().create_request("HEAD", "data.example.org", "foo.bar");
+        assert!(dispatcher.dispatch(req, None).wait().is_err());
+
+        let headers = headers.lock().expect("Error acquiring headers lock");
+        assert_eq!(
+            headers[0].get("user-agent"),
+            Some(&vec![b"storage-media/1.0".to_vec()])
+        );
+        assert!(DEFAULT_USER_AGENT.starts_with("storage/"));
+    }
+
+    #[test]
+    fn max_url_length_exceeded() {
+        let mut client = client();
+        client.set_max_url_length(256);
+
+        let object = "x".repeat(256);
+        let err = client
+            .presigned_url("GET", "data.example.org", &object)
+            .expect_err("Expected an over-length uri to be rejected");
+        assert!(err.is::<UrlTooLong>());
+
+        client.set_max_url_length(4096);
+        assert!(client
+            .presigned_url("GET", "data.example.org", &object)
+            .is_ok());
+    }
+
+    #[test]
+    fn redirect_loop_rejected() {
+        let mut client = client();
+        client.set_service_hosts(&["storage.example.org".to_owned()]);
+        assert!(client
+            .presigned_url("GET", "data.example.org", "foo.bar")
+            .is_ok());
+
+        client.set_proxy_host("storage.example.org");
+        let err = client
+            .presigned_url("GET", "data.example.org", "foo.bar")
+            .expect_err("Expected a self-referential uri to be rejected");
+        assert!(err.is::<RedirectLoop>());
+    }
+
+    #[test]
+    fn probed_region_selected() {
+        assert_eq!(
+            select_region("us-east-1", Ok(String::from("eu-central-1"))),
+            "eu-central-1"
+        );
+        assert_eq!(
+            select_region("us-east-1", Err(anyhow::format_err!("unreachable"))),
+            "us-east-1"
+        );
+    }
+
+    #[test]
+    fn location_constraint_region() {
+        assert_eq!(location_region(None), "us-east-1");
+        assert_eq!(location_region(Some("")), "us-east-1");
+        assert_eq!(location_region(Some("EU")), "eu-west-1");
+        assert_eq!(location_region(Some("ap-south-1")), "ap-south-1");
+    }
+
+    #[test]
+    fn request_payer_disabled() {
+        let uri = client()
+            .presigned_url("GET", "data.example.org", "foo.bar")
+            .expect("Error signing a request");
+        assert!(!uri.contains("x-amz-request-payer"), "{}", uri);
+    }
+
+    #[test]
+    fn download_inline_small_object() {
+        assert!(inline(Some(0), 1024));
+        assert!(inline(Some(1024), 1024));
+    }
+
+    #[test]
+    fn download_redirect_large_object() {
+        assert!(!inline(Some(1025), 1024));
+        assert!(!inline(None, 1024));
+    }
+
+    #[test]
+    fn extra_query_appended() {
+        let mut client = client();
+        client.set_extra_query("token=abc", false);
+        let uri = client
+            .presigned_url("GET", "data.example.org", "foo.bar")
+            .expect("Error signing a request");
+        assert!(uri.ends_with("&token=abc"), "{}", uri);
+
+        client.set_extra_query("?token=abc", true);
+        let uri = client
+            .presigned_url("GET", "data.example.org", "foo.bar")
+            .expect("Error signing a request");
+        let query = uri.splitn(2, '?').nth(1).expect("Missing query string");
+        assert!(query.split('&').any(|p| p == "token=abc"), "{}", uri);
+        assert!(!uri.ends_with("&token=abc"), "{}", uri);
+    }
+
+    #[test]
+    fn service_name_in_credential_scope() {
+        let mut client = client();
+        let uri = client
+            .presigned_url("GET", "data.example.org", "foo.bar")
+            .expect("Error signing a request");
+        assert!(uri.contains("%2Fus-east-1%2Fs3%2Faws4_request"), "{}", uri);
+
+        client.set_service_name("gateway");
+        let uri = client
+            .presigned_url("GET", "data.example.org", "foo.bar")
+            .expect("Error signing a request");
+        assert!(
+            uri.contains("%2Fus-east-1%2Fgateway%2Faws4_request"),
+            "{}",
+            uri
+        );
+    }
+
+    #[test]
+    fn other_service_signed() {
+        let mut client = client();
+        client.set_proxy_host("cdn.example.org");
+        let mut services = BTreeMap::new();
+        services.insert(
+            "mediaservice".to_owned(),
+            "https://media.example.org".to_owned(),
+        );
+        client.set_services(&services);
+
+        let mut req = client
+            .create_service_request("mediaservice", "GET", "data.example.org", "foo.bar")
+            .expect("Error creating a request");
+        let uri = client
+            .sign_request(&mut req)
+            .expect("Error signing a request");
+        assert!(
+            uri.starts_with("https://media.example.org/data.example.org/foo.bar?"),
+            "{}",
+            uri
+        );
+        assert!(
+            uri.contains("X-Amz-Credential=key%2F")
+                && uri.contains("%2Fus-east-1%2Fmediaservice%2Faws4_request"),
+            "{}",
+            uri
+        );
+
+        // The backend itself is signed as usual
+        let mut req = client
+            .create_service_request("s3", "GET", "data.example.org", "foo.bar")
+            .expect("Error creating a request");
+        let uri = client
+            .sign_request(&mut req)
+            .expect("Error signing a request");
+        assert!(uri.starts_with("https://cdn.example.org/"), "{}", uri);
+        assert!(uri.contains("%2Fus-east-1%2Fs3%2Faws4_request"), "{}", uri);
+
+        assert!(client
+            .create_service_request("unknown", "GET", "data.example.org", "foo.bar")
+            .is_err());
+    }
+
+    #[test]
+    fn anonymous_url_unsigned() {
+        let mut client = client();
+        client.set_anonymous(true);
+        let uri = client
+            .presigned_url("GET", "data.example.org", "foo.bar")
+            .expect("Error signing a request");
+        assert_eq!(uri, "https://s3.example.org/data.example.org/foo.bar");
+
+        let mut req = client.create_request("GET", "data.example.org", "foo.bar");
+        req.add_param("response-content-type", "text/plain");
+        let uri = client
+            .sign_request(&mut req)
+            .expect("Error signing a request");
+        assert!(!uri.contains("X-Amz-Signature"), "{}", uri);
+        assert!(
+            uri.ends_with("?response-content-type=text%2Fplain"),
+            "{}",
+            uri
+        );
+
+        client.set_extra_query("token=abc", false);
+        let uri = client
+            .presigned_url("GET", "data.example.org", "foo.bar")
+            .expect("Error signing a request");
+        assert_eq!(
+            uri,
+            "https://s3.example.org/data.example.org/foo.bar?token=abc"
+        );
+    }
+
+    #[test]
+    fn missing_object_told_apart() {
+        use rusoto_core::request::BufferedHttpResponse;
+        use rusoto_core::RusotoError;
+        use rusoto_s3::HeadObjectError;
+
+        let unknown = |status| {
+            RusotoError::Unknown(BufferedHttpResponse {
+                status,
+                body: Default::default(),
+                headers: Default::default(),
+            })
+        };
+        assert!(object_missing(&unknown(http::StatusCode::NOT_FOUND)));
+        assert!(object_missing(&RusotoError::Service(
+            HeadObjectError::NoSuchKey("foo.bar".to_owned())
+        )));
+        assert!(!object_missing(&unknown(http::StatusCode::FORBIDDEN)));
+        assert!(!object_missing(&unknown(
+            http::StatusCode::INTERNAL_SERVER_ERROR
+        )));
+    }
+
+    // Nothing listens on the endpoint, so answers not served from the cache fail
+    fn unreachable() -> Client {
+        Client::new(
+            "key",
+            "secret",
+            "us-east-1",
+            "http://127.0.0.1:1",
+            Duration::from_secs(300),
+        )
+    }
+
+    fn unreachable_client() -> Arc<Client> {
+        let mut client = unreachable();
+        client.set_existence_cache(Duration::from_secs(60), Duration::from_secs(60));
+        Arc::new(client)
+    }
+
+    fn exists(client: &Arc<Client>, object: &str) -> Result<bool> {
+        tokio::runtime::current_thread::Runtime::new()
+            .expect("Error creating a runtime")
+            .block_on(client.clone().object_exists("data.example.org", object))
+    }
+
+    #[test]
+    fn missing_object_cached() {
+        let client = unreachable_client();
+        assert!(exists(&client, "foo.bar").is_err());
+
+        client.remember_existence(("data.example.org".to_owned(), "foo.bar".to_owned()), false);
+        assert_eq!(exists(&client, "foo.bar").ok(), Some(false));
+        assert_eq!(exists(&client, "foo.bar").ok(), Some(false));
+        assert!(exists(&client, "other.bar").is_err());
+    }
+
+    #[test]
+    fn missing_object_invalidated_by_write() {
+        let client = unreachable_client();
+        client.remember_existence(("data.example.org".to_owned(), "foo.bar".to_owned()), false);
+        client.remember_existence(
+            ("data.example.org".to_owned(), "other.bar".to_owned()),
+            false,
+        );
+
+        // Reads leave the answer intact
+        client
+            .presigned_url("GET", "data.example.org", "foo.bar")
+            .expect("Error signing a request");
+        assert_eq!(exists(&client, "foo.bar").ok(), Some(false));
+
+        client
+            .presigned_url("PUT", "data.example.org", "foo.bar")
+            .expect("Error signing a request");
+        assert!(exists(&client, "foo.bar").is_err());
+        assert_eq!(exists(&client, "other.bar").ok(), Some(false));
+    }
+
+    #[test]
+    fn missing_object_not_cached_until_signed_write_expires() {
+        let mut client = unreachable();
+        client.set_existence_cache(Duration::from_secs(60), Duration::from_secs(60));
+        let client = Arc::new(client);
+        let key = || ("data.example.org".to_owned(), "foo.bar".to_owned());
+
+        client
+            .sign_request_expiring(
+                &mut client.create_request("PUT", "data.example.org", "foo.bar"),
+                &Duration::from_millis(100),
+            )
+            .expect("Error signing a request");
+
+        // The object is checked before the client uploads it, the answer is stale once it does
+        client.remember_existence(key(), false);
+        assert!(exists(&client, "foo.bar").is_err());
+        client.remember_existence(key(), true);
+        assert!(exists(&client, "foo.bar").is_err());
+
+        // Once the signature expires no write is pending anymore
+        std::thread::sleep(Duration::from_millis(150));
+        client.remember_existence(key(), true);
+        assert_eq!(exists(&client, "foo.bar").ok(), Some(true));
+    }
+
+    #[test]
+    fn existence_ttl() {
+        let mut client = client();
+        client.set_existence_cache(Duration::from_secs(0), Duration::from_secs(60));
+
+        let key = |object: &str| ("data.example.org".to_owned(), object.to_owned());
+        client.remember_existence(key("found.bar"), true);
+        client.remember_existence(key("missing.bar"), false);
+        assert_eq!(client.existence.get(&key("found.bar")), None);
+        assert_eq!(client.existence.get(&key("missing.bar")), Some(false));
+
+        let cache = TtlCache::new();
+        cache.insert("foo", 1, Duration::from_millis(10));
+        assert_eq!(cache.get(&"foo"), Some(1));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get(&"foo"), None);
+    }
+
+    fn list(client: &Arc<Client>, prefix: &str) -> Result<Listing> {
+        tokio::runtime::current_thread::Runtime::new()
+            .expect("Error creating a runtime")
+            .block_on(
+                client
+                    .clone()
+                    .list("data.example.org", prefix, None, Some(10)),
+            )
+    }
+
+    fn listing(keys: &[&str]) -> Listing {
+        Listing {
+            keys: keys.iter().map(|key| (*key).to_owned()).collect(),
+            continuation_token: None,
+        }
+    }
+
+    #[test]
+    fn listing_cached() {
+        let mut client = unreachable();
+        client.set_listing_cache(Duration::from_millis(500));
+        let client = Arc::new(client);
+        let key = |prefix: &str| {
+            (
+                "data.example.org".to_owned(),
+                prefix.to_owned(),
+                None,
+                Some(10),
+            )
+        };
+
+        client.remember_listing(key("foo."), &listing(&["foo.a", "foo.b"]));
+        client.remember_listing(key("bar."), &listing(&["bar.a"]));
+        assert_eq!(
+            list(&client, "foo.").ok(),
+            Some(listing(&["foo.a", "foo.b"]))
+        );
+        assert!(list(&client, "baz.").is_err());
+
+        // Writes under the prefix forget the listing, the ones of other prefixes are kept
+        client
+            .presigned_url("PUT", "data.example.org", "foo.c")
+            .expect("Error signing a request");
+        assert!(list(&client, "foo.").is_err());
+        assert_eq!(list(&client, "bar.").ok(), Some(listing(&["bar.a"])));
+
+        std::thread::sleep(Duration::from_millis(600));
+        assert!(list(&client, "bar.").is_err());
+    }
+
+    #[test]
+    fn listing_not_cached_until_signed_write_expires() {
+        let mut client = unreachable();
+        client.set_listing_cache(Duration::from_secs(60));
+        let client = Arc::new(client);
+        let key = |prefix: &str| {
+            (
+                "data.example.org".to_owned(),
+                prefix.to_owned(),
+                None,
+                Some(10),
+            )
+        };
+
+        client
+            .sign_request_expiring(
+                &mut client.create_request("PUT", "data.example.org", "foo.c"),
+                &Duration::from_millis(100),
+            )
+            .expect("Error signing a request");
+
+        // The prefix is listed before the client uploads the object, the page is stale once it does
+        client.remember_listing(key("foo."), &listing(&["foo.a"]));
+        assert!(list(&client, "foo.").is_err());
+        client.remember_listing(key("bar."), &listing(&["bar.a"]));
+        assert_eq!(list(&client, "bar.").ok(), Some(listing(&["bar.a"])));
+
+        // Once the signature expires no write is pending anymore
+        std::thread::sleep(Duration::from_millis(150));
+        client.remember_listing(key("foo."), &listing(&["foo.a", "foo.c"]));
+        assert_eq!(
+            list(&client, "foo.").ok(),
+            Some(listing(&["foo.a", "foo.c"]))
+        );
+    }
+
+    #[test]
+    fn custom_ca_trusted() {
+        let ca = include_bytes!("testdata/ca.pem");
+        let identity = include_bytes!("testdata/client.p12");
+
+        let tls = Tls::new(Some(ca), None, false).expect("Error configuring TLS");
+        assert!(http_client(Some(&tls)).is_ok());
+        let tls = Tls::new(Some(ca), Some((identity, "storage")), false)
+            .expect("Error configuring mutual TLS");
+        assert!(http_client(Some(&tls)).is_ok());
+        assert!(Tls::new(None, None, true).is_ok());
+
+        let mut client = client();
+        assert!(client.core().is_ok());
+        client.set_tls(tls);
+        assert!(client
+            .core
+            .0
+            .lock()
+            .expect("Error acquiring core lock")
+            .is_none());
+        assert!(client.core().is_ok());
+        assert!(client
+            .core
+            .0
+            .lock()
+            .expect("Error acquiring core lock")
+            .is_some());
+
+        assert!(Tls::new(Some(b"not a certificate"), None, false).is_err());
+        assert!(Tls::new(Some(ca), Some((identity, "wrong")), false).is_err());
+    }
+
+    #[test]
+    fn region_redirect_followed() {
+        use std::sync::Mutex;
+
+        let client = client();
+        let attempts = Mutex::new(Vec::new());
+        let result = client
+            .retry_in_region(
+                "data.example.org",
+                |region| {
+                    attempts
+                        .lock()
+                        .expect("Error locking attempts")
+                        .push(region.name().to_owned());
+                    if region.name() == "eu-west-1" {
+                        future::ok("object")
+                    } else {
+                        future::err(http::StatusCode::TEMPORARY_REDIRECT)
+                    }
+                },
+                |status| redirect_region(*status, Some("eu-west-1")),
+            )
+            .wait();
+
+        assert_eq!(result, Ok("object"));
+        assert_eq!(
+            *attempts.lock().expect("Error locking attempts"),
+            vec!["us-east-1", "eu-west-1"]
+        );
+
+        // The corrected region is used by following requests and signatures
+        assert_eq!(client.bucket_region("data.example.org").name(), "eu-west-1");
+        assert_eq!(
+            client.bucket_region("other.example.org").name(),
+            "us-east-1"
+        );
+        let uri = client
+            .presigned_url("GET", "data.example.org", "foo.bar")
+            .expect("Error signing a request");
+        assert!(uri.contains("%2Feu-west-1%2Fs3%2Faws4_request"), "{}", uri);
+    }
+
+    #[test]
+    fn region_redirect_followed_once() {
+        let client = client();
+        let result: std::result::Result<(), http::StatusCode> = client
+            .retry_in_region(
+                "data.example.org",
+                |_| future::err(http::StatusCode::TEMPORARY_REDIRECT),
+                |status| redirect_region(*status, Some("us-east-1")),
+            )
+            .wait();
+        assert_eq!(result, Err(http::StatusCode::TEMPORARY_REDIRECT));
+
+        assert_eq!(
+            redirect_region(http::StatusCode::MOVED_PERMANENTLY, Some("eu-west-1")),
+            Some("eu-west-1".to_owned())
+        );
+        assert_eq!(
+            redirect_region(http::StatusCode::NOT_FOUND, Some("eu-west-1")),
+            None
+        );
+        assert_eq!(
+            redirect_region(http::StatusCode::TEMPORARY_REDIRECT, None),
+            None
+        );
+    }
 }
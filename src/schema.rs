@@ -9,3 +9,47 @@ table! {
         created_at -> Timestamptz,
     }
 }
+
+table! {
+    use diesel::sql_types::*;
+    use crate::db::sql::*;
+
+    object_size (bucket, object) {
+        audience -> Text,
+        bucket -> Text,
+        object -> Text,
+        size -> Int8,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::db::sql::*;
+
+    tombstone (backend, bucket, object) {
+        audience -> Text,
+        backend -> Text,
+        bucket -> Text,
+        object -> Text,
+        deleted_at -> Timestamptz,
+        purge_at -> Timestamptz,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::db::sql::*;
+
+    share (token) {
+        token -> Text,
+        backend -> Text,
+        bucket -> Text,
+        object -> Text,
+        created_by -> Text,
+        max_downloads -> Nullable<Int8>,
+        downloads -> Int8,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+    }
+}
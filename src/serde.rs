@@ -1,5 +1,7 @@
+use http::header::HeaderName;
+use regex::Regex;
 use serde::de;
-use serde::de::{Deserializer, Error, SeqAccess, Unexpected, Visitor};
+use serde::de::{Deserialize, Deserializer, Error, SeqAccess, Unexpected, Visitor};
 use std::fmt;
 use std::time::Duration;
 use tower_web::middleware::cors::AllowedOrigins;
@@ -73,3 +75,38 @@ where
 {
     deserializer.deserialize_seq(AllowedOriginsVisitor)
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) fn regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    Regex::new(&value).map_err(|_| Error::invalid_value(Unexpected::Str(&value), &"a regex"))
+}
+
+pub(crate) fn optional_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| {
+            Regex::new(&value)
+                .map_err(|_| Error::invalid_value(Unexpected::Str(&value), &"a regex"))
+        })
+        .transpose()
+}
+
+pub(crate) fn header_names<'de, D>(deserializer: D) -> Result<Vec<HeaderName>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|value| {
+            HeaderName::from_bytes(value.as_bytes())
+                .map_err(|_| Error::invalid_value(Unexpected::Str(&value), &"a header name"))
+        })
+        .collect()
+}